@@ -20,13 +20,15 @@ use std::error::Error;
 use std::rc::Rc;
 use std::cell::RefCell;
 use std::process::exit;
+use std::time::Duration;
 
 use clap::{App, Arg};
 use log::LogLevelFilter;
 use env_logger::LogBuilder;
 use dbus::WatchEvent;
 
-use libstratis::engine::{Engine, SimEngine, StratEngine};
+use libstratis::engine::{Engine, EngineLimits, SimEngine, StratEngine};
+use libstratis::engine::strat_engine::set_mount_timeout;
 use libstratis::stratis::{StratisResult, StratisError, VERSION};
 
 /// Try to write the error from the program to stderr, vehemently.
@@ -57,6 +59,28 @@ fn run() -> StratisResult<()> {
         .arg(Arg::with_name("sim")
                  .long("sim")
                  .help("Use simulator engine"))
+        .arg(Arg::with_name("repair-mdv")
+                 .long("repair-mdv")
+                 .help("Attempt to repair pool metadata volumes that can not be mounted"))
+        .arg(Arg::with_name("safe-mode")
+                 .long("safe-mode")
+                 .conflicts_with("repair-mdv")
+                 .help("Quarantine pools which fail an integrity probe instead of failing"))
+        .arg(Arg::with_name("mount-timeout")
+                 .long("mount-timeout")
+                 .value_name("SECONDS")
+                 .validator(|v| v.parse::<u64>().map(|_| ()).map_err(|e| e.to_string()))
+                 .help("Give up on a mount or unmount which takes longer than this"))
+        .arg(Arg::with_name("max-pools")
+                 .long("max-pools")
+                 .value_name("COUNT")
+                 .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                 .help("Refuse to create more than this many pools"))
+        .arg(Arg::with_name("max-filesystems")
+                 .long("max-filesystems")
+                 .value_name("COUNT")
+                 .validator(|v| v.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                 .help("Refuse to create more than this many filesystems in a pool"))
         .get_matches();
 
     let mut builder = LogBuilder::new();
@@ -73,13 +97,30 @@ fn run() -> StratisResult<()> {
         .init()
         .expect("This is the first and only initialization of the logger; it must succeed");
 
+    if let Some(secs) = matches.value_of("mount-timeout") {
+        set_mount_timeout(Duration::from_secs(secs.parse().expect("validated by clap")));
+    }
+
+    let mut limits = EngineLimits::default();
+    if let Some(count) = matches.value_of("max-pools") {
+        limits.max_pools = count.parse().expect("validated by clap");
+    }
+    if let Some(count) = matches.value_of("max-filesystems") {
+        limits.max_filesystems_per_pool = count.parse().expect("validated by clap");
+    }
+
     let engine: Rc<RefCell<Engine>> = {
         if matches.is_present("sim") {
             info!("Using SimEngine");
-            Rc::new(RefCell::new(SimEngine::default()))
+            Rc::new(RefCell::new(SimEngine::new(limits)))
         } else {
             info!("Using StratEngine");
-            Rc::new(RefCell::new(StratEngine::initialize()?))
+            let engine = if matches.is_present("safe-mode") {
+                StratEngine::initialize_safe(limits)?
+            } else {
+                StratEngine::initialize(matches.is_present("repair-mdv"), limits)?
+            };
+            Rc::new(RefCell::new(engine))
         }
     };
 
@@ -109,8 +150,17 @@ fn run() -> StratisResult<()> {
             }
         }
 
-        // Ask the engine to check its pools
-        engine.borrow_mut().check()
+        // Ask the engine to check its pools, unless the check is paused
+        match engine.borrow_mut().periodic_check() {
+            Some(Ok(report)) => {
+                for &(pool_uuid, ref err) in &report.failed {
+                    warn!("Could not check pool {}: {}", pool_uuid, err);
+                }
+            }
+            Some(Err(err)) => warn!("Could not check pools: {}", err),
+            None => {}
+        }
+        libstratis::dbus_api::send_signals(&dbus_conn, &dbus_context);
     }
 }
 
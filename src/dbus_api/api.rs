@@ -25,13 +25,13 @@ use dbus::tree::PropInfo;
 use dbus::tree::Tree;
 use dbus::ConnectionItem;
 
-use engine::Engine;
+use engine::{AvailableBlockDev, CreateForce, DevOwnership, Engine, EngineEvent, EngineResult};
 use stratis::VERSION;
 
-use super::filesystem::create_dbus_filesystem;
+use super::filesystem::{create_dbus_filesystem, space_low_signal};
 use super::blockdev::create_dbus_blockdev;
-use super::pool::create_dbus_pool;
-use super::types::{ActionQueue, DeferredAction, DbusContext, DbusErrorEnum, TData};
+use super::pool::{create_dbus_pool, pool_size_signals};
+use super::types::{DeferredAction, DbusContext, DbusErrorEnum, TData};
 use super::util::STRATIS_BASE_PATH;
 use super::util::STRATIS_BASE_SERVICE;
 use super::util::engine_to_dbus_err_tuple;
@@ -40,6 +40,8 @@ use super::util::msg_code_ok;
 use super::util::msg_string_ok;
 use super::util::tuple_to_option;
 
+/// Create a pool. If force is true, devices which carry the signature of
+/// another application are overwritten.
 fn create_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     let message: &Message = m.msg;
     let mut iter = message.iter_init();
@@ -51,28 +53,89 @@ fn create_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
 
     let blockdevs = devs.map(|x| Path::new(x)).collect::<Vec<&Path>>();
 
+    let force = CreateForce {
+        overwrite_signatures: force,
+        ..CreateForce::default()
+    };
+    create_pool_force(m, name, &blockdevs, redundancy, force)
+}
+
+/// Create a pool, overriding each of the checks on its devices which is
+/// asked for separately; see CreateForce.
+fn create_pool_with_overrides(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let mut iter = message.iter_init();
+
+    let name: &str = get_next_arg(&mut iter, 0)?;
+    let redundancy: (bool, u16) = get_next_arg(&mut iter, 1)?;
+    let (overwrite_signatures, ignore_size, reuse_partial): (bool, bool, bool) =
+        get_next_arg(&mut iter, 2)?;
+    let devs: Array<&str, _> = get_next_arg(&mut iter, 3)?;
+
+    let blockdevs = devs.map(Path::new).collect::<Vec<&Path>>();
+
+    let force = CreateForce {
+        overwrite_signatures: overwrite_signatures,
+        ignore_size: ignore_size,
+        reuse_partial: reuse_partial,
+    };
+    create_pool_force(m, name, &blockdevs, redundancy, force)
+}
+
+fn create_pool_force(m: &MethodInfo<MTFn<TData>, TData>,
+                     name: &str,
+                     blockdevs: &[&Path],
+                     redundancy: (bool, u16),
+                     force: CreateForce)
+                     -> MethodResult {
+    let message: &Message = m.msg;
     let object_path = m.path.get_name();
     let dbus_context = m.tree.get_data();
     let mut engine = dbus_context.engine.borrow_mut();
-    let result = engine.create_pool(name, &blockdevs, tuple_to_option(redundancy), force);
+    let result = engine.create_pool(name,
+                                    blockdevs,
+                                    tuple_to_option(redundancy),
+                                    force,
+                                    None,
+                                    None,
+                                    &[]);
 
     let return_message = message.method_return();
 
-    let default_return: (dbus::Path, Vec<dbus::Path>) = (dbus::Path::default(), Vec::new());
+    let default_return: (bool, (dbus::Path, Vec<dbus::Path>)) =
+        (false, (dbus::Path::default(), Vec::new()));
 
     let msg = match result {
-        Ok(pool_uuid) => {
-            let pool_object_path: dbus::Path =
-                create_dbus_pool(dbus_context, object_path.clone(), pool_uuid);
-
+        Ok(action) => {
+            let changed = action.is_changed();
+            let pool_uuid = action.into_inner();
             let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
 
-            let bd_object_paths = pool.blockdevs()
-                .iter()
-                .map(|bd| create_dbus_blockdev(dbus_context, pool_object_path.clone(), bd.uuid()))
-                .collect::<Vec<_>>();
-
-            return_message.append3((pool_object_path, bd_object_paths),
+            // A pool which already existed already has its object paths.
+            let (pool_object_path, bd_object_paths) = if changed {
+                let pool_object_path: dbus::Path =
+                    create_dbus_pool(dbus_context, object_path.clone(), pool_uuid);
+                let bd_object_paths = pool.blockdevs()
+                    .iter()
+                    .map(|bd| {
+                             create_dbus_blockdev(dbus_context,
+                                                  pool_object_path.clone(),
+                                                  bd.uuid())
+                         })
+                    .collect::<Vec<_>>();
+                (pool_object_path, bd_object_paths)
+            } else {
+                let bd_object_paths = pool.blockdevs()
+                    .iter()
+                    .filter_map(|bd| dbus_context.get_object_path(bd.uuid()))
+                    .collect::<Vec<_>>();
+                (dbus_context
+                     .get_object_path(pool_uuid)
+                     .unwrap_or_default(),
+                 bd_object_paths)
+            };
+
+            return_message.append3((changed, (pool_object_path, bd_object_paths)),
                                    msg_code_ok(),
                                    msg_string_ok())
         }
@@ -90,6 +153,7 @@ fn destroy_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     let mut iter = message.iter_init();
 
     let object_path: dbus::Path<'static> = get_next_arg(&mut iter, 0)?;
+    let force: bool = get_next_arg(&mut iter, 1)?;
 
     let dbus_context = m.tree.get_data();
 
@@ -103,13 +167,22 @@ fn destroy_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
         }
     };
 
-    let msg = match dbus_context.engine.borrow_mut().destroy_pool(pool_uuid) {
+    // Filesystems of the pool are destroyed along with it if force is set.
+    let fs_uuids: Vec<_> = match dbus_context.engine.borrow().get_pool(pool_uuid) {
+        Some(pool) => pool.filesystems().iter().map(|fs| fs.uuid()).collect(),
+        None => vec![],
+    };
+
+    let msg = match dbus_context.engine.borrow_mut().destroy_pool(pool_uuid, force) {
         Ok(action) => {
-            dbus_context
-                .actions
-                .borrow_mut()
-                .push_remove(object_path);
-            return_message.append3(action, msg_code_ok(), msg_string_ok())
+            let mut actions = dbus_context.actions.borrow_mut();
+            for fs_uuid in fs_uuids {
+                if let Some(fs_path) = dbus_context.get_object_path(fs_uuid) {
+                    actions.push_remove(fs_path);
+                }
+            }
+            actions.push_remove(object_path);
+            return_message.append3(action.is_changed(), msg_code_ok(), msg_string_ok())
         }
         Err(err) => {
             let (rc, rs) = engine_to_dbus_err_tuple(&err);
@@ -119,11 +192,128 @@ fn destroy_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     Ok(vec![msg])
 }
 
+/// Every pool which has an object path, as an (object path, name, UUID)
+/// triple.
+fn pool_list(dbus_context: &DbusContext) -> Vec<(dbus::Path<'static>, String, String)> {
+    dbus_context
+        .engine
+        .borrow()
+        .pools()
+        .iter()
+        .filter_map(|pool| {
+                        dbus_context
+                            .get_object_path(pool.uuid())
+                            .map(|path| {
+                                     (path,
+                                      pool.name().to_owned(),
+                                      format!("{}", pool.uuid().simple()))
+                                 })
+                    })
+        .collect()
+}
+
+fn list_pools(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let pools = pool_list(m.tree.get_data());
+    Ok(vec![message
+                .method_return()
+                .append3(pools, msg_code_ok(), msg_string_ok())])
+}
+
+/// An engine event as a (kind, pool UUID, object UUID) triple. The object
+/// UUID is empty if the event concerns only the pool.
+fn event_tuple(event: &EngineEvent) -> (String, String, String) {
+    (event.kind().to_owned(),
+     format!("{}", event.pool_uuid().simple()),
+     event
+         .object_uuid()
+         .map_or_else(String::new, |uuid| format!("{}", uuid.simple())))
+}
+
+fn get_events(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let events: Vec<_> = m.tree
+        .get_data()
+        .engine
+        .borrow()
+        .recent_events()
+        .iter()
+        .map(event_tuple)
+        .collect();
+    Ok(vec![message
+                .method_return()
+                .append3(events, msg_code_ok(), msg_string_ok())])
+}
+
+/// What is known of the device at path as a (path, ownership, pool UUID,
+/// size in bytes, meets minimum size, error) tuple. The pool UUID is empty
+/// unless the device belongs to a Stratis pool. If the device could not be
+/// examined, the error describes why and the other fields are empty.
+fn available_blockdev_tuple(path: &str,
+                            result: &EngineResult<AvailableBlockDev>)
+                            -> (String, String, String, u64, bool, String) {
+    match *result {
+        Ok(ref dev) => {
+            let (ownership, pool_uuid) = match dev.ownership {
+                DevOwnership::Ours(pool_uuid, _) => ("Ours", format!("{}", pool_uuid.simple())),
+                DevOwnership::Unowned => ("Unowned", String::new()),
+                DevOwnership::Theirs => ("Theirs", String::new()),
+            };
+            (path.to_owned(),
+             ownership.to_owned(),
+             pool_uuid,
+             *dev.size,
+             dev.meets_min_size,
+             String::new())
+        }
+        Err(ref err) => {
+            (path.to_owned(), String::new(), String::new(), 0, false, format!("{}", err))
+        }
+    }
+}
+
+fn list_available_blockdevs(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let mut iter = message.iter_init();
+
+    let devs: Array<&str, _> = get_next_arg(&mut iter, 0)?;
+    let devs = devs.collect::<Vec<&str>>();
+    let paths = devs.iter().map(|x| Path::new(x)).collect::<Vec<&Path>>();
+
+    let available: Vec<_> = m.tree
+        .get_data()
+        .engine
+        .borrow()
+        .available_blockdevs(&paths)
+        .iter()
+        .zip(devs.iter())
+        .map(|(result, dev)| available_blockdev_tuple(dev, result))
+        .collect();
+    Ok(vec![message
+                .method_return()
+                .append3(available, msg_code_ok(), msg_string_ok())])
+}
+
+fn get_metrics(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let metrics = m.tree.get_data().engine.borrow().metrics();
+    Ok(vec![message
+                .method_return()
+                .append3(metrics, msg_code_ok(), msg_string_ok())])
+}
+
 fn get_version(i: &mut IterAppend, _p: &PropInfo<MTFn<TData>, TData>) -> Result<(), MethodErr> {
     i.append(VERSION);
     Ok(())
 }
 
+fn get_metadata_version(i: &mut IterAppend,
+                        p: &PropInfo<MTFn<TData>, TData>)
+                        -> Result<(), MethodErr> {
+    i.append(p.tree.get_data().engine.borrow().metadata_version());
+    Ok(())
+}
+
 fn configure_simulator(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     let message = m.msg;
     let mut iter = message.iter_init();
@@ -148,6 +338,28 @@ fn configure_simulator(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     Ok(vec![msg])
 }
 
+fn set_check_paused(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message = m.msg;
+    let mut iter = message.iter_init();
+
+    let paused: bool = get_next_arg(&mut iter, 0)?;
+
+    let dbus_context = m.tree.get_data();
+    let mut engine = dbus_context.engine.borrow_mut();
+    let changed = engine.check_paused() != paused;
+    engine.set_check_paused(paused);
+
+    let return_message = message.method_return();
+    Ok(vec![return_message.append3(changed, msg_code_ok(), msg_string_ok())])
+}
+
+fn get_check_paused(i: &mut IterAppend,
+                    p: &PropInfo<MTFn<TData>, TData>)
+                    -> Result<(), MethodErr> {
+    i.append(p.tree.get_data().engine.borrow().check_paused());
+    Ok(())
+}
+
 fn get_base_tree<'a>(dbus_context: DbusContext) -> (Tree<MTFn<TData>, TData>, dbus::Path<'a>) {
 
     let f = Factory::new_fn();
@@ -159,26 +371,80 @@ fn get_base_tree<'a>(dbus_context: DbusContext) -> (Tree<MTFn<TData>, TData>, db
         .in_arg(("redundancy", "(bq)"))
         .in_arg(("force", "b"))
         .in_arg(("devices", "as"))
-        .out_arg(("result", "(oao)"))
+        .out_arg(("result", "(b(oao))"))
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
+    let create_pool_with_overrides_method =
+        f.method("CreatePoolWithOverrides", (), create_pool_with_overrides)
+            .in_arg(("name", "s"))
+            .in_arg(("redundancy", "(bq)"))
+            .in_arg(("overrides", "(bbb)"))
+            .in_arg(("devices", "as"))
+            .out_arg(("result", "(b(oao))"))
+            .out_arg(("return_code", "q"))
+            .out_arg(("return_string", "s"));
+
     let destroy_pool_method = f.method("DestroyPool", (), destroy_pool)
         .in_arg(("pool", "o"))
+        .in_arg(("force", "b"))
         .out_arg(("action", "b"))
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
+    let list_pools_method = f.method("ListPools", (), list_pools)
+        .out_arg(("pools", "a(oss)"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
     let configure_simulator_method = f.method("ConfigureSimulator", (), configure_simulator)
         .in_arg(("denominator", "u"))
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
+    let get_events_method = f.method("GetEvents", (), get_events)
+        .out_arg(("events", "a(sss)"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let list_available_blockdevs_method =
+        f.method("ListAvailableBlockDevs", (), list_available_blockdevs)
+            .in_arg(("devices", "as"))
+            .out_arg(("devices", "a(ssstbs)"))
+            .out_arg(("return_code", "q"))
+            .out_arg(("return_string", "s"));
+
+    let get_metrics_method = f.method("GetMetrics", (), get_metrics)
+        .out_arg(("metrics", "s"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let set_check_paused_method = f.method("SetCheckPaused", (), set_check_paused)
+        .in_arg(("paused", "b"))
+        .out_arg(("changed", "b"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let event_signal = f.signal("Event", ())
+        .sarg::<&str, _>("kind")
+        .sarg::<&str, _>("pool")
+        .sarg::<&str, _>("object");
+
     let version_property = f.property::<&str, _>("Version", ())
         .access(Access::Read)
         .emits_changed(EmitsChangedSignal::Const)
         .on_get(get_version);
 
+    let metadata_version_property = f.property::<u32, _>("MetadataVersion", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::Const)
+        .on_get(get_metadata_version);
+
+    let check_paused_property = f.property::<bool, _>("CheckPaused", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_check_paused);
+
     let interface_name = format!("{}.{}", STRATIS_BASE_SERVICE, "Manager");
 
     let obj_path = f.object_path(STRATIS_BASE_PATH, None)
@@ -186,9 +452,18 @@ fn get_base_tree<'a>(dbus_context: DbusContext) -> (Tree<MTFn<TData>, TData>, db
         .object_manager()
         .add(f.interface(interface_name, ())
                  .add_m(create_pool_method)
+                 .add_m(create_pool_with_overrides_method)
                  .add_m(destroy_pool_method)
+                 .add_m(list_pools_method)
                  .add_m(configure_simulator_method)
-                 .add_p(version_property));
+                 .add_m(get_events_method)
+                 .add_m(list_available_blockdevs_method)
+                 .add_m(get_metrics_method)
+                 .add_m(set_check_paused_method)
+                 .add_s(event_signal)
+                 .add_p(version_property)
+                 .add_p(metadata_version_property)
+                 .add_p(check_paused_property));
 
     let path = obj_path.get_name().to_owned();
     (base_tree.add(obj_path), path)
@@ -221,25 +496,35 @@ pub fn connect(engine: Rc<RefCell<Engine>>)
 
     c.register_name(STRATIS_BASE_SERVICE, NameFlag::ReplaceExisting as u32)?;
 
-    process_deferred_actions(&c, &mut tree, &mut dbus_context.actions.borrow_mut())?;
+    process_deferred_actions(&c, &mut tree, &dbus_context)?;
 
     Ok((c, tree, dbus_context))
 }
 
 /// Update the dbus tree with deferred adds and removes.
+/// Keep the UUID to object path index in step with the tree.
 fn process_deferred_actions(c: &Connection,
                             tree: &mut Tree<MTFn<TData>, TData>,
-                            actions: &mut ActionQueue)
+                            dbus_context: &DbusContext)
                             -> Result<(), dbus::Error> {
+    let mut actions = dbus_context.actions.borrow_mut();
+    let mut object_paths = dbus_context.object_paths.borrow_mut();
     for action in actions.drain() {
         match action {
             DeferredAction::Add(path) => {
                 c.register_object_path(path.get_name())?;
+                if let Some(ref data) = *path.get_data() {
+                    object_paths.insert(data.uuid, path.get_name().clone());
+                }
                 tree.insert(path);
             }
             DeferredAction::Remove(path) => {
                 c.unregister_object_path(&path);
-                tree.remove(&path);
+                if let Some(object_path) = tree.remove(&path) {
+                    if let Some(ref data) = *object_path.get_data() {
+                        object_paths.remove(&data.uuid);
+                    }
+                }
             }
         }
     }
@@ -260,8 +545,119 @@ pub fn handle(c: &Connection,
             }
         }
 
-        process_deferred_actions(c, tree, &mut dbus_context.actions.borrow_mut())?;
+        process_deferred_actions(c, tree, dbus_context)?;
+        send_signals(c, dbus_context);
     }
 
     Ok(())
 }
+
+/// An Event signal for each event which has occurred since the last call,
+/// and a SpaceLow signal from each filesystem which an event reports to be
+/// low on space.
+fn event_signals(dbus_context: &DbusContext) -> Vec<Message> {
+    let interface_name = format!("{}.{}", STRATIS_BASE_SERVICE, "Manager");
+    let events = dbus_context.engine.borrow_mut().drain_events();
+    let mut signals = Vec::new();
+    for event in &events {
+        let (kind, pool, object) = event_tuple(event);
+        signals.push(Message::new_signal(STRATIS_BASE_PATH, interface_name.as_str(), "Event")
+                         .expect("path, interface, and member names are valid")
+                         .append3(kind, pool, object));
+        if let EngineEvent::FilesystemSpaceLow(_, fs_uuid, used) = *event {
+            signals.extend(space_low_signal(dbus_context, fs_uuid, used));
+        }
+    }
+    signals
+}
+
+/// Send any signals for changes in the state of the engine.
+pub fn send_signals(c: &Connection, dbus_context: &DbusContext) {
+    for m in event_signals(dbus_context) {
+        let _ = c.send(m);
+    }
+    for m in pool_size_signals(dbus_context) {
+        let _ = c.send(m);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use engine::{EngineError, ErrorEnum, IEC, SimEngine};
+
+    use super::*;
+
+    #[test]
+    /// Every pool is listed with its object path, name, and UUID.
+    fn list_pools() {
+        let engine: Rc<RefCell<Engine>> = Rc::new(RefCell::new(SimEngine::default()));
+        let (tree, base_path) = get_base_tree(DbusContext::new(Rc::clone(&engine)));
+        let dbus_context = tree.get_data().clone();
+
+        let mut expected = Vec::new();
+        for name in &["pool1", "pool2"] {
+            let pool_uuid = engine
+                .borrow_mut()
+                .create_pool(name, &[], None, CreateForce::default(), None, None, &[])
+                .unwrap()
+                .into_inner();
+            let pool_path = create_dbus_pool(&dbus_context, base_path.clone(), pool_uuid);
+            expected.push((pool_path, name.to_string(), format!("{}", pool_uuid.simple())));
+        }
+
+        // Index the new object paths, as process_deferred_actions() does.
+        for action in dbus_context.actions.borrow_mut().drain() {
+            if let DeferredAction::Add(path) = action {
+                if let Some(ref data) = *path.get_data() {
+                    dbus_context
+                        .object_paths
+                        .borrow_mut()
+                        .insert(data.uuid, path.get_name().clone());
+                }
+            }
+        }
+
+        let mut listed = pool_list(&dbus_context);
+        listed.sort();
+        expected.sort();
+        assert_eq!(listed, expected);
+    }
+
+    #[test]
+    /// A device which belongs to a pool is listed with the pool's UUID,
+    /// one which does not is listed as unowned, and one which could not be
+    /// examined is listed with its error.
+    fn list_available_blockdevs() {
+        let mut engine = SimEngine::default();
+        let pool_uuid = engine
+            .create_pool("name",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+
+        let available = engine.available_blockdevs(&[Path::new("/s/d"), Path::new("/s/e")]);
+        assert_eq!(available_blockdev_tuple("/s/d", &available[0]),
+                   ("/s/d".into(),
+                    "Ours".into(),
+                    format!("{}", pool_uuid.simple()),
+                    IEC::Gi,
+                    true,
+                    "".into()));
+        assert_eq!(available_blockdev_tuple("/s/e", &available[1]),
+                   ("/s/e".into(), "Unowned".into(), "".into(), IEC::Gi, true, "".into()));
+
+        let err = Err(EngineError::Engine(ErrorEnum::NotFound, "no such device".into()));
+        let (_, ownership, _, size, meets_min_size, error) = available_blockdev_tuple("/s/f",
+                                                                                      &err);
+        assert_eq!((ownership.as_str(), size, meets_min_size), ("", 0, false));
+        assert!(error.contains("no such device"));
+    }
+}
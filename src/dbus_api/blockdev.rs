@@ -16,7 +16,7 @@ use dbus::tree::PropInfo;
 
 use uuid::Uuid;
 
-use super::super::engine::BlockDev;
+use super::super::engine::{BlockDev, HardwareInfo};
 use super::super::engine::types::BlockDevState;
 
 use super::types::{DbusContext, DbusErrorEnum, OPContext, TData};
@@ -42,6 +42,11 @@ pub fn create_dbus_blockdev<'a>(dbus_context: &DbusContext,
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
+    let get_health_method = f.method("GetHealth", (), get_health)
+        .out_arg(("health", "s"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
     let devnode_property = f.property::<&str, _>("Devnode", ())
         .access(Access::Read)
         .emits_changed(EmitsChangedSignal::Const)
@@ -52,6 +57,16 @@ pub fn create_dbus_blockdev<'a>(dbus_context: &DbusContext,
         .emits_changed(EmitsChangedSignal::Const)
         .on_get(get_blockdev_hardware_info);
 
+    let model_property = f.property::<&str, _>("Model", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::Const)
+        .on_get(get_blockdev_model);
+
+    let serial_property = f.property::<&str, _>("Serial", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::Const)
+        .on_get(get_blockdev_serial);
+
     let user_info_property = f.property::<&str, _>("UserInfo", ())
         .access(Access::Read)
         .emits_changed(EmitsChangedSignal::False)
@@ -67,6 +82,11 @@ pub fn create_dbus_blockdev<'a>(dbus_context: &DbusContext,
         .emits_changed(EmitsChangedSignal::False)
         .on_get(get_blockdev_physical_size);
 
+    let metadata_size_property = f.property::<&str, _>("MetadataSize", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::Const)
+        .on_get(get_blockdev_metadata_size);
+
     let state_property = f.property::<u16, _>("State", ())
         .access(Access::Read)
         .emits_changed(EmitsChangedSignal::False)
@@ -92,11 +112,15 @@ pub fn create_dbus_blockdev<'a>(dbus_context: &DbusContext,
         .introspectable()
         .add(f.interface(interface_name, ())
                  .add_m(set_userid_method)
+                 .add_m(get_health_method)
                  .add_p(devnode_property)
                  .add_p(hardware_info_property)
                  .add_p(initialization_time_property)
                  .add_p(total_physical_size_property)
+                 .add_p(metadata_size_property)
+                 .add_p(model_property)
                  .add_p(pool_property)
+                 .add_p(serial_property)
                  .add_p(state_property)
                  .add_p(user_info_property)
                  .add_p(uuid_property));
@@ -156,6 +180,40 @@ fn set_user_info(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     Ok(vec![msg])
 }
 
+/// Query the blockdev for its SMART health, "" if it could not be queried.
+/// This is a method rather than a property, since the query may be slow.
+fn get_health(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return = "";
+
+    let blockdev_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let blockdev_data = get_data!(blockdev_path; default_return; return_message);
+
+    let pool_path = get_parent!(m; blockdev_data; default_return; return_message);
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let engine = dbus_context.engine.borrow();
+    let blockdev = match engine
+              .get_pool(pool_uuid)
+              .and_then(|pool| pool.get_blockdev(blockdev_data.uuid)) {
+        Some(blockdev) => blockdev,
+        None => {
+            let message = format!("no blockdev with uuid {}", blockdev_data.uuid);
+            let (rc, rs) = (u16::from(DbusErrorEnum::INTERNAL_ERROR), message);
+            return Ok(vec![return_message.append3(default_return, rc, rs)]);
+        }
+    };
+
+    let health = blockdev.health().unwrap_or_default();
+    Ok(vec![return_message.append3(health, msg_code_ok(), msg_string_ok())])
+}
+
 
 /// Get a blockdev property and place it on the D-Bus. The property is
 /// found by means of the getter method which takes a reference to a
@@ -221,7 +279,35 @@ fn get_blockdev_devnode(i: &mut IterAppend,
 fn get_blockdev_hardware_info(i: &mut IterAppend,
                               p: &PropInfo<MTFn<TData>, TData>)
                               -> Result<(), MethodErr> {
-    get_blockdev_property(i, p, |p| Ok(p.hardware_info().unwrap_or("").to_owned()))
+    get_blockdev_property(i, p, |p| Ok(p.hardware_id().unwrap_or("").to_owned()))
+}
+
+/// Get a string from the hardware info of the blockdev, "" if the device
+/// does not report it.
+fn get_blockdev_hardware_field<F>(i: &mut IterAppend,
+                                  p: &PropInfo<MTFn<TData>, TData>,
+                                  field: F)
+                                  -> Result<(), MethodErr>
+    where F: Fn(&HardwareInfo) -> Option<&String>
+{
+    get_blockdev_property(i, p, |p| {
+        Ok(p.hardware_info()
+               .and_then(&field)
+               .cloned()
+               .unwrap_or_default())
+    })
+}
+
+fn get_blockdev_model(i: &mut IterAppend,
+                      p: &PropInfo<MTFn<TData>, TData>)
+                      -> Result<(), MethodErr> {
+    get_blockdev_hardware_field(i, p, |info| info.model.as_ref())
+}
+
+fn get_blockdev_serial(i: &mut IterAppend,
+                       p: &PropInfo<MTFn<TData>, TData>)
+                       -> Result<(), MethodErr> {
+    get_blockdev_hardware_field(i, p, |info| info.serial.as_ref())
 }
 
 fn get_blockdev_user_info(i: &mut IterAppend,
@@ -242,6 +328,12 @@ fn get_blockdev_physical_size(i: &mut IterAppend,
     get_blockdev_property(i, p, |p| Ok(format!("{}", *p.total_size())))
 }
 
+fn get_blockdev_metadata_size(i: &mut IterAppend,
+                              p: &PropInfo<MTFn<TData>, TData>)
+                              -> Result<(), MethodErr> {
+    get_blockdev_property(i, p, |p| Ok(format!("{}", *p.metadata_size())))
+}
+
 fn get_blockdev_state(i: &mut IterAppend,
                       p: &PropInfo<MTFn<TData>, TData>)
                       -> Result<(), MethodErr> {
@@ -16,20 +16,22 @@ use dbus::tree::PropInfo;
 
 use uuid::Uuid;
 
-use engine::RenameAction;
+use engine::{AllocationInfo, RenameAction};
 
-use super::super::engine::Filesystem;
+use super::super::engine::{Filesystem, Pool};
 
 use super::types::{DbusContext, DbusErrorEnum, OPContext, TData};
 
 use super::util::STRATIS_BASE_PATH;
 use super::util::STRATIS_BASE_SERVICE;
+use super::util::bytes_to_sectors_round_up;
 use super::util::engine_to_dbus_err_tuple;
 use super::util::get_next_arg;
 use super::util::get_parent;
 use super::util::get_uuid;
 use super::util::msg_code_ok;
 use super::util::msg_string_ok;
+use super::util::sectors_to_bytes;
 
 
 pub fn create_dbus_filesystem<'a>(dbus_context: &DbusContext,
@@ -44,6 +46,21 @@ pub fn create_dbus_filesystem<'a>(dbus_context: &DbusContext,
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
+    let set_size_method = f.method("SetFilesystemSize", (), set_filesystem_size)
+        .in_arg(("size", "t"))
+        .out_arg(("size", "t"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let allocation_info_method = f.method("AllocationInfo", (), allocation_info)
+        .out_arg(("allocation", "(b(ttty))"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let space_low_signal = f.signal("SpaceLow", ())
+        .sarg::<&dbus::Path, _>("filesystem")
+        .sarg::<u8, _>("used");
+
     let devnode_property = f.property::<&str, _>("Devnode", ())
         .access(Access::Read)
         .emits_changed(EmitsChangedSignal::Const)
@@ -54,6 +71,26 @@ pub fn create_dbus_filesystem<'a>(dbus_context: &DbusContext,
         .emits_changed(EmitsChangedSignal::False)
         .on_get(get_filesystem_name);
 
+    let created_property = f.property::<u64, _>("Created", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::Const)
+        .on_get(get_filesystem_created);
+
+    let mounted_property = f.property::<bool, _>("Mounted", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_filesystem_mounted);
+
+    let size_property = f.property::<u64, _>("Size", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_filesystem_size);
+
+    let origin_property = f.property::<&str, _>("Origin", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::Const)
+        .on_get(get_filesystem_origin);
+
     let pool_property = f.property::<&dbus::Path, _>("Pool", ())
         .access(Access::Read)
         .emits_changed(EmitsChangedSignal::Const)
@@ -74,9 +111,16 @@ pub fn create_dbus_filesystem<'a>(dbus_context: &DbusContext,
         .introspectable()
         .add(f.interface(interface_name, ())
                  .add_m(rename_method)
+                 .add_m(set_size_method)
+                 .add_m(allocation_info_method)
+                 .add_s(space_low_signal)
+                 .add_p(created_property)
                  .add_p(devnode_property)
+                 .add_p(mounted_property)
                  .add_p(name_property)
+                 .add_p(origin_property)
                  .add_p(pool_property)
+                 .add_p(size_property)
                  .add_p(uuid_property));
 
     let path = object_path.get_name().to_owned();
@@ -84,6 +128,20 @@ pub fn create_dbus_filesystem<'a>(dbus_context: &DbusContext,
     path
 }
 
+/// A SpaceLow signal for the filesystem with this UUID, which is using the
+/// given percentage of its space, or None if the filesystem has no object
+/// path.
+pub fn space_low_signal(dbus_context: &DbusContext, fs_uuid: Uuid, used: u8) -> Option<Message> {
+    let interface_name = format!("{}.{}", STRATIS_BASE_SERVICE, "filesystem");
+    dbus_context
+        .get_object_path(fs_uuid)
+        .map(|path| {
+                 Message::new_signal(&*path, interface_name.as_str(), "SpaceLow")
+                     .expect("path, interface, and member names are valid")
+                     .append2(path, used)
+             })
+}
+
 fn rename_filesystem(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     let message: &Message = m.msg;
     let mut iter = message.iter_init();
@@ -127,6 +185,106 @@ fn rename_filesystem(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     Ok(vec![msg])
 }
 
+/// Grow the filesystem to a size in bytes, rounded up to a whole number of
+/// sectors. Returns the size of the filesystem afterwards, in bytes.
+fn set_filesystem_size(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let mut iter = message.iter_init();
+
+    let size: u64 = get_next_arg(&mut iter, 0)?;
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return: u64 = 0;
+
+    let filesystem_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let filesystem_data = get_data!(filesystem_path; default_return; return_message);
+
+    let pool_path = get_parent!(m; filesystem_data; default_return; return_message);
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let mut engine = dbus_context.engine.borrow_mut();
+    let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
+
+    let (new_size, rc, rs) = set_size(pool, filesystem_data.uuid, size);
+    Ok(vec![return_message.append3(new_size, rc, rs)])
+}
+
+/// Grow the filesystem fs_uuid of pool to size bytes, rounded up to a whole
+/// number of sectors.
+/// Returns the size of the filesystem afterwards in bytes, or 0 on failure,
+/// with the return code and string of the SetFilesystemSize method.
+fn set_size(pool: &mut Pool, fs_uuid: Uuid, size: u64) -> (u64, u16, String) {
+    match pool.set_filesystem_size(fs_uuid, bytes_to_sectors_round_up(size)) {
+        Ok(new_size) => {
+            match sectors_to_bytes(new_size) {
+                Some(bytes) => (bytes, msg_code_ok(), msg_string_ok()),
+                None => {
+                    let error_message = format!("size {} can not be expressed in bytes",
+                                                new_size);
+                    (0, u16::from(DbusErrorEnum::INTERNAL_ERROR), error_message)
+                }
+            }
+        }
+        Err(err) => {
+            let (rc, rs) = engine_to_dbus_err_tuple(&err);
+            (0, rc, rs)
+        }
+    }
+}
+
+/// A filesystem's allocation, as passed to a D-Bus client.
+type AllocationTuple = (bool, (u64, u64, u64, u8));
+
+/// The allocation is a struct of whether it could be found, and, if so,
+/// a struct of the filesystem's size in bytes, the number of its pool's
+/// data blocks allocated to it, the number of bytes in those blocks, and
+/// the estimated fragmentation of the allocation as a percentage.
+fn allocation_tuple(info: Option<AllocationInfo>) -> AllocationTuple {
+    match info {
+        Some(info) => {
+            (true,
+             (*info.size.bytes(),
+              *info.allocated_blocks,
+              *info.allocated.bytes(),
+              info.fragmentation))
+        }
+        None => (false, (0, 0, 0, 0)),
+    }
+}
+
+fn allocation_info(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return = allocation_tuple(None);
+
+    let filesystem_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let filesystem_data = get_data!(filesystem_path; default_return; return_message);
+
+    let pool_path = get_parent!(m; filesystem_data; default_return; return_message);
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let mut engine = dbus_context.engine.borrow_mut();
+    let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
+
+    let msg = match pool.filesystem_allocation_info(filesystem_data.uuid) {
+        Ok(info) => return_message.append3(allocation_tuple(info), msg_code_ok(), msg_string_ok()),
+        Err(err) => {
+            let (rc, rs) = engine_to_dbus_err_tuple(&err);
+            return_message.append3(default_return, rc, rs)
+        }
+    };
+    Ok(vec![msg])
+}
+
 /// Get a filesystem property and place it on the D-Bus. The property is
 /// found by means of the getter method which takes a reference to a
 /// Filesystem and obtains the property from the filesystem.
@@ -193,3 +351,90 @@ fn get_filesystem_name(i: &mut IterAppend,
                        -> Result<(), MethodErr> {
     get_filesystem_property(i, p, |f| Ok(f.name().to_owned()))
 }
+
+fn get_filesystem_created(i: &mut IterAppend,
+                          p: &PropInfo<MTFn<TData>, TData>)
+                          -> Result<(), MethodErr> {
+    get_filesystem_property(i, p, |f| Ok(f.created().timestamp() as u64))
+}
+
+fn get_filesystem_mounted(i: &mut IterAppend,
+                          p: &PropInfo<MTFn<TData>, TData>)
+                          -> Result<(), MethodErr> {
+    get_filesystem_property(i, p, |f| {
+        f.is_mounted()
+            .map_err(|err| MethodErr::failed(&err.to_string()))
+    })
+}
+
+/// The logical size of the filesystem in bytes, regardless of how much of
+/// it is used.
+fn get_filesystem_size(i: &mut IterAppend,
+                       p: &PropInfo<MTFn<TData>, TData>)
+                       -> Result<(), MethodErr> {
+    get_filesystem_property(i, p, |f| {
+        sectors_to_bytes(f.size())
+            .ok_or_else(|| MethodErr::failed(&"filesystem size overflows u64 bytes"))
+    })
+}
+
+/// The UUID of the filesystem of which the filesystem is a snapshot, or
+/// the empty string if it is not a snapshot.
+fn get_filesystem_origin(i: &mut IterAppend,
+                         p: &PropInfo<MTFn<TData>, TData>)
+                         -> Result<(), MethodErr> {
+    get_filesystem_property(i, p, |f| {
+        Ok(f.snapshot_of()
+               .map_or_else(String::new, |uuid| uuid.simple().to_string()))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use devicemapper::{DataBlocks, Sectors};
+
+    use engine::{CreateForce, Engine, SimEngine};
+
+    use super::*;
+
+    #[test]
+    /// A filesystem is grown to the requested size, rounded up to a whole
+    /// number of sectors, and can not be shrunk.
+    fn set_filesystem_size() {
+        let mut engine = SimEngine::default();
+        let pool_uuid = engine
+            .create_pool("pool", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(pool_uuid).unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", Some(Sectors(1024)), None)])
+            .unwrap()
+            .into_inner()[0]
+            .1;
+
+        assert_eq!(set_size(pool, fs_uuid, 2048 * 512),
+                   (2048 * 512, msg_code_ok(), msg_string_ok()));
+        assert_eq!(set_size(pool, fs_uuid, 4096 * 512 + 1).0, 4097 * 512);
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().size(), Sectors(4097));
+
+        let (size, rc, _) = set_size(pool, fs_uuid, 1024 * 512);
+        assert_eq!(size, 0);
+        assert_eq!(rc, u16::from(DbusErrorEnum::INVALID));
+    }
+
+    #[test]
+    /// An allocation is passed as its size and allocated space in bytes,
+    /// its number of allocated blocks, and its fragmentation; a missing
+    /// allocation is flagged as such.
+    fn allocation() {
+        let info = AllocationInfo {
+            size: Sectors(4096),
+            allocated_blocks: DataBlocks(1),
+            allocated: Sectors(2048),
+            fragmentation: 50,
+        };
+        assert_eq!(allocation_tuple(Some(info)),
+                   (true, (4096 * 512, 1, 2048 * 512, 50)));
+        assert_eq!(allocation_tuple(None), (false, (0, 0, 0, 0)));
+    }
+}
@@ -3,13 +3,18 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::collections::HashMap;
+use std::collections::hash_map::Entry;
 use std::path::Path;
 use std::vec::Vec;
 
 use dbus;
 use dbus::Message;
+use dbus::SignalArgs;
 use dbus::arg::Array;
 use dbus::arg::IterAppend;
+use dbus::arg::RefArg;
+use dbus::arg::Variant;
+use dbus::stdintf::org_freedesktop_dbus::PropertiesPropertiesChanged;
 use dbus::tree::Access;
 use dbus::tree::EmitsChangedSignal;
 use dbus::tree::Factory;
@@ -21,15 +26,15 @@ use dbus::tree::PropInfo;
 
 use uuid::Uuid;
 
-use devicemapper::Sectors;
-
-use engine::{Pool, RenameAction};
+use engine::{CreateForce, DataLowWater, DestroyAction, Engine, Pool, PoolCheckReport, PoolUuid,
+             RenameAction};
 
 use super::blockdev::create_dbus_blockdev;
 use super::filesystem::create_dbus_filesystem;
-use super::types::{DbusContext, DbusErrorEnum, OPContext, TData};
+use super::types::{DbusContext, DbusErrorEnum, OPContext, PoolSizes, TData};
 
-use super::util::{engine_to_dbus_err_tuple, get_next_arg, get_uuid, msg_code_ok, msg_string_ok,
+use super::util::{bytes_to_sectors, engine_to_dbus_err_tuple, get_next_arg, get_uuid,
+                  msg_code_ok, msg_string_ok, sectors_to_bytes, tuple_to_option,
                   STRATIS_BASE_PATH, STRATIS_BASE_SERVICE};
 
 fn create_filesystems(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
@@ -41,8 +46,7 @@ fn create_filesystems(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
 
     let object_path = m.path.get_name();
     let return_message = message.method_return();
-    let default_return: Vec<(dbus::Path, &str)> = Vec::new();
-
+    let default_return: (bool, Vec<(dbus::Path, &str)>) = (false, Vec::new());
 
     let pool_path = m.tree
         .get(object_path)
@@ -52,21 +56,21 @@ fn create_filesystems(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     let mut engine = dbus_context.engine.borrow_mut();
     let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
 
-    let result =
-        pool.create_filesystems(&filesystems
-                                     .map(|x| (x, None))
-                                     .collect::<Vec<(&str, Option<Sectors>)>>());
+    let result = pool.create_filesystems(&filesystems
+                                              .map(|x| (x, None, None))
+                                              .collect::<Vec<_>>());
 
     let msg = match result {
-        Ok(ref infos) => {
+        Ok(action) => {
+            let changed = action.is_changed();
             let mut return_value = Vec::new();
-            for &(name, uuid) in infos {
+            for (name, uuid) in action.into_inner() {
                 let fs_object_path: dbus::Path =
                     create_dbus_filesystem(dbus_context, object_path.clone(), uuid);
                 return_value.push((fs_object_path, name));
             }
 
-            return_message.append3(return_value, msg_code_ok(), msg_string_ok())
+            return_message.append3((changed, return_value), msg_code_ok(), msg_string_ok())
         }
         Err(err) => {
             let (rc, rs) = engine_to_dbus_err_tuple(&err);
@@ -78,6 +82,15 @@ fn create_filesystems(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
 }
 
 fn destroy_filesystems(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    destroy_filesystems_force(m, false)
+}
+
+/// Destroy filesystems even if they are in use; see Pool::destroy_filesystems().
+fn force_destroy_filesystems(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    destroy_filesystems_force(m, true)
+}
+
+fn destroy_filesystems_force(m: &MethodInfo<MTFn<TData>, TData>, force: bool) -> MethodResult {
     let message: &Message = m.msg;
     let mut iter = message.iter_init();
 
@@ -104,29 +117,69 @@ fn destroy_filesystems(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
         }
     }
 
-    let result = pool.destroy_filesystems(&filesystem_map.keys().cloned().collect::<Vec<Uuid>>());
-    let msg = match result {
-        Ok(ref uuids) => {
-            for uuid in uuids {
-                let op = filesystem_map
-                    .get(uuid)
-                    .expect("'uuids' is a subset of filesystem_map.keys()")
-                    .clone();
-                dbus_context.actions.borrow_mut().push_remove(op);
-            }
-
-            let return_value: Vec<String> = uuids
-                .iter()
-                .map(|n| format!("{}", n.simple()))
-                .collect();
-            return_message.append3(return_value, msg_code_ok(), msg_string_ok())
-        }
+    let result = pool.destroy_filesystems(&filesystem_map.keys().cloned().collect::<Vec<Uuid>>(),
+                                          force);
+    // Filesystems destroyed before a failure are gone, and are reported
+    // along with the error.
+    let (uuids, (rc, rs)) = match result {
+        Ok(DestroyAction::Destroyed(uuids)) => (uuids, (msg_code_ok(), msg_string_ok())),
+        Ok(DestroyAction::NotFound) => (vec![], (msg_code_ok(), msg_string_ok())),
         Err(err) => {
-            let (rc, rs) = engine_to_dbus_err_tuple(&err);
-            return_message.append3(default_return, rc, rs)
+            let rc_rs = engine_to_dbus_err_tuple(&err.error);
+            (err.destroyed, rc_rs)
         }
     };
-    Ok(vec![msg])
+    for uuid in &uuids {
+        let op = filesystem_map
+            .get(uuid)
+            .expect("'uuids' is a subset of filesystem_map.keys()")
+            .clone();
+        dbus_context.actions.borrow_mut().push_remove(op);
+    }
+
+    let return_value: Vec<String> = uuids
+        .iter()
+        .map(|n| format!("{}", n.simple()))
+        .collect();
+    Ok(vec![return_message.append3(return_value, rc, rs)])
+}
+
+fn list_filesystems(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return: Vec<dbus::Path> = Vec::new();
+
+    let pool_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let engine = dbus_context.engine.borrow();
+    let pool = match engine.get_pool(pool_uuid) {
+        Some(pool) => pool,
+        None => {
+            let message = format!("engine does not know about pool with uuid {}", pool_uuid);
+            let (rc, rs) = (u16::from(DbusErrorEnum::INTERNAL_ERROR), message);
+            return Ok(vec![return_message.append3(default_return, rc, rs)]);
+        }
+    };
+
+    let mut return_value = Vec::new();
+    for fs_uuid in pool.filesystems().iter().map(|fs| fs.uuid()) {
+        match dbus_context.get_object_path(fs_uuid) {
+            Some(fs_object_path) => return_value.push(fs_object_path),
+            None => {
+                let message = format!("no object path for filesystem with uuid {}", fs_uuid);
+                let (rc, rs) = (u16::from(DbusErrorEnum::INTERNAL_ERROR), message);
+                return Ok(vec![return_message.append3(default_return, rc, rs)]);
+            }
+        }
+    }
+
+    Ok(vec![return_message.append3(return_value, msg_code_ok(), msg_string_ok())])
 }
 
 fn snapshot_filesystem(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
@@ -195,6 +248,10 @@ fn add_devs(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
 
     let blockdevs = devs.map(|x| Path::new(x)).collect::<Vec<&Path>>();
 
+    let force = CreateForce {
+        overwrite_signatures: force,
+        ..CreateForce::default()
+    };
     let result = pool.add_blockdevs(&blockdevs, force);
     let msg = match result {
         Ok(uuids) => {
@@ -249,6 +306,410 @@ fn rename_pool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
     Ok(vec![msg])
 }
 
+fn extend_thinpool(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return: u64 = 0;
+
+    let pool_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let mut engine = dbus_context.engine.borrow_mut();
+    let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
+
+    let msg = match pool.extend_thinpool() {
+        Ok(added) => {
+            match sectors_to_bytes(added) {
+                Some(added) => return_message.append3(added, msg_code_ok(), msg_string_ok()),
+                None => {
+                    let error_message = format!("{} sectors overflows u64 bytes", added);
+                    let (rc, rs) = (u16::from(DbusErrorEnum::INTERNAL_ERROR), error_message);
+                    return_message.append3(default_return, rc, rs)
+                }
+            }
+        }
+        Err(err) => {
+            let (rc, rs) = engine_to_dbus_err_tuple(&err);
+            return_message.append3(default_return, rc, rs)
+        }
+    };
+    Ok(vec![msg])
+}
+
+/// An audit report, as passed to a D-Bus client.
+type AuditTuple = (Vec<String>, Vec<String>, Vec<(String, u64, u64)>);
+
+/// The audit report is a struct of the UUIDs of the filesystems which are
+/// recorded but unknown, the UUIDs of those which are known but
+/// unrecorded, and, for each filesystem whose device is not the recorded
+/// size, its UUID, its recorded size, and the size of its device, in bytes.
+fn audit(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return: AuditTuple = (vec![], vec![], vec![]);
+
+    let pool_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let engine = dbus_context.engine.borrow();
+    let pool = match engine.get_pool(pool_uuid) {
+        Some(pool) => pool,
+        None => {
+            let message = format!("engine does not know about pool with uuid {}", pool_uuid);
+            let (rc, rs) = (u16::from(DbusErrorEnum::INTERNAL_ERROR), message);
+            return Ok(vec![return_message.append3(default_return, rc, rs)]);
+        }
+    };
+
+    let report = match pool.audit() {
+        Ok(report) => report,
+        Err(err) => {
+            let (rc, rs) = engine_to_dbus_err_tuple(&err);
+            return Ok(vec![return_message.append3(default_return, rc, rs)]);
+        }
+    };
+
+    let uuid_strings = |uuids: &[Uuid]| {
+        uuids
+            .iter()
+            .map(|uuid| uuid.simple().to_string())
+            .collect::<Vec<_>>()
+    };
+    let mut size_mismatches = Vec::new();
+    for &(fs_uuid, recorded, actual) in &report.size_mismatches {
+        match (sectors_to_bytes(recorded), sectors_to_bytes(actual)) {
+            (Some(recorded), Some(actual)) => {
+                size_mismatches.push((fs_uuid.simple().to_string(), recorded, actual))
+            }
+            _ => {
+                let message = format!("size of filesystem {} overflows u64 bytes", fs_uuid);
+                let (rc, rs) = (u16::from(DbusErrorEnum::INTERNAL_ERROR), message);
+                return Ok(vec![return_message.append3(default_return, rc, rs)]);
+            }
+        }
+    }
+
+    let return_value = (uuid_strings(&report.unknown_records),
+                        uuid_strings(&report.unrecorded_filesystems),
+                        size_mismatches);
+    Ok(vec![return_message.append3(return_value, msg_code_ok(), msg_string_ok())])
+}
+
+/// A check report, as passed to a D-Bus client.
+type CheckTuple = (u16, bool, Vec<String>, Vec<String>, u64);
+
+/// The check report is a struct of the pool's extend state, whether its
+/// thinpool metadata is low on space, the UUIDs of the filesystems which
+/// are inconsistent with their devices, the UUIDs of those which use more
+/// of their space than the pool's watermark, and the number of temp files
+/// removed from its MDV.
+fn check_tuple(report: &PoolCheckReport) -> CheckTuple {
+    let uuid_strings = |uuids: &[Uuid]| {
+        uuids
+            .iter()
+            .map(|uuid| uuid.simple().to_string())
+            .collect::<Vec<_>>()
+    };
+    (u16::from(report.extend_state),
+     report.metadata_low,
+     uuid_strings(&report.inconsistent_filesystems),
+     uuid_strings(&report.space_low_filesystems),
+     report.temp_files_removed)
+}
+
+fn check(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return: CheckTuple = (0, false, vec![], vec![], 0);
+
+    let pool_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let mut engine = dbus_context.engine.borrow_mut();
+    let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
+
+    let msg = match pool.check() {
+        Ok(report) => return_message.append3(check_tuple(&report), msg_code_ok(), msg_string_ok()),
+        Err(err) => {
+            let (rc, rs) = engine_to_dbus_err_tuple(&err);
+            return_message.append3(default_return, rc, rs)
+        }
+    };
+    Ok(vec![msg])
+}
+
+fn set_default_filesystem_size(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let mut iter = message.iter_init();
+
+    let size: u64 = get_next_arg(&mut iter, 0)?;
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return = false;
+
+    let size = match bytes_to_sectors(size) {
+        Some(size) => size,
+        None => {
+            let error_message = format!("size {} is not a multiple of the sector size", size);
+            let (rc, rs) = (u16::from(DbusErrorEnum::ERROR), error_message);
+            return Ok(vec![return_message.append3(default_return, rc, rs)]);
+        }
+    };
+
+    let pool_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let mut engine = dbus_context.engine.borrow_mut();
+    let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
+
+    let msg = if pool.default_fs_size() == size {
+        return_message.append3(false, msg_code_ok(), msg_string_ok())
+    } else {
+        match pool.set_default_fs_size(size) {
+            Ok(_) => return_message.append3(true, msg_code_ok(), msg_string_ok()),
+            Err(err) => {
+                let (rc, rs) = engine_to_dbus_err_tuple(&err);
+                return_message.append3(default_return, rc, rs)
+            }
+        }
+    };
+    Ok(vec![msg])
+}
+
+fn set_filesystem_space_watermark(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let mut iter = message.iter_init();
+
+    let percent: u8 = get_next_arg(&mut iter, 0)?;
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return = false;
+
+    let pool_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let mut engine = dbus_context.engine.borrow_mut();
+    let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
+
+    let msg = if pool.fs_space_watermark() == percent {
+        return_message.append3(false, msg_code_ok(), msg_string_ok())
+    } else {
+        match pool.set_fs_space_watermark(percent) {
+            Ok(_) => return_message.append3(true, msg_code_ok(), msg_string_ok()),
+            Err(err) => {
+                let (rc, rs) = engine_to_dbus_err_tuple(&err);
+                return_message.append3(default_return, rc, rs)
+            }
+        }
+    };
+    Ok(vec![msg])
+}
+
+fn set_space_reserve(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let mut iter = message.iter_init();
+
+    let reserve: u64 = get_next_arg(&mut iter, 0)?;
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return = false;
+
+    let reserve = match bytes_to_sectors(reserve) {
+        Some(reserve) => reserve,
+        None => {
+            let error_message = format!("reserve {} is not a multiple of the sector size",
+                                        reserve);
+            let (rc, rs) = (u16::from(DbusErrorEnum::ERROR), error_message);
+            return Ok(vec![return_message.append3(default_return, rc, rs)]);
+        }
+    };
+
+    let pool_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let mut engine = dbus_context.engine.borrow_mut();
+    let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
+
+    let msg = if pool.space_reserve() == reserve {
+        return_message.append3(false, msg_code_ok(), msg_string_ok())
+    } else {
+        match pool.set_space_reserve(reserve) {
+            Ok(_) => return_message.append3(true, msg_code_ok(), msg_string_ok()),
+            Err(err) => {
+                let (rc, rs) = engine_to_dbus_err_tuple(&err);
+                return_message.append3(default_return, rc, rs)
+            }
+        }
+    };
+    Ok(vec![msg])
+}
+
+/// Set the maximum filesystem size, given as (true, bytes), or (false, _)
+/// for no maximum.
+fn set_max_filesystem_size(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let mut iter = message.iter_init();
+
+    let max: Option<u64> = tuple_to_option(get_next_arg(&mut iter, 0)?);
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return = false;
+
+    let max = match max {
+        Some(max) => {
+            match bytes_to_sectors(max) {
+                Some(max) => Some(max),
+                None => {
+                    let error_message =
+                        format!("maximum filesystem size {} is not a multiple of the sector size",
+                                max);
+                    let (rc, rs) = (u16::from(DbusErrorEnum::ERROR), error_message);
+                    return Ok(vec![return_message.append3(default_return, rc, rs)]);
+                }
+            }
+        }
+        None => None,
+    };
+
+    let pool_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let mut engine = dbus_context.engine.borrow_mut();
+    let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
+
+    let msg = if pool.max_fs_size() == max {
+        return_message.append3(false, msg_code_ok(), msg_string_ok())
+    } else {
+        match pool.set_max_fs_size(max) {
+            Ok(_) => return_message.append3(true, msg_code_ok(), msg_string_ok()),
+            Err(err) => {
+                let (rc, rs) = engine_to_dbus_err_tuple(&err);
+                return_message.append3(default_return, rc, rs)
+            }
+        }
+    };
+    Ok(vec![msg])
+}
+
+/// Set the data low water mark, given as (true, percent) or (false, bytes).
+fn set_data_low_water(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let mut iter = message.iter_init();
+
+    let (is_percent, value): (bool, u64) = get_next_arg(&mut iter, 0)?;
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return = false;
+
+    let low_water = if is_percent {
+        if value > 100 {
+            let error_message = format!("{} is not a percentage", value);
+            let (rc, rs) = (u16::from(DbusErrorEnum::ERROR), error_message);
+            return Ok(vec![return_message.append3(default_return, rc, rs)]);
+        }
+        DataLowWater::Percent(value as u8)
+    } else {
+        match bytes_to_sectors(value) {
+            Some(sectors) => DataLowWater::Sectors(sectors),
+            None => {
+                let error_message = format!("low water mark {} is not a multiple of the \
+                                             sector size",
+                                            value);
+                let (rc, rs) = (u16::from(DbusErrorEnum::ERROR), error_message);
+                return Ok(vec![return_message.append3(default_return, rc, rs)]);
+            }
+        }
+    };
+
+    let pool_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let mut engine = dbus_context.engine.borrow_mut();
+    let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
+
+    let msg = if pool.data_low_water() == low_water {
+        return_message.append3(false, msg_code_ok(), msg_string_ok())
+    } else {
+        match pool.set_data_low_water(low_water) {
+            Ok(_) => return_message.append3(true, msg_code_ok(), msg_string_ok()),
+            Err(err) => {
+                let (rc, rs) = engine_to_dbus_err_tuple(&err);
+                return_message.append3(default_return, rc, rs)
+            }
+        }
+    };
+    Ok(vec![msg])
+}
+
+fn set_label(m: &MethodInfo<MTFn<TData>, TData>) -> MethodResult {
+    let message: &Message = m.msg;
+    let mut iter = message.iter_init();
+
+    let key: &str = get_next_arg(&mut iter, 0)?;
+    let value: Option<&str> = tuple_to_option(get_next_arg(&mut iter, 1)?);
+
+    let dbus_context = m.tree.get_data();
+    let object_path = m.path.get_name();
+    let return_message = message.method_return();
+    let default_return = false;
+
+    let pool_path = m.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree");
+    let pool_uuid = get_data!(pool_path; default_return; return_message).uuid;
+
+    let mut engine = dbus_context.engine.borrow_mut();
+    let pool = get_mut_pool!(engine; pool_uuid; default_return; return_message);
+
+    let msg = if pool.labels().get(key).map(|v| v.as_str()) == value {
+        return_message.append3(false, msg_code_ok(), msg_string_ok())
+    } else {
+        match pool.set_label(key, value) {
+            Ok(_) => return_message.append3(true, msg_code_ok(), msg_string_ok()),
+            Err(err) => {
+                let (rc, rs) = engine_to_dbus_err_tuple(&err);
+                return_message.append3(default_return, rc, rs)
+            }
+        }
+    };
+    Ok(vec![msg])
+}
+
 /// Get a pool property and place it on the D-Bus. The property is
 /// found by means of the getter method which takes a reference to a
 /// Pool and obtains the property from the pool.
@@ -288,6 +749,36 @@ fn get_pool_name(i: &mut IterAppend, p: &PropInfo<MTFn<TData>, TData>) -> Result
     get_pool_property(i, p, |p| Ok(p.name().to_owned()))
 }
 
+/// The directory in the Stratis /dev directory which holds the links to the
+/// filesystems of the pool pool_uuid, which is named for the pool's current
+/// name, or the empty string if the pool is not set up.
+fn pool_devnode(engine: &Engine, pool_uuid: PoolUuid) -> String {
+    engine
+        .get_pool(pool_uuid)
+        .map_or_else(String::new, |pool| {
+            format!("{}", engine.paths().pool_dir(pool.name()).display())
+        })
+}
+
+/// Get the devnode for an object path. Unlike the pool's other properties,
+/// this is not an error if the pool is not set up.
+fn get_pool_devnode(i: &mut IterAppend,
+                    p: &PropInfo<MTFn<TData>, TData>)
+                    -> Result<(), MethodErr> {
+    let dbus_context = p.tree.get_data();
+    let object_path = p.path.get_name();
+    let pool_uuid = p.tree
+        .get(object_path)
+        .expect("implicit argument must be in tree")
+        .get_data()
+        .as_ref()
+        .ok_or_else(|| MethodErr::failed(&format!("no data for object path {}", object_path)))?
+        .uuid;
+
+    i.append(pool_devnode(&*dbus_context.engine.borrow(), pool_uuid));
+    Ok(())
+}
+
 fn get_pool_total_physical_used(i: &mut IterAppend,
                                 p: &PropInfo<MTFn<TData>, TData>)
                                 -> Result<(), MethodErr> {
@@ -311,6 +802,184 @@ fn get_pool_total_physical_size(i: &mut IterAppend,
     get_pool_property(i, p, |p| Ok(format!("{}", *p.total_physical_size())))
 }
 
+fn get_pool_free_space(i: &mut IterAppend,
+                       p: &PropInfo<MTFn<TData>, TData>)
+                       -> Result<(), MethodErr> {
+    fn get_free(pool: &Pool) -> Result<String, MethodErr> {
+        let err_func = |_| {
+            MethodErr::failed(&format!("no free space computed for pool with uuid {}",
+                                       pool.uuid()))
+        };
+
+        pool.total_physical_used()
+            .map(|u| Ok(format!("{}", *(pool.total_physical_size() - u))))
+            .map_err(err_func)?
+    }
+
+    get_pool_property(i, p, get_free)
+}
+
+fn get_pool_extend_state(i: &mut IterAppend,
+                         p: &PropInfo<MTFn<TData>, TData>)
+                         -> Result<(), MethodErr> {
+    get_pool_property(i, p, |p| Ok(u16::from(p.extend_state())))
+}
+
+fn get_pool_metadata_low(i: &mut IterAppend,
+                         p: &PropInfo<MTFn<TData>, TData>)
+                         -> Result<(), MethodErr> {
+    get_pool_property(i, p, |p| Ok(p.metadata_low()))
+}
+
+fn get_pool_created(i: &mut IterAppend,
+                    p: &PropInfo<MTFn<TData>, TData>)
+                    -> Result<(), MethodErr> {
+    get_pool_property(i, p, |p| Ok(p.created().timestamp() as u64))
+}
+
+fn get_pool_last_modified(i: &mut IterAppend,
+                          p: &PropInfo<MTFn<TData>, TData>)
+                          -> Result<(), MethodErr> {
+    get_pool_property(i, p, |p| Ok(p.last_modified().timestamp() as u64))
+}
+
+fn get_pool_default_filesystem_size(i: &mut IterAppend,
+                                    p: &PropInfo<MTFn<TData>, TData>)
+                                    -> Result<(), MethodErr> {
+    get_pool_property(i, p, |p| {
+        sectors_to_bytes(p.default_fs_size())
+            .ok_or_else(|| MethodErr::failed(&"default filesystem size overflows u64 bytes"))
+    })
+}
+
+fn get_pool_filesystem_space_watermark(i: &mut IterAppend,
+                                       p: &PropInfo<MTFn<TData>, TData>)
+                                       -> Result<(), MethodErr> {
+    get_pool_property(i, p, |p| Ok(p.fs_space_watermark()))
+}
+
+fn get_pool_space_reserve(i: &mut IterAppend,
+                          p: &PropInfo<MTFn<TData>, TData>)
+                          -> Result<(), MethodErr> {
+    get_pool_property(i, p, |p| {
+        sectors_to_bytes(p.space_reserve())
+            .ok_or_else(|| MethodErr::failed(&"space reserve overflows u64 bytes"))
+    })
+}
+
+/// The data low water mark, as (true, percent) or (false, bytes).
+fn get_pool_data_low_water(i: &mut IterAppend,
+                           p: &PropInfo<MTFn<TData>, TData>)
+                           -> Result<(), MethodErr> {
+    get_pool_property(i, p, |p| match p.data_low_water() {
+        DataLowWater::Percent(percent) => Ok((true, u64::from(percent))),
+        DataLowWater::Sectors(sectors) => {
+            sectors_to_bytes(sectors)
+                .map(|bytes| (false, bytes))
+                .ok_or_else(|| MethodErr::failed(&"data low water mark overflows u64 bytes"))
+        }
+    })
+}
+
+/// The maximum filesystem size, as (true, bytes), or (false, 0) if there is
+/// no maximum.
+fn get_pool_max_filesystem_size(i: &mut IterAppend,
+                                p: &PropInfo<MTFn<TData>, TData>)
+                                -> Result<(), MethodErr> {
+    get_pool_property(i, p, |p| match p.max_fs_size() {
+        Some(max) => {
+            sectors_to_bytes(max)
+                .map(|bytes| (true, bytes))
+                .ok_or_else(|| MethodErr::failed(&"maximum filesystem size overflows u64 bytes"))
+        }
+        None => Ok((false, 0)),
+    })
+}
+
+fn get_pool_redundancy(i: &mut IterAppend,
+                       p: &PropInfo<MTFn<TData>, TData>)
+                       -> Result<(), MethodErr> {
+    get_pool_property(i, p, |p| Ok(u16::from(p.redundancy())))
+}
+
+fn get_pool_labels(i: &mut IterAppend,
+                   p: &PropInfo<MTFn<TData>, TData>)
+                   -> Result<(), MethodErr> {
+    get_pool_property(i, p, |p| Ok(p.labels().clone().into_iter().collect::<HashMap<_, _>>()))
+}
+
+/// Get the current values of a pool's size properties.
+fn get_pool_sizes(pool: &Pool) -> Option<PoolSizes> {
+    let total_physical_size = pool.total_physical_size();
+    pool.total_physical_used()
+        .ok()
+        .map(|used| {
+                 PoolSizes {
+                     total_physical_size: format!("{}", *total_physical_size),
+                     free_space: format!("{}", *(total_physical_size - used)),
+                 }
+             })
+}
+
+/// Record the current sizes of the pool with this UUID. If the sizes were
+/// previously recorded and have changed, return a PropertiesChanged signal
+/// for the properties that changed.
+fn pool_sizes_changed(recorded: &mut HashMap<PoolUuid, PoolSizes>,
+                      pool_uuid: PoolUuid,
+                      sizes: PoolSizes)
+                      -> Option<PropertiesPropertiesChanged> {
+    let old_sizes = match recorded.entry(pool_uuid) {
+        Entry::Occupied(mut entry) => {
+            if *entry.get() == sizes {
+                return None;
+            }
+            entry.insert(sizes.clone())
+        }
+        Entry::Vacant(entry) => {
+            entry.insert(sizes);
+            return None;
+        }
+    };
+
+    let mut changed_properties = HashMap::new();
+    if old_sizes.total_physical_size != sizes.total_physical_size {
+        changed_properties.insert("TotalPhysicalSize".into(),
+                                  Variant(Box::new(sizes.total_physical_size) as Box<RefArg>));
+    }
+    if old_sizes.free_space != sizes.free_space {
+        changed_properties.insert("FreeSpace".into(),
+                                  Variant(Box::new(sizes.free_space) as Box<RefArg>));
+    }
+
+    Some(PropertiesPropertiesChanged {
+             interface_name: format!("{}.{}", STRATIS_BASE_SERVICE, "pool"),
+             changed_properties: changed_properties,
+             invalidated_properties: vec![],
+         })
+}
+
+/// Get PropertiesChanged signals for all pools whose size properties have
+/// changed since they were last recorded.
+pub fn pool_size_signals(dbus_context: &DbusContext) -> Vec<Message> {
+    let engine = dbus_context.engine.borrow();
+    let mut recorded = dbus_context.pool_sizes.borrow_mut();
+
+    let mut signals = Vec::new();
+    for pool in engine.pools() {
+        let pool_uuid = pool.uuid();
+        let changed = match get_pool_sizes(pool) {
+            Some(sizes) => pool_sizes_changed(&mut recorded, pool_uuid, sizes),
+            None => None,
+        };
+        if let (Some(changed), Some(path)) = (changed, dbus_context.get_object_path(pool_uuid)) {
+            signals.push(changed.to_emit_message(&path));
+        }
+    }
+
+    recorded.retain(|uuid, _| engine.get_pool(*uuid).is_some());
+    signals
+}
+
 pub fn create_dbus_pool<'a>(dbus_context: &DbusContext,
                             parent: dbus::Path<'static>,
                             uuid: Uuid)
@@ -320,7 +989,7 @@ pub fn create_dbus_pool<'a>(dbus_context: &DbusContext,
 
     let create_filesystems_method = f.method("CreateFilesystems", (), create_filesystems)
         .in_arg(("specs", "as"))
-        .out_arg(("filesystems", "a(os)"))
+        .out_arg(("filesystems", "(ba(os))"))
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
@@ -330,6 +999,18 @@ pub fn create_dbus_pool<'a>(dbus_context: &DbusContext,
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
+    let force_destroy_filesystems_method =
+        f.method("ForceDestroyFilesystems", (), force_destroy_filesystems)
+            .in_arg(("filesystems", "ao"))
+            .out_arg(("results", "as"))
+            .out_arg(("return_code", "q"))
+            .out_arg(("return_string", "s"));
+
+    let list_filesystems_method = f.method("ListFilesystems", (), list_filesystems)
+        .out_arg(("filesystems", "ao"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
     let add_devs_method = f.method("AddDevs", (), add_devs)
         .in_arg(("force", "b"))
         .in_arg(("devices", "as"))
@@ -350,14 +1031,74 @@ pub fn create_dbus_pool<'a>(dbus_context: &DbusContext,
         .out_arg(("return_code", "q"))
         .out_arg(("return_string", "s"));
 
+    let extend_thinpool_method = f.method("ExtendThinpool", (), extend_thinpool)
+        .out_arg(("added", "t"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let audit_method = f.method("Audit", (), audit)
+        .out_arg(("report", "(asasa(stt))"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let check_method = f.method("Check", (), check)
+        .out_arg(("report", "(qbasast)"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let set_default_filesystem_size_method =
+        f.method("SetDefaultFilesystemSize", (), set_default_filesystem_size)
+            .in_arg(("size", "t"))
+            .out_arg(("action", "b"))
+            .out_arg(("return_code", "q"))
+            .out_arg(("return_string", "s"));
+
+    let set_filesystem_space_watermark_method =
+        f.method("SetFilesystemSpaceWatermark", (), set_filesystem_space_watermark)
+            .in_arg(("percent", "y"))
+            .out_arg(("action", "b"))
+            .out_arg(("return_code", "q"))
+            .out_arg(("return_string", "s"));
+
+    let set_space_reserve_method = f.method("SetSpaceReserve", (), set_space_reserve)
+        .in_arg(("reserve", "t"))
+        .out_arg(("action", "b"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let set_data_low_water_method = f.method("SetDataLowWater", (), set_data_low_water)
+        .in_arg(("low_water", "(bt)"))
+        .out_arg(("action", "b"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
+    let set_max_filesystem_size_method =
+        f.method("SetMaxFilesystemSize", (), set_max_filesystem_size)
+            .in_arg(("max_size", "(bt)"))
+            .out_arg(("action", "b"))
+            .out_arg(("return_code", "q"))
+            .out_arg(("return_string", "s"));
+
+    let set_label_method = f.method("SetLabel", (), set_label)
+        .in_arg(("key", "s"))
+        .in_arg(("value", "(bs)"))
+        .out_arg(("action", "b"))
+        .out_arg(("return_code", "q"))
+        .out_arg(("return_string", "s"));
+
     let name_property = f.property::<&str, _>("Name", ())
         .access(Access::Read)
         .emits_changed(EmitsChangedSignal::False)
         .on_get(get_pool_name);
 
-    let total_physical_size_property = f.property::<&str, _>("TotalPhysicalSize", ())
+    let devnode_property = f.property::<&str, _>("Devnode", ())
         .access(Access::Read)
         .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_pool_devnode);
+
+    let total_physical_size_property = f.property::<&str, _>("TotalPhysicalSize", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::True)
         .on_get(get_pool_total_physical_size);
 
     let total_physical_used_property = f.property::<&str, _>("TotalPhysicalUsed", ())
@@ -365,6 +1106,67 @@ pub fn create_dbus_pool<'a>(dbus_context: &DbusContext,
         .emits_changed(EmitsChangedSignal::False)
         .on_get(get_pool_total_physical_used);
 
+    let free_space_property = f.property::<&str, _>("FreeSpace", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::True)
+        .on_get(get_pool_free_space);
+
+    let extend_state_property = f.property::<u16, _>("ExtendState", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_pool_extend_state);
+
+    let metadata_low_property = f.property::<bool, _>("MetadataLow", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_pool_metadata_low);
+
+    let created_property = f.property::<u64, _>("Created", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::Const)
+        .on_get(get_pool_created);
+
+    let last_modified_property = f.property::<u64, _>("LastModified", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_pool_last_modified);
+
+    let default_filesystem_size_property = f.property::<u64, _>("DefaultFilesystemSize", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_pool_default_filesystem_size);
+
+    let filesystem_space_watermark_property =
+        f.property::<u8, _>("FilesystemSpaceWatermark", ())
+            .access(Access::Read)
+            .emits_changed(EmitsChangedSignal::False)
+            .on_get(get_pool_filesystem_space_watermark);
+
+    let space_reserve_property = f.property::<u64, _>("SpaceReserve", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_pool_space_reserve);
+
+    let data_low_water_property = f.property::<(bool, u64), _>("DataLowWater", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_pool_data_low_water);
+
+    let max_filesystem_size_property = f.property::<(bool, u64), _>("MaxFilesystemSize", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_pool_max_filesystem_size);
+
+    let redundancy_property = f.property::<u16, _>("Redundancy", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::Const)
+        .on_get(get_pool_redundancy);
+
+    let labels_property = f.property::<HashMap<&str, &str>, _>("Labels", ())
+        .access(Access::Read)
+        .emits_changed(EmitsChangedSignal::False)
+        .on_get(get_pool_labels);
+
     let uuid_property = f.property::<&str, _>("Uuid", ())
         .access(Access::Read)
         .emits_changed(EmitsChangedSignal::Const)
@@ -381,15 +1183,129 @@ pub fn create_dbus_pool<'a>(dbus_context: &DbusContext,
         .add(f.interface(interface_name, ())
                  .add_m(create_filesystems_method)
                  .add_m(destroy_filesystems_method)
+                 .add_m(force_destroy_filesystems_method)
+                 .add_m(list_filesystems_method)
                  .add_m(snapshot_method)
                  .add_m(add_devs_method)
                  .add_m(rename_method)
+                 .add_m(extend_thinpool_method)
+                 .add_m(set_default_filesystem_size_method)
+                 .add_m(set_filesystem_space_watermark_method)
+                 .add_m(set_space_reserve_method)
+                 .add_m(set_data_low_water_method)
+                 .add_m(set_max_filesystem_size_method)
+                 .add_m(set_label_method)
+                 .add_m(audit_method)
+                 .add_m(check_method)
                  .add_p(name_property)
+                 .add_p(devnode_property)
                  .add_p(total_physical_size_property)
                  .add_p(total_physical_used_property)
+                 .add_p(free_space_property)
+                 .add_p(extend_state_property)
+                 .add_p(metadata_low_property)
+                 .add_p(created_property)
+                 .add_p(last_modified_property)
+                 .add_p(default_filesystem_size_property)
+                 .add_p(filesystem_space_watermark_property)
+                 .add_p(space_reserve_property)
+                 .add_p(data_low_water_property)
+                 .add_p(max_filesystem_size_property)
+                 .add_p(redundancy_property)
+                 .add_p(labels_property)
                  .add_p(uuid_property));
 
     let path = object_path.get_name().to_owned();
     dbus_context.actions.borrow_mut().push_add(object_path);
     path
 }
+
+#[cfg(test)]
+mod tests {
+    use engine::{PoolExtendState, SimEngine};
+
+    use super::*;
+
+    fn sizes(total_physical_size: &str, free_space: &str) -> PoolSizes {
+        PoolSizes {
+            total_physical_size: total_physical_size.into(),
+            free_space: free_space.into(),
+        }
+    }
+
+    #[test]
+    /// A signal is only sent for a change in previously recorded sizes, and
+    /// contains only the properties that changed.
+    fn pool_sizes_signal() {
+        let mut recorded = HashMap::new();
+        let pool_uuid = Uuid::new_v4();
+
+        assert!(pool_sizes_changed(&mut recorded, pool_uuid, sizes("10", "5")).is_none());
+        assert!(pool_sizes_changed(&mut recorded, pool_uuid, sizes("10", "5")).is_none());
+
+        let changed = pool_sizes_changed(&mut recorded, pool_uuid, sizes("20", "15")).unwrap();
+        assert_eq!(changed.interface_name,
+                   format!("{}.{}", STRATIS_BASE_SERVICE, "pool"));
+        assert!(changed.changed_properties.contains_key("TotalPhysicalSize"));
+        assert!(changed.changed_properties.contains_key("FreeSpace"));
+
+        let changed = pool_sizes_changed(&mut recorded, pool_uuid, sizes("20", "10")).unwrap();
+        assert!(!changed.changed_properties.contains_key("TotalPhysicalSize"));
+        assert!(changed.changed_properties.contains_key("FreeSpace"));
+
+        assert_eq!(recorded.get(&pool_uuid), Some(&sizes("20", "10")));
+    }
+
+    #[test]
+    /// A pool's devnode is its directory in the Stratis /dev directory,
+    /// which follows the pool's name, and is empty once the pool is gone.
+    fn pool_devnode_rename() {
+        let mut engine = SimEngine::default();
+        let pool_uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let paths = engine.paths().clone();
+        assert_eq!(pool_devnode(&engine, pool_uuid),
+                   format!("{}", paths.pool_dir("name").display()));
+
+        engine.rename_pool(pool_uuid, "new_name").unwrap();
+        assert_eq!(pool_devnode(&engine, pool_uuid),
+                   format!("{}", paths.pool_dir("new_name").display()));
+
+        engine.destroy_pool(pool_uuid, false).unwrap();
+        assert_eq!(pool_devnode(&engine, pool_uuid), "");
+    }
+
+    #[test]
+    /// A check of a pool is reported as its extend state, whether its
+    /// metadata is low, and the UUIDs of the filesystems which are
+    /// inconsistent or low on space.
+    fn check_report() {
+        let mut engine = SimEngine::default();
+        let pool_uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(pool_uuid).unwrap();
+        let report = pool.check().unwrap();
+        assert_eq!(check_tuple(&report),
+                   (u16::from(report.extend_state), report.metadata_low, vec![], vec![], 0));
+
+        let fs_uuid = Uuid::new_v4();
+        let report = PoolCheckReport {
+            extend_state: PoolExtendState::Extended,
+            metadata_low: true,
+            inconsistent_filesystems: vec![],
+            space_low_filesystems: vec![fs_uuid],
+            temp_files_removed: 2,
+            unreadable_records: vec![],
+        };
+        assert_eq!(check_tuple(&report),
+                   (u16::from(PoolExtendState::Extended),
+                    true,
+                    vec![],
+                    vec![fs_uuid.simple().to_string()],
+                    2));
+    }
+}
@@ -3,6 +3,7 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::cell::{Cell, RefCell};
+use std::collections::HashMap;
 use std::collections::vec_deque::{Drain, VecDeque};
 use std::convert::From;
 use std::rc::Rc;
@@ -29,6 +30,11 @@ custom_derive! {
         INTERNAL_ERROR,
         NIX_ERROR,
         NOTFOUND,
+        LIMIT_REACHED,
+        INVALID,
+        NO_SPACE,
+        KEY_NOT_FOUND,
+        WRONG_KEY,
     }
 }
 
@@ -50,6 +56,11 @@ impl DbusErrorEnum {
             DbusErrorEnum::IO_ERROR => "IO error during operation",
             DbusErrorEnum::NIX_ERROR => "System error during operation",
             DbusErrorEnum::NOTFOUND => "Not found",
+            DbusErrorEnum::LIMIT_REACHED => "A configured limit has been reached",
+            DbusErrorEnum::INVALID => "Invalid request",
+            DbusErrorEnum::NO_SPACE => "Insufficient space",
+            DbusErrorEnum::KEY_NOT_FOUND => "Key not found in the kernel keyring",
+            DbusErrorEnum::WRONG_KEY => "Key does not match that of the pool",
         }
     }
 }
@@ -78,11 +89,21 @@ impl OPContext {
     }
 }
 
+/// The values of a pool's TotalPhysicalSize and FreeSpace properties, as
+/// last made known to clients.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolSizes {
+    pub total_physical_size: String,
+    pub free_space: String,
+}
+
 #[derive(Debug, Clone)]
 pub struct DbusContext {
     pub next_index: Rc<Cell<u64>>,
     pub engine: Rc<RefCell<Engine>>,
     pub actions: Rc<RefCell<ActionQueue>>,
+    pub object_paths: Rc<RefCell<HashMap<Uuid, Path<'static>>>>,
+    pub pool_sizes: Rc<RefCell<HashMap<Uuid, PoolSizes>>>,
 }
 
 impl DbusContext {
@@ -91,6 +112,8 @@ impl DbusContext {
             actions: Rc::new(RefCell::new(ActionQueue::default())),
             engine: engine,
             next_index: Rc::new(Cell::new(0)),
+            object_paths: Rc::new(RefCell::new(HashMap::new())),
+            pool_sizes: Rc::new(RefCell::new(HashMap::new())),
         }
     }
 
@@ -102,6 +125,11 @@ impl DbusContext {
         self.next_index.set(self.next_index.get() + 1);
         self.next_index.get()
     }
+
+    /// Get the object path registered for the object with this UUID.
+    pub fn get_object_path(&self, uuid: Uuid) -> Option<Path<'static>> {
+        self.object_paths.borrow().get(&uuid).cloned()
+    }
 }
 
 #[derive(Default, Debug)]
@@ -8,7 +8,9 @@ use dbus;
 use dbus::arg::{ArgType, Iter, IterAppend};
 use dbus::tree::{MethodErr, MTFn, PropInfo};
 
-use engine::{EngineError, ErrorEnum};
+use devicemapper::Sectors;
+
+use engine::{EngineError, ErrorEnum, SECTOR_SIZE};
 
 use super::types::{DbusErrorEnum, TData};
 
@@ -32,6 +34,33 @@ pub fn get_next_arg<'a, T>(iter: &mut Iter<'a>, loc: u16) -> Result<T, MethodErr
     Ok(value)
 }
 
+/// Convert a size in bytes, as passed by a D-Bus client, to sectors.
+/// Sizes are never rounded: returns None if bytes is not a whole number of
+/// sectors.
+pub fn bytes_to_sectors(bytes: u64) -> Option<Sectors> {
+    if bytes % SECTOR_SIZE as u64 == 0 {
+        Some(Sectors(bytes / SECTOR_SIZE as u64))
+    } else {
+        None
+    }
+}
+
+/// Convert a size in bytes, as passed by a D-Bus client, to sectors,
+/// rounding up to a whole number of sectors.
+pub fn bytes_to_sectors_round_up(bytes: u64) -> Sectors {
+    let sectors = Sectors(bytes / SECTOR_SIZE as u64);
+    if bytes % SECTOR_SIZE as u64 == 0 {
+        sectors
+    } else {
+        sectors + Sectors(1)
+    }
+}
+
+/// Convert a size in sectors to bytes, as passed to a D-Bus client.
+/// Returns None if the number of bytes does not fit in a u64.
+pub fn sectors_to_bytes(sectors: Sectors) -> Option<u64> {
+    sectors.checked_mul(SECTOR_SIZE as u64)
+}
 
 /// Translates an engine error to the (errorcode, string) tuple that Stratis
 /// D-Bus methods return.
@@ -43,8 +72,13 @@ pub fn engine_to_dbus_err_tuple(err: &EngineError) -> (u16, String) {
                 ErrorEnum::Error => DbusErrorEnum::ERROR,
                 ErrorEnum::AlreadyExists => DbusErrorEnum::ALREADY_EXISTS,
                 ErrorEnum::Busy => DbusErrorEnum::BUSY,
-                ErrorEnum::Invalid => DbusErrorEnum::ERROR,
+                ErrorEnum::Corrupt => DbusErrorEnum::ERROR,
+                ErrorEnum::Invalid => DbusErrorEnum::INVALID,
+                ErrorEnum::KeyNotFound => DbusErrorEnum::KEY_NOT_FOUND,
+                ErrorEnum::LimitReached => DbusErrorEnum::LIMIT_REACHED,
+                ErrorEnum::NoSpace => DbusErrorEnum::NO_SPACE,
                 ErrorEnum::NotFound => DbusErrorEnum::NOTFOUND,
+                ErrorEnum::WrongKey => DbusErrorEnum::WRONG_KEY,
             }
         }
         EngineError::Io(_) => DbusErrorEnum::IO_ERROR,
@@ -99,3 +133,49 @@ pub fn get_parent(i: &mut IterAppend, p: &PropInfo<MTFn<TData>, TData>) -> Resul
     i.append(data.parent.clone());
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::u64;
+
+    use super::*;
+
+    #[test]
+    /// Only whole numbers of sectors are converted from bytes.
+    fn bytes_alignment() {
+        assert_eq!(bytes_to_sectors(0), Some(Sectors(0)));
+        assert_eq!(bytes_to_sectors(512), Some(Sectors(1)));
+        assert_eq!(bytes_to_sectors(4096), Some(Sectors(8)));
+        assert_eq!(bytes_to_sectors(1), None);
+        assert_eq!(bytes_to_sectors(511), None);
+        assert_eq!(bytes_to_sectors(513), None);
+        assert_eq!(bytes_to_sectors(u64::MAX), None);
+        assert_eq!(bytes_to_sectors(u64::MAX - 511), Some(Sectors(u64::MAX / 512)));
+    }
+
+    #[test]
+    /// Sizes which are not a whole number of sectors are rounded up.
+    fn bytes_round_up() {
+        assert_eq!(bytes_to_sectors_round_up(0), Sectors(0));
+        assert_eq!(bytes_to_sectors_round_up(1), Sectors(1));
+        assert_eq!(bytes_to_sectors_round_up(512), Sectors(1));
+        assert_eq!(bytes_to_sectors_round_up(513), Sectors(2));
+        assert_eq!(bytes_to_sectors_round_up(u64::MAX), Sectors(u64::MAX / 512 + 1));
+    }
+
+    #[test]
+    /// Conversion to bytes fails only if the result would overflow, and
+    /// inverts conversion from bytes.
+    fn sectors_overflow() {
+        assert_eq!(sectors_to_bytes(Sectors(0)), Some(0));
+        assert_eq!(sectors_to_bytes(Sectors(1)), Some(512));
+        assert_eq!(sectors_to_bytes(Sectors(u64::MAX / 512)), Some(u64::MAX - 511));
+        assert_eq!(sectors_to_bytes(Sectors(u64::MAX / 512 + 1)), None);
+        assert_eq!(sectors_to_bytes(Sectors(u64::MAX)), None);
+
+        for bytes in &[0, 512, 1 << 30, u64::MAX - 511] {
+            assert_eq!(bytes_to_sectors(*bytes).and_then(sectors_to_bytes),
+                       Some(*bytes));
+        }
+    }
+}
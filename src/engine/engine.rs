@@ -2,16 +2,389 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::cmp::min;
+use std::collections::{BTreeMap, HashSet};
+use std::env;
 use std::fmt::Debug;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
 use std::path::{Path, PathBuf};
 
 use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
-use devicemapper::Sectors;
+use devicemapper::{Bytes, Sectors, ThinDevId};
 
-use super::errors::EngineResult;
-use super::types::{BlockDevState, FilesystemUuid, PoolUuid, DevUuid, RenameAction};
+use super::errors::{EngineError, EngineResult, ErrorEnum};
+use super::event::EngineEvent;
+use super::metrics;
+use super::spec::{self, ApplyReport, EngineSpec};
+use super::types::{AllocationInfo, AuditReport, AvailableBlockDev, BlockDevState, BlockDevTier,
+                   CheckReport, CreateAction, CreateForce, DEFAULT_MDV_SIZE, DataLowWater,
+                   DestroyAction, DestroyError, DeviceMetadata, FilesystemType, FilesystemUuid,
+                   FsOptions, HardwareInfo, KeyDescription, MIN_MDV_SIZE, PoolCheckReport,
+                   PoolExtendState, PoolUuid, DevUuid, Redundancy, RenameAction};
+
+/// The default Stratis /dev directory.
+pub const DEV_PATH: &str = "/dev/stratis";
+
+/// The environment variable which, if set, overrides DEV_PATH, e.g., when
+/// running in a container or a test sandbox.
+pub const DEV_PATH_ENV: &str = "STRATIS_DEV_PATH";
+
+/// The paths of the directories and links which Stratis makes in its /dev
+/// directory. All paths in the directory are constructed here, so that
+/// nothing else depends on how they are laid out.
+/// An engine resolves its paths once, when it is constructed, and passes
+/// them to its pools; everything else asks the engine for them.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct StratisPaths {
+    base: PathBuf,
+}
+
+impl StratisPaths {
+    /// The paths in the Stratis /dev directory base.
+    pub fn new<P: Into<PathBuf>>(base: P) -> StratisPaths {
+        StratisPaths { base: base.into() }
+    }
+
+    /// The paths in the Stratis /dev directory, which is DEV_PATH unless
+    /// overridden by the DEV_PATH_ENV environment variable.
+    pub fn from_env() -> StratisPaths {
+        StratisPaths::new(env::var_os(DEV_PATH_ENV).map_or_else(|| PathBuf::from(DEV_PATH),
+                                                                 PathBuf::from))
+    }
+
+    /// The Stratis /dev directory itself.
+    pub fn base(&self) -> &Path {
+        &self.base
+    }
+
+    /// The hidden directory at which the MDV of pool pool_uuid is mounted.
+    /// It is named for the pool's UUID, so it is unaffected by renames.
+    pub fn mdv_dir(&self, pool_uuid: PoolUuid) -> PathBuf {
+        self.base.join(format!(".mdv-{}", pool_uuid.simple()))
+    }
+
+    /// The directory which holds the links to the filesystems of pool
+    /// pool_name.
+    pub fn pool_dir(&self, pool_name: &str) -> PathBuf {
+        self.base.join(pool_name)
+    }
+
+    /// The link to the device node of filesystem fs_name of pool pool_name.
+    pub fn filesystem_devnode(&self, pool_name: &str, fs_name: &str) -> PathBuf {
+        self.pool_dir(pool_name).join(fs_name)
+    }
+}
+
+impl Default for StratisPaths {
+    fn default() -> StratisPaths {
+        StratisPaths::new(DEV_PATH)
+    }
+}
+
+/// The maximum length in bytes of a pool or filesystem name.
+pub const MAX_NAME_LEN: usize = 255;
+
+/// Check that name can be used as the name of a pool or filesystem.
+/// Names become components of paths in the Stratis /dev directory, so a
+/// name must be non-empty, may be no longer than MAX_NAME_LEN bytes, may
+/// not begin with '.', and may not contain '/' or any control character.
+/// Returns an Invalid error describing the rule name breaks, if any.
+pub fn validate_name(name: &str) -> EngineResult<()> {
+    let rule = if name.is_empty() {
+        Some("must not be empty".to_owned())
+    } else if name.len() > MAX_NAME_LEN {
+        Some(format!("must be no longer than {} bytes", MAX_NAME_LEN))
+    } else if name.starts_with('.') {
+        Some("must not begin with '.'".to_owned())
+    } else if name.contains('/') {
+        Some("must not contain '/'".to_owned())
+    } else if name.chars().any(|c| c.is_control()) {
+        Some("must not contain control characters".to_owned())
+    } else {
+        None
+    };
+
+    match rule {
+        Some(rule) => {
+            Err(EngineError::Engine(ErrorEnum::Invalid,
+                                    format!("name {:?} is invalid: a name {}", name, rule)))
+        }
+        None => Ok(()),
+    }
+}
+
+/// The maximum length in bytes of the key of a pool label.
+pub const MAX_LABEL_KEY_LEN: usize = 63;
+
+/// The maximum length in bytes of the value of a pool label.
+pub const MAX_LABEL_VALUE_LEN: usize = 255;
+
+/// Check that key, and value, if any, can be used as a pool label.
+/// A key must be non-empty, may be no longer than MAX_LABEL_KEY_LEN bytes,
+/// must begin with an ASCII letter or digit, and may contain only ASCII
+/// letters, digits, '-', '_' and '.'. A value may be no longer than
+/// MAX_LABEL_VALUE_LEN bytes and may not contain any control character.
+/// Returns an Invalid error describing the rule broken, if any.
+pub fn validate_label(key: &str, value: Option<&str>) -> EngineResult<()> {
+    let valid_key_char = |c: char| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.';
+    let rule = if key.is_empty() {
+        Some("key must not be empty".to_owned())
+    } else if key.len() > MAX_LABEL_KEY_LEN {
+        Some(format!("key must be no longer than {} bytes", MAX_LABEL_KEY_LEN))
+    } else if !key.starts_with(|c: char| c.is_ascii_alphanumeric()) {
+        Some("key must begin with an ASCII letter or digit".to_owned())
+    } else if !key.chars().all(valid_key_char) {
+        Some("key may contain only ASCII letters, digits, '-', '_' and '.'".to_owned())
+    } else {
+        match value {
+            Some(value) if value.len() > MAX_LABEL_VALUE_LEN => {
+                Some(format!("value must be no longer than {} bytes", MAX_LABEL_VALUE_LEN))
+            }
+            Some(value) if value.chars().any(|c| c.is_control()) => {
+                Some("value must not contain control characters".to_owned())
+            }
+            _ => None,
+        }
+    };
+
+    match rule {
+        Some(rule) => {
+            Err(EngineError::Engine(ErrorEnum::Invalid,
+                                    format!("label {:?} is invalid: its {}", key, rule)))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Check that opts can be passed to mount(2) as filesystem mount options.
+/// An option must be non-empty, and may not contain ',', which separates
+/// options, or any whitespace.
+/// Returns an Invalid error naming the first invalid option, if any.
+pub fn validate_mount_options(opts: &[&str]) -> EngineResult<()> {
+    let invalid = |opt: &str| {
+        opt.is_empty() || opt.contains(',') || opt.contains(char::is_whitespace)
+    };
+    match opts.iter().find(|opt| invalid(opt)) {
+        Some(opt) => {
+            let err_msg = format!("mount option {:?} is invalid: an option must not be empty, \
+                                   and must not contain ',' or whitespace",
+                                  opt);
+            Err(EngineError::Engine(ErrorEnum::Invalid, err_msg))
+        }
+        None => Ok(()),
+    }
+}
+
+/// Merge a pool's default mount options, defaults, with the options opts
+/// given for one mount, into the comma-separated data argument of mount(2).
+/// An option in opts replaces any default of the same name, i.e., with the
+/// same text before any '='.
+/// Returns an Invalid error if any of opts is invalid; see
+/// validate_mount_options().
+pub fn merge_mount_options(defaults: &[String], opts: &[&str]) -> EngineResult<String> {
+    validate_mount_options(opts)?;
+    let name = |opt: &str| opt.split('=').next().map(|name| name.to_owned());
+    let mut merged = defaults
+        .iter()
+        .map(|opt| opt.as_str())
+        .filter(|default| opts.iter().all(|opt| name(opt) != name(default)))
+        .collect::<Vec<_>>();
+    merged.extend(opts);
+    Ok(merged.join(","))
+}
+
+/// Check that a filesystem can be made with options.
+/// XFS filesystems are made in the XFS v5 format, which requires a block
+/// size of at least 1 KiB and an inode size of at least 512 bytes, and
+/// every block must hold at least two inodes. An ext4 inode must be at
+/// least 128 bytes, and no larger than a block, and ext4 does not share
+/// data blocks between files.
+/// Returns an Invalid error describing the first problem found, if any.
+pub fn validate_fs_options(options: &FsOptions) -> EngineResult<()> {
+    let invalid = |msg: String| Err(EngineError::Engine(ErrorEnum::Invalid, msg));
+    let valid_size = |size: Bytes, min: u64, max: u64| {
+        let size = *size;
+        size.is_power_of_two() && min <= size && size <= max
+    };
+    let (min_inode_size, max_inode_size, inodes_per_block) = match options.fs_type {
+        FilesystemType::Xfs => (512, 2048, 2),
+        FilesystemType::Ext4 => (128, 65536, 1),
+    };
+
+    if let Some(block_size) = options.block_size {
+        if !valid_size(block_size, 1024, 65536) {
+            return invalid(format!("block size {} is not a power of 2 from 1024 to 65536",
+                                   *block_size));
+        }
+    }
+    if let Some(inode_size) = options.inode_size {
+        if !valid_size(inode_size, min_inode_size, max_inode_size) {
+            return invalid(format!("inode size {} is not a power of 2 from {} to {}",
+                                   *inode_size,
+                                   min_inode_size,
+                                   max_inode_size));
+        }
+        if let Some(block_size) = options.block_size {
+            if *inode_size > *block_size / inodes_per_block {
+                return invalid(format!("inode size {} is too large for the block size {}",
+                                       *inode_size,
+                                       *block_size));
+            }
+        }
+    }
+    if options.fs_type == FilesystemType::Ext4 && options.reflink == Some(true) {
+        return invalid("ext4 does not support reflink".into());
+    }
+    Ok(())
+}
+
+/// Get the size of the metadata volume of a new pool, given the size
+/// requested, if any.
+/// Returns an Invalid error if the size requested is less than MIN_MDV_SIZE.
+pub fn validate_mdv_size(mdv_size: Option<Sectors>) -> EngineResult<Sectors> {
+    match mdv_size {
+        None => Ok(DEFAULT_MDV_SIZE),
+        Some(size) if size < MIN_MDV_SIZE => {
+            let err_msg = format!("metadata volume size {} is less than the minimum, {}",
+                                  size,
+                                  MIN_MDV_SIZE);
+            Err(EngineError::Engine(ErrorEnum::Invalid, err_msg))
+        }
+        Some(size) => Ok(size),
+    }
+}
+
+/// Check that percent is a usable filesystem space watermark.
+/// Returns an Invalid error if it is zero or more than 100.
+pub fn validate_fs_space_watermark(percent: u8) -> EngineResult<()> {
+    if percent == 0 || percent > 100 {
+        let err_msg = format!("filesystem space watermark {}% is not between 1% and 100%",
+                              percent);
+        return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+    Ok(())
+}
+
+/// Check that low_water is a usable low water mark for the data device of a
+/// pool's thinpool.
+/// Returns an Invalid error if it is a percentage which is zero or more than
+/// 50%, or a number of sectors which is zero. Extending the data device
+/// doubles it, which leaves at least half of it free; a higher percentage
+/// would have it extended again at every check.
+pub fn validate_data_low_water(low_water: DataLowWater) -> EngineResult<()> {
+    match low_water {
+        DataLowWater::Percent(percent) if percent == 0 || percent > 50 => {
+            let err_msg = format!("data low water mark {}% is not between 1% and 50%", percent);
+            Err(EngineError::Engine(ErrorEnum::Invalid, err_msg))
+        }
+        DataLowWater::Sectors(Sectors(0)) => {
+            let err_msg = "data low water mark must not be 0 sectors";
+            Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check that metadata_paths, the paths of the blockdevs of a new pool which
+/// are to be placed in its metadata tier, are all among blockdev_paths,
+/// the paths of all its blockdevs, and leave at least one blockdev in its
+/// data tier.
+/// Returns an Invalid error if they do not.
+pub fn validate_metadata_paths(blockdev_paths: &[&Path],
+                               metadata_paths: &[&Path])
+                               -> EngineResult<()> {
+    if metadata_paths.is_empty() {
+        return Ok(());
+    }
+
+    let blockdev_paths: HashSet<_> = blockdev_paths.iter().collect();
+    let metadata_paths: HashSet<_> = metadata_paths.iter().collect();
+    if let Some(path) = metadata_paths.difference(&blockdev_paths).next() {
+        let err_msg = format!("metadata tier device {} is not among the pool's devices",
+                              path.display());
+        return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+    if metadata_paths == blockdev_paths {
+        let err_msg = "at least one of the pool's devices must be left to hold its data";
+        return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+    }
+    Ok(())
+}
+
+/// Check that reserve is a usable space reserve for a pool of total sectors.
+/// Returns an Invalid error if it is more than total.
+pub fn validate_space_reserve(reserve: Sectors, total: Sectors) -> EngineResult<()> {
+    if reserve > total {
+        let err_msg = format!("space reserve {} is more than the pool's size, {}",
+                              reserve,
+                              total);
+        return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+    Ok(())
+}
+
+/// Check that max is a usable cap on the size of the filesystems of a pool.
+/// Returns an Invalid error if it is zero.
+pub fn validate_max_fs_size(max: Option<Sectors>) -> EngineResult<()> {
+    if max == Some(Sectors(0)) {
+        let err_msg = "the maximum filesystem size must be non-zero";
+        return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+    }
+    Ok(())
+}
+
+/// Check that the filesystem name may be made, or grown to, size, in a pool
+/// whose filesystems may be at most max.
+/// Returns an Invalid error if size is more than max.
+pub fn check_max_fs_size(name: &str, size: Sectors, max: Option<Sectors>) -> EngineResult<()> {
+    match max {
+        Some(max) if size > max => {
+            let err_msg = format!("filesystem {} can not be {}, more than the pool's maximum \
+                                   filesystem size {}",
+                                  name,
+                                  size,
+                                  max);
+            Err(EngineError::Engine(ErrorEnum::Invalid, err_msg))
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Check that a pool of total sectors, of which used are in use, has space
+/// beyond its reserve of reserve sectors, so that more of it may be
+/// allocated to filesystems.
+/// Returns a NoSpace error if the pool's free space is within its reserve.
+pub fn check_space_reserve(total: Sectors, used: Sectors, reserve: Sectors) -> EngineResult<()> {
+    if used + reserve >= total {
+        let err_msg = format!("only {} of {} is free, which is within the reserve of {}",
+                              total - min(used, total),
+                              total,
+                              reserve);
+        return Err(EngineError::Engine(ErrorEnum::NoSpace, err_msg));
+    }
+    Ok(())
+}
+
+/// Check that none of paths is the device node of one of claimed, the
+/// blockdevs of the pools of an engine, as found by claimed_devices().
+/// Returns a Busy error if one is.
+pub fn check_unclaimed(claimed: &[(PoolUuid, DevUuid, PathBuf)],
+                       paths: &[&Path])
+                       -> EngineResult<()> {
+    for path in paths {
+        let devnode = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        if let Some(&(pool_uuid, _, _)) = claimed.iter().find(|&&(_, _, ref d)| *d == devnode) {
+            let err_msg = format!("{} belongs to pool {}, which is set up",
+                                  path.display(),
+                                  pool_uuid);
+            return Err(EngineError::Engine(ErrorEnum::Busy, err_msg));
+        }
+    }
+    Ok(())
+}
 
 pub trait HasUuid: Debug {
     fn uuid(&self) -> Uuid;
@@ -24,6 +397,35 @@ pub trait HasName: Debug {
 pub trait Filesystem: HasName + HasUuid {
     /// path of the device node
     fn devnode(&self) -> PathBuf;
+
+    /// The time that this filesystem was created, rounded down to the
+    /// nearest second. For a filesystem created before creation times were
+    /// recorded, this is the Unix epoch.
+    fn created(&self) -> DateTime<Utc>;
+
+    /// Whether the filesystem is currently mounted anywhere, as recorded by
+    /// the kernel, regardless of who mounted it.
+    fn is_mounted(&self) -> EngineResult<bool>;
+
+    /// Whether writes to the filesystem are refused.
+    fn read_only(&self) -> bool;
+
+    /// Whether the filesystem passes discards to its pool; None if this was
+    /// never set. A filesystem which passes discards should be mounted with
+    /// the discard option, so that the space of deleted files is returned
+    /// to the pool.
+    fn discards(&self) -> Option<bool>;
+
+    /// The filesystem of which this filesystem is a snapshot, if it is one.
+    fn snapshot_of(&self) -> Option<FilesystemUuid>;
+
+    /// The logical size of the filesystem, i.e., of the thin device on
+    /// which it resides, regardless of how much of it is used.
+    fn size(&self) -> Sectors;
+
+    /// The size past which the filesystem is never grown; None if it has
+    /// no quota.
+    fn quota(&self) -> Option<Sectors>;
 }
 
 pub trait BlockDev: HasUuid {
@@ -36,8 +438,19 @@ pub trait BlockDev: HasUuid {
     /// Set the user-settable string associated with this blockdev.
     fn set_user_info(&mut self, user_info: Option<&str>) -> bool;
 
-    /// Get the hardware ID for this blockdev.
-    fn hardware_info(&self) -> Option<&str>;
+    /// Get the hardware ID for this blockdev: an identifier of the device
+    /// which, unlike its device node, does not change across reboots.
+    fn hardware_id(&self) -> Option<&str>;
+
+    /// The device's model and serial number, as read when the blockdev was
+    /// set up. None if the device does not report them, e.g., a loop
+    /// device.
+    fn hardware_info(&self) -> Option<&HardwareInfo>;
+
+    /// Query the device for its SMART overall health assessment, e.g.,
+    /// "PASSED". None if the device could not be queried. The query is made
+    /// each time this is called, and may be slow.
+    fn health(&self) -> Option<String>;
 
     /// The time that this blockdev was initialized by Stratis, rounded down
     /// to the nearest second.
@@ -46,58 +459,284 @@ pub trait BlockDev: HasUuid {
     /// The usable size of the device, not counting Stratis overhead.
     fn total_size(&self) -> Sectors;
 
+    /// The number of sectors at the start of the device which hold Stratis
+    /// metadata, and so are not included in total_size().
+    fn metadata_size(&self) -> Sectors;
+
     /// The current state of the blockdev.
     fn state(&self) -> BlockDevState;
+
+    /// The tier of the pool to which the blockdev belongs.
+    fn tier(&self) -> BlockDevTier;
 }
 
 pub trait Pool: HasName + HasUuid {
     /// Creates the filesystems specified by specs.
-    /// Returns a list of the names of filesystems actually created.
+    /// Returns a list of the names of filesystems actually created, with
+    /// their UUIDs; the action is Identity if there were none to create.
     /// Returns an error if any of the specified names are already in use
-    /// for filesystems in this pool. If the same name is passed multiple
-    /// times, the size associated with the last item is used.
+    /// for filesystems in this pool or are invalid, or if any specified
+    /// size is zero.
+    /// Returns an Invalid error if any specified options can not be used
+    /// together; see validate_fs_options(), or if any filesystem would be
+    /// larger than the pool's maximum filesystem size.
+    /// If the same name is passed multiple times, the size and options
+    /// associated with the last item are used. Filesystems specified without
+    /// a size have the pool's default filesystem size, and those specified
+    /// without options are made with mkfs.xfs's defaults.
+    /// Either all the filesystems are created, or, if an error is returned,
+    /// none of them.
+    /// Returns a NoSpace error if the pool's free space is within its
+    /// space reserve.
     fn create_filesystems<'a, 'b>(&'a mut self,
-                                  specs: &[(&'b str, Option<Sectors>)])
-                                  -> EngineResult<Vec<(&'b str, FilesystemUuid)>>;
+                                  specs: &[(&'b str, Option<Sectors>, Option<FsOptions>)])
+                                  -> EngineResult<CreateAction<Vec<(&'b str, FilesystemUuid)>>>;
 
     /// Adds blockdevs specified by paths to pool.
     /// Returns a list of uuids corresponding to devices actually added.
     /// Returns an error if a blockdev can not be added because it is owned
     /// or there was an error while reading or writing a blockdev.
-    fn add_blockdevs(&mut self, paths: &[&Path], force: bool) -> EngineResult<Vec<DevUuid>>;
+    /// The checks on the devices which force overrides are those made on
+    /// the devices of a new pool; see CreateForce.
+    fn add_blockdevs(&mut self,
+                     paths: &[&Path],
+                     force: CreateForce)
+                     -> EngineResult<Vec<DevUuid>>;
+
+    /// Allocate all the free space on the pool's blockdevs to the pool's
+    /// thinpool now, rather than waiting for the pool to need it.
+    /// Returns the number of sectors added, zero if the thinpool already
+    /// had all the space.
+    fn extend_thinpool(&mut self) -> EngineResult<Sectors>;
+
+    /// Remove the blockdev dev_uuid from the pool, first moving any data
+    /// on it to other blockdevs in the pool, and then wiping its Stratis
+    /// metadata.
+    /// Returns a NoSpace error if the other blockdevs can not hold the
+    /// data, and an Invalid error if the pool would be left with too few
+    /// blockdevs for its redundancy.
+    fn remove_blockdev(&mut self, dev_uuid: DevUuid) -> EngineResult<()>;
 
     /// Destroy the pool.
     /// Precondition: All filesystems belonging to this pool must be
-    /// unmounted.
+    /// unmounted, and the pool must not be quiesced.
     fn destroy(self) -> EngineResult<()>;
 
     /// Ensures that all designated filesystems are gone from pool.
-    /// Returns a list of the filesystems found, and actually destroyed.
-    /// This list will be a subset of the uuids passed in fs_uuids.
-    /// Precondition: All filesystems given must be unmounted.
+    /// Returns the list of the filesystems found, and actually destroyed,
+    /// which will be a subset of the uuids passed in fs_uuids, or NotFound
+    /// if none of them were found.
+    /// A mounted filesystem is unmounted first. If it is in use, a Busy
+    /// error is returned, unless force is true, in which case it is
+    /// detached from its mount points and destroyed regardless.
+    /// If a filesystem can not be destroyed, the error is returned along
+    /// with the list of the filesystems destroyed before it.
     fn destroy_filesystems<'a>(&'a mut self,
-                               fs_uuids: &[FilesystemUuid])
-                               -> EngineResult<Vec<FilesystemUuid>>;
+                               fs_uuids: &[FilesystemUuid],
+                               force: bool)
+                               -> Result<DestroyAction<Vec<FilesystemUuid>>,
+                                         DestroyError<Vec<FilesystemUuid>>>;
 
     /// Rename filesystem
     /// Rename pool with uuid to new_name.
     /// Raises an error if the mapping can't be applied because
-    /// new_name is already in use.
+    /// new_name is already in use or is invalid.
     /// The result indicate whether an action was performed, and if not, why.
     fn rename_filesystem(&mut self,
                          uuid: FilesystemUuid,
                          new_name: &str)
                          -> EngineResult<RenameAction>;
 
+    /// Grow the filesystem with this UUID to size.
+    /// Returns the size of the filesystem afterwards.
+    /// Returns an Invalid error if size is less than the filesystem's size,
+    /// since filesystems can not be shrunk, or more than its quota or the
+    /// pool's maximum filesystem size, and a NoSpace error if the pool has
+    /// no space for the filesystem to grow into, or if its free space is
+    /// within its space reserve.
+    fn set_filesystem_size(&mut self,
+                           uuid: FilesystemUuid,
+                           size: Sectors)
+                           -> EngineResult<Sectors>;
+
+    /// Make the filesystem with this UUID read-only, so that the kernel
+    /// refuses writes to it, or writable again. A mounted filesystem is
+    /// remounted to match. The filesystem stays read-only when the pool is
+    /// set up again.
+    fn set_filesystem_read_only(&mut self,
+                                uuid: FilesystemUuid,
+                                read_only: bool)
+                                -> EngineResult<()>;
+
+    /// How much of the filesystem with this UUID is backed by the pool's
+    /// data blocks; None if the filesystem has not been activated, so that
+    /// this can not be found.
+    fn filesystem_allocation_info(&self,
+                                  fs_uuid: FilesystemUuid)
+                                  -> EngineResult<Option<AllocationInfo>>;
+
+    /// Limit the filesystem with this UUID to size limit, or lift its limit
+    /// if limit is None. A filesystem with a quota is grown, neither by
+    /// set_filesystem_size() nor when it is low on space, past its quota,
+    /// so that writes which would make it use more than that fail, however
+    /// much space the pool has. The quota is kept when the pool is set up
+    /// again.
+    /// Returns an Invalid error if limit is less than the filesystem's
+    /// size, since filesystems can not be shrunk.
+    fn set_filesystem_quota(&mut self,
+                            fs_uuid: FilesystemUuid,
+                            limit: Option<Sectors>)
+                            -> EngineResult<()>;
+
+    /// Let the filesystem with this UUID pass discards down to the pool,
+    /// or refuse them. The setting is kept when the pool is set up again.
+    fn enable_discards(&mut self, fs_uuid: FilesystemUuid, enable: bool) -> EngineResult<()>;
+
+    /// Discard the unused blocks of the filesystem with this UUID, once,
+    /// returning their space to the pool.
+    /// Returns an Invalid error if the filesystem is not mounted.
+    fn trim_filesystem(&mut self, fs_uuid: FilesystemUuid) -> EngineResult<()>;
+
     /// Snapshot filesystem
     /// Create a CoW snapshot of the origin
+    /// Returns a NoSpace error if the pool's free space is within its
+    /// space reserve.
     fn snapshot_filesystem(&mut self,
                            origin_uuid: FilesystemUuid,
                            snapshot_name: &str)
                            -> EngineResult<FilesystemUuid>;
 
-    /// Rename this pool.
-    fn rename(&mut self, name: &str) -> ();
+    /// Create a full copy of the origin, named new_name, which shares no
+    /// blocks with it, so that the two may diverge without either holding
+    /// space for the other.
+    /// Returns a NoSpace error if the pool does not have space, beyond its
+    /// space reserve, for all the data allocated to the origin, and an
+    /// AlreadyExists error if a filesystem is already named new_name.
+    fn clone_filesystem(&mut self,
+                        origin_uuid: FilesystemUuid,
+                        new_name: &str)
+                        -> EngineResult<FilesystemUuid>;
+
+    /// Revert the filesystem fs_uuid to the state of snapshot_uuid.
+    /// The filesystem is unmounted first; returns a Busy error if it can
+    /// not be. Returns an Invalid error if snapshot_uuid is not a snapshot
+    /// of fs_uuid, or if fs_uuid is read-only. The current contents of the
+    /// filesystem are lost.
+    fn revert_to_snapshot(&mut self,
+                          fs_uuid: FilesystemUuid,
+                          snapshot_uuid: FilesystemUuid)
+                          -> EngineResult<()>;
+
+    /// Freeze all of the pool's mounted filesystems, so that their contents
+    /// are consistent on disk, e.g., for a snapshot or a backup. Until the
+    /// pool is unquiesced, writes to the filesystems block, and operations
+    /// which would change the pool, other than taking snapshots, return a
+    /// Busy error.
+    /// Does nothing if the pool is already quiesced.
+    fn quiesce(&mut self) -> EngineResult<()>;
+
+    /// Thaw the filesystems frozen when the pool was quiesced.
+    /// Does nothing if the pool is not quiesced.
+    fn unquiesce(&mut self) -> EngineResult<()>;
+
+    /// Whether the pool is quiesced.
+    fn is_quiesced(&self) -> bool;
+
+    /// Set the mount options to be used by default for all filesystems
+    /// belonging to this pool.
+    /// Returns an Invalid error if any of opts is invalid; see
+    /// validate_mount_options().
+    fn set_default_mount_options(&mut self, opts: &[&str]) -> EngineResult<()>;
+
+    /// The mount options to be used by default for all filesystems
+    /// belonging to this pool.
+    fn default_mount_options(&self) -> &[String];
+
+    /// Mount the filesystem with this UUID at mount_point, with the pool's
+    /// default mount options merged with opts; see merge_mount_options().
+    /// A read-only filesystem is mounted read-only.
+    /// Returns an Invalid error if any of opts is invalid, or if the
+    /// filesystem has not been activated.
+    fn mount_filesystem(&self,
+                        fs_uuid: FilesystemUuid,
+                        mount_point: &Path,
+                        opts: &[&str])
+                        -> EngineResult<()>;
+
+    /// Set the size of filesystems created in this pool without an
+    /// explicit size.
+    /// Returns an error if size is zero.
+    fn set_default_fs_size(&mut self, size: Sectors) -> EngineResult<()>;
+
+    /// The size of filesystems created in this pool without an explicit
+    /// size.
+    fn default_fs_size(&self) -> Sectors;
+
+    /// Set the percentage of its space a filesystem in this pool may use
+    /// before a FilesystemSpaceLow event is raised for it.
+    /// Returns an error if percent is zero or more than 100.
+    fn set_fs_space_watermark(&mut self, percent: u8) -> EngineResult<()>;
+
+    /// The percentage of its space a filesystem in this pool may use
+    /// before a FilesystemSpaceLow event is raised for it.
+    fn fs_space_watermark(&self) -> u8;
+
+    /// Set the number of sectors of the pool which are kept free. Once the
+    /// pool's free space is within the reserve, filesystems can be neither
+    /// created nor grown, so that the space already given to filesystems
+    /// can still be written.
+    /// Returns an Invalid error if reserve is more than the pool's total
+    /// physical size.
+    fn set_space_reserve(&mut self, reserve: Sectors) -> EngineResult<()>;
+
+    /// The number of sectors of the pool which are kept free.
+    fn space_reserve(&self) -> Sectors;
+
+    /// Set how little of the data device of this pool's thinpool may be
+    /// free before the data device is extended by check().
+    /// Returns an Invalid error if low_water breaks the rules of
+    /// validate_data_low_water().
+    fn set_data_low_water(&mut self, low_water: DataLowWater) -> EngineResult<()>;
+
+    /// How little of the data device of this pool's thinpool may be free
+    /// before the data device is extended.
+    fn data_low_water(&self) -> DataLowWater;
+
+    /// Set the largest size to which a filesystem of this pool may be made
+    /// or grown by create_filesystems() or set_filesystem_size(); None if
+    /// filesystems may be any size. Filesystems which are already larger
+    /// are left alone.
+    /// Returns an Invalid error if max is zero.
+    fn set_max_fs_size(&mut self, max: Option<Sectors>) -> EngineResult<()>;
+
+    /// The largest size to which a filesystem of this pool may be made or
+    /// grown; None if filesystems may be any size.
+    fn max_fs_size(&self) -> Option<Sectors>;
+
+    /// Set the label of this pool with this key to value, or remove it if
+    /// value is None.
+    /// Returns an Invalid error if the key or value breaks the rules of
+    /// validate_label().
+    fn set_label(&mut self, key: &str, value: Option<&str>) -> EngineResult<()>;
+
+    /// The labels of this pool, by key.
+    fn labels(&self) -> &BTreeMap<String, String>;
+
+    /// The time that this pool was created, rounded down to the nearest
+    /// second.
+    fn created(&self) -> DateTime<Utc>;
+
+    /// The time that a change to this pool was last saved, rounded down to
+    /// the nearest second.
+    fn last_modified(&self) -> DateTime<Utc>;
+
+    /// The description of the key in the kernel keyring with which the
+    /// pool's data is encrypted, None if the pool is not encrypted.
+    fn key_description(&self) -> Option<&KeyDescription>;
+
+    /// The redundancy the pool was created with. A pool whose redundancy
+    /// was not recorded has Redundancy::NONE.
+    fn redundancy(&self) -> Redundancy;
 
     /// The total number of Sectors belonging to this pool.
     /// There are no exclusions, so this number includes overhead sectors
@@ -112,12 +751,30 @@ pub trait Pool: HasName + HasUuid {
     /// or to reserve for some other purpose.
     fn total_physical_used(&self) -> EngineResult<Sectors>;
 
+    /// The outcome of the most recent check of whether the pool needed
+    /// to be extended.
+    fn extend_state(&self) -> PoolExtendState;
+
+    /// Whether, as of the most recent check, the pool's thin-pool metadata
+    /// is nearly full and could not be extended.
+    fn metadata_low(&self) -> bool;
+
     /// Get all the filesystems belonging to this pool.
     fn filesystems(&self) -> Vec<&Filesystem>;
 
     /// Get the filesystem in this pool with this UUID.
     fn get_filesystem(&self, uuid: FilesystemUuid) -> Option<&Filesystem>;
 
+    /// The UUIDs of the filesystems in this pool which are snapshots of the
+    /// filesystem fs_uuid. Snapshots of those snapshots are not included.
+    fn snapshots_of(&self, fs_uuid: FilesystemUuid) -> Vec<FilesystemUuid> {
+        self.filesystems()
+            .into_iter()
+            .filter(|fs| fs.snapshot_of() == Some(fs_uuid))
+            .map(|fs| fs.uuid())
+            .collect()
+    }
+
     /// Get the mutable filesystem in this pool with this UUID.
     fn get_mut_filesystem(&mut self, uuid: FilesystemUuid) -> Option<&mut Filesystem>;
 
@@ -130,30 +787,96 @@ pub trait Pool: HasName + HasUuid {
     /// Get the mutable filesystem in this pool with this UUID.
     fn get_mut_blockdev(&mut self, uuid: DevUuid) -> Option<&mut BlockDev>;
 
+    /// Compare the pool's filesystems with their records in the pool's
+    /// metadata and with their devices, and report any drift between them.
+    /// Nothing is repaired.
+    fn audit(&self) -> EngineResult<AuditReport>;
+
+    /// Delete the thin devices which an audit reports as orphaned, so that
+    /// their space is returned to the pool. They are never deleted
+    /// otherwise, as a device may be orphaned because its filesystem's
+    /// record was lost.
+    /// Returns the thin ids of the devices deleted.
+    /// Returns a Busy error, and deletes nothing, if the pool is quiesced,
+    /// and an Invalid error if any of its filesystem records can not be
+    /// read, as the devices may be the filesystems of those records.
+    fn delete_orphaned_thin_devs(&mut self) -> EngineResult<Vec<ThinDevId>>;
+
+    /// Check the pool's current state and take appropriate actions, e.g.,
+    /// extend its thinpool if it is running out of space.
+    /// Returns a Busy error, and checks nothing, if the pool is quiesced.
+    fn check(&mut self) -> EngineResult<PoolCheckReport>;
+
     /// Save the state of the pool. FIXME, see #614.
     fn save_state(&mut self) -> EngineResult<()>;
 }
 
 pub trait Engine: Debug {
     /// Create a Stratis pool.
-    /// Returns the UUID of the newly created pool.
+    /// Returns the UUID of the newly created pool. If a pool of that name,
+    /// made of exactly the devices of blockdev_paths, already exists, returns
+    /// its UUID as the Identity; if a pool of that name has other devices,
+    /// returns an AlreadyExists error.
+    /// The pool's metadata volume is mdv_size, if specified, otherwise
+    /// DEFAULT_MDV_SIZE.
+    /// If encryption is given, the pool's data is encrypted with the key in
+    /// the kernel keyring which it describes; the pool can only be set up
+    /// again while the key is in the keyring.
     /// Returns an error if the redundancy code does not correspond to a
-    /// supported redundancy.
+    /// supported redundancy, if name is invalid, see validate_name(), or if
+    /// mdv_size is too small, see validate_mdv_size().
+    /// Returns an Invalid error, without claiming any device, if two of
+    /// blockdev_paths refer to the same device, e.g., a device node and a
+    /// symbolic link to it.
+    /// Returns an Invalid error if any device fails a check made on the
+    /// devices of a new pool, unless force overrides that check; see
+    /// CreateForce.
+    /// Returns a KeyNotFound error if the key for encryption is not in the
+    /// keyring.
+    /// The blockdevs of metadata_paths, which must be among blockdev_paths,
+    /// are placed in the metadata tier, and hold the pool's thinpool
+    /// metadata, while the remaining blockdevs hold its data; see
+    /// validate_metadata_paths(). If metadata_paths is empty, all the
+    /// blockdevs are in the data tier and hold both.
+    #[allow(too_many_arguments)]
     fn create_pool(&mut self,
                    name: &str,
                    blockdev_paths: &[&Path],
                    redundancy: Option<u16>,
-                   force: bool)
-                   -> EngineResult<PoolUuid>;
+                   force: CreateForce,
+                   mdv_size: Option<Sectors>,
+                   encryption: Option<KeyDescription>,
+                   metadata_paths: &[&Path])
+                   -> EngineResult<CreateAction<PoolUuid>>;
+
+    /// Unlock the encrypted pool uuid, which could not be set up because
+    /// its key was not in the kernel keyring, by setting up the pool with
+    /// key, which must since have been added to the keyring.
+    /// Returns true if the pool was unlocked, false if it was already set
+    /// up.
+    /// Returns a NotFound error if there is no such pool, a WrongKey error
+    /// if key is not the pool's key or its data does not match the pool's
+    /// key check value, and a KeyNotFound error if key is not in the
+    /// keyring.
+    fn unlock_pool(&mut self, uuid: PoolUuid, key: &KeyDescription) -> EngineResult<bool>;
+
+    /// The UUIDs of the encrypted pools which were found, but could not be
+    /// set up because their keys were not in the kernel keyring.
+    fn locked_pools(&self) -> Vec<PoolUuid>;
 
     /// Destroy a pool.
     /// Ensures that the pool of the given UUID is absent on completion.
-    /// Returns true if some action was necessary, otherwise false.
-    fn destroy_pool(&mut self, uuid: PoolUuid) -> EngineResult<bool>;
+    /// Returns NotFound if there was no such pool, so that nothing was done.
+    /// Returns an error if the pool has filesystems, unless force is true,
+    /// in which case the filesystems are destroyed first.
+    fn destroy_pool(&mut self,
+                    uuid: PoolUuid,
+                    force: bool)
+                    -> EngineResult<DestroyAction<PoolUuid>>;
 
     /// Rename pool with uuid to new_name.
     /// Raises an error if the mapping can't be applied because
-    /// new_name is already in use.
+    /// new_name is already in use or is invalid.
     /// Returns true if it was necessary to perform an action, false if not.
     fn rename_pool(&mut self, uuid: PoolUuid, new_name: &str) -> EngineResult<RenameAction>;
 
@@ -167,9 +890,231 @@ pub trait Engine: Debug {
     /// denominator: the probably of failure is 1/denominator.
     fn configure_simulator(&mut self, denominator: u32) -> EngineResult<()>;
 
-    /// Check pools' current state and take appropriate actions
-    fn check(&mut self) -> ();
+    /// Check pools' current state and take appropriate actions.
+    /// Returns the outcome of the check of each pool; a pool which can not
+    /// be checked does not prevent the others from being checked.
+    fn check(&mut self) -> EngineResult<CheckReport>;
+
+    /// Pause the periodic check of the pools, e.g., for a maintenance
+    /// window, or resume it. While it is paused, check() still checks the
+    /// pools when it is called, so that they can be checked on demand.
+    fn set_check_paused(&mut self, paused: bool);
+
+    /// Whether the periodic check of the pools is paused.
+    fn check_paused(&self) -> bool;
+
+    /// The paths in the Stratis /dev directory of this engine.
+    fn paths(&self) -> &StratisPaths;
+
+    /// The periodic check of the pools: check(), unless the periodic check
+    /// is paused, in which case nothing is done, and None is returned.
+    fn periodic_check(&mut self) -> Option<EngineResult<CheckReport>> {
+        if self.check_paused() {
+            None
+        } else {
+            Some(self.check())
+        }
+    }
 
     /// Get all pools belonging to this engine.
     fn pools(&self) -> Vec<&Pool>;
+
+    /// Get all filesystems belonging to all pools of this engine, each
+    /// with the UUID of the pool to which it belongs.
+    fn all_filesystems(&self) -> Vec<(PoolUuid, &Filesystem)> {
+        self.pools()
+            .into_iter()
+            .flat_map(|pool| {
+                          let pool_uuid = pool.uuid();
+                          pool.filesystems()
+                              .into_iter()
+                              .map(move |fs| (pool_uuid, fs))
+                      })
+            .collect()
+    }
+
+    /// The summed total_physical_size() and total_physical_used() of all
+    /// pools of this engine, as (total, used).
+    /// A pool whose usage can not be found is left out of both sums.
+    fn total_capacity(&self) -> (Sectors, Sectors) {
+        self.pools()
+            .into_iter()
+            .fold((Sectors(0), Sectors(0)),
+                  |(total, used), pool| match pool.total_physical_used() {
+                      Ok(pool_used) => (total + pool.total_physical_size(), used + pool_used),
+                      Err(err) => {
+                          warn!("Could not find the space used by pool {}: {}",
+                                pool.uuid(),
+                                err);
+                          (total, used)
+                      }
+                  })
+    }
+
+    /// The metrics of this engine's pools and filesystems, in the Prometheus
+    /// text exposition format.
+    fn metrics(&self) -> String {
+        metrics::render(self)
+    }
+
+    /// Create the pools and filesystems described by spec which are
+    /// missing, and, if spec is pruning, destroy those which are not in it,
+    /// except pools which still have filesystems.
+    /// Returns what was created, what already existed, what was destroyed,
+    /// and what failed. Applying the same spec again does nothing.
+    /// Returns an Invalid error if spec names a pool, or a filesystem of
+    /// one of its pools, more than once.
+    fn apply_spec(&mut self, spec: &EngineSpec) -> EngineResult<ApplyReport> {
+        spec::apply(self, spec)
+    }
+
+    /// Get all blockdevs belonging to all pools of this engine, each with
+    /// the UUID of the pool to which it belongs, and its device node.
+    /// A device node which is a symbolic link is resolved to its target,
+    /// if it currently exists.
+    fn claimed_devices(&self) -> Vec<(PoolUuid, DevUuid, PathBuf)> {
+        self.pools()
+            .into_iter()
+            .flat_map(|pool| {
+                          let pool_uuid = pool.uuid();
+                          pool.blockdevs()
+                              .into_iter()
+                              .map(move |bd| {
+                                       let devnode = bd.devnode();
+                                       (pool_uuid,
+                                        bd.uuid(),
+                                        fs::canonicalize(&devnode).unwrap_or(devnode))
+                                   })
+                      })
+            .collect()
+    }
+
+    /// Find the filesystem on which the file or directory at path resides,
+    /// by comparing the device which holds it with the device node of each
+    /// filesystem of this engine.
+    /// Returns the UUIDs of the filesystem and of its pool, or None if path
+    /// is not on a Stratis filesystem.
+    /// Returns an error if path can not be examined.
+    fn resolve_path(&self, path: &Path) -> EngineResult<Option<(PoolUuid, FilesystemUuid)>> {
+        let device = fs::metadata(path)?.dev();
+        Ok(self.all_filesystems()
+               .into_iter()
+               .find(|&(_, fs)| {
+                         fs::metadata(fs.devnode())
+                             .map(|metadata| metadata.rdev() == device)
+                             .unwrap_or(false)
+                     })
+               .map(|(pool_uuid, fs)| (pool_uuid, fs.uuid())))
+    }
+
+    /// Take the events which have occurred since the last call, oldest
+    /// first.
+    fn drain_events(&mut self) -> Vec<EngineEvent>;
+
+    /// The most recent events, oldest first, whether or not they have been
+    /// taken by drain_events().
+    fn recent_events(&self) -> Vec<EngineEvent>;
+
+    /// Read the Stratis metadata on the device at path, without setting up
+    /// or claiming the device.
+    /// Returns None if the device does not belong to a Stratis pool.
+    fn read_device_metadata(&self, path: &Path) -> EngineResult<Option<DeviceMetadata>>;
+
+    /// Find out, for each of the devices at paths, whether it could be
+    /// added to a pool, without changing it. The results are in the order
+    /// of paths. A device which can not be examined has an error of its
+    /// own, which does not prevent the others from being examined.
+    fn available_blockdevs(&self, paths: &[&Path]) -> Vec<EngineResult<AvailableBlockDev>>;
+
+    /// Give the Stratis devices at paths, which must all belong to one
+    /// pool that is not set up, the pool UUID new_pool_uuid, so that they
+    /// form a pool of their own. This is for copies of the devices of a
+    /// pool, made by copying their contents, which otherwise collide with
+    /// the devices they were copied from. It rewrites the devices' static
+    /// headers, so that they no longer belong to the pool they did.
+    /// Returns a Busy error if any of the devices belongs to a pool which
+    /// is set up, an Invalid error if they do not all belong to one pool,
+    /// and an AlreadyExists error if devices of pool new_pool_uuid are
+    /// present.
+    fn relabel_pool(&self, paths: &[&Path], new_pool_uuid: PoolUuid) -> EngineResult<()>;
+
+    /// Give the Stratis device at path, whose pool is not set up, the
+    /// device UUID new_dev_uuid, rewriting its static header and the
+    /// metadata of its pool. A copy of a device must first be given a pool
+    /// of its own with relabel_pool().
+    /// Returns a Busy error if the device belongs to a pool which is set
+    /// up, an Invalid error if another device in its pool has the same
+    /// UUID, and an AlreadyExists error if its pool already has a device
+    /// with UUID new_dev_uuid.
+    fn relabel_device(&self, path: &Path, new_dev_uuid: DevUuid) -> EngineResult<()>;
+
+    /// The version of the on-disk metadata format written by this engine.
+    fn metadata_version(&self) -> u32;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    /// The base directory is the one given.
+    fn paths_base() {
+        let paths = StratisPaths::new("/run/stratis");
+        assert_eq!(paths.base(), Path::new("/run/stratis"));
+    }
+
+    #[test]
+    /// The MDV directory is hidden, in the base directory, and named for
+    /// the pool's UUID.
+    fn paths_mdv_dir() {
+        let paths = StratisPaths::new(DEV_PATH);
+        let pool_uuid = Uuid::new_v4();
+        assert_eq!(paths.mdv_dir(pool_uuid),
+                   PathBuf::from(format!("/dev/stratis/.mdv-{}", pool_uuid.simple())));
+    }
+
+    #[test]
+    /// A pool's directory is in the base directory, named for the pool.
+    fn paths_pool_dir() {
+        let paths = StratisPaths::new(DEV_PATH);
+        assert_eq!(paths.pool_dir("pool"), Path::new("/dev/stratis/pool"));
+    }
+
+    #[test]
+    /// A filesystem's link is in its pool's directory, named for the
+    /// filesystem.
+    fn paths_filesystem_devnode() {
+        let paths = StratisPaths::new(DEV_PATH);
+        assert_eq!(paths.filesystem_devnode("pool", "fs"),
+                   Path::new("/dev/stratis/pool/fs"));
+        assert_eq!(paths.filesystem_devnode("pool", "fs").parent(),
+                   Some(paths.pool_dir("pool").as_path()));
+    }
+
+    #[test]
+    /// Options given for a mount follow the defaults, replacing any default
+    /// of the same name.
+    fn merge_mount_options_overrides() {
+        let defaults = vec!["discard".to_string(), "logbufs=4".to_string()];
+        assert_eq!(merge_mount_options(&defaults, &[]).unwrap(),
+                   "discard,logbufs=4");
+        assert_eq!(merge_mount_options(&defaults, &["logbufs=8", "nouuid"]).unwrap(),
+                   "discard,logbufs=8,nouuid");
+        assert_eq!(merge_mount_options(&[], &["nouuid"]).unwrap(), "nouuid");
+    }
+
+    #[test]
+    /// Options which mount(2) could not take apart again are refused.
+    fn merge_mount_options_invalid() {
+        for opt in &["", "discard,nouuid", "logbufs= 8"] {
+            assert!(match merge_mount_options(&[], &[opt]) {
+                        Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                        _ => false,
+                    });
+        }
+    }
 }
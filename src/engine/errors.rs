@@ -19,8 +19,16 @@ pub enum ErrorEnum {
 
     AlreadyExists,
     Busy,
+    Corrupt,
     Invalid,
+    /// A key needed to set up an encrypted pool is not in the kernel
+    /// keyring.
+    KeyNotFound,
+    LimitReached,
+    NoSpace,
     NotFound,
+    /// A key is not the key with which an encrypted pool was encrypted.
+    WrongKey,
 }
 
 #[derive(Debug)]
@@ -0,0 +1,190 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Events which clients may wish to be notified of.
+//
+// An engine and its pools share a single EventQueue. Operations which
+// change the engine's state, and checks which find that a pool needs
+// attention, push events onto the queue. The D-Bus layer drains the queue
+// to send signals, and a bounded history of recent events is kept for
+// clients which were not listening when the events occurred.
+
+use std::collections::{HashSet, VecDeque};
+
+use uuid::Uuid;
+
+use super::types::{DevUuid, FilesystemUuid, PoolUuid};
+
+/// The number of events kept in the history of recent events.
+pub const MAX_RECENT_EVENTS: usize = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EngineEvent {
+    PoolCreated(PoolUuid),
+    PoolDestroyed(PoolUuid),
+    PoolRenamed(PoolUuid),
+    FilesystemCreated(PoolUuid, FilesystemUuid),
+    FilesystemDestroyed(PoolUuid, FilesystemUuid),
+    BlockdevAdded(PoolUuid, DevUuid),
+    BlockdevRemoved(PoolUuid, DevUuid),
+    /// The pool's data device needed to be extended, but could not be.
+    CapacityLow(PoolUuid),
+    /// The pool's metadata device is low on space, and could not be
+    /// extended.
+    MetadataLow(PoolUuid),
+    /// The filesystem has come to use more than its pool's filesystem
+    /// space watermark, and is using the given percentage of its space.
+    FilesystemSpaceLow(PoolUuid, FilesystemUuid, u8),
+}
+
+impl EngineEvent {
+    /// The name of this kind of event.
+    pub fn kind(&self) -> &'static str {
+        match *self {
+            EngineEvent::PoolCreated(_) => "PoolCreated",
+            EngineEvent::PoolDestroyed(_) => "PoolDestroyed",
+            EngineEvent::PoolRenamed(_) => "PoolRenamed",
+            EngineEvent::FilesystemCreated(_, _) => "FilesystemCreated",
+            EngineEvent::FilesystemDestroyed(_, _) => "FilesystemDestroyed",
+            EngineEvent::BlockdevAdded(_, _) => "BlockdevAdded",
+            EngineEvent::BlockdevRemoved(_, _) => "BlockdevRemoved",
+            EngineEvent::CapacityLow(_) => "CapacityLow",
+            EngineEvent::MetadataLow(_) => "MetadataLow",
+            EngineEvent::FilesystemSpaceLow(_, _, _) => "FilesystemSpaceLow",
+        }
+    }
+
+    /// The UUID of the pool the event concerns.
+    pub fn pool_uuid(&self) -> PoolUuid {
+        match *self {
+            EngineEvent::PoolCreated(uuid) |
+            EngineEvent::PoolDestroyed(uuid) |
+            EngineEvent::PoolRenamed(uuid) |
+            EngineEvent::FilesystemCreated(uuid, _) |
+            EngineEvent::FilesystemDestroyed(uuid, _) |
+            EngineEvent::BlockdevAdded(uuid, _) |
+            EngineEvent::BlockdevRemoved(uuid, _) |
+            EngineEvent::CapacityLow(uuid) |
+            EngineEvent::MetadataLow(uuid) |
+            EngineEvent::FilesystemSpaceLow(uuid, _, _) => uuid,
+        }
+    }
+
+    /// The UUID of the filesystem or blockdev the event concerns, if any.
+    pub fn object_uuid(&self) -> Option<Uuid> {
+        match *self {
+            EngineEvent::FilesystemCreated(_, uuid) |
+            EngineEvent::FilesystemDestroyed(_, uuid) |
+            EngineEvent::BlockdevAdded(_, uuid) |
+            EngineEvent::BlockdevRemoved(_, uuid) |
+            EngineEvent::FilesystemSpaceLow(_, uuid, _) => Some(uuid),
+            _ => None,
+        }
+    }
+}
+
+/// Find the filesystems of pool pool_uuid which have come to use more than
+/// watermark percent of their space, given the percentage used by each
+/// filesystem whose usage is known, and those which were above the
+/// watermark when last checked, which are updated.
+/// Returns a FilesystemSpaceLow event for each filesystem which has crossed
+/// the watermark since it was last checked, so that a filesystem which
+/// stays above the watermark is reported only once.
+pub fn fs_space_low_events(pool_uuid: PoolUuid,
+                           usage: &[(FilesystemUuid, u8)],
+                           watermark: u8,
+                           space_low: &mut HashSet<FilesystemUuid>)
+                           -> Vec<EngineEvent> {
+    let mut events = Vec::new();
+    let mut now_low = HashSet::new();
+    for &(fs_uuid, percent) in usage {
+        if percent > watermark {
+            if !space_low.contains(&fs_uuid) {
+                events.push(EngineEvent::FilesystemSpaceLow(pool_uuid, fs_uuid, percent));
+            }
+            now_low.insert(fs_uuid);
+        }
+    }
+    *space_low = now_low;
+    events
+}
+
+/// Events which have not yet been taken, and the most recent events,
+/// whether taken or not.
+#[derive(Debug, Default)]
+pub struct EventQueue {
+    pending: Vec<EngineEvent>,
+    recent: VecDeque<EngineEvent>,
+}
+
+impl EventQueue {
+    /// Add an event to the queue.
+    pub fn push(&mut self, event: EngineEvent) {
+        if self.recent.len() == MAX_RECENT_EVENTS {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(event);
+        self.pending.push(event);
+    }
+
+    /// Take all the events pushed since the last call, oldest first.
+    pub fn drain(&mut self) -> Vec<EngineEvent> {
+        self.pending.drain(..).collect()
+    }
+
+    /// The most recent events, oldest first.
+    pub fn recent(&self) -> Vec<EngineEvent> {
+        self.recent.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Draining takes each event once, but the history keeps only the
+    /// most recent events, drained or not.
+    fn drain_and_recent() {
+        let mut queue = EventQueue::default();
+        let events: Vec<_> = (0..MAX_RECENT_EVENTS + 1)
+            .map(|_| EngineEvent::PoolCreated(Uuid::new_v4()))
+            .collect();
+
+        queue.push(events[0]);
+        assert_eq!(queue.drain(), vec![events[0]]);
+        assert_eq!(queue.drain(), vec![]);
+
+        for event in &events[1..] {
+            queue.push(*event);
+        }
+        assert_eq!(queue.drain(), &events[1..]);
+        assert_eq!(queue.recent(), &events[1..]);
+    }
+
+    #[test]
+    /// A filesystem is reported when it crosses the watermark, not while it
+    /// stays above it, and again if it crosses it after falling below it.
+    fn space_low_edges() {
+        let pool_uuid = Uuid::new_v4();
+        let fs_uuid = Uuid::new_v4();
+        let mut space_low = HashSet::new();
+
+        let events = fs_space_low_events(pool_uuid, &[(fs_uuid, 85)], 85, &mut space_low);
+        assert_eq!(events, vec![]);
+
+        let events = fs_space_low_events(pool_uuid, &[(fs_uuid, 90)], 85, &mut space_low);
+        assert_eq!(events,
+                   vec![EngineEvent::FilesystemSpaceLow(pool_uuid, fs_uuid, 90)]);
+        let events = fs_space_low_events(pool_uuid, &[(fs_uuid, 95)], 85, &mut space_low);
+        assert_eq!(events, vec![]);
+
+        let events = fs_space_low_events(pool_uuid, &[(fs_uuid, 50)], 85, &mut space_low);
+        assert_eq!(events, vec![]);
+        assert!(space_low.is_empty());
+        let events = fs_space_low_events(pool_uuid, &[(fs_uuid, 86)], 85, &mut space_low);
+        assert_eq!(events,
+                   vec![EngineEvent::FilesystemSpaceLow(pool_uuid, fs_uuid, 86)]);
+    }
+}
@@ -20,19 +20,27 @@ macro_rules! calculate_redundancy {
 }
 
 macro_rules! destroy_pool {
-    ( $s:ident; $uuid: ident) => {
-        if let Some(ref pool) = $s.pools.get_by_uuid($uuid) {
+    ( $s:ident; $uuid: ident; $force: ident) => {
+        if let Some(pool) = $s.pools.get_mut_by_uuid($uuid) {
+            if pool.is_quiesced() {
+                return Err(EngineError::Engine(ErrorEnum::Busy, "pool is quiesced".into()));
+            }
             if pool.has_filesystems() {
-                return Err(EngineError::Engine(
-                    ErrorEnum::Busy, "filesystems remaining on pool".into()));
+                if !$force {
+                    return Err(EngineError::Engine(
+                        ErrorEnum::Busy, "filesystems remaining on pool".into()));
+                }
+                let fs_uuids: Vec<_> = pool.filesystems().iter().map(|fs| fs.uuid()).collect();
+                pool.destroy_filesystems(&fs_uuids, false)?;
             };
         } else {
-            return Ok(false);
+            return Ok(DestroyAction::NotFound);
         }
         $s.pools.remove_by_uuid($uuid)
              .expect("Must succeed since $s.pool.get_by_uuid() returned a value")
              .destroy()?;
-        Ok(true)
+        $s.events.borrow_mut().push(EngineEvent::PoolDestroyed($uuid));
+        Ok(DestroyAction::Destroyed($uuid))
     }
 }
 
@@ -51,6 +59,8 @@ macro_rules! get_mut_pool {
 macro_rules! rename_filesystem_pre {
     ( $s:ident; $uuid:ident; $new_name:ident ) => {
         {
+            validate_name($new_name)?;
+
             let old_name = match $s.filesystems.get_by_uuid($uuid) {
                 Some(filesystem) => filesystem.name().to_owned(),
                 None => return Ok(RenameAction::NoSource),
@@ -71,6 +81,8 @@ macro_rules! rename_filesystem_pre {
 macro_rules! rename_pool_pre {
     ( $s:ident; $uuid:ident; $new_name:ident ) => {
         {
+            validate_name($new_name)?;
+
             let old_name = match $s.pools.get_by_uuid($uuid) {
                 Some(pool) => pool.name().to_owned(),
                 None => return Ok(RenameAction::NoSource),
@@ -89,12 +101,16 @@ macro_rules! rename_pool_pre {
 }
 
 macro_rules! check_engine {
-    ( $s:ident ) => {
+    ( $s:ident ) => {{
+        let mut report = CheckReport::default();
         for pool in &mut $s.pools {
-            // FIXME: It is not really correct to ignore result of pool.check().
-            let _ = pool.check();
+            match pool.check() {
+                Ok(pool_report) => report.pools.push((pool.uuid(), pool_report)),
+                Err(err) => report.failed.push((pool.uuid(), err.to_string())),
+            }
         }
-    }
+        Ok(report)
+    }}
 }
 
 macro_rules! set_blockdev_user_info {
@@ -0,0 +1,227 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Metrics of an engine's pools and filesystems, rendered in the Prometheus
+// text exposition format, so that they can be scraped by operators.
+//
+// Every sample is labeled with the UUID and name of its pool, and, for a
+// filesystem, with the UUID and name of the filesystem. A value which can
+// not be found, e.g., the space used by a pool whose usage can not be
+// read, is left out rather than reported as 0.
+
+use std::collections::BTreeMap;
+
+use super::engine::Engine;
+use super::types::BlockDevState;
+
+/// A metric, with its samples so far, each a label set and a value.
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    samples: Vec<String>,
+}
+
+impl Metric {
+    fn new(name: &'static str, help: &'static str) -> Metric {
+        Metric {
+            name: name,
+            help: help,
+            samples: Vec::new(),
+        }
+    }
+
+    /// Add a sample with labels and value.
+    fn add(&mut self, labels: &[(&str, &str)], value: u64) {
+        let labels = labels
+            .iter()
+            .map(|&(key, value)| format!("{}=\"{}\"", key, escape_label_value(value)))
+            .collect::<Vec<_>>()
+            .join(",");
+        self.samples.push(format!("{}{{{}}} {}", self.name, labels, value));
+    }
+
+    /// Append the metric, in the text exposition format, to out.
+    fn render(&self, out: &mut String) {
+        out.push_str(&format!("# HELP {} {}\n", self.name, self.help));
+        out.push_str(&format!("# TYPE {} gauge\n", self.name));
+        for sample in &self.samples {
+            out.push_str(sample);
+            out.push('\n');
+        }
+    }
+}
+
+/// Escape value for use as a label value: backslashes, double quotes, and
+/// line feeds must be escaped.
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Render the metrics of engine's pools and filesystems. Pools are in order
+/// of name, and filesystems in order of name within their pools.
+pub fn render<E: Engine + ?Sized>(engine: &E) -> String {
+    let mut pool_total = Metric::new("stratis_pool_total_bytes",
+                                     "The total size of the pool's blockdevs.");
+    let mut pool_used = Metric::new("stratis_pool_used_bytes",
+                                    "The space of the pool which is in use.");
+    let mut pool_filesystems = Metric::new("stratis_pool_filesystems",
+                                           "The number of filesystems of the pool.");
+    let mut pool_degraded = Metric::new("stratis_pool_degraded_blockdevs",
+                                        "The number of the pool's blockdevs which are missing \
+                                         or bad.");
+    let mut pool_events = Metric::new("stratis_pool_recent_events",
+                                      "The number of recent events of each kind concerning \
+                                       the pool.");
+    let mut fs_size = Metric::new("stratis_filesystem_size_bytes",
+                                  "The logical size of the filesystem.");
+    let mut fs_allocated = Metric::new("stratis_filesystem_allocated_bytes",
+                                       "The space of the pool allocated to the filesystem.");
+
+    let events = engine.recent_events();
+    let mut pools = engine.pools();
+    pools.sort_by(|a, b| a.name().cmp(b.name()));
+    for pool in pools {
+        let pool_uuid = format!("{}", pool.uuid().simple());
+        let pool_labels = [("pool_uuid", pool_uuid.as_str()), ("pool_name", pool.name())];
+
+        pool_total.add(&pool_labels, *pool.total_physical_size().bytes());
+        if let Ok(used) = pool.total_physical_used() {
+            pool_used.add(&pool_labels, *used.bytes());
+        }
+        pool_filesystems.add(&pool_labels, pool.filesystems().len() as u64);
+        let degraded = pool.blockdevs()
+            .iter()
+            .filter(|bd| match bd.state() {
+                        BlockDevState::Missing | BlockDevState::Bad => true,
+                        _ => false,
+                    })
+            .count();
+        pool_degraded.add(&pool_labels, degraded as u64);
+
+        let mut counts = BTreeMap::new();
+        for event in events.iter().filter(|e| e.pool_uuid() == pool.uuid()) {
+            *counts.entry(event.kind()).or_insert(0) += 1;
+        }
+        for (kind, count) in counts {
+            pool_events.add(&[pool_labels[0], pool_labels[1], ("kind", kind)], count);
+        }
+
+        let mut filesystems = pool.filesystems();
+        filesystems.sort_by(|a, b| a.name().cmp(b.name()));
+        for fs in filesystems {
+            let fs_uuid = format!("{}", fs.uuid().simple());
+            let fs_labels = [pool_labels[0],
+                             pool_labels[1],
+                             ("filesystem_uuid", fs_uuid.as_str()),
+                             ("filesystem_name", fs.name())];
+            if let Ok(Some(info)) = pool.filesystem_allocation_info(fs.uuid()) {
+                fs_size.add(&fs_labels, *info.size.bytes());
+                fs_allocated.add(&fs_labels, *info.allocated.bytes());
+            }
+        }
+    }
+
+    let mut out = String::new();
+    for metric in &[pool_total,
+                    pool_used,
+                    pool_filesystems,
+                    pool_degraded,
+                    pool_events,
+                    fs_size,
+                    fs_allocated] {
+        metric.render(&mut out);
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::super::sim_engine::SimEngine;
+    use super::super::types::CreateForce;
+
+    use super::*;
+
+    #[test]
+    /// Each pool and filesystem has a sample of each of its metrics,
+    /// labeled with its UUID and name.
+    fn render_two_pools() {
+        let mut engine = SimEngine::default();
+        let uuid1 = engine
+            .create_pool("pool1",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        let uuid2 = engine
+            .create_pool("pool2",
+                         &[Path::new("/s/e")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        let fs_uuid = engine
+            .get_mut_pool(uuid2)
+            .unwrap()
+            .create_filesystems(&[("fs", None, None)])
+            .unwrap()
+            .into_inner()[0]
+            .1;
+
+        let metrics = engine.metrics();
+        let lines = metrics.lines().collect::<Vec<_>>();
+        let pool1_labels = format!("pool_uuid=\"{}\",pool_name=\"pool1\"", uuid1.simple());
+        let pool2_labels = format!("pool_uuid=\"{}\",pool_name=\"pool2\"", uuid2.simple());
+
+        for &(uuid, ref labels) in &[(uuid1, &pool1_labels), (uuid2, &pool2_labels)] {
+            let pool = engine.get_pool(uuid).unwrap();
+            assert!(lines.contains(&format!("stratis_pool_total_bytes{{{}}} {}",
+                                            labels,
+                                            *pool.total_physical_size().bytes())
+                                        .as_str()));
+            assert!(lines.contains(&format!("stratis_pool_degraded_blockdevs{{{}}} 0", labels)
+                                        .as_str()));
+        }
+        assert!(lines.contains(&format!("stratis_pool_filesystems{{{}}} 0", pool1_labels)
+                                    .as_str()));
+        assert!(lines.contains(&format!("stratis_pool_filesystems{{{}}} 1", pool2_labels)
+                                    .as_str()));
+        assert!(lines.contains(&format!("stratis_pool_recent_events{{{},kind=\"PoolCreated\"}} 1",
+                                        pool1_labels)
+                                    .as_str()));
+
+        let size = engine
+            .get_pool(uuid2)
+            .unwrap()
+            .filesystem_allocation_info(fs_uuid)
+            .unwrap()
+            .unwrap()
+            .size;
+        assert!(lines.contains(&format!("stratis_filesystem_size_bytes{{{},filesystem_uuid=\"{}\",\
+                                         filesystem_name=\"fs\"}} {}",
+                                        pool2_labels,
+                                        fs_uuid.simple(),
+                                        *size.bytes())
+                                    .as_str()));
+        assert!(lines.contains(&"# TYPE stratis_pool_used_bytes gauge"));
+    }
+
+    #[test]
+    /// Backslashes, double quotes, and line feeds in label values are
+    /// escaped.
+    fn escape() {
+        assert_eq!(escape_label_value("a\\b\"c\nd"), "a\\\\b\\\"c\\nd");
+    }
+}
@@ -7,20 +7,50 @@ pub use devicemapper::{IEC, SECTOR_SIZE};
 pub use self::engine::BlockDev;
 pub use self::engine::Engine;
 pub use self::engine::Filesystem;
+pub use self::engine::{MAX_LABEL_KEY_LEN, MAX_LABEL_VALUE_LEN};
 pub use self::engine::Pool;
+pub use self::engine::StratisPaths;
 
 pub use self::errors::EngineError;
 pub use self::errors::EngineResult;
 pub use self::errors::ErrorEnum;
 
+pub use self::event::EngineEvent;
+
 pub use self::sim_engine::SimEngine;
+pub use self::spec::{ApplyReport, EngineSpec, FilesystemSpec, PoolSpec, SpecItem};
 pub use self::strat_engine::StratEngine;
 
+pub use self::types::AllocationInfo;
+pub use self::types::AuditReport;
+pub use self::types::AvailableBlockDev;
+pub use self::types::BlockDevTier;
+pub use self::types::CheckReport;
+pub use self::types::CreateAction;
+pub use self::types::CreateForce;
+pub use self::types::DEFAULT_DATA_LOW_WATER;
+pub use self::types::DEFAULT_FS_SIZE;
+pub use self::types::DEFAULT_FS_SPACE_WATERMARK;
+pub use self::types::DEFAULT_MDV_SIZE;
+pub use self::types::DataLowWater;
+pub use self::types::DestroyAction;
+pub use self::types::DestroyError;
+pub use self::types::DevOwnership;
 pub use self::types::DevUuid;
+pub use self::types::DeviceMetadata;
+pub use self::types::EngineLimits;
+pub use self::types::FilesystemType;
 pub use self::types::FilesystemUuid;
+pub use self::types::HardwareInfo;
+pub use self::types::KeyDescription;
+pub use self::types::MIN_DEV_SIZE;
+pub use self::types::MIN_MDV_SIZE;
+pub use self::types::PoolCheckReport;
+pub use self::types::PoolExtendState;
 pub use self::types::PoolUuid;
 pub use self::types::Redundancy;
 pub use self::types::RenameAction;
+pub use self::types::FsOptions;
 
 #[macro_use]
 mod macros;
@@ -31,6 +61,9 @@ pub mod strat_engine;
 #[allow(module_inception)]
 pub mod engine;
 mod errors;
+mod event;
+mod metrics;
 mod sim_engine;
+mod spec;
 mod structures;
 pub mod types;
@@ -13,7 +13,7 @@ use uuid::Uuid;
 use devicemapper::{Bytes, Sectors, IEC};
 
 use super::super::engine::{BlockDev, HasUuid};
-use super::super::types::{BlockDevState, DevUuid};
+use super::super::types::{BlockDevState, BlockDevTier, DevUuid, HardwareInfo};
 
 use super::randomization::Randomizer;
 
@@ -26,6 +26,7 @@ pub struct SimDev {
     user_info: Option<String>,
     hardware_info: Option<String>,
     initialization_time: u64,
+    tier: BlockDevTier,
 }
 
 impl BlockDev for SimDev {
@@ -41,10 +42,18 @@ impl BlockDev for SimDev {
         set_blockdev_user_info!(self; user_info)
     }
 
-    fn hardware_info(&self) -> Option<&str> {
+    fn hardware_id(&self) -> Option<&str> {
         self.hardware_info.as_ref().map(|x| &**x)
     }
 
+    fn hardware_info(&self) -> Option<&HardwareInfo> {
+        None
+    }
+
+    fn health(&self) -> Option<String> {
+        None
+    }
+
     fn initialization_time(&self) -> DateTime<Utc> {
         Utc.timestamp(self.initialization_time as i64, 0)
     }
@@ -53,9 +62,17 @@ impl BlockDev for SimDev {
         Bytes(IEC::Gi).sectors()
     }
 
+    fn metadata_size(&self) -> Sectors {
+        Bytes(4 * IEC::Mi).sectors()
+    }
+
     fn state(&self) -> BlockDevState {
         BlockDevState::InUse
     }
+
+    fn tier(&self) -> BlockDevTier {
+        self.tier
+    }
 }
 
 impl HasUuid for SimDev {
@@ -66,7 +83,7 @@ impl HasUuid for SimDev {
 
 impl SimDev {
     /// Generates a new device from any devnode.
-    pub fn new(rdm: Rc<RefCell<Randomizer>>, devnode: &Path) -> SimDev {
+    pub fn new(rdm: Rc<RefCell<Randomizer>>, devnode: &Path, tier: BlockDevTier) -> SimDev {
         SimDev {
             devnode: devnode.to_owned(),
             rdm: rdm,
@@ -74,6 +91,7 @@ impl SimDev {
             user_info: None,
             hardware_info: None,
             initialization_time: Utc::now().timestamp() as u64,
+            tier: tier,
         }
     }
 }
@@ -6,13 +6,20 @@ use std::cell::RefCell;
 use std::collections::HashSet;
 use std::collections::hash_map::RandomState;
 use std::iter::FromIterator;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
 
-use super::super::engine::{Engine, HasName, HasUuid, Pool};
+use devicemapper::{Bytes, IEC, Sectors};
+
+use super::super::engine::{Engine, HasName, HasUuid, Pool, StratisPaths, check_unclaimed,
+                           validate_mdv_size, validate_metadata_paths, validate_name};
 use super::super::errors::{EngineError, EngineResult, ErrorEnum};
+use super::super::event::{EngineEvent, EventQueue};
+use super::super::strat_engine::METADATA_VERSION;
 use super::super::structures::Table;
-use super::super::types::{PoolUuid, Redundancy, RenameAction};
+use super::super::types::{AvailableBlockDev, CheckReport, CreateAction, CreateForce,
+                          DestroyAction, DevOwnership, DevUuid, DeviceMetadata, EngineLimits,
+                          KeyDescription, MIN_DEV_SIZE, PoolUuid, Redundancy, RenameAction};
 
 use super::pool::SimPool;
 use super::randomization::Randomizer;
@@ -22,31 +29,78 @@ use super::randomization::Randomizer;
 pub struct SimEngine {
     pools: Table<SimPool>,
     rdm: Rc<RefCell<Randomizer>>,
+    events: Rc<RefCell<EventQueue>>,
+    limits: EngineLimits,
+    check_paused: bool,
+    paths: StratisPaths,
 }
 
-impl SimEngine {}
+impl SimEngine {
+    /// A SimEngine which may create no more pools or filesystems than
+    /// limits allows.
+    pub fn new(limits: EngineLimits) -> SimEngine {
+        SimEngine {
+            limits: limits,
+            paths: StratisPaths::from_env(),
+            ..SimEngine::default()
+        }
+    }
+}
 
 impl Engine for SimEngine {
     fn create_pool(&mut self,
                    name: &str,
                    blockdev_paths: &[&Path],
                    redundancy: Option<u16>,
-                   _force: bool)
-                   -> EngineResult<PoolUuid> {
+                   _force: CreateForce,
+                   mdv_size: Option<Sectors>,
+                   encryption: Option<KeyDescription>,
+                   metadata_paths: &[&Path])
+                   -> EngineResult<CreateAction<PoolUuid>> {
 
         let redundancy = calculate_redundancy!(redundancy);
+        validate_name(name)?;
+        validate_mdv_size(mdv_size)?;
+        validate_metadata_paths(blockdev_paths, metadata_paths)?;
 
-        if self.pools.contains_name(name) {
+        if let Some(pool) = self.pools.get_by_name(name) {
+            let devnodes: HashSet<PathBuf> = pool.blockdevs()
+                .iter()
+                .map(|bd| bd.devnode())
+                .collect();
+            let requested: HashSet<PathBuf> =
+                blockdev_paths.iter().map(|p| p.to_path_buf()).collect();
+            if devnodes == requested {
+                return Ok(CreateAction::Identity(pool.uuid()));
+            }
             return Err(EngineError::Engine(ErrorEnum::AlreadyExists, name.into()));
         }
+        if self.pools.len() >= self.limits.max_pools {
+            let err_msg = format!("the limit of {} pools has been reached",
+                                  self.limits.max_pools);
+            return Err(EngineError::Engine(ErrorEnum::LimitReached, err_msg));
+        }
 
+        // Simulated devices can only be told apart by their paths.
         let device_set: HashSet<_, RandomState> = HashSet::from_iter(blockdev_paths);
+        if device_set.len() != blockdev_paths.len() {
+            let err_msg = "a device is given more than once";
+            return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+        }
         let devices = device_set
             .into_iter()
             .map(|x| *x)
             .collect::<Vec<&Path>>();
 
-        let pool = SimPool::new(&Rc::clone(&self.rdm), name, &devices, redundancy);
+        let mut pool = SimPool::new(&Rc::clone(&self.rdm),
+                                    &Rc::clone(&self.events),
+                                    &self.paths,
+                                    name,
+                                    &devices,
+                                    redundancy,
+                                    encryption,
+                                    metadata_paths);
+        pool.set_max_filesystems(self.limits.max_filesystems_per_pool);
 
         if self.rdm.borrow_mut().throw_die() {
             return Err(EngineError::Engine(ErrorEnum::Error, "X".into()));
@@ -54,23 +108,40 @@ impl Engine for SimEngine {
 
         let uuid = pool.uuid();
         self.pools.insert(pool);
+        self.events
+            .borrow_mut()
+            .push(EngineEvent::PoolCreated(uuid));
 
-        Ok(uuid)
+        Ok(CreateAction::Created(uuid))
     }
 
-    fn destroy_pool(&mut self, uuid: PoolUuid) -> EngineResult<bool> {
-        destroy_pool!{self; uuid}
+    fn destroy_pool(&mut self,
+                    uuid: PoolUuid,
+                    force: bool)
+                    -> EngineResult<DestroyAction<PoolUuid>> {
+        destroy_pool!{self; uuid; force}
+    }
+
+    /// Simulated pools are never locked, since they are never set up again.
+    fn unlock_pool(&mut self, uuid: PoolUuid, _key: &KeyDescription) -> EngineResult<bool> {
+        if self.pools.contains_uuid(uuid) {
+            Ok(false)
+        } else {
+            Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string()))
+        }
+    }
+
+    fn locked_pools(&self) -> Vec<PoolUuid> {
+        vec![]
     }
 
     fn rename_pool(&mut self, uuid: PoolUuid, new_name: &str) -> EngineResult<RenameAction> {
         rename_pool_pre!(self; uuid; new_name);
 
-        let mut pool = self.pools
-            .remove_by_uuid(uuid)
-            .expect("Must succeed since self.pools.get_by_uuid() returned a value");
-        pool.rename(new_name);
-
-        self.pools.insert(pool);
+        self.pools.rename(uuid, new_name)?;
+        self.events
+            .borrow_mut()
+            .push(EngineEvent::PoolRenamed(uuid));
         Ok(RenameAction::Renamed)
     }
 
@@ -88,20 +159,107 @@ impl Engine for SimEngine {
         Ok(())
     }
 
-    fn check(&mut self) -> () {
+    fn check(&mut self) -> EngineResult<CheckReport> {
         check_engine!(self)
     }
 
+    fn set_check_paused(&mut self, paused: bool) {
+        self.check_paused = paused;
+    }
+
+    fn check_paused(&self) -> bool {
+        self.check_paused
+    }
+
+    fn paths(&self) -> &StratisPaths {
+        &self.paths
+    }
+
     fn pools(&self) -> Vec<&Pool> {
         self.pools.into_iter().map(|x| x as &Pool).collect()
     }
+
+    fn drain_events(&mut self) -> Vec<EngineEvent> {
+        self.events.borrow_mut().drain()
+    }
+
+    fn recent_events(&self) -> Vec<EngineEvent> {
+        self.events.borrow().recent()
+    }
+
+    /// A simulated device has no metadata on it, but one claimed by a
+    /// pool is reported as if it did.
+    fn read_device_metadata(&self, path: &Path) -> EngineResult<Option<DeviceMetadata>> {
+        Ok(self.pools
+               .into_iter()
+               .flat_map(|pool| {
+                             let pool_uuid = pool.uuid();
+                             pool.blockdevs()
+                                 .into_iter()
+                                 .filter(|bd| bd.devnode() == path)
+                                 .map(move |bd| {
+                                          DeviceMetadata {
+                                              pool_uuid: pool_uuid,
+                                              dev_uuid: bd.uuid(),
+                                              dev_size: bd.total_size(),
+                                              mda_offset: Sectors(0),
+                                              data_offset: Sectors(0),
+                                              initialization_time: bd.initialization_time(),
+                                              last_update_time: None,
+                                          }
+                                      })
+                         })
+               .next())
+    }
+
+    /// Every simulated device is as big as a simulated blockdev, and
+    /// belongs to the pool which claims it, if any.
+    fn available_blockdevs(&self, paths: &[&Path]) -> Vec<EngineResult<AvailableBlockDev>> {
+        paths
+            .iter()
+            .map(|path| {
+                let ownership = match self.read_device_metadata(path)? {
+                    Some(metadata) => DevOwnership::Ours(metadata.pool_uuid, metadata.dev_uuid),
+                    None => DevOwnership::Unowned,
+                };
+                Ok(AvailableBlockDev {
+                       ownership: ownership,
+                       size: Bytes(IEC::Gi),
+                       meets_min_size: Bytes(IEC::Gi) >= MIN_DEV_SIZE,
+                   })
+            })
+            .collect()
+    }
+
+    /// A simulated device which is not claimed by a pool is not a Stratis
+    /// device, and one which is can not be relabeled.
+    fn relabel_pool(&self, paths: &[&Path], _new_pool_uuid: PoolUuid) -> EngineResult<()> {
+        check_unclaimed(&self.claimed_devices(), paths)?;
+        match paths.first() {
+            Some(path) => {
+                let err_msg = format!("{} is not a Stratis device", path.display());
+                Err(EngineError::Engine(ErrorEnum::NotFound, err_msg))
+            }
+            None => Ok(()),
+        }
+    }
+
+    fn relabel_device(&self, path: &Path, _new_dev_uuid: DevUuid) -> EngineResult<()> {
+        check_unclaimed(&self.claimed_devices(), &[path])?;
+        let err_msg = format!("{} is not a Stratis device", path.display());
+        Err(EngineError::Engine(ErrorEnum::NotFound, err_msg))
+    }
+
+    fn metadata_version(&self) -> u32 {
+        METADATA_VERSION
+    }
 }
 
 #[cfg(test)]
 mod tests {
 
     use std;
-    use std::path::Path;
+    use std::path::{Path, PathBuf};
 
     use uuid::Uuid;
 
@@ -109,11 +267,26 @@ mod tests {
 
     use super::SimEngine;
 
+    use engine::BlockDevTier;
     use engine::Engine;
+    use engine::Filesystem;
     use engine::EngineError;
+    use engine::EngineEvent;
+    use engine::EngineLimits;
+    use engine::KeyDescription;
+    use engine::CreateAction;
+    use engine::CreateForce;
+    use engine::DestroyAction;
+    use engine::DevOwnership;
     use engine::ErrorEnum;
+    use engine::FilesystemUuid;
+    use engine::MIN_MDV_SIZE;
+    use engine::PoolUuid;
+    use engine::Redundancy;
     use engine::RenameAction;
 
+    use devicemapper::Sectors;
+
     #[test]
     fn prop_configure_simulator_runs() {
 
@@ -138,15 +311,51 @@ mod tests {
     #[test]
     /// When an engine has no pools, destroying any pool must succeed
     fn destroy_pool_empty() {
-        assert!(SimEngine::default().destroy_pool(Uuid::new_v4()).is_ok());
+        assert!(SimEngine::default().destroy_pool(Uuid::new_v4(), false).is_ok());
+    }
+
+    #[test]
+    /// Pools may be created up to the engine's limit, but no more.
+    fn create_pool_limit() {
+        let mut engine = SimEngine::new(EngineLimits {
+                                            max_pools: 2,
+                                            max_filesystems_per_pool: 1,
+                                        });
+        engine.create_pool("name1", &[], None, CreateForce::default(), None, None, &[]).unwrap();
+        let uuid = engine
+            .create_pool("name2", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        assert!(match engine.create_pool("name3",
+                                         &[],
+                                         None,
+                                         CreateForce::default(),
+                                         None,
+                                         None,
+                                         &[]) {
+                    Err(EngineError::Engine(ErrorEnum::LimitReached, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(engine.pools().len(), 2);
+
+        // The engine's limit on filesystems applies to each of its pools.
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        pool.create_filesystems(&[("fs1", None, None)]).unwrap();
+        assert!(match pool.create_filesystems(&[("fs2", None, None)]) {
+                    Err(EngineError::Engine(ErrorEnum::LimitReached, _)) => true,
+                    _ => false,
+                });
     }
 
     #[test]
     /// Destroying an empty pool should succeed.
     fn destroy_empty_pool() {
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("name", &[], None, false).unwrap();
-        assert!(engine.destroy_pool(uuid).is_ok());
+        let uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        assert!(engine.destroy_pool(uuid, false).is_ok());
     }
 
     #[test]
@@ -154,9 +363,16 @@ mod tests {
     fn destroy_pool_w_devices() {
         let mut engine = SimEngine::default();
         let uuid = engine
-            .create_pool("name", &[Path::new("/s/d")], None, false)
-            .unwrap();
-        assert!(engine.destroy_pool(uuid).is_ok());
+            .create_pool("name",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        assert!(engine.destroy_pool(uuid, false).is_ok());
     }
 
     #[test]
@@ -164,25 +380,64 @@ mod tests {
     fn destroy_pool_w_filesystem() {
         let mut engine = SimEngine::default();
         let uuid = engine
-            .create_pool("name", &[Path::new("/s/d")], None, false)
-            .unwrap();
+            .create_pool("name",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
         {
             let pool = engine.get_mut_pool(uuid).unwrap();
-            pool.create_filesystems(&[("test", None)]).unwrap();
+            pool.create_filesystems(&[("test", None, None)]).unwrap();
         }
-        assert!(engine.destroy_pool(uuid).is_err());
+        assert!(match engine.destroy_pool(uuid, false) {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// Destroying a pool with filesystems should succeed if forced
+    fn destroy_pool_w_filesystem_force() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("name",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        {
+            let pool = engine.get_mut_pool(uuid).unwrap();
+            pool.create_filesystems(&[("test", None, None)]).unwrap();
+        }
+        assert_eq!(engine.destroy_pool(uuid, true).unwrap(), DestroyAction::Destroyed(uuid));
+        assert!(engine.get_pool(uuid).is_none());
     }
 
     #[test]
-    #[ignore]
     /// Creating a new pool identical to the previous should succeed
     fn create_new_pool_twice() {
         let name = "name";
         let mut engine = SimEngine::default();
-        engine.create_pool(name, &[], None, false).unwrap();
-        assert!(match engine.create_pool(name, &[], None, false) {
-                    Ok(uuid) => engine.get_pool(uuid).unwrap().blockdevs().is_empty(),
-                    Err(_) => false,
+        engine.create_pool(name, &[], None, CreateForce::default(), None, None, &[]).unwrap();
+        assert!(match engine.create_pool(name,
+                                         &[],
+                                         None,
+                                         CreateForce::default(),
+                                         None,
+                                         None,
+                                         &[]) {
+                    Ok(CreateAction::Identity(uuid)) => {
+                        engine.get_pool(uuid).unwrap().blockdevs().is_empty()
+                    }
+                    _ => false,
                 });
     }
 
@@ -192,24 +447,115 @@ mod tests {
         let name = "name";
         let mut engine = SimEngine::default();
         engine
-            .create_pool(name, &[Path::new("/s/d")], None, false)
+            .create_pool(name, &[Path::new("/s/d")], None, CreateForce::default(), None, None, &[])
             .unwrap();
-        assert!(match engine.create_pool(name, &[], None, false) {
+        assert!(match engine.create_pool(name,
+                                         &[],
+                                         None,
+                                         CreateForce::default(),
+                                         None,
+                                         None,
+                                         &[]) {
                     Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
                     _ => false,
                 });
     }
 
     #[test]
-    /// Creating a pool with duplicate devices should succeed
+    /// Creating a pool with a metadata volume smaller than the minimum should
+    /// fail, but a larger one is accepted
+    fn create_pool_mdv_size() {
+        let mut engine = SimEngine::default();
+        assert!(match engine.create_pool("name",
+                                         &[],
+                                         None,
+                                         CreateForce::default(),
+                                         Some(MIN_MDV_SIZE - Sectors(1)),
+                                         None,
+                                         &[]) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert!(engine
+                    .create_pool("name",
+                                 &[],
+                                 None,
+                                 CreateForce::default(),
+                                 Some(MIN_MDV_SIZE * 2u64),
+                                 None,
+                                 &[])
+                    .is_ok());
+    }
+
+    #[test]
+    /// The key description of an encrypted pool is reported, and a pool
+    /// which exists is already unlocked, but one which does not can not be
+    /// unlocked.
+    fn encrypted_pool() {
+        let mut engine = SimEngine::default();
+        let key = KeyDescription::new("stratis-key").unwrap();
+        let uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, Some(key.clone()), &[])
+            .unwrap()
+            .into_inner();
+        assert_eq!(engine.get_pool(uuid).unwrap().key_description(), Some(&key));
+
+        assert!(engine.locked_pools().is_empty());
+        assert!(!engine.unlock_pool(uuid, &key).unwrap());
+        assert!(match engine.unlock_pool(Uuid::new_v4(), &key) {
+                    Err(EngineError::Engine(ErrorEnum::NotFound, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// Creating a pool with duplicate devices should fail
     fn create_pool_duplicate_devices() {
         let path = "/s/d";
         let mut engine = SimEngine::default();
         let devices = vec![Path::new(path), Path::new(path)];
-        assert!(match engine.create_pool("name", &devices, None, false) {
-                    Ok(uuid) => engine.get_pool(uuid).unwrap().blockdevs().len() == 1,
+        assert!(match engine.create_pool("name",
+                                         &devices,
+                                         None,
+                                         CreateForce::default(),
+                                         None,
+                                         None,
+                                         &[]) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
                     _ => false,
                 });
+        assert!(engine.pools().is_empty());
+    }
+
+    #[test]
+    /// Creating a pool with a metadata tier should place exactly the given
+    /// devices in it, but a metadata tier which is not among the pool's
+    /// devices, or which would leave none for its data, should be refused
+    fn create_pool_metadata_tier() {
+        let mut engine = SimEngine::default();
+        let devices = [Path::new("/s/d"), Path::new("/s/e")];
+        let invalid = [(&devices[..1], &devices[1..]), (&devices[..], &devices[..])];
+        for &(paths, metadata_paths) in &invalid {
+            assert!(match engine.create_pool("name",
+                                             paths,
+                                             None,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             metadata_paths) {
+                        Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                        _ => false,
+                    });
+        }
+
+        let uuid = engine
+            .create_pool("name", &devices, None, CreateForce::default(), None, None, &devices[..1])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_pool(uuid).unwrap();
+        assert!(pool.blockdevs().iter().all(|bd| {
+            (bd.tier() == BlockDevTier::Metadata) == (bd.devnode() == devices[0])
+        }));
     }
 
     #[test]
@@ -217,10 +563,31 @@ mod tests {
     fn create_pool_max_u16_raid() {
         let mut engine = SimEngine::default();
         assert!(engine
-                    .create_pool("name", &[], Some(std::u16::MAX), false)
+                    .create_pool("name",
+                                 &[],
+                                 Some(std::u16::MAX),
+                                 CreateForce::default(),
+                                 None,
+                                 None,
+                                 &[])
                     .is_err());
     }
 
+    #[test]
+    /// A pool has the redundancy it was created with, which is NONE if
+    /// none was specified.
+    fn create_pool_redundancy() {
+        let mut engine = SimEngine::default();
+        let redundancies = vec![("name1", None), ("name2", Some(u16::from(Redundancy::NONE)))];
+        for (name, redundancy) in redundancies {
+            let uuid = engine
+                .create_pool(name, &[], redundancy, CreateForce::default(), None, None, &[])
+                .unwrap()
+                .into_inner();
+            assert_eq!(engine.get_pool(uuid).unwrap().redundancy(), Redundancy::NONE);
+        }
+    }
+
     #[test]
     /// Renaming a pool on an empty engine always works
     fn rename_empty() {
@@ -236,7 +603,10 @@ mod tests {
     fn rename_identity() {
         let name = "name";
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool(name, &[], None, false).unwrap();
+        let uuid = engine
+            .create_pool(name, &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         assert!(match engine.rename_pool(uuid, name) {
                     Ok(RenameAction::Identity) => true,
                     _ => false,
@@ -247,7 +617,10 @@ mod tests {
     /// Renaming a pool to another pool should work if new name not taken
     fn rename_happens() {
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("old_name", &[], None, false).unwrap();
+        let uuid = engine
+            .create_pool("old_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         assert!(match engine.rename_pool(uuid, "new_name") {
                     Ok(RenameAction::Renamed) => true,
                     _ => false,
@@ -259,8 +632,11 @@ mod tests {
     fn rename_fails() {
         let new_name = "new_name";
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("old_name", &[], None, false).unwrap();
-        engine.create_pool(new_name, &[], None, false).unwrap();
+        let uuid = engine
+            .create_pool("old_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        engine.create_pool(new_name, &[], None, CreateForce::default(), None, None, &[]).unwrap();
         assert!(match engine.rename_pool(uuid, new_name) {
                     Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
                     _ => false,
@@ -272,11 +648,447 @@ mod tests {
     fn rename_no_op() {
         let new_name = "new_name";
         let mut engine = SimEngine::default();
-        engine.create_pool(new_name, &[], None, false).unwrap();
+        engine.create_pool(new_name, &[], None, CreateForce::default(), None, None, &[]).unwrap();
         assert!(match engine.rename_pool(Uuid::new_v4(), new_name) {
                     Ok(RenameAction::NoSource) => true,
                     _ => false,
                 });
     }
 
+    #[test]
+    /// All filesystems of all pools should be found, each with its pool
+    fn all_filesystems() {
+        let mut engine = SimEngine::default();
+        let uuid1 = engine
+            .create_pool("name1", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let uuid2 = engine
+            .create_pool("name2", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let fs_uuids1: Vec<_> = engine
+            .get_mut_pool(uuid1)
+            .unwrap()
+            .create_filesystems(&[("fs1", None, None), ("fs2", None, None)])
+            .unwrap()
+            .into_inner()
+            .iter()
+            .map(|&(_, fs_uuid)| (uuid1, fs_uuid))
+            .collect();
+        let fs_uuids2: Vec<_> = engine
+            .get_mut_pool(uuid2)
+            .unwrap()
+            .create_filesystems(&[("fs3", None, None)])
+            .unwrap()
+            .into_inner()
+            .iter()
+            .map(|&(_, fs_uuid)| (uuid2, fs_uuid))
+            .collect();
+
+        let mut expected: Vec<_> = fs_uuids1.into_iter().chain(fs_uuids2).collect();
+        let mut found: Vec<_> = engine
+            .all_filesystems()
+            .iter()
+            .map(|&(pool_uuid, fs)| (pool_uuid, fs.uuid()))
+            .collect();
+        expected.sort();
+        found.sort();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    /// The capacity of the engine is the sum of the capacities of its pools
+    fn total_capacity() {
+        let mut engine = SimEngine::default();
+        assert_eq!(engine.total_capacity(), (Sectors(0), Sectors(0)));
+
+        let uuid1 = engine
+            .create_pool("name1",
+                         &[Path::new("/s/d"), Path::new("/s/e")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        let uuid2 = engine
+            .create_pool("name2",
+                         &[Path::new("/s/f")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+
+        let (total, used) = engine.total_capacity();
+        let pool1 = engine.get_pool(uuid1).unwrap();
+        let pool2 = engine.get_pool(uuid2).unwrap();
+        assert!(pool1.total_physical_size() > Sectors(0));
+        assert!(pool2.total_physical_size() > Sectors(0));
+        assert_eq!(total, pool1.total_physical_size() + pool2.total_physical_size());
+        assert_eq!(used,
+                   pool1.total_physical_used().unwrap() + pool2.total_physical_used().unwrap());
+    }
+
+    #[test]
+    /// All blockdevs of all pools should be found, each with its pool
+    fn claimed_devices() {
+        let mut engine = SimEngine::default();
+        let uuid1 = engine
+            .create_pool("name1",
+                         &[Path::new("/s/d"), Path::new("/s/e")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        let uuid2 = engine
+            .create_pool("name2",
+                         &[Path::new("/s/f")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+
+        let mut found: Vec<_> = engine
+            .claimed_devices()
+            .into_iter()
+            .map(|(pool_uuid, _, devnode)| (pool_uuid, devnode))
+            .collect();
+        found.sort();
+        let mut expected = vec![(uuid1, PathBuf::from("/s/d")),
+                                (uuid1, PathBuf::from("/s/e")),
+                                (uuid2, PathBuf::from("/s/f"))];
+        expected.sort();
+        assert_eq!(found, expected);
+    }
+
+    #[test]
+    /// A path which is not on a simulated filesystem resolves to nothing;
+    /// a path which does not exist can not be resolved.
+    fn resolve_path() {
+        let mut engine = SimEngine::default();
+        let pool_uuid = engine
+            .create_pool("name",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        engine
+            .get_mut_pool(pool_uuid)
+            .unwrap()
+            .create_filesystems(&[("fs", None, None)])
+            .unwrap();
+        assert_eq!(engine.resolve_path(Path::new("/")).unwrap(), None);
+        assert!(engine
+                    .resolve_path(Path::new("/stratis/no/such/path"))
+                    .is_err());
+    }
+
+    #[test]
+    /// Creating and destroying a pool and its filesystems enqueues an event
+    /// for each, in order, and each event is drained only once.
+    fn events_create_destroy() {
+        let mut engine = SimEngine::default();
+        let pool_uuid = engine
+            .create_pool("name",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        let fs_uuid = engine
+            .get_mut_pool(pool_uuid)
+            .unwrap()
+            .create_filesystems(&[("fs", None, None)])
+            .unwrap()
+            .into_inner()[0]
+            .1;
+        assert_eq!(engine.destroy_pool(pool_uuid, true).unwrap(),
+                   DestroyAction::Destroyed(pool_uuid));
+
+        let expected = vec![EngineEvent::PoolCreated(pool_uuid),
+                            EngineEvent::FilesystemCreated(pool_uuid, fs_uuid),
+                            EngineEvent::FilesystemDestroyed(pool_uuid, fs_uuid),
+                            EngineEvent::PoolDestroyed(pool_uuid)];
+        assert_eq!(engine.drain_events(), expected);
+        assert_eq!(engine.drain_events(), vec![]);
+        assert_eq!(engine.recent_events(), expected);
+    }
+
+    #[test]
+    /// A check reports on each pool which could be checked, and lists
+    /// each which could not, such as a quiesced pool.
+    fn check_report() {
+        let mut engine = SimEngine::default();
+        let uuid1 = engine
+            .create_pool("name1", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let uuid2 = engine
+            .create_pool("name2", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        engine.get_mut_pool(uuid2).unwrap().quiesce().unwrap();
+
+        let report = engine.check().unwrap();
+        assert_eq!(report.pools.len(), 1);
+        assert_eq!(report.pools[0].0, uuid1);
+        assert_eq!(report.pools[0].1.temp_files_removed, 0);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, uuid2);
+    }
+
+    #[test]
+    /// A check which finds a pool out of space enqueues an event only when
+    /// the pool first runs out of space.
+    fn events_capacity_low() {
+        let mut engine = SimEngine::default();
+        let pool_uuid = engine
+            .create_pool("name",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        engine.drain_events();
+
+        engine.configure_simulator(1).unwrap();
+        engine.check().unwrap();
+        engine.check().unwrap();
+        assert_eq!(engine.drain_events(),
+                   vec![EngineEvent::CapacityLow(pool_uuid),
+                        EngineEvent::MetadataLow(pool_uuid)]);
+
+        engine.configure_simulator(0).unwrap();
+        engine.check().unwrap();
+        engine.configure_simulator(1).unwrap();
+        engine.check().unwrap();
+        assert_eq!(engine.drain_events(),
+                   vec![EngineEvent::CapacityLow(pool_uuid),
+                        EngineEvent::MetadataLow(pool_uuid)]);
+    }
+
+    #[test]
+    /// A check which finds a filesystem above its pool's space watermark
+    /// enqueues a single event, until the filesystem falls below the
+    /// watermark and crosses it again.
+    fn events_fs_space_low() {
+        fn set_used_percent(engine: &mut SimEngine,
+                            pool_uuid: PoolUuid,
+                            fs_uuid: FilesystemUuid,
+                            percent: u64) {
+            let fs = engine
+                .pools
+                .get_mut_by_uuid(pool_uuid)
+                .unwrap()
+                .filesystems
+                .get_mut_by_uuid(fs_uuid)
+                .unwrap();
+            let used = Sectors((*fs.size() * percent + 99) / 100);
+            fs.set_used(used);
+        }
+
+        let mut engine = SimEngine::default();
+        let pool_uuid = engine
+            .create_pool("name",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        let fs_uuid = engine
+            .get_mut_pool(pool_uuid)
+            .unwrap()
+            .create_filesystems(&[("fs", None, None)])
+            .unwrap()
+            .into_inner()[0]
+            .1;
+        engine.drain_events();
+
+        set_used_percent(&mut engine, pool_uuid, fs_uuid, 80);
+        engine.check().unwrap();
+        assert_eq!(engine.drain_events(), vec![]);
+
+        set_used_percent(&mut engine, pool_uuid, fs_uuid, 90);
+        engine.check().unwrap();
+        engine.check().unwrap();
+        assert_eq!(engine.drain_events(),
+                   vec![EngineEvent::FilesystemSpaceLow(pool_uuid, fs_uuid, 90)]);
+
+        engine
+            .get_mut_pool(pool_uuid)
+            .unwrap()
+            .set_fs_space_watermark(95)
+            .unwrap();
+        engine.check().unwrap();
+        set_used_percent(&mut engine, pool_uuid, fs_uuid, 96);
+        engine.check().unwrap();
+        assert_eq!(engine.drain_events(),
+                   vec![EngineEvent::FilesystemSpaceLow(pool_uuid, fs_uuid, 96)]);
+    }
+
+    #[test]
+    /// Only devices claimed by a pool have metadata.
+    fn read_device_metadata() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("name",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+
+        let metadata = engine
+            .read_device_metadata(Path::new("/s/d"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(metadata.pool_uuid, uuid);
+        assert_eq!(metadata.dev_uuid,
+                   engine.get_pool(uuid).unwrap().blockdevs()[0].uuid());
+
+        assert_eq!(engine.read_device_metadata(Path::new("/s/e")).unwrap(), None);
+    }
+
+    #[test]
+    /// A device claimed by a pool is ours, and one which is not is
+    /// unowned; both are big enough for a pool.
+    fn available_blockdevs() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("name",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        let dev_uuid = engine.get_pool(uuid).unwrap().blockdevs()[0].uuid();
+
+        let available = engine.available_blockdevs(&[Path::new("/s/e"), Path::new("/s/d")]);
+        assert_eq!(available
+                       .into_iter()
+                       .map(|result| result.unwrap())
+                       .map(|dev| (dev.ownership, dev.meets_min_size))
+                       .collect::<Vec<_>>(),
+                   vec![(DevOwnership::Unowned, true),
+                        (DevOwnership::Ours(uuid, dev_uuid), true)]);
+    }
+
+    #[test]
+    /// A device claimed by a pool can not be relabeled, and one which is
+    /// not is not a Stratis device.
+    fn relabel() {
+        let mut engine = SimEngine::default();
+        engine
+            .create_pool("name",
+                         &[Path::new("/s/d")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap();
+
+        assert!(match engine.relabel_pool(&[Path::new("/s/d")], Uuid::new_v4()) {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+        assert!(match engine.relabel_device(Path::new("/s/d"), Uuid::new_v4()) {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+        assert!(match engine.relabel_pool(&[Path::new("/s/e")], Uuid::new_v4()) {
+                    Err(EngineError::Engine(ErrorEnum::NotFound, _)) => true,
+                    _ => false,
+                });
+        assert!(match engine.relabel_device(Path::new("/s/e"), Uuid::new_v4()) {
+                    Err(EngineError::Engine(ErrorEnum::NotFound, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// Pools may not be created with, or renamed to, invalid names
+    fn pool_names() {
+        let mut engine = SimEngine::default();
+        let long_name = "a".repeat(256);
+        for name in &["", ".", "..", ".hidden", "a/b", "/", "a\0b", "a\nb", long_name.as_str()] {
+            assert!(match engine.create_pool(name,
+                                             &[],
+                                             None,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[]) {
+                        Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                        _ => false,
+                    });
+        }
+        assert!(engine.pools().is_empty());
+
+        let uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        assert!(match engine.rename_pool(uuid, "a/b") {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        let long_name = "a".repeat(255);
+        for name in &["a.b", "name with spaces", "n\u{e4}me", "a-b_c", long_name.as_str()] {
+            assert_eq!(engine.rename_pool(uuid, name).unwrap(), RenameAction::Renamed);
+        }
+    }
+
+    #[test]
+    /// While the periodic check is paused, it checks nothing, but the pools
+    /// can still be checked on demand; once it is resumed, it checks them
+    /// again.
+    fn check_paused() {
+        let mut engine = SimEngine::default();
+        engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap();
+        assert!(!engine.check_paused());
+        assert!(engine.periodic_check().is_some());
+
+        engine.set_check_paused(true);
+        assert!(engine.check_paused());
+        assert!(engine.periodic_check().is_none());
+        assert!(engine.check().unwrap().failed.is_empty());
+
+        engine.set_check_paused(false);
+        assert!(!engine.check_paused());
+        assert!(engine.periodic_check().unwrap().is_ok());
+    }
 }
@@ -4,32 +4,157 @@
 
 use std::path::PathBuf;
 
-use super::super::engine::{HasName, HasUuid, Filesystem};
-use super::super::types::FilesystemUuid;
+use chrono::{DateTime, TimeZone, Utc};
+
+use devicemapper::{DataBlocks, Sectors};
+
+use super::super::engine::{HasName, HasUuid, Filesystem, StratisPaths};
+use super::super::errors::EngineResult;
+use super::super::structures::SetName;
+use super::super::types::{AllocationInfo, FilesystemUuid};
+
+/// The size of the data blocks of a simulated pool.
+const DATA_BLOCK_SIZE: Sectors = Sectors(2048);
 
 #[derive(Debug)]
 pub struct SimFilesystem {
     fs_id: FilesystemUuid,
     name: String,
+    size: Sectors,
+    used: Sectors,
+    created: u64,
+    origin: Option<FilesystemUuid>,
+    read_only: bool,
+    discards: Option<bool>,
+    quota: Option<Sectors>,
+    paths: StratisPaths,
 }
 
 impl SimFilesystem {
-    pub fn new(fs_id: FilesystemUuid, name: &str) -> SimFilesystem {
+    pub fn new(fs_id: FilesystemUuid,
+               name: &str,
+               size: Sectors,
+               paths: &StratisPaths)
+               -> SimFilesystem {
         SimFilesystem {
             fs_id: fs_id,
             name: name.to_owned(),
+            size: size,
+            used: Sectors(0),
+            created: Utc::now().timestamp() as u64,
+            origin: None,
+            read_only: false,
+            discards: None,
+            quota: None,
+            paths: paths.clone(),
         }
     }
 
-    /// Set the name of this filesystem to name.
-    pub fn rename(&mut self, name: &str) {
-        self.name = name.to_owned();
+    /// Make a snapshot of this filesystem.
+    pub fn snapshot(&self, fs_id: FilesystemUuid, name: &str) -> SimFilesystem {
+        SimFilesystem {
+            fs_id: fs_id,
+            name: name.to_owned(),
+            size: self.size,
+            used: self.used,
+            created: Utc::now().timestamp() as u64,
+            origin: Some(self.fs_id),
+            read_only: false,
+            discards: None,
+            quota: None,
+            paths: self.paths.clone(),
+        }
+    }
+
+    /// Make a copy of this filesystem, which is not a snapshot of it.
+    pub fn copy(&self, fs_id: FilesystemUuid, name: &str) -> SimFilesystem {
+        SimFilesystem {
+            origin: None,
+            ..self.snapshot(fs_id, name)
+        }
+    }
+
+    /// Set the size of this filesystem to size.
+    pub fn set_size(&mut self, size: Sectors) {
+        self.size = size;
+    }
+
+    /// Set the simulated amount of this filesystem's space which is used.
+    #[cfg(test)]
+    pub fn set_used(&mut self, used: Sectors) {
+        self.used = used;
+    }
+
+    /// The percentage of this filesystem's space which is used.
+    pub fn used_percent(&self) -> u8 {
+        if self.size == Sectors(0) {
+            return 0;
+        }
+        (*self.used * 100 / *self.size).min(100) as u8
+    }
+
+    /// The simulated allocation of this filesystem: enough whole data
+    /// blocks to hold the space which is used, without fragmentation.
+    pub fn allocation_info(&self) -> AllocationInfo {
+        let mut allocated_blocks = *self.used / *DATA_BLOCK_SIZE;
+        if *self.used % *DATA_BLOCK_SIZE != 0 {
+            allocated_blocks += 1;
+        }
+        AllocationInfo {
+            size: self.size,
+            allocated_blocks: DataBlocks(allocated_blocks),
+            allocated: DATA_BLOCK_SIZE * allocated_blocks,
+            fragmentation: 0,
+        }
+    }
+
+    /// Make this filesystem read-only, or writable again.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Let this filesystem pass discards to its pool, or refuse them.
+    pub fn set_discards(&mut self, enable: bool) {
+        self.discards = Some(enable);
+    }
+
+    /// Cap the size of this filesystem at limit, or lift its cap.
+    pub fn set_quota(&mut self, limit: Option<Sectors>) {
+        self.quota = limit;
     }
 }
 
 impl Filesystem for SimFilesystem {
     fn devnode(&self) -> PathBuf {
-        ["/dev/stratis", &self.name].into_iter().collect()
+        self.paths.base().join(&self.name)
+    }
+
+    fn created(&self) -> DateTime<Utc> {
+        Utc.timestamp(self.created as i64, 0)
+    }
+
+    fn is_mounted(&self) -> EngineResult<bool> {
+        Ok(false)
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn discards(&self) -> Option<bool> {
+        self.discards
+    }
+
+    fn snapshot_of(&self) -> Option<FilesystemUuid> {
+        self.origin
+    }
+
+    fn size(&self) -> Sectors {
+        self.size
+    }
+
+    fn quota(&self) -> Option<Sectors> {
+        self.quota
     }
 }
 
@@ -39,6 +164,12 @@ impl HasName for SimFilesystem {
     }
 }
 
+impl SetName for SimFilesystem {
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_owned();
+    }
+}
+
 impl HasUuid for SimFilesystem {
     fn uuid(&self) -> FilesystemUuid {
         self.fs_id
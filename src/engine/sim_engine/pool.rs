@@ -3,21 +3,31 @@
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
 use std::cell::RefCell;
-use std::collections::{HashMap, HashSet};
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::collections::hash_map::RandomState;
 use std::iter::FromIterator;
 use std::path::Path;
 use std::rc::Rc;
 use std::vec::Vec;
 
+use chrono::{DateTime, TimeZone, Utc};
 use uuid::Uuid;
 
-use devicemapper::{IEC, Sectors};
+use devicemapper::{IEC, Sectors, ThinDevId};
 
-use super::super::engine::{Filesystem, BlockDev, HasName, HasUuid, Pool};
+use super::super::engine::{Filesystem, BlockDev, HasName, HasUuid, Pool, check_max_fs_size,
+                           check_space_reserve, merge_mount_options, validate_data_low_water,
+                           validate_fs_space_watermark, validate_label, validate_max_fs_size,
+                           validate_mount_options, validate_name, validate_space_reserve,
+                           validate_fs_options, StratisPaths};
 use super::super::errors::{EngineError, EngineResult, ErrorEnum};
-use super::super::structures::Table;
-use super::super::types::{DevUuid, FilesystemUuid, PoolUuid, RenameAction, Redundancy};
+use super::super::event::{EngineEvent, EventQueue, fs_space_low_events};
+use super::super::structures::{SetName, Table};
+use super::super::types::{AllocationInfo, AuditReport, BlockDevTier, CreateAction, CreateForce,
+                          DEFAULT_DATA_LOW_WATER, DEFAULT_FS_SIZE, DEFAULT_FS_SPACE_WATERMARK,
+                          DEFAULT_MAX_FILESYSTEMS_PER_POOL, DestroyAction, DestroyError,
+                          DataLowWater, DevUuid, FilesystemUuid, KeyDescription, PoolCheckReport,
+                          PoolExtendState, PoolUuid, RenameAction, Redundancy, FsOptions};
 
 use super::blockdev::SimDev;
 use super::filesystem::SimFilesystem;
@@ -30,21 +40,49 @@ pub struct SimPool {
     pub block_devs: HashMap<DevUuid, SimDev>,
     pub filesystems: Table<SimFilesystem>,
     redundancy: Redundancy,
+    key_description: Option<KeyDescription>,
     rdm: Rc<RefCell<Randomizer>>,
+    extend_state: PoolExtendState,
+    metadata_low: bool,
+    mount_options: Vec<String>,
+    default_fs_size: Sectors,
+    fs_space_watermark: u8,
+    space_reserve: Sectors,
+    data_low_water: DataLowWater,
+    max_fs_size: Option<Sectors>,
+    labels: BTreeMap<String, String>,
+    // Filesystems which were above the watermark when last checked
+    space_low_filesystems: HashSet<FilesystemUuid>,
+    created: u64,
+    last_modified: u64,
+    events: Rc<RefCell<EventQueue>>,
+    max_filesystems: usize,
+    quiesced: bool,
+    paths: StratisPaths,
 }
 
 impl SimPool {
+    #[allow(too_many_arguments)]
     pub fn new(rdm: &Rc<RefCell<Randomizer>>,
+               events: &Rc<RefCell<EventQueue>>,
+               stratis_paths: &StratisPaths,
                name: &str,
                paths: &[&Path],
-               redundancy: Redundancy)
+               redundancy: Redundancy,
+               encryption: Option<KeyDescription>,
+               metadata_paths: &[&Path])
                -> SimPool {
 
         let devices: HashSet<_, RandomState> = HashSet::from_iter(paths);
         let device_pairs = devices
             .iter()
             .map(|p| {
-                     let bd = SimDev::new(Rc::clone(rdm), p);
+                     let tier = if metadata_paths.contains(p) {
+                         BlockDevTier::Metadata
+                     } else {
+                         BlockDevTier::Data
+                     };
+                     let bd = SimDev::new(Rc::clone(rdm), p, tier);
                      (bd.uuid(), bd)
                  });
         SimPool {
@@ -53,44 +91,148 @@ impl SimPool {
             block_devs: HashMap::from_iter(device_pairs),
             filesystems: Table::default(),
             redundancy: redundancy,
+            key_description: encryption,
             rdm: Rc::clone(rdm),
+            extend_state: PoolExtendState::Good,
+            metadata_low: false,
+            mount_options: vec![],
+            default_fs_size: DEFAULT_FS_SIZE,
+            fs_space_watermark: DEFAULT_FS_SPACE_WATERMARK,
+            space_reserve: Sectors(0),
+            data_low_water: DEFAULT_DATA_LOW_WATER,
+            max_fs_size: None,
+            labels: BTreeMap::new(),
+            space_low_filesystems: HashSet::new(),
+            created: Utc::now().timestamp() as u64,
+            last_modified: Utc::now().timestamp() as u64,
+            events: Rc::clone(events),
+            max_filesystems: DEFAULT_MAX_FILESYSTEMS_PER_POOL,
+            quiesced: false,
+            paths: stratis_paths.clone(),
         }
     }
 
-    pub fn check(&mut self) -> EngineResult<()> {
+    /// Set the number of filesystems, including snapshots, beyond which
+    /// no more may be created.
+    pub fn set_max_filesystems(&mut self, max_filesystems: usize) {
+        self.max_filesystems = max_filesystems;
+    }
+
+    /// Return a LimitReached error if creating count more filesystems would
+    /// exceed the pool's limit.
+    fn check_filesystem_limit(&self, count: usize) -> EngineResult<()> {
+        if self.filesystems.len() + count > self.max_filesystems {
+            let err_msg = format!("the limit of {} filesystems in pool {} has been reached",
+                                  self.max_filesystems,
+                                  self.name);
+            return Err(EngineError::Engine(ErrorEnum::LimitReached, err_msg));
+        }
         Ok(())
     }
 
+    /// Return a NoSpace error if the pool's free space is within its
+    /// space reserve.
+    fn check_space_reserve(&self) -> EngineResult<()> {
+        check_space_reserve(self.total_physical_size(),
+                            self.total_physical_used()?,
+                            self.space_reserve)
+    }
+
+    /// Return a Busy error if the pool is quiesced.
+    fn check_not_quiesced(&self) -> EngineResult<()> {
+        if self.quiesced {
+            let err_msg = format!("pool {} is quiesced", self.name);
+            return Err(EngineError::Engine(ErrorEnum::Busy, err_msg));
+        }
+        Ok(())
+    }
+
+    /// Record that the pool has been changed, as the real engine does when
+    /// it saves the pool's metadata.
+    fn modified(&mut self) {
+        self.last_modified = Utc::now().timestamp() as u64;
+    }
+
     pub fn has_filesystems(&self) -> bool {
         !self.filesystems.is_empty()
     }
 }
 
 impl Pool for SimPool {
-    fn add_blockdevs(&mut self, paths: &[&Path], _force: bool) -> EngineResult<Vec<DevUuid>> {
+    fn add_blockdevs(&mut self,
+                     paths: &[&Path],
+                     _force: CreateForce)
+                     -> EngineResult<Vec<DevUuid>> {
+        self.check_not_quiesced()?;
         let devices: HashSet<_, RandomState> = HashSet::from_iter(paths);
         let device_pairs: Vec<_> = devices
             .iter()
             .map(|p| {
-                     let bd = SimDev::new(Rc::clone(&self.rdm), p);
+                     let bd = SimDev::new(Rc::clone(&self.rdm), p, BlockDevTier::Data);
                      (bd.uuid(), bd)
                  })
             .collect();
-        let ret_uuids = device_pairs.iter().map(|&(uuid, _)| uuid).collect();
+        let ret_uuids: Vec<_> = device_pairs.iter().map(|&(uuid, _)| uuid).collect();
         self.block_devs.extend(device_pairs);
+        self.modified();
+        for dev_uuid in &ret_uuids {
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::BlockdevAdded(self.pool_uuid, *dev_uuid));
+        }
         Ok(ret_uuids)
     }
 
+    /// A simulated pool is always fully extended.
+    fn extend_thinpool(&mut self) -> EngineResult<Sectors> {
+        self.check_not_quiesced()?;
+        Ok(Sectors(0))
+    }
+
+    fn remove_blockdev(&mut self, dev_uuid: DevUuid) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        if !self.block_devs.contains_key(&dev_uuid) {
+            let err_msg = format!("No blockdev with uuid {} in pool", dev_uuid);
+            return Err(EngineError::Engine(ErrorEnum::NotFound, err_msg));
+        }
+        if self.block_devs.len() == 1 {
+            let err_msg = "A pool must have at least one blockdev";
+            return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+        }
+        self.block_devs.remove(&dev_uuid);
+        self.modified();
+        self.events
+            .borrow_mut()
+            .push(EngineEvent::BlockdevRemoved(self.pool_uuid, dev_uuid));
+        Ok(())
+    }
+
+    /// Simulated filesystems are never in use, so force makes no difference.
     fn destroy_filesystems<'a>(&'a mut self,
-                               fs_uuids: &[FilesystemUuid])
-                               -> EngineResult<Vec<FilesystemUuid>> {
+                               fs_uuids: &[FilesystemUuid],
+                               _force: bool)
+                               -> Result<DestroyAction<Vec<FilesystemUuid>>,
+                                         DestroyError<Vec<FilesystemUuid>>> {
+        if let Err(err) = self.check_not_quiesced() {
+            return Err(DestroyError {
+                           destroyed: vec![],
+                           error: err,
+                       });
+        }
         let mut removed = Vec::new();
         for &uuid in fs_uuids {
             if self.filesystems.remove_by_uuid(uuid).is_some() {
+                self.events
+                    .borrow_mut()
+                    .push(EngineEvent::FilesystemDestroyed(self.pool_uuid, uuid));
                 removed.push(uuid);
             }
         }
-        Ok(removed)
+        if removed.is_empty() {
+            Ok(DestroyAction::NotFound)
+        } else {
+            Ok(DestroyAction::Destroyed(removed))
+        }
     }
 
     fn destroy(self) -> EngineResult<()> {
@@ -99,59 +241,375 @@ impl Pool for SimPool {
     }
 
     fn create_filesystems<'a, 'b>(&'a mut self,
-                                  specs: &[(&'b str, Option<Sectors>)])
-                                  -> EngineResult<Vec<(&'b str, FilesystemUuid)>> {
-        let names: HashMap<_, _> = HashMap::from_iter(specs.iter().map(|&tup| (tup.0, tup.1)));
-        for name in names.keys() {
+                                  specs: &[(&'b str, Option<Sectors>, Option<FsOptions>)])
+                                  -> EngineResult<CreateAction<Vec<(&'b str, FilesystemUuid)>>> {
+        self.check_not_quiesced()?;
+        let names: HashMap<_, _> =
+            HashMap::from_iter(specs.iter().map(|&tup| (tup.0, (tup.1, tup.2))));
+        for (name, &(size, options)) in &names {
+            validate_name(name)?;
             if self.filesystems.contains_name(name) {
                 return Err(EngineError::Engine(ErrorEnum::AlreadyExists, name.to_string()));
             }
+            if size == Some(Sectors(0)) {
+                let err_msg = format!("filesystem {} must have a non-zero size", name);
+                return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+            if let Some(ref options) = options {
+                validate_fs_options(options)?;
+            }
+            check_max_fs_size(name, size.unwrap_or(self.default_fs_size), self.max_fs_size)?;
+        }
+        if names.is_empty() {
+            return Ok(CreateAction::Identity(vec![]));
         }
+        self.check_filesystem_limit(names.len())?;
+        self.check_space_reserve()?;
 
         let mut result = Vec::new();
         for name in names.keys() {
             let uuid = Uuid::new_v4();
-            let new_filesystem = SimFilesystem::new(uuid, name);
+            let size = names[name].0.unwrap_or(self.default_fs_size);
+            let new_filesystem = SimFilesystem::new(uuid, name, size, &self.paths);
             self.filesystems.insert(new_filesystem);
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::FilesystemCreated(self.pool_uuid, uuid));
             result.push((*name, uuid));
         }
 
-        Ok(result)
+        Ok(CreateAction::Created(result))
     }
 
     fn snapshot_filesystem(&mut self,
                            origin_uuid: FilesystemUuid,
                            snapshot_name: &str)
                            -> EngineResult<FilesystemUuid> {
+        validate_name(snapshot_name)?;
+        self.check_filesystem_limit(1)?;
+        self.check_space_reserve()?;
         let uuid = Uuid::new_v4();
-        let snapshot = match self.get_filesystem(origin_uuid) {
-            Some(_filesystem) => SimFilesystem::new(uuid, snapshot_name),
+        let snapshot = match self.filesystems.get_by_uuid(origin_uuid) {
+            Some(filesystem) => filesystem.snapshot(uuid, snapshot_name),
             None => {
                 return Err(EngineError::Engine(ErrorEnum::NotFound, origin_uuid.to_string()));
             }
         };
         self.filesystems.insert(snapshot);
+        self.events
+            .borrow_mut()
+            .push(EngineEvent::FilesystemCreated(self.pool_uuid, uuid));
         Ok(uuid)
     }
 
+    fn clone_filesystem(&mut self,
+                        origin_uuid: FilesystemUuid,
+                        new_name: &str)
+                        -> EngineResult<FilesystemUuid> {
+        self.check_not_quiesced()?;
+        validate_name(new_name)?;
+        if self.filesystems.contains_name(new_name) {
+            return Err(EngineError::Engine(ErrorEnum::AlreadyExists, new_name.to_string()));
+        }
+        self.check_filesystem_limit(1)?;
+        let uuid = Uuid::new_v4();
+        let copy = match self.filesystems.get_by_uuid(origin_uuid) {
+            Some(filesystem) => {
+                check_space_reserve(self.total_physical_size(),
+                                    self.total_physical_used()? +
+                                    filesystem.allocation_info().allocated,
+                                    self.space_reserve)?;
+                filesystem.copy(uuid, new_name)
+            }
+            None => {
+                return Err(EngineError::Engine(ErrorEnum::NotFound, origin_uuid.to_string()));
+            }
+        };
+        self.filesystems.insert(copy);
+        self.events
+            .borrow_mut()
+            .push(EngineEvent::FilesystemCreated(self.pool_uuid, uuid));
+        Ok(uuid)
+    }
+
+    fn revert_to_snapshot(&mut self,
+                          fs_uuid: FilesystemUuid,
+                          snapshot_uuid: FilesystemUuid)
+                          -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        match self.filesystems.get_by_uuid(fs_uuid) {
+            Some(fs) if fs.read_only() => {
+                let err_msg = format!("filesystem {} is read-only", fs_uuid);
+                return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+            Some(_) => {}
+            None => return Err(EngineError::Engine(ErrorEnum::NotFound, fs_uuid.to_string())),
+        }
+        match self.filesystems.get_by_uuid(snapshot_uuid) {
+            Some(snapshot) => {
+                if snapshot.snapshot_of() != Some(fs_uuid) {
+                    let err_msg = format!("filesystem {} is not a snapshot of filesystem {}",
+                                          snapshot_uuid,
+                                          fs_uuid);
+                    return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+                }
+            }
+            None => {
+                return Err(EngineError::Engine(ErrorEnum::NotFound, snapshot_uuid.to_string()))
+            }
+        }
+        Ok(())
+    }
+
     fn rename_filesystem(&mut self,
                          uuid: FilesystemUuid,
                          new_name: &str)
                          -> EngineResult<RenameAction> {
+        self.check_not_quiesced()?;
         rename_filesystem_pre!(self; uuid; new_name);
 
-        let mut filesystem =
-            self.filesystems
-                .remove_by_uuid(uuid)
-                .expect("Must succeed since self.filesystems.get_by_uuid() returned a value");
+        self.filesystems.rename(uuid, new_name)
 
-        filesystem.rename(new_name);
-        self.filesystems.insert(filesystem);
-        Ok(RenameAction::Renamed)
     }
 
-    fn rename(&mut self, name: &str) {
-        self.name = name.to_owned();
+    fn set_filesystem_size(&mut self,
+                           uuid: FilesystemUuid,
+                           size: Sectors)
+                           -> EngineResult<Sectors> {
+        self.check_not_quiesced()?;
+        if self.filesystems
+               .get_by_uuid(uuid)
+               .map_or(false, |fs| size > fs.size()) {
+            self.check_space_reserve()?;
+        }
+        let filesystem = match self.filesystems.get_mut_by_uuid(uuid) {
+            Some(filesystem) => filesystem,
+            None => return Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        };
+        if size < filesystem.size() {
+            let err_msg = format!("filesystem {} can not be shrunk from {} to {}",
+                                  filesystem.name(),
+                                  filesystem.size(),
+                                  size);
+            return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+        if size > filesystem.size() {
+            check_max_fs_size(filesystem.name(), size, self.max_fs_size)?;
+        }
+        if let Some(quota) = filesystem.quota() {
+            if size > quota {
+                let err_msg = format!("filesystem {} can not be grown to {} past its quota {}",
+                                      filesystem.name(),
+                                      size,
+                                      quota);
+                return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        filesystem.set_size(size);
+        Ok(size)
+    }
+
+    fn set_filesystem_read_only(&mut self,
+                                uuid: FilesystemUuid,
+                                read_only: bool)
+                                -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some(filesystem) => {
+                filesystem.set_read_only(read_only);
+                Ok(())
+            }
+            None => Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
+
+    fn filesystem_allocation_info(&self,
+                                  fs_uuid: FilesystemUuid)
+                                  -> EngineResult<Option<AllocationInfo>> {
+        match self.filesystems.get_by_uuid(fs_uuid) {
+            Some(filesystem) => Ok(Some(filesystem.allocation_info())),
+            None => Err(EngineError::Engine(ErrorEnum::NotFound, fs_uuid.to_string())),
+        }
+    }
+
+    fn set_filesystem_quota(&mut self,
+                            fs_uuid: FilesystemUuid,
+                            limit: Option<Sectors>)
+                            -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        let filesystem = match self.filesystems.get_mut_by_uuid(fs_uuid) {
+            Some(filesystem) => filesystem,
+            None => return Err(EngineError::Engine(ErrorEnum::NotFound, fs_uuid.to_string())),
+        };
+        if let Some(limit) = limit {
+            if limit < filesystem.size() {
+                let err_msg = format!("filesystem {} of size {} can not be limited to {}",
+                                      filesystem.name(),
+                                      filesystem.size(),
+                                      limit);
+                return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        filesystem.set_quota(limit);
+        Ok(())
+    }
+
+    fn enable_discards(&mut self, fs_uuid: FilesystemUuid, enable: bool) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        match self.filesystems.get_mut_by_uuid(fs_uuid) {
+            Some(filesystem) => {
+                filesystem.set_discards(enable);
+                Ok(())
+            }
+            None => Err(EngineError::Engine(ErrorEnum::NotFound, fs_uuid.to_string())),
+        }
+    }
+
+    /// A simulated filesystem is never mounted, so it can not be trimmed.
+    fn trim_filesystem(&mut self, fs_uuid: FilesystemUuid) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        match self.filesystems.get_by_uuid(fs_uuid) {
+            Some(filesystem) => {
+                let err_msg = format!("filesystem {} is not mounted", filesystem.name());
+                Err(EngineError::Engine(ErrorEnum::Invalid, err_msg))
+            }
+            None => Err(EngineError::Engine(ErrorEnum::NotFound, fs_uuid.to_string())),
+        }
+    }
+
+    /// A simulated filesystem is never mounted, so there is nothing to
+    /// freeze.
+    fn quiesce(&mut self) -> EngineResult<()> {
+        self.quiesced = true;
+        Ok(())
+    }
+
+    fn unquiesce(&mut self) -> EngineResult<()> {
+        self.quiesced = false;
+        Ok(())
+    }
+
+    fn is_quiesced(&self) -> bool {
+        self.quiesced
+    }
+
+    fn set_default_mount_options(&mut self, opts: &[&str]) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_mount_options(opts)?;
+        self.mount_options = opts.iter().map(|o| o.to_string()).collect();
+        self.modified();
+        Ok(())
+    }
+
+    fn default_mount_options(&self) -> &[String] {
+        &self.mount_options
+    }
+
+    fn mount_filesystem(&self,
+                        fs_uuid: FilesystemUuid,
+                        _mount_point: &Path,
+                        opts: &[&str])
+                        -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        merge_mount_options(&self.mount_options, opts)?;
+        match self.filesystems.get_by_uuid(fs_uuid) {
+            Some(_) => Ok(()),
+            None => Err(EngineError::Engine(ErrorEnum::NotFound, fs_uuid.to_string())),
+        }
+    }
+
+    fn set_default_fs_size(&mut self, size: Sectors) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        if size == Sectors(0) {
+            let err_msg = "default filesystem size must be non-zero";
+            return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+        }
+        self.default_fs_size = size;
+        self.modified();
+        Ok(())
+    }
+
+    fn default_fs_size(&self) -> Sectors {
+        self.default_fs_size
+    }
+
+    fn set_fs_space_watermark(&mut self, percent: u8) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_fs_space_watermark(percent)?;
+        self.fs_space_watermark = percent;
+        self.modified();
+        Ok(())
+    }
+
+    fn fs_space_watermark(&self) -> u8 {
+        self.fs_space_watermark
+    }
+
+    fn set_space_reserve(&mut self, reserve: Sectors) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_space_reserve(reserve, self.total_physical_size())?;
+        self.space_reserve = reserve;
+        self.modified();
+        Ok(())
+    }
+
+    fn space_reserve(&self) -> Sectors {
+        self.space_reserve
+    }
+
+    fn set_data_low_water(&mut self, low_water: DataLowWater) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_data_low_water(low_water)?;
+        self.data_low_water = low_water;
+        self.modified();
+        Ok(())
+    }
+
+    fn data_low_water(&self) -> DataLowWater {
+        self.data_low_water
+    }
+
+    fn set_max_fs_size(&mut self, max: Option<Sectors>) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_max_fs_size(max)?;
+        self.max_fs_size = max;
+        self.modified();
+        Ok(())
+    }
+
+    fn max_fs_size(&self) -> Option<Sectors> {
+        self.max_fs_size
+    }
+
+    fn set_label(&mut self, key: &str, value: Option<&str>) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_label(key, value)?;
+        match value {
+            Some(value) => self.labels.insert(key.to_owned(), value.to_owned()),
+            None => self.labels.remove(key),
+        };
+        self.modified();
+        Ok(())
+    }
+
+    fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    fn created(&self) -> DateTime<Utc> {
+        Utc.timestamp(self.created as i64, 0)
+    }
+
+    fn last_modified(&self) -> DateTime<Utc> {
+        Utc.timestamp(self.last_modified as i64, 0)
+    }
+
+    fn key_description(&self) -> Option<&KeyDescription> {
+        self.key_description.as_ref()
+    }
+
+    fn redundancy(&self) -> Redundancy {
+        self.redundancy
     }
 
     fn total_physical_size(&self) -> Sectors {
@@ -164,6 +622,14 @@ impl Pool for SimPool {
         Ok(Sectors(0))
     }
 
+    fn extend_state(&self) -> PoolExtendState {
+        self.extend_state
+    }
+
+    fn metadata_low(&self) -> bool {
+        self.metadata_low
+    }
+
     fn filesystems(&self) -> Vec<&Filesystem> {
         self.filesystems
             .into_iter()
@@ -200,6 +666,63 @@ impl Pool for SimPool {
             .map(|p| p as &mut BlockDev)
     }
 
+    /// A simulated pool keeps no records apart from its filesystems, so
+    /// there is never any drift.
+    fn audit(&self) -> EngineResult<AuditReport> {
+        Ok(AuditReport::default())
+    }
+
+    fn delete_orphaned_thin_devs(&mut self) -> EngineResult<Vec<ThinDevId>> {
+        self.check_not_quiesced()?;
+        Ok(vec![])
+    }
+
+    fn check(&mut self) -> EngineResult<PoolCheckReport> {
+        self.check_not_quiesced()?;
+        let extend_state = if self.rdm.borrow_mut().throw_die() {
+            PoolExtendState::Failed
+        } else {
+            PoolExtendState::Good
+        };
+        let metadata_low = self.rdm.borrow_mut().throw_die();
+
+        if extend_state == PoolExtendState::Failed && self.extend_state != PoolExtendState::Failed {
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::CapacityLow(self.pool_uuid));
+        }
+        if metadata_low && !self.metadata_low {
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::MetadataLow(self.pool_uuid));
+        }
+        self.extend_state = extend_state;
+        self.metadata_low = metadata_low;
+
+        let usage: Vec<_> = self.filesystems
+            .into_iter()
+            .map(|fs| (fs.uuid(), fs.used_percent()))
+            .collect();
+        for event in fs_space_low_events(self.pool_uuid,
+                                         &usage,
+                                         self.fs_space_watermark,
+                                         &mut self.space_low_filesystems) {
+            self.events.borrow_mut().push(event);
+        }
+
+        let mut space_low_filesystems: Vec<_> =
+            self.space_low_filesystems.iter().cloned().collect();
+        space_low_filesystems.sort();
+        Ok(PoolCheckReport {
+               extend_state: extend_state,
+               metadata_low: metadata_low,
+               inconsistent_filesystems: vec![],
+               space_low_filesystems: space_low_filesystems,
+               temp_files_removed: 0,
+               unreadable_records: vec![],
+           })
+    }
+
     fn save_state(&mut self) -> EngineResult<()> {
         Ok(())
     }
@@ -217,18 +740,40 @@ impl HasName for SimPool {
     }
 }
 
+impl SetName for SimPool {
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_owned();
+        self.modified();
+    }
+}
+
 
 #[cfg(test)]
 mod tests {
 
     use std::path::Path;
+    use std::thread;
+    use std::time::Duration;
 
     use uuid::Uuid;
 
+    use devicemapper::{Bytes, Sectors};
+
+    use engine::CreateAction;
+    use engine::CreateForce;
+    use engine::DestroyAction;
+    use engine::DestroyError;
     use engine::Engine;
+    use engine::{DEFAULT_DATA_LOW_WATER, DEFAULT_FS_SIZE, DEFAULT_FS_SPACE_WATERMARK};
+    use engine::DataLowWater;
     use engine::ErrorEnum;
     use engine::EngineError;
+    use engine::EngineLimits;
+    use engine::{MAX_LABEL_KEY_LEN, MAX_LABEL_VALUE_LEN};
+    use engine::PoolExtendState;
     use engine::RenameAction;
+    use engine::FilesystemType;
+    use engine::FsOptions;
 
     use super::super::SimEngine;
 
@@ -236,7 +781,10 @@ mod tests {
     /// Renaming a filesystem on an empty pool always works
     fn rename_empty() {
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("name", &[], None, false).unwrap();
+        let uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
         assert!(match pool.rename_filesystem(Uuid::new_v4(), "new_name") {
                     Ok(RenameAction::NoSource) => true,
@@ -248,9 +796,12 @@ mod tests {
     /// Renaming a filesystem to another filesystem should work if new name not taken
     fn rename_happens() {
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("name", &[], None, false).unwrap();
+        let uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
-        let infos = pool.create_filesystems(&[("old_name", None)]).unwrap();
+        let infos = pool.create_filesystems(&[("old_name", None, None)]).unwrap().into_inner();
         assert!(match pool.rename_filesystem(infos[0].1, "new_name") {
                     Ok(RenameAction::Renamed) => true,
                     _ => false,
@@ -263,10 +814,14 @@ mod tests {
         let old_name = "old_name";
         let new_name = "new_name";
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("name", &[], None, false).unwrap();
+        let uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
-        let results = pool.create_filesystems(&[(old_name, None), (new_name, None)])
-            .unwrap();
+        let results = pool.create_filesystems(&[(old_name, None, None), (new_name, None, None)])
+            .unwrap()
+            .into_inner();
         let old_uuid = results.iter().find(|x| x.0 == old_name).unwrap().1;
         assert!(match pool.rename_filesystem(old_uuid, new_name) {
                     Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
@@ -279,7 +834,10 @@ mod tests {
     fn rename_no_op() {
         let new_name = "new_name";
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("name", &[], None, false).unwrap();
+        let uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
         assert!(match pool.rename_filesystem(Uuid::new_v4(), new_name) {
                     Ok(RenameAction::NoSource) => true,
@@ -291,35 +849,40 @@ mod tests {
     /// Removing an empty list of filesystems should always succeed
     fn destroy_fs_empty() {
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("name", &[], None, false).unwrap();
+        let uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
-        assert!(match pool.destroy_filesystems(&[]) {
-                    Ok(names) => names.is_empty(),
-                    _ => false,
-                });
+        assert_eq!(pool.destroy_filesystems(&[], false).unwrap(), DestroyAction::NotFound);
     }
 
     #[test]
     /// Removing a non-empty list of filesystems should succeed on empty pool
     fn destroy_fs_some() {
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("name", &[], None, false).unwrap();
+        let uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
-        assert!(pool.destroy_filesystems(&[Uuid::new_v4()]).is_ok());
+        assert_eq!(pool.destroy_filesystems(&[Uuid::new_v4()], false).unwrap(),
+                   DestroyAction::NotFound);
     }
 
     #[test]
     /// Removing a non-empty list of filesystems should succeed on any pool
     fn destroy_fs_any() {
         let mut engine = SimEngine::default();
-        let uuid = engine.create_pool("name", &[], None, false).unwrap();
+        let uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
-        let fs_results = pool.create_filesystems(&[("fs_name", None)]).unwrap();
+        let fs_results = pool.create_filesystems(&[("fs_name", None, None)]).unwrap().into_inner();
         let fs_uuid = fs_results[0].1;
-        assert!(match pool.destroy_filesystems(&[fs_uuid, Uuid::new_v4()]) {
-                    Ok(filesystems) => filesystems == vec![fs_uuid],
-                    _ => false,
-                });
+        assert_eq!(pool.destroy_filesystems(&[fs_uuid, Uuid::new_v4()], false).unwrap(),
+                   DestroyAction::Destroyed(vec![fs_uuid]));
     }
 
     #[test]
@@ -327,11 +890,12 @@ mod tests {
     fn create_fs_none() {
         let mut engine = SimEngine::default();
         let uuid = engine
-            .create_pool("pool_name", &[], None, false)
-            .unwrap();
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
         assert!(match pool.create_filesystems(&[]) {
-                    Ok(names) => names.is_empty(),
+                    Ok(CreateAction::Identity(names)) => names.is_empty(),
                     _ => false,
                 });
     }
@@ -341,13 +905,52 @@ mod tests {
     fn create_fs_some() {
         let mut engine = SimEngine::default();
         let uuid = engine
-            .create_pool("pool_name", &[], None, false)
-            .unwrap();
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        assert!(match pool.create_filesystems(&[("name", None, None)]) {
+                    Ok(CreateAction::Created(names)) => {
+                        (names.len() == 1) & (names[0].0 == "name")
+                    }
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// Filesystems, including snapshots, may be created up to the pool's
+    /// limit, but no more, and if creating all of the filesystems requested
+    /// would exceed the limit, none are created.
+    fn create_fs_limit() {
+        let mut engine = SimEngine::new(EngineLimits {
+                                            max_pools: 1,
+                                            max_filesystems_per_pool: 3,
+                                        });
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
-        assert!(match pool.create_filesystems(&[("name", None)]) {
-                    Ok(names) => (names.len() == 1) & (names[0].0 == "name"),
+
+        let fs_uuid = pool.create_filesystems(&[("fs1", None, None)]).unwrap().into_inner()[0].1;
+        assert!(match pool.create_filesystems(&[("fs2", None, None), ("fs3", None, None),
+                                                ("fs4", None, None)]) {
+                    Err(EngineError::Engine(ErrorEnum::LimitReached, _)) => true,
                     _ => false,
                 });
+        assert_eq!(pool.filesystems().len(), 1);
+
+        pool.create_filesystems(&[("fs2", None, None)]).unwrap();
+        pool.snapshot_filesystem(fs_uuid, "snap1").unwrap();
+        assert!(match pool.snapshot_filesystem(fs_uuid, "snap2") {
+                    Err(EngineError::Engine(ErrorEnum::LimitReached, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.create_filesystems(&[("fs3", None, None)]) {
+                    Err(EngineError::Engine(ErrorEnum::LimitReached, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(pool.filesystems().len(), 3);
     }
 
     #[test]
@@ -356,14 +959,638 @@ mod tests {
         let fs_name = "fs_name";
         let mut engine = SimEngine::default();
         let uuid = engine
-            .create_pool("pool_name", &[], None, false)
-            .unwrap();
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        pool.create_filesystems(&[(fs_name, None, None)]).unwrap();
+        assert!(match pool.create_filesystems(&[(fs_name, None, None)]) {
+                    Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// If any requested filesystem conflicts, none are created.
+    fn create_fs_conflict_none_created() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        pool.create_filesystems(&[("fs2", None, None)]).unwrap();
+        let specs = [("fs1", None, None), ("fs2", None, None), ("fs3", None, None)];
+        assert!(match pool.create_filesystems(&specs) {
+                    Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(pool.filesystems().len(), 1);
+    }
+
+    #[test]
+    /// Requesting a filesystem of zero size fails, and nothing is created.
+    fn create_fs_zero_size() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let specs = [("fs1", None, None), ("fs2", Some(Sectors(0)), None)];
+        assert!(match pool.create_filesystems(&specs) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert!(pool.filesystems().is_empty());
+    }
+
+    #[test]
+    /// Filesystems may not be created with options that XFS does not
+    /// support, and nothing is created if any options are invalid.
+    fn create_fs_xfs_options() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let options = |block_size: Option<u64>, inode_size: Option<u64>| {
+            FsOptions {
+                fs_type: FilesystemType::Xfs,
+                block_size: block_size.map(Bytes),
+                inode_size: inode_size.map(Bytes),
+                reflink: Some(true),
+            }
+        };
+
+        let bad = [options(Some(512), None),
+                   options(Some(3072), None),
+                   options(Some(131072), None),
+                   options(None, Some(256)),
+                   options(None, Some(4096)),
+                   options(Some(1024), Some(1024))];
+        for bad_options in &bad {
+            let specs = [("fs1", None, None), ("fs2", None, Some(*bad_options))];
+            assert!(match pool.create_filesystems(&specs) {
+                        Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                        _ => false,
+                    },
+                    "{:?}",
+                    bad_options);
+        }
+        assert!(pool.filesystems().is_empty());
+
+        let specs = [("fs1", None, Some(options(Some(2048), Some(1024)))),
+                     ("fs2", None, Some(options(None, Some(2048))))];
+        assert_eq!(pool.create_filesystems(&specs).unwrap().into_inner().len(), 2);
+    }
+
+    #[test]
+    /// Ext4 filesystems may have smaller inodes than XFS filesystems, but
+    /// may not share data blocks between files.
+    fn create_fs_ext4_options() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let options = |inode_size: Option<u64>, reflink: Option<bool>| {
+            FsOptions {
+                fs_type: FilesystemType::Ext4,
+                block_size: Some(Bytes(1024)),
+                inode_size: inode_size.map(Bytes),
+                reflink: reflink,
+            }
+        };
+
+        for bad_options in &[options(Some(64), None),
+                             options(Some(2048), None),
+                             options(None, Some(true))] {
+            assert!(match pool.create_filesystems(&[("fs", None, Some(*bad_options))]) {
+                        Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                        _ => false,
+                    },
+                    "{:?}",
+                    bad_options);
+        }
+        assert!(pool.filesystems().is_empty());
+
+        let specs = [("fs1", None, Some(options(Some(128), None))),
+                     ("fs2", None, Some(options(Some(1024), Some(false))))];
+        assert_eq!(pool.create_filesystems(&specs).unwrap().into_inner().len(), 2);
+    }
+
+    #[test]
+    /// Filesystems may not be created with, snapshotted to, or renamed to
+    /// invalid names.
+    fn filesystem_names() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        for name in &["", "..", "a/b", "a\tb"] {
+            assert!(match pool.create_filesystems(&[("fs1", None, None), (name, None, None)]) {
+                        Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                        _ => false,
+                    });
+        }
+        assert!(pool.filesystems().is_empty());
+
+        let fs_uuid = pool.create_filesystems(&[("fs.1", None, None)]).unwrap().into_inner()[0].1;
+        assert!(match pool.snapshot_filesystem(fs_uuid, ".snap") {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.rename_filesystem(fs_uuid, "a/b") {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(pool.rename_filesystem(fs_uuid, "fs 2").unwrap(),
+                   RenameAction::Renamed);
+    }
+
+    #[test]
+    /// A pool's default filesystem size can be set to any non-zero size.
+    fn default_fs_size() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        assert_eq!(pool.default_fs_size(), DEFAULT_FS_SIZE);
+        pool.set_default_fs_size(Sectors(2048)).unwrap();
+        assert_eq!(pool.default_fs_size(), Sectors(2048));
+        assert!(match pool.set_default_fs_size(Sectors(0)) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(pool.default_fs_size(), Sectors(2048));
+    }
+
+    #[test]
+    /// A pool's filesystem space watermark can be set to any percentage
+    /// from 1 to 100.
+    fn fs_space_watermark() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
-        pool.create_filesystems(&[(fs_name, None)]).unwrap();
-        assert!(match pool.create_filesystems(&[(fs_name, None)]) {
+        assert_eq!(pool.fs_space_watermark(), DEFAULT_FS_SPACE_WATERMARK);
+        pool.set_fs_space_watermark(100).unwrap();
+        assert_eq!(pool.fs_space_watermark(), 100);
+        for percent in &[0, 101] {
+            assert!(match pool.set_fs_space_watermark(*percent) {
+                        Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                        _ => false,
+                    });
+        }
+        assert_eq!(pool.fs_space_watermark(), 100);
+    }
+
+    #[test]
+    /// A pool's data low water mark may be a percentage from 1 to 50 or any
+    /// nonzero size.
+    fn data_low_water() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        assert_eq!(pool.data_low_water(), DEFAULT_DATA_LOW_WATER);
+        pool.set_data_low_water(DataLowWater::Percent(20)).unwrap();
+        assert_eq!(pool.data_low_water(), DataLowWater::Percent(20));
+        for low_water in &[DataLowWater::Percent(0),
+                           DataLowWater::Percent(51),
+                           DataLowWater::Sectors(Sectors(0))] {
+            assert!(match pool.set_data_low_water(*low_water) {
+                        Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                        _ => false,
+                    });
+        }
+        assert_eq!(pool.data_low_water(), DataLowWater::Percent(20));
+    }
+
+    #[test]
+    /// A filesystem can be made or grown to at most the pool's maximum
+    /// filesystem size, once one is set, and to any size once it is lifted.
+    fn max_fs_size() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        assert_eq!(pool.max_fs_size(), None);
+        assert!(match pool.set_max_fs_size(Some(Sectors(0))) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        let max = Sectors(2048);
+        pool.set_max_fs_size(Some(max)).unwrap();
+        assert_eq!(pool.max_fs_size(), Some(max));
+        let fs_uuid = pool.create_filesystems(&[("fs", Some(max), None)])
+            .unwrap()
+            .into_inner()[0]
+            .1;
+        assert!(match pool.create_filesystems(&[("fs2", Some(max + Sectors(1)), None)]) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        // The pool's default filesystem size is also subject to the maximum.
+        assert!(match pool.create_filesystems(&[("fs2", None, None)]) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.set_filesystem_size(fs_uuid, max + Sectors(1)) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        pool.set_max_fs_size(None).unwrap();
+        assert_eq!(pool.set_filesystem_size(fs_uuid, max + Sectors(1)).unwrap(),
+                   max + Sectors(1));
+    }
+
+    #[test]
+    /// Labels can be set, replaced and removed; a label whose key or value
+    /// breaks the rules is refused.
+    fn labels() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        assert!(pool.labels().is_empty());
+
+        pool.set_label("team", Some("infra")).unwrap();
+        pool.set_label("env", Some("test")).unwrap();
+        pool.set_label("env", Some("prod")).unwrap();
+        assert_eq!(pool.labels().get("team").map(String::as_str), Some("infra"));
+        assert_eq!(pool.labels().get("env").map(String::as_str), Some("prod"));
+
+        pool.set_label("team", None).unwrap();
+        assert_eq!(pool.labels().keys().collect::<Vec<_>>(), vec!["env"]);
+
+        let long_key = "k".repeat(MAX_LABEL_KEY_LEN + 1);
+        let long_value = "v".repeat(MAX_LABEL_VALUE_LEN + 1);
+        for &(key, value) in &[("", Some("value")),
+                               (long_key.as_str(), Some("value")),
+                               ("-key", Some("value")),
+                               ("a key", None),
+                               ("key", Some(long_value.as_str())),
+                               ("key", Some("a\nvalue"))] {
+            assert!(match pool.set_label(key, value) {
+                        Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                        _ => false,
+                    });
+        }
+        assert_eq!(pool.labels().len(), 1);
+    }
+
+    #[test]
+    /// Once a pool's free space is within its space reserve, filesystems
+    /// can be neither created, nor snapshotted, nor grown; a reserve larger
+    /// than the pool is refused.
+    fn space_reserve() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        let size = DEFAULT_FS_SIZE;
+        assert_eq!(pool.space_reserve(), Sectors(0));
+
+        let total = pool.total_physical_size();
+        assert!(match pool.set_space_reserve(total + Sectors(1)) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        pool.set_space_reserve(total).unwrap();
+        assert_eq!(pool.space_reserve(), total);
+        assert!(match pool.create_filesystems(&[("fs2", None, None)]) {
+                    Err(EngineError::Engine(ErrorEnum::NoSpace, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.snapshot_filesystem(fs_uuid, "snapshot") {
+                    Err(EngineError::Engine(ErrorEnum::NoSpace, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.clone_filesystem(fs_uuid, "copy") {
+                    Err(EngineError::Engine(ErrorEnum::NoSpace, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.set_filesystem_size(fs_uuid, size + Sectors(1)) {
+                    Err(EngineError::Engine(ErrorEnum::NoSpace, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(pool.set_filesystem_size(fs_uuid, size).unwrap(), size);
+
+        pool.set_space_reserve(total - Sectors(1)).unwrap();
+        pool.create_filesystems(&[("fs2", None, None)]).unwrap();
+        pool.snapshot_filesystem(fs_uuid, "snapshot").unwrap();
+        pool.clone_filesystem(fs_uuid, "copy").unwrap();
+        pool.set_filesystem_size(fs_uuid, size + Sectors(1)).unwrap();
+    }
+
+    #[test]
+    /// Renaming a pool advances its last modification time but not its
+    /// creation time.
+    fn last_modified() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let (created, last_modified) = {
+            let pool = engine.get_pool(uuid).unwrap();
+            (pool.created(), pool.last_modified())
+        };
+        assert!(created <= last_modified);
+
+        // Times are recorded to the second.
+        thread::sleep(Duration::from_secs(1));
+        engine.rename_pool(uuid, "new_name").unwrap();
+
+        let pool = engine.get_pool(uuid).unwrap();
+        assert_eq!(pool.created(), created);
+        assert!(pool.last_modified() > last_modified);
+    }
+
+    #[test]
+    /// Removing a blockdev succeeds unless it is the pool's last blockdev.
+    fn remove_blockdev() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name",
+                         &[Path::new("/s/d"), Path::new("/s/e")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let dev_uuids = pool.blockdevs()
+            .iter()
+            .map(|bd| bd.uuid())
+            .collect::<Vec<_>>();
+        pool.remove_blockdev(dev_uuids[0]).unwrap();
+        assert!(match pool.remove_blockdev(dev_uuids[0]) {
+                    Err(EngineError::Engine(ErrorEnum::NotFound, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.remove_blockdev(dev_uuids[1]) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(pool.blockdevs().len(), 1);
+    }
+
+    #[test]
+    /// Reverting a filesystem to its snapshot succeeds, reverting to any
+    /// other filesystem fails.
+    fn revert_to_snapshot() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let fs_uuids = pool.create_filesystems(&[("fs1", None, None), ("fs2", None, None)])
+            .unwrap()
+            .into_inner()
+            .iter()
+            .map(|&(_, fs_uuid)| fs_uuid)
+            .collect::<Vec<_>>();
+        let snapshot_uuid = pool.snapshot_filesystem(fs_uuids[0], "snapshot").unwrap();
+        assert!(pool.revert_to_snapshot(fs_uuids[0], snapshot_uuid).is_ok());
+        assert!(match pool.revert_to_snapshot(fs_uuids[1], snapshot_uuid) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.revert_to_snapshot(snapshot_uuid, fs_uuids[0]) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// Each snapshot of a filesystem records the filesystem as its origin,
+    /// and the snapshots of a filesystem are exactly those which record it;
+    /// a snapshot of a snapshot belongs to the snapshot only.
+    fn snapshots_of() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        let snapshot1 = pool.snapshot_filesystem(fs_uuid, "snapshot1").unwrap();
+        let snapshot2 = pool.snapshot_filesystem(fs_uuid, "snapshot2").unwrap();
+        let snapshot3 = pool.snapshot_filesystem(snapshot1, "snapshot3").unwrap();
+
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().snapshot_of(), None);
+        for &(snapshot, origin) in &[(snapshot1, fs_uuid),
+                                     (snapshot2, fs_uuid),
+                                     (snapshot3, snapshot1)] {
+            assert_eq!(pool.get_filesystem(snapshot).unwrap().snapshot_of(),
+                       Some(origin));
+        }
+
+        let mut snapshots = pool.snapshots_of(fs_uuid);
+        snapshots.sort();
+        let mut expected = vec![snapshot1, snapshot2];
+        expected.sort();
+        assert_eq!(snapshots, expected);
+        assert_eq!(pool.snapshots_of(snapshot1), vec![snapshot3]);
+        assert_eq!(pool.snapshots_of(snapshot2), vec![]);
+    }
+
+    #[test]
+    /// A copy of a filesystem is not a snapshot of it, so the filesystem
+    /// can not be reverted to it.
+    fn clone_filesystem() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        let copy_uuid = pool.clone_filesystem(fs_uuid, "copy").unwrap();
+        assert_eq!(pool.get_filesystem(copy_uuid).unwrap().name(), "copy");
+        assert!(match pool.revert_to_snapshot(fs_uuid, copy_uuid) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.clone_filesystem(fs_uuid, "copy") {
                     Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
                     _ => false,
                 });
+        assert!(match pool.clone_filesystem(Uuid::new_v4(), "copy2") {
+                    Err(EngineError::Engine(ErrorEnum::NotFound, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// A filesystem can be made read-only and writable again, and can not
+    /// be reverted to a snapshot while it is read-only.
+    fn filesystem_read_only() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        let snapshot_uuid = pool.snapshot_filesystem(fs_uuid, "snapshot").unwrap();
+        assert!(!pool.get_filesystem(fs_uuid).unwrap().read_only());
+
+        pool.set_filesystem_read_only(fs_uuid, true).unwrap();
+        assert!(pool.get_filesystem(fs_uuid).unwrap().read_only());
+        assert!(!pool.get_filesystem(snapshot_uuid).unwrap().read_only());
+        assert!(match pool.revert_to_snapshot(fs_uuid, snapshot_uuid) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        pool.set_filesystem_read_only(fs_uuid, false).unwrap();
+        assert!(!pool.get_filesystem(fs_uuid).unwrap().read_only());
+        assert!(match pool.set_filesystem_read_only(Uuid::new_v4(), true) {
+                    Err(EngineError::Engine(ErrorEnum::NotFound, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// A filesystem can not be grown past its quota, nor given a quota
+    /// smaller than it is, and can be grown again once its quota is lifted.
+    fn filesystem_quota() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        let size = pool.filesystem_allocation_info(fs_uuid)
+            .unwrap()
+            .unwrap()
+            .size;
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().quota(), None);
+
+        assert!(match pool.set_filesystem_quota(fs_uuid, Some(size - Sectors(1))) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        pool.set_filesystem_quota(fs_uuid, Some(size * 2u64)).unwrap();
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().quota(), Some(size * 2u64));
+        assert_eq!(pool.set_filesystem_size(fs_uuid, size * 2u64).unwrap(), size * 2u64);
+        assert!(match pool.set_filesystem_size(fs_uuid, size * 3u64) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        pool.set_filesystem_quota(fs_uuid, None).unwrap();
+        assert_eq!(pool.set_filesystem_size(fs_uuid, size * 3u64).unwrap(), size * 3u64);
+        assert!(match pool.set_filesystem_quota(Uuid::new_v4(), None) {
+                    Err(EngineError::Engine(ErrorEnum::NotFound, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// Discards can be enabled and disabled for a filesystem; a filesystem
+    /// which is not mounted can not be trimmed.
+    fn filesystem_discards() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().discards(), None);
+
+        pool.enable_discards(fs_uuid, true).unwrap();
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().discards(), Some(true));
+        pool.enable_discards(fs_uuid, false).unwrap();
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().discards(), Some(false));
+        assert!(match pool.enable_discards(Uuid::new_v4(), true) {
+                    Err(EngineError::Engine(ErrorEnum::NotFound, _)) => true,
+                    _ => false,
+                });
+
+        assert!(match pool.trim_filesystem(fs_uuid) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.trim_filesystem(Uuid::new_v4()) {
+                    Err(EngineError::Engine(ErrorEnum::NotFound, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// While a pool is quiesced, operations which would change it, other
+    /// than taking snapshots, fail with a Busy error, as does destroying
+    /// it; once it is unquiesced, they succeed.
+    fn quiesce() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let fs_uuid = {
+            let pool = engine.get_mut_pool(uuid).unwrap();
+            let (_, fs_uuid) = pool.create_filesystems(&[("fs", None, None)])
+                .unwrap()
+                .into_inner()
+                .pop()
+                .unwrap();
+
+            pool.quiesce().unwrap();
+            pool.quiesce().unwrap();
+            assert!(pool.is_quiesced());
+            assert!(match pool.create_filesystems(&[("fs2", None, None)]) {
+                        Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                        _ => false,
+                    });
+            assert!(match pool.destroy_filesystems(&[fs_uuid], false) {
+                        Err(DestroyError {
+                                error: EngineError::Engine(ErrorEnum::Busy, _),
+                                ..
+                            }) => true,
+                        _ => false,
+                    });
+            pool.snapshot_filesystem(fs_uuid, "snapshot").unwrap();
+            fs_uuid
+        };
+        assert!(match engine.destroy_pool(uuid, true) {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        pool.unquiesce().unwrap();
+        assert!(!pool.is_quiesced());
+        assert_eq!(pool.destroy_filesystems(&[fs_uuid], false).unwrap(),
+                   DestroyAction::Destroyed(vec![fs_uuid]));
     }
 
     #[test]
@@ -372,27 +1599,74 @@ mod tests {
         let fs_name = "fs_name";
         let mut engine = SimEngine::default();
         let uuid = engine
-            .create_pool("pool_name", &[], None, false)
-            .unwrap();
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
-        assert!(match pool.create_filesystems(&[(fs_name, None), (fs_name, None)]) {
-                    Ok(names) => (names.len() == 1) & (names[0].0 == fs_name),
+        assert!(match pool.create_filesystems(&[(fs_name, None, None), (fs_name, None, None)]) {
+                    Ok(CreateAction::Created(names)) => {
+                        (names.len() == 1) & (names[0].0 == fs_name)
+                    }
                     _ => false,
                 });
     }
 
+    #[test]
+    /// Filesystems created are enumerated, filesystems destroyed are not.
+    fn list_fs_created_destroyed() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let pool = engine.get_mut_pool(uuid).unwrap();
+        let results = pool.create_filesystems(&[("fs1", None, None), ("fs2", None, None)])
+            .unwrap()
+            .into_inner();
+
+        let mut listed: Vec<_> = pool.filesystems().iter().map(|fs| fs.uuid()).collect();
+        let mut created: Vec<_> = results.iter().map(|x| x.1).collect();
+        listed.sort();
+        created.sort();
+        assert_eq!(listed, created);
+
+        pool.destroy_filesystems(&[created[0]], false).unwrap();
+        let listed = pool.filesystems();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].uuid(), created[1]);
+    }
+
     #[test]
     /// Adding a list of devices to an empty pool should yield list.
     fn add_device_empty() {
         let mut engine = SimEngine::default();
         let uuid = engine
-            .create_pool("pool_name", &[], None, false)
-            .unwrap();
+            .create_pool("pool_name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
         let pool = engine.get_mut_pool(uuid).unwrap();
         let devices = [Path::new("/s/a"), Path::new("/s/b")];
-        assert!(match pool.add_blockdevs(&devices, false) {
+        assert!(match pool.add_blockdevs(&devices, CreateForce::default()) {
                     Ok(devs) => devs.len() == devices.len(),
                     _ => false,
                 });
     }
+
+    #[test]
+    /// A pool which runs out of space on check reports a failed extension
+    fn check_out_of_space() {
+        let mut engine = SimEngine::default();
+        let uuid = engine
+            .create_pool("name", &[], None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        assert_eq!(engine.get_pool(uuid).unwrap().extend_state(),
+                   PoolExtendState::Good);
+        assert!(!engine.get_pool(uuid).unwrap().metadata_low());
+        engine.configure_simulator(1).unwrap();
+        engine.check().unwrap();
+        assert_eq!(engine.get_pool(uuid).unwrap().extend_state(),
+                   PoolExtendState::Failed);
+        assert!(engine.get_pool(uuid).unwrap().metadata_low());
+    }
 }
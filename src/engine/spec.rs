@@ -0,0 +1,353 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// A declarative description of the pools and filesystems which an engine
+// should have, and the reconciliation of an engine to it.
+//
+// Pools and filesystems are identified by name. Applying a spec creates the
+// pools and filesystems which it describes and which are missing; one which
+// exists is left alone, even if it differs from its description, e.g., in
+// its devices or size. Nothing which is not in the spec is destroyed unless
+// the spec's prune flag is set, so that applying a spec a second time does
+// nothing. Even then, a pool which is not in the spec is destroyed only if
+// it has no filesystems, so that a mistake in a pool's name in a spec can
+// not destroy the pool's data.
+
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use devicemapper::Sectors;
+
+use super::engine::Engine;
+use super::errors::{EngineError, EngineResult, ErrorEnum};
+use super::types::{CreateForce, PoolUuid};
+
+/// The pools which an engine should have.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize)]
+pub struct EngineSpec {
+    #[serde(default)]
+    pub pools: Vec<PoolSpec>,
+    /// Whether pools, and filesystems of the pools in the spec, which are
+    /// not in the spec are destroyed. A pool which has filesystems is not
+    /// destroyed, but reported as failed.
+    #[serde(default)]
+    pub prune: bool,
+}
+
+/// A pool, made of devices, and the filesystems which it should have.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct PoolSpec {
+    pub name: String,
+    pub devices: Vec<PathBuf>,
+    /// The redundancy code of the pool; see Engine::create_pool().
+    #[serde(default)]
+    pub redundancy: Option<u16>,
+    #[serde(default)]
+    pub filesystems: Vec<FilesystemSpec>,
+}
+
+/// A filesystem, which has its pool's default size unless size is given.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct FilesystemSpec {
+    pub name: String,
+    #[serde(default)]
+    pub size: Option<Sectors>,
+}
+
+/// A pool, or a filesystem of a pool, by name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpecItem {
+    Pool(String),
+    /// The names of the pool and of the filesystem.
+    Filesystem(String, String),
+}
+
+/// The outcome of applying a spec to an engine.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ApplyReport {
+    /// Items which were missing, and were created.
+    pub created: Vec<SpecItem>,
+    /// Items which already existed, and were left alone.
+    pub existing: Vec<SpecItem>,
+    /// Items which were not in the spec, and were pruned.
+    pub destroyed: Vec<SpecItem>,
+    /// Items which could not be created or destroyed, and why.
+    pub failed: Vec<(SpecItem, String)>,
+}
+
+impl ApplyReport {
+    /// Whether applying the spec changed nothing, and nothing failed.
+    pub fn is_noop(&self) -> bool {
+        self.created.is_empty() && self.destroyed.is_empty() && self.failed.is_empty()
+    }
+}
+
+/// Return an Invalid error if two pools of the spec, or two filesystems of
+/// one of its pools, have the same name.
+fn validate_spec(spec: &EngineSpec) -> EngineResult<()> {
+    let mut pool_names = HashSet::new();
+    for pool in &spec.pools {
+        if !pool_names.insert(pool.name.as_str()) {
+            let err_msg = format!("pool {} is in the spec more than once", pool.name);
+            return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+        let mut fs_names = HashSet::new();
+        for fs in &pool.filesystems {
+            if !fs_names.insert(fs.name.as_str()) {
+                let err_msg = format!("filesystem {} is in the spec of pool {} more than once",
+                                      fs.name,
+                                      pool.name);
+                return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The UUID of the engine's pool named name, if there is one.
+fn pool_uuid_by_name<E: Engine + ?Sized>(engine: &E, name: &str) -> Option<PoolUuid> {
+    engine
+        .pools()
+        .into_iter()
+        .find(|pool| pool.name() == name)
+        .map(|pool| pool.uuid())
+}
+
+/// Create the filesystems of pool_spec which the pool pool_uuid is missing,
+/// one at a time, so that one which can not be created does not prevent
+/// the others from being created, and, if prune is set, destroy those which
+/// are not in pool_spec.
+fn apply_filesystems<E: Engine + ?Sized>(engine: &mut E,
+                                         pool_uuid: PoolUuid,
+                                         pool_spec: &PoolSpec,
+                                         prune: bool,
+                                         report: &mut ApplyReport) {
+    let pool = engine
+        .get_mut_pool(pool_uuid)
+        .expect("pool_uuid is the UUID of a pool of engine");
+    let item = |fs_name: &str| SpecItem::Filesystem(pool_spec.name.clone(), fs_name.to_owned());
+
+    for fs_spec in &pool_spec.filesystems {
+        if pool.filesystems()
+               .iter()
+               .any(|fs| fs.name() == fs_spec.name) {
+            report.existing.push(item(&fs_spec.name));
+            continue;
+        }
+        match pool.create_filesystems(&[(&fs_spec.name, fs_spec.size, None)]) {
+            Ok(_) => report.created.push(item(&fs_spec.name)),
+            Err(err) => report.failed.push((item(&fs_spec.name), err.to_string())),
+        }
+    }
+
+    if prune {
+        let unwanted = pool.filesystems()
+            .iter()
+            .filter(|fs| !pool_spec.filesystems.iter().any(|spec| spec.name == fs.name()))
+            .map(|fs| (fs.uuid(), fs.name().to_owned()))
+            .collect::<Vec<_>>();
+        for (fs_uuid, fs_name) in unwanted {
+            match pool.destroy_filesystems(&[fs_uuid], false) {
+                Ok(_) => report.destroyed.push(item(&fs_name)),
+                Err(err) => report.failed.push((item(&fs_name), err.to_string())),
+            }
+        }
+    }
+}
+
+/// Reconcile engine to spec. An item which can not be created or destroyed
+/// is reported as failed, and does not prevent the others from being
+/// reconciled; the filesystems of a pool which can not be created are not
+/// attempted.
+/// Returns an Invalid error, without changing anything, if the spec names a
+/// pool, or a filesystem of one of its pools, more than once.
+pub fn apply<E: Engine + ?Sized>(engine: &mut E, spec: &EngineSpec) -> EngineResult<ApplyReport> {
+    validate_spec(spec)?;
+
+    let mut report = ApplyReport::default();
+    for pool_spec in &spec.pools {
+        let item = SpecItem::Pool(pool_spec.name.clone());
+        let pool_uuid = match pool_uuid_by_name(engine, &pool_spec.name) {
+            Some(uuid) => {
+                report.existing.push(item);
+                uuid
+            }
+            None => {
+                let devices = pool_spec
+                    .devices
+                    .iter()
+                    .map(|path| path.as_path())
+                    .collect::<Vec<&Path>>();
+                match engine.create_pool(&pool_spec.name,
+                                         &devices,
+                                         pool_spec.redundancy,
+                                         CreateForce::default(),
+                                         None,
+                                         None,
+                                         &[]) {
+                    Ok(action) => {
+                        report.created.push(item);
+                        action.into_inner()
+                    }
+                    Err(err) => {
+                        report.failed.push((item, err.to_string()));
+                        continue;
+                    }
+                }
+            }
+        };
+        apply_filesystems(engine, pool_uuid, pool_spec, spec.prune, &mut report);
+    }
+
+    if spec.prune {
+        let unwanted = engine
+            .pools()
+            .into_iter()
+            .filter(|pool| !spec.pools.iter().any(|spec| spec.name == pool.name()))
+            .map(|pool| (pool.uuid(), pool.name().to_owned()))
+            .collect::<Vec<_>>();
+        for (pool_uuid, pool_name) in unwanted {
+            match engine.destroy_pool(pool_uuid, false) {
+                Ok(_) => report.destroyed.push(SpecItem::Pool(pool_name)),
+                Err(err) => report.failed.push((SpecItem::Pool(pool_name), err.to_string())),
+            }
+        }
+    }
+
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::super::sim_engine::SimEngine;
+
+    use super::*;
+
+    /// A spec of one pool with two filesystems, one of a given size.
+    fn spec() -> EngineSpec {
+        serde_json::from_str(r#"{"pools": [{"name": "pool",
+                                            "devices": ["/s/d", "/s/e"],
+                                            "filesystems": [{"name": "fs1"},
+                                                            {"name": "fs2",
+                                                             "size": 2097152}]}]}"#)
+                .unwrap()
+    }
+
+    #[test]
+    /// Applying a spec creates the pool and filesystems it describes;
+    /// applying it again does nothing.
+    fn apply_twice() {
+        let mut engine = SimEngine::default();
+        let spec = spec();
+
+        let report = engine.apply_spec(&spec).unwrap();
+        assert_eq!(report.created,
+                   vec![SpecItem::Pool("pool".into()),
+                        SpecItem::Filesystem("pool".into(), "fs1".into()),
+                        SpecItem::Filesystem("pool".into(), "fs2".into())]);
+        assert!(report.existing.is_empty());
+        assert!(report.failed.is_empty());
+
+        let pool = engine.pools()[0];
+        assert_eq!(pool.blockdevs().len(), 2);
+        let fs2 = pool.filesystems()
+            .into_iter()
+            .find(|fs| fs.name() == "fs2")
+            .unwrap();
+        assert_eq!(fs2.size(), Sectors(2097152));
+
+        let report = engine.apply_spec(&spec).unwrap();
+        assert!(report.is_noop());
+        assert_eq!(report.existing.len(), 3);
+        assert_eq!(engine.pools().len(), 1);
+        assert_eq!(engine.pools()[0].filesystems().len(), 2);
+    }
+
+    #[test]
+    /// Pools and filesystems which are not in the spec are destroyed only
+    /// if the spec is pruning.
+    fn apply_prune() {
+        let mut engine = SimEngine::default();
+        engine
+            .create_pool("other",
+                         &[Path::new("/s/f")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap();
+        let mut spec = spec();
+        engine.apply_spec(&spec).unwrap();
+        spec.pools[0].filesystems.pop();
+
+        let report = engine.apply_spec(&spec).unwrap();
+        assert!(report.is_noop());
+        assert_eq!(engine.pools().len(), 2);
+
+        spec.prune = true;
+        let report = engine.apply_spec(&spec).unwrap();
+        assert_eq!(report.destroyed,
+                   vec![SpecItem::Filesystem("pool".into(), "fs2".into()),
+                        SpecItem::Pool("other".into())]);
+        assert!(report.failed.is_empty());
+        assert_eq!(engine.pools().len(), 1);
+        assert_eq!(engine.pools()[0].filesystems().len(), 1);
+    }
+
+    #[test]
+    /// A pool which is not in a pruning spec, but which has filesystems, is
+    /// reported as failed, and is left alone.
+    fn apply_prune_pool_with_filesystems() {
+        let mut engine = SimEngine::default();
+        let other_uuid = engine
+            .create_pool("other",
+                         &[Path::new("/s/f")],
+                         None,
+                         CreateForce::default(),
+                         None,
+                         None,
+                         &[])
+            .unwrap()
+            .into_inner();
+        engine
+            .get_mut_pool(other_uuid)
+            .unwrap()
+            .create_filesystems(&[("fs", None, None)])
+            .unwrap();
+        let mut spec = spec();
+        spec.prune = true;
+
+        let report = engine.apply_spec(&spec).unwrap();
+        assert!(report.destroyed.is_empty());
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, SpecItem::Pool("other".into()));
+        assert_eq!(engine.pools().len(), 2);
+        assert_eq!(engine.get_pool(other_uuid).unwrap().filesystems().len(), 1);
+    }
+
+    #[test]
+    /// A spec which names a pool, or a filesystem of a pool, twice is
+    /// refused, and nothing is created.
+    fn apply_duplicate_names() {
+        let mut engine = SimEngine::default();
+        let mut spec = spec();
+        spec.pools.push(spec.pools[0].clone());
+        assert!(match engine.apply_spec(&spec) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        let mut spec = self::spec();
+        let fs_spec = spec.pools[0].filesystems[0].clone();
+        spec.pools[0].filesystems.push(fs_spec);
+        assert!(match engine.apply_spec(&spec) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert!(engine.pools().is_empty());
+    }
+}
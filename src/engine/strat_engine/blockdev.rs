@@ -13,11 +13,13 @@ use devicemapper::{Device, Sectors};
 
 use super::super::engine::{BlockDev, HasUuid};
 use super::super::errors::EngineResult;
-use super::super::types::{BlockDevState, DevUuid, PoolUuid};
+use super::super::types::{BlockDevState, BlockDevTier, DevUuid, HardwareInfo, PoolUuid};
 
+use super::device::blkdev_identity;
 use super::metadata::BDA;
 use super::range_alloc::RangeAllocator;
 use super::serde_structs::{BlockDevSave, Recordable};
+use super::util::smart_health;
 
 
 #[derive(Debug)]
@@ -28,6 +30,8 @@ pub struct StratBlockDev {
     used: RangeAllocator,
     user_info: Option<String>,
     hardware_info: Option<String>,
+    identity: Option<HardwareInfo>,
+    tier: BlockDevTier,
 }
 
 impl StratBlockDev {
@@ -36,8 +40,24 @@ impl StratBlockDev {
                bda: BDA,
                allocator: RangeAllocator,
                user_info: Option<String>,
-               hardware_info: Option<String>)
+               hardware_info: Option<String>,
+               tier: BlockDevTier)
                -> StratBlockDev {
+        let identity = match blkdev_identity(dev) {
+            Ok((None, None)) => None,
+            Ok((model, serial)) => {
+                Some(HardwareInfo {
+                         model: model,
+                         serial: serial,
+                     })
+            }
+            Err(err) => {
+                warn!("Could not read the model and serial number of {}: {}",
+                      devnode.display(),
+                      err);
+                None
+            }
+        };
         StratBlockDev {
             dev: dev,
             devnode: devnode,
@@ -45,6 +65,8 @@ impl StratBlockDev {
             used: allocator,
             user_info: user_info,
             hardware_info: hardware_info,
+            identity: identity,
+            tier: tier,
         }
     }
 
@@ -58,9 +80,12 @@ impl StratBlockDev {
         BDA::wipe(&mut f)
     }
 
+    /// Save metadata to the BDA, and do not return until it is on disk.
     pub fn save_state(&mut self, time: &DateTime<Utc>, metadata: &[u8]) -> EngineResult<()> {
         let mut f = OpenOptions::new().write(true).open(&self.devnode)?;
-        self.bda.save_state(time, metadata, &mut f)
+        self.bda.save_state(time, metadata, &mut f)?;
+        f.sync_all()?;
+        Ok(())
     }
 
     /// List the available-for-upper-layer-use range in this blockdev.
@@ -97,6 +122,11 @@ impl StratBlockDev {
         self.used.request(size)
     }
 
+    /// Mark the range of length sectors at start as no longer in use.
+    pub fn release_space(&mut self, start: Sectors, length: Sectors) {
+        self.used.remove_ranges(&[(start, length)])
+    }
+
     // ALL SIZE METHODS
 
     /// The size of the device as recorded in the metadata.
@@ -110,11 +140,6 @@ impl StratBlockDev {
         self.used.capacity()
     }
 
-    /// The number of Sectors on this device used by Stratis for metadata
-    pub fn metadata_size(&self) -> Sectors {
-        self.bda.size()
-    }
-
     /// The number of Sectors on this device not allocated for any purpose.
     /// self.current_capacity() - self.metadata_size() >= self.available()
     pub fn available(&self) -> Sectors {
@@ -147,10 +172,18 @@ impl BlockDev for StratBlockDev {
         set_blockdev_user_info!(self; user_info)
     }
 
-    fn hardware_info(&self) -> Option<&str> {
+    fn hardware_id(&self) -> Option<&str> {
         self.hardware_info.as_ref().map(|x| &**x)
     }
 
+    fn hardware_info(&self) -> Option<&HardwareInfo> {
+        self.identity.as_ref()
+    }
+
+    fn health(&self) -> Option<String> {
+        smart_health(&self.devnode)
+    }
+
     fn initialization_time(&self) -> DateTime<Utc> {
         // This cast will result in an incorrect, negative value starting in
         // the year 292,277,026,596. :-)
@@ -161,10 +194,18 @@ impl BlockDev for StratBlockDev {
         self.avail_range().1
     }
 
+    fn metadata_size(&self) -> Sectors {
+        self.bda.size()
+    }
+
     fn state(&self) -> BlockDevState {
         // TODO: Implement states for blockdevs
         BlockDevState::InUse
     }
+
+    fn tier(&self) -> BlockDevTier {
+        self.tier
+    }
 }
 
 impl Recordable<BlockDevSave> for StratBlockDev {
@@ -173,6 +214,7 @@ impl Recordable<BlockDevSave> for StratBlockDev {
             devnode: Some(self.devnode.clone()),
             user_info: self.user_info.clone(),
             hardware_info: self.hardware_info.clone(),
+            metadata_tier: self.tier == BlockDevTier::Metadata,
         }
     }
 }
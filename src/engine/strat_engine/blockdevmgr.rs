@@ -12,21 +12,22 @@ use chrono::{DateTime, Duration, Utc};
 use rand::{thread_rng, sample};
 use uuid::Uuid;
 
-use devicemapper::{Bytes, Device, IEC, Sectors, Segment};
+use devicemapper::{Bytes, Device, Sectors, Segment};
 
 use super::super::engine::BlockDev;
 use super::super::errors::{EngineError, EngineResult, ErrorEnum};
-use super::super::types::{DevUuid, PoolUuid};
+use super::super::types::{AvailableBlockDev, BlockDevTier, CreateForce, DevOwnership, DevUuid,
+                          MIN_DEV_SIZE, PoolUuid};
 
 use super::cleanup::wipe_blockdevs;
+use super::failpoint::fail_point;
 use super::blockdev::StratBlockDev;
-use super::device::{blkdev_size, resolve_devices};
-use super::engine::DevOwnership;
-use super::metadata::{BDA, MIN_MDA_SECTORS, StaticHeader, validate_mda_size};
+use super::device::{blkdev_size, blkdev_stable_id, resolve_devices, wipe_device};
+use super::metadata::{BDA, BDA_STATIC_HDR_SECTORS, MDA_RESERVED_SECTORS, MIN_MDA_SECTORS,
+                      StaticHeader, validate_mda_size};
 use super::range_alloc::RangeAllocator;
 use super::serde_structs::{BlockDevSave, Recordable};
 
-const MIN_DEV_SIZE: Bytes = Bytes(IEC::Gi);
 const MAX_NUM_TO_WRITE: usize = 10;
 
 #[derive(Clone, Debug)]
@@ -83,14 +84,28 @@ impl BlockDevMgr {
     }
 
     /// Initialize a new StratBlockDevMgr with specified pool and devices.
+    /// The devices of metadata_paths, which must be among paths, are placed
+    /// in the metadata tier, and the rest in the data tier.
+    /// The checks on the devices which force overrides are skipped; see
+    /// check_device().
     pub fn initialize(pool_uuid: PoolUuid,
                       paths: &[&Path],
+                      metadata_paths: &[&Path],
                       mda_size: Sectors,
-                      force: bool)
+                      force: CreateForce)
                       -> EngineResult<BlockDevMgr> {
         let devices = resolve_devices(paths)?;
+        let metadata_devices = resolve_devices(metadata_paths)?
+            .keys()
+            .cloned()
+            .collect::<HashSet<_>>();
         Ok(BlockDevMgr::new(pool_uuid,
-                            initialize(pool_uuid, devices, mda_size, force, &HashSet::new())?))
+                            initialize(pool_uuid,
+                                       devices,
+                                       &metadata_devices,
+                                       mda_size,
+                                       force,
+                                       &HashSet::new())?))
     }
 
     /// Get a function that maps UUIDs to Devices.
@@ -103,11 +118,22 @@ impl BlockDevMgr {
         Box::new(move |uuid: DevUuid| -> Option<Device> { uuid_map.get(&uuid).cloned() })
     }
 
-    pub fn add(&mut self, paths: &[&Path], force: bool) -> EngineResult<Vec<DevUuid>> {
+    /// The devices of the managed blockdevs.
+    pub fn devices(&self) -> HashSet<Device> {
+        self.block_devs
+            .values()
+            .map(|bd| *bd.device())
+            .collect()
+    }
+
+    /// Add the devices of paths to the pool, overriding the checks on them
+    /// which force asks for.
+    pub fn add(&mut self, paths: &[&Path], force: CreateForce) -> EngineResult<Vec<DevUuid>> {
         let devices = resolve_devices(paths)?;
         let current_uuids = self.block_devs.keys().cloned().collect();
         let bds = initialize(self.pool_uuid,
                              devices,
+                             &HashSet::new(),
                              MIN_MDA_SECTORS,
                              force,
                              &current_uuids)?;
@@ -117,6 +143,17 @@ impl BlockDevMgr {
         Ok(bdev_uuids)
     }
 
+    /// Stop managing the blockdev with the given uuid, returning it.
+    /// Space is no longer allocated from a blockdev once it is removed.
+    pub fn remove(&mut self, uuid: DevUuid) -> Option<StratBlockDev> {
+        self.block_devs.remove(&uuid)
+    }
+
+    /// Resume managing a blockdev previously obtained from remove().
+    pub fn insert(&mut self, bd: StratBlockDev) {
+        self.block_devs.insert(bd.uuid(), bd);
+    }
+
     pub fn destroy_all(mut self) -> EngineResult<()> {
         let bds = self.block_devs
             .drain()
@@ -125,17 +162,40 @@ impl BlockDevMgr {
         wipe_blockdevs(&bds)
     }
 
-    /// Allocate space according to sizes vector request.
+    /// Whether any of the blockdevs are in the metadata tier.
+    pub fn has_metadata_tier(&self) -> bool {
+        self.block_devs
+            .values()
+            .any(|bd| bd.tier() == BlockDevTier::Metadata)
+    }
+
+    /// The tier from which space for tier is allocated: the data tier holds
+    /// everything if there is no metadata tier.
+    fn alloc_tier(&self, tier: BlockDevTier) -> BlockDevTier {
+        if tier == BlockDevTier::Metadata && !self.has_metadata_tier() {
+            BlockDevTier::Data
+        } else {
+            tier
+        }
+    }
+
+    /// Allocate space according to sizes vector request, from the blockdevs
+    /// from which space for tier is allocated.
     /// Return the segments allocated for each request, or None if it was
     /// not possible to satisfy the request.
     /// This method is atomic, it either allocates all requested or allocates
     /// nothing.
-    pub fn alloc_space(&mut self, sizes: &[Sectors]) -> Option<Vec<Vec<BlkDevSegment>>> {
+    pub fn alloc_space(&mut self,
+                       tier: BlockDevTier,
+                       sizes: &[Sectors])
+                       -> Option<Vec<Vec<BlkDevSegment>>> {
         let total_needed: Sectors = sizes.iter().cloned().sum();
-        if self.avail_space() < total_needed {
+        if self.tier_avail_space(tier) < total_needed {
             return None;
         }
 
+        let tier = self.alloc_tier(tier);
+
         let mut lists = Vec::new();
         for &needed in sizes {
             let mut alloc = Sectors(0);
@@ -147,7 +207,10 @@ impl BlockDevMgr {
             // In the context of this major inefficiency that ensues over time
             // the obvious but more minor inefficiency of this inner loop is
             // not worth worrying about.
-            for bd in self.block_devs.values_mut() {
+            for bd in self
+                    .block_devs
+                    .values_mut()
+                    .filter(|bd| bd.tier() == tier) {
                 if alloc == needed {
                     break;
                 }
@@ -169,6 +232,16 @@ impl BlockDevMgr {
         Some(lists)
     }
 
+    /// Return the space of segments, which was allocated by alloc_space(),
+    /// to the blockdevs from which it was allocated.
+    pub fn release_space(&mut self, segments: &[BlkDevSegment]) {
+        for seg in segments {
+            if let Some(bd) = self.block_devs.get_mut(&seg.uuid) {
+                bd.release_space(seg.segment.start, seg.segment.length);
+            }
+        }
+    }
+
     #[allow(dead_code)]
     pub fn devnodes(&self) -> Vec<PathBuf> {
         self.block_devs
@@ -241,6 +314,17 @@ impl BlockDevMgr {
         self.block_devs.values().map(|bd| bd.available()).sum()
     }
 
+    /// The number of sectors not allocated for any purpose on the blockdevs
+    /// from which space for tier is allocated.
+    pub fn tier_avail_space(&self, tier: BlockDevTier) -> Sectors {
+        let tier = self.alloc_tier(tier);
+        self.block_devs
+            .values()
+            .filter(|bd| bd.tier() == tier)
+            .map(|bd| bd.available())
+            .sum()
+    }
+
     /// The current capacity of all the blockdevs.
     /// self.current_capacity() > self.avail_space() because some sectors
     /// are certainly allocated for Stratis metadata
@@ -270,12 +354,104 @@ impl Recordable<HashMap<DevUuid, BlockDevSave>> for BlockDevMgr {
     }
 }
 
+/// Find out whether the device at devnode could be added to a pool: who it
+/// belongs to, and whether it is big enough. The device is opened
+/// read-only, and nothing is written to it.
+pub fn probe_blockdev(devnode: &Path) -> EngineResult<AvailableBlockDev> {
+    let mut f = OpenOptions::new().read(true).open(devnode)?;
+    let size = blkdev_size(&f)?;
+    Ok(AvailableBlockDev {
+           ownership: StaticHeader::determine_ownership(&mut f)?,
+           size: size,
+           meets_min_size: size >= MIN_DEV_SIZE,
+       })
+}
+
+/// Decide whether the device at devnode, of dev_size, whose ownership was
+/// found by determine_ownership(), may be initialized for the pool
+/// pool_uuid, skipping the checks which force overrides.
+/// Returns false if the device already belongs to the pool, as one of
+/// owned_devs, so that there is nothing to do.
+/// Returns an Invalid error if the device may not be initialized, and the
+/// error in finding its ownership if that was an IO error.
+fn check_device(devnode: &Path,
+                dev_size: Bytes,
+                ownership: EngineResult<DevOwnership>,
+                pool_uuid: PoolUuid,
+                mda_size: Sectors,
+                force: CreateForce,
+                owned_devs: &HashSet<DevUuid>)
+                -> EngineResult<bool> {
+    if dev_size < MIN_DEV_SIZE {
+        if !force.ignore_size {
+            let error_message = format!("{} too small, size {} bytes, minimum {} bytes",
+                                        devnode.display(),
+                                        dev_size,
+                                        MIN_DEV_SIZE);
+            return Err(EngineError::Engine(ErrorEnum::Invalid, error_message));
+        }
+        // No check can be overridden for a device too small to hold its
+        // own metadata.
+        let metadata_size = BDA_STATIC_HDR_SECTORS + mda_size + MDA_RESERVED_SECTORS;
+        if dev_size.sectors() <= metadata_size {
+            let error_message = format!("{} too small, size {} bytes, can not hold its \
+                                         Stratis metadata, {} bytes",
+                                        devnode.display(),
+                                        dev_size,
+                                        metadata_size.bytes());
+            return Err(EngineError::Engine(ErrorEnum::Invalid, error_message));
+        }
+    }
+    match ownership {
+        Ok(DevOwnership::Unowned) => Ok(true),
+        Ok(DevOwnership::Theirs) => {
+            if !force.overwrite_signatures {
+                let err_str = format!("Device {} appears to belong to another application",
+                                      devnode.display());
+                return Err(EngineError::Engine(ErrorEnum::Invalid, err_str));
+            }
+            Ok(true)
+        }
+        Ok(DevOwnership::Ours(uuid, dev_uuid)) => {
+            if pool_uuid == uuid {
+                if !owned_devs.contains(&dev_uuid) {
+                    let error_str = format!("Device {} with pool UUID is unknown to pool",
+                                            devnode.display());
+                    return Err(EngineError::Engine(ErrorEnum::Invalid, error_str));
+                }
+                Ok(false)
+            } else {
+                let error_str = format!("Device {} already belongs to Stratis pool {}",
+                                        devnode.display(),
+                                        uuid);
+                Err(EngineError::Engine(ErrorEnum::Invalid, error_str))
+            }
+        }
+        Err(err @ EngineError::Io(_)) => Err(err),
+        Err(err) => {
+            if !force.reuse_partial {
+                let error_str = format!("Device {} appears to be a partially initialized \
+                                         Stratis device: {}",
+                                        devnode.display(),
+                                        err);
+                return Err(EngineError::Engine(ErrorEnum::Invalid, error_str));
+            }
+            Ok(true)
+        }
+    }
+}
+
 /// Initialize multiple blockdevs at once. This allows all of them
 /// to be checked for usability before writing to any of them.
+/// Any signatures left by a device's previous user are wiped before the
+/// device is initialized.
+/// The devices in metadata_devices are placed in the metadata tier, and the
+/// rest in the data tier.
 fn initialize(pool_uuid: PoolUuid,
               devices: HashMap<Device, &Path>,
+              metadata_devices: &HashSet<Device>,
               mda_size: Sectors,
-              force: bool,
+              force: CreateForce,
               owned_devs: &HashSet<DevUuid>)
               -> EngineResult<Vec<StratBlockDev>> {
 
@@ -284,13 +460,15 @@ fn initialize(pool_uuid: PoolUuid,
     /// Returns a tuple with the device's path, its size in bytes,
     /// its ownership as determined by calling determine_ownership(),
     /// and an open File handle, all of which are needed later.
-    fn dev_info(devnode: &Path) -> EngineResult<(&Path, Bytes, DevOwnership, File)> {
+    #[allow(type_complexity)]
+    fn dev_info(devnode: &Path)
+                -> EngineResult<(&Path, Bytes, EngineResult<DevOwnership>, File)> {
         let mut f = OpenOptions::new()
             .read(true)
             .write(true)
             .open(&devnode)?;
         let dev_size = blkdev_size(&f)?;
-        let ownership = StaticHeader::determine_ownership(&mut f)?;
+        let ownership = StaticHeader::determine_ownership(&mut f);
 
         Ok((devnode, dev_size, ownership, f))
     }
@@ -301,45 +479,27 @@ fn initialize(pool_uuid: PoolUuid,
     #[allow(type_complexity)]
     fn filter_devs<'a, I>(dev_infos: I,
                           pool_uuid: PoolUuid,
-                          force: bool,
+                          mda_size: Sectors,
+                          force: CreateForce,
                           owned_devs: &HashSet<DevUuid>)
                           -> EngineResult<Vec<(Device, (&'a Path, Bytes, File))>>
-        where I: Iterator<Item = (Device, EngineResult<(&'a Path, Bytes, DevOwnership, File)>)>
+        where I: Iterator<Item = (Device,
+                                  EngineResult<(&'a Path,
+                                                Bytes,
+                                                EngineResult<DevOwnership>,
+                                                File)>)>
     {
         let mut add_devs = Vec::new();
         for (dev, dev_result) in dev_infos {
             let (devnode, dev_size, ownership, f) = dev_result?;
-            if dev_size < MIN_DEV_SIZE {
-                let error_message = format!("{} too small, minimum {} bytes",
-                                            devnode.display(),
-                                            MIN_DEV_SIZE);
-                return Err(EngineError::Engine(ErrorEnum::Invalid, error_message));
-            };
-            match ownership {
-                DevOwnership::Unowned => add_devs.push((dev, (devnode, dev_size, f))),
-                DevOwnership::Theirs => {
-                    if !force {
-                        let err_str = format!("Device {} appears to belong to another application",
-                                              devnode.display());
-                        return Err(EngineError::Engine(ErrorEnum::Invalid, err_str));
-                    } else {
-                        add_devs.push((dev, (devnode, dev_size, f)))
-                    }
-                }
-                DevOwnership::Ours(uuid, dev_uuid) => {
-                    if pool_uuid == uuid {
-                        if !owned_devs.contains(&dev_uuid) {
-                            let error_str = format!("Device {} with pool UUID is unknown to pool",
-                                                    devnode.display());
-                            return Err(EngineError::Engine(ErrorEnum::Invalid, error_str));
-                        }
-                    } else {
-                        let error_str = format!("Device {} already belongs to Stratis pool {}",
-                                                devnode.display(),
-                                                uuid);
-                        return Err(EngineError::Engine(ErrorEnum::Invalid, error_str));
-                    }
-                }
+            if check_device(devnode,
+                            dev_size,
+                            ownership,
+                            pool_uuid,
+                            mda_size,
+                            force,
+                            owned_devs)? {
+                add_devs.push((dev, (devnode, dev_size, f)));
             }
         }
         Ok(add_devs)
@@ -349,10 +509,14 @@ fn initialize(pool_uuid: PoolUuid,
 
     let dev_infos = devices.into_iter().map(|(d, p)| (d, dev_info(p)));
 
-    let add_devs = filter_devs(dev_infos, pool_uuid, force, owned_devs)?;
+    let add_devs = filter_devs(dev_infos, pool_uuid, mda_size, force, owned_devs)?;
 
     let mut bds: Vec<StratBlockDev> = Vec::new();
     for (dev, (devnode, dev_size, mut f)) in add_devs {
+        if let Err(err) = wipe_device(devnode, dev_size.sectors(), false) {
+            let _ = wipe_blockdevs(&bds);
+            return Err(err);
+        }
 
         let bda = BDA::initialize(&mut f,
                                   pool_uuid,
@@ -364,8 +528,30 @@ fn initialize(pool_uuid: PoolUuid,
             let allocator = RangeAllocator::new(bda.dev_size(), &[(Sectors(0), bda.size())])
                 .expect("bda.size() < bda.dev_size() and single range");
 
-            // TODO: support getting hw info and passing in here. See #615
-            bds.push(StratBlockDev::new(dev, devnode.to_owned(), bda, allocator, None, None));
+            let stable_id = match blkdev_stable_id(dev) {
+                Ok(stable_id) => stable_id,
+                Err(err) => {
+                    let _ = wipe_blockdevs(&bds);
+                    return Err(err);
+                }
+            };
+            let tier = if metadata_devices.contains(&dev) {
+                BlockDevTier::Metadata
+            } else {
+                BlockDevTier::Data
+            };
+            bds.push(StratBlockDev::new(dev,
+                                        devnode.to_owned(),
+                                        bda,
+                                        allocator,
+                                        None,
+                                        stable_id,
+                                        tier));
+
+            if let Err(err) = fail_point("BlockDevMgr::initialize") {
+                let _ = wipe_blockdevs(&bds);
+                return Err(err);
+            }
         } else {
             // TODO: check the return values and update state machine on failure
             let _ = BDA::wipe(&mut f);
@@ -381,6 +567,8 @@ fn initialize(pool_uuid: PoolUuid,
 #[cfg(test)]
 mod tests {
     use std::fs::OpenOptions;
+    use std::io;
+    use std::io::{Read, Seek, SeekFrom};
 
     use rand;
     use uuid::Uuid;
@@ -389,8 +577,11 @@ mod tests {
     use devicemapper::SECTOR_SIZE;
 
     use super::super::device::write_sectors;
-    use super::super::metadata::{BDA_STATIC_HDR_SECTORS, MIN_MDA_SECTORS};
-    use super::super::setup::{find_all, get_metadata};
+    use super::super::failpoint::arm;
+    use super::super::metadata::{BDA_STATIC_HDR_SECTORS, MDA_RESERVED_SECTORS, MIN_MDA_SECTORS};
+    use super::super::device::devnode_to_devno;
+    use super::super::setup::{DeviceDiscovery, RejectReason, discover_devices, find_all,
+                              get_metadata};
     use super::super::tests::{loopbacked, real};
 
     use super::*;
@@ -400,13 +591,17 @@ mod tests {
     /// After 2 Sectors have been allocated, that amount must also be included
     /// in balance.
     fn test_blockdevmgr_used(paths: &[&Path]) -> () {
-        let mut mgr = BlockDevMgr::initialize(Uuid::new_v4(), paths, MIN_MDA_SECTORS, false)
+        let mut mgr = BlockDevMgr::initialize(Uuid::new_v4(),
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
             .unwrap();
         assert_eq!(mgr.avail_space() + mgr.metadata_size(),
                    mgr.current_capacity());
 
         let allocated = Sectors(2);
-        mgr.alloc_space(&[allocated]).unwrap();
+        mgr.alloc_space(BlockDevTier::Data, &[allocated]).unwrap();
         assert_eq!(mgr.avail_space() + allocated + mgr.metadata_size(),
                    mgr.current_capacity());
     }
@@ -426,6 +621,41 @@ mod tests {
         loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_blockdevmgr_used);
     }
 
+    /// Verify that the metadata size of each blockdev of a new pool is the
+    /// size of the region reserved for the BDA, and that the metadata size
+    /// and total size of the blockdevs account for all their capacity.
+    fn test_metadata_size(paths: &[&Path]) -> () {
+        let mgr = BlockDevMgr::initialize(Uuid::new_v4(),
+                                          paths,
+                                          &[],
+                                          MIN_MDA_SECTORS,
+                                          CreateForce::default())
+            .unwrap();
+        let blockdevs = mgr.blockdevs();
+        assert_eq!(blockdevs.len(), paths.len());
+        for bd in &blockdevs {
+            assert_eq!(bd.metadata_size(),
+                       BDA_STATIC_HDR_SECTORS + MIN_MDA_SECTORS + MDA_RESERVED_SECTORS);
+        }
+        assert_eq!(blockdevs
+                       .iter()
+                       .map(|bd| bd.metadata_size() + bd.total_size())
+                       .sum::<Sectors>(),
+                   mgr.current_capacity());
+        assert_eq!(mgr.metadata_size(),
+                   blockdevs.iter().map(|bd| bd.metadata_size()).sum());
+    }
+
+    #[test]
+    pub fn loop_test_metadata_size() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_metadata_size);
+    }
+
+    #[test]
+    pub fn real_test_metadata_size() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_metadata_size);
+    }
+
     /// Verify that it is impossible to initialize a set of disks of which
     /// even one is dirty, i.e, has some data written within BDA_STATIC_HDR_SECTORS
     /// of start of disk. Choose the dirty disk randomly. This means that even
@@ -444,7 +674,11 @@ mod tests {
                 .unwrap();
 
         let pool_uuid = Uuid::new_v4();
-        assert!(BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).is_err());
+        assert!(BlockDevMgr::initialize(pool_uuid,
+                                        paths,
+                                        &[],
+                                        MIN_MDA_SECTORS,
+                                        CreateForce::default()).is_err());
         assert!(paths
                     .iter()
                     .enumerate()
@@ -461,7 +695,11 @@ mod tests {
             }
         }));
 
-        assert!(BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, true).is_ok());
+        let force = CreateForce {
+            overwrite_signatures: true,
+            ..CreateForce::default()
+        };
+        assert!(BlockDevMgr::initialize(pool_uuid, paths, &[], MIN_MDA_SECTORS, force).is_ok());
         assert!(paths
                     .iter()
                     .all(|path| {
@@ -496,7 +734,7 @@ mod tests {
     /// 1. Initialize devices with pool uuid.
     /// 2. Initializing again with different uuid must fail.
     /// 3. Adding the devices must succeed, because they already belong.
-    /// 4. Initializing again with different uuid and force = true also fails.
+    /// 4. Initializing again with different uuid and any force overrides fails.
     fn test_force_flag_stratis(paths: &[&Path]) -> () {
         assert!(paths.len() > 1);
         let (paths1, paths2) = paths.split_at(paths.len() / 2);
@@ -504,18 +742,36 @@ mod tests {
         let uuid = Uuid::new_v4();
         let uuid2 = Uuid::new_v4();
 
-        let mut bd_mgr = BlockDevMgr::initialize(uuid, paths1, MIN_MDA_SECTORS, false).unwrap();
-        assert!(BlockDevMgr::initialize(uuid2, paths1, MIN_MDA_SECTORS, false).is_err());
+        let mut bd_mgr = BlockDevMgr::initialize(uuid,
+                                                 paths1,
+                                                 &[],
+                                                 MIN_MDA_SECTORS,
+                                                 CreateForce::default())
+            .unwrap();
+        assert!(BlockDevMgr::initialize(uuid2,
+                                        paths1,
+                                        &[],
+                                        MIN_MDA_SECTORS,
+                                        CreateForce::default()).is_err());
         // FIXME: this should succeed, but currently it fails, to be extra safe.
         // See: https://github.com/stratis-storage/stratisd/pull/292
-        assert!(BlockDevMgr::initialize(uuid2, paths1, MIN_MDA_SECTORS, true).is_err());
+        let force = CreateForce {
+            overwrite_signatures: true,
+            ignore_size: true,
+            reuse_partial: true,
+        };
+        assert!(BlockDevMgr::initialize(uuid2, paths1, &[], MIN_MDA_SECTORS, force).is_err());
 
         let original_length = bd_mgr.block_devs.len();
-        assert!(bd_mgr.add(paths1, false).is_ok());
+        assert!(bd_mgr.add(paths1, CreateForce::default()).is_ok());
         assert_eq!(bd_mgr.block_devs.len(), original_length);
 
-        BlockDevMgr::initialize(uuid, paths2, MIN_MDA_SECTORS, false).unwrap();
-        assert!(bd_mgr.add(paths2, false).is_err());
+        BlockDevMgr::initialize(uuid,
+                                paths2,
+                                &[],
+                                MIN_MDA_SECTORS,
+                                CreateForce::default()).unwrap();
+        assert!(bd_mgr.add(paths2, CreateForce::default()).is_err());
     }
 
     #[test]
@@ -535,6 +791,74 @@ mod tests {
                                    test_force_flag_stratis);
     }
 
+    #[test]
+    /// Verify that each force override lets a device past the one check
+    /// which it is for, and no other, and that none of them lets through a
+    /// device too small to hold its own metadata or one which can not be
+    /// read.
+    fn test_check_device_force() {
+        let devnode = Path::new("/dev/stratis_test");
+        let pool_uuid = Uuid::new_v4();
+        let owned_devs = HashSet::new();
+        let check = |size: Bytes, ownership: EngineResult<DevOwnership>, force: CreateForce| {
+            check_device(devnode,
+                         size,
+                         ownership,
+                         pool_uuid,
+                         MIN_MDA_SECTORS,
+                         force,
+                         &owned_devs)
+        };
+        let partial = || Err(EngineError::Engine(ErrorEnum::Invalid, "bad MDA".into()));
+
+        let small = MIN_DEV_SIZE - Bytes(SECTOR_SIZE as u64);
+        let tiny = (BDA_STATIC_HDR_SECTORS + MIN_MDA_SECTORS + MDA_RESERVED_SECTORS).bytes();
+
+        let none = CreateForce::default();
+        let overwrite_signatures = CreateForce {
+            overwrite_signatures: true,
+            ..none
+        };
+        let ignore_size = CreateForce {
+            ignore_size: true,
+            ..none
+        };
+        let reuse_partial = CreateForce {
+            reuse_partial: true,
+            ..none
+        };
+
+        for &force in &[none, overwrite_signatures, ignore_size, reuse_partial] {
+            assert!(check(MIN_DEV_SIZE, Ok(DevOwnership::Unowned), force).unwrap());
+            assert_eq!(check(MIN_DEV_SIZE, Ok(DevOwnership::Theirs), force).is_ok(),
+                       force == overwrite_signatures);
+            assert_eq!(check(small, Ok(DevOwnership::Unowned), force).is_ok(),
+                       force == ignore_size);
+            assert_eq!(check(MIN_DEV_SIZE, partial(), force).is_ok(),
+                       force == reuse_partial);
+            assert!(check(tiny, Ok(DevOwnership::Unowned), force).is_err());
+            assert!(check(MIN_DEV_SIZE,
+                          Ok(DevOwnership::Ours(Uuid::new_v4(), Uuid::new_v4())),
+                          force)
+                            .is_err());
+        }
+
+        let io_err = io::Error::new(io::ErrorKind::Other, "unreadable");
+        assert!(match check(MIN_DEV_SIZE, Err(EngineError::Io(io_err)), reuse_partial) {
+                    Err(EngineError::Io(_)) => true,
+                    _ => false,
+                });
+
+        let all = CreateForce {
+            overwrite_signatures: true,
+            ignore_size: true,
+            reuse_partial: true,
+        };
+        assert!(check(small, Ok(DevOwnership::Theirs), overwrite_signatures).is_err());
+        assert!(check(small, Ok(DevOwnership::Theirs), all).unwrap());
+        assert!(check(tiny, Ok(DevOwnership::Theirs), all).is_err());
+    }
+
     /// Verify that find_all function locates and assigns pools appropriately.
     /// 1. Split available paths into 2 discrete sets.
     /// 2. Initialize the block devices in the first set with a pool uuid.
@@ -551,7 +875,11 @@ mod tests {
         let (paths1, paths2) = paths.split_at(paths.len() / 2);
 
         let uuid1 = Uuid::new_v4();
-        BlockDevMgr::initialize(uuid1, paths1, MIN_MDA_SECTORS, false).unwrap();
+        BlockDevMgr::initialize(uuid1,
+                                paths1,
+                                &[],
+                                MIN_MDA_SECTORS,
+                                CreateForce::default()).unwrap();
 
         let pools = find_all().unwrap();
         assert_eq!(pools.len(), 1);
@@ -560,7 +888,11 @@ mod tests {
         assert_eq!(devices.len(), paths1.len());
 
         let uuid2 = Uuid::new_v4();
-        BlockDevMgr::initialize(uuid2, paths2, MIN_MDA_SECTORS, false).unwrap();
+        BlockDevMgr::initialize(uuid2,
+                                paths2,
+                                &[],
+                                MIN_MDA_SECTORS,
+                                CreateForce::default()).unwrap();
 
         let pools = find_all().unwrap();
         assert_eq!(pools.len(), 2);
@@ -594,11 +926,150 @@ mod tests {
         loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_initialize);
     }
 
+    /// Verify that a loop device, which has no hardware beneath it, reports
+    /// no hardware info, rather than an error.
+    fn test_hardware_info(paths: &[&Path]) -> () {
+        let mgr = BlockDevMgr::initialize(Uuid::new_v4(),
+                                          paths,
+                                          &[],
+                                          MIN_MDA_SECTORS,
+                                          CreateForce::default())
+            .unwrap();
+        for bd in mgr.blockdevs() {
+            assert_eq!(bd.hardware_info(), None);
+        }
+    }
+
+    #[test]
+    pub fn loop_test_hardware_info() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_hardware_info);
+    }
+
+    /// Verify that discover_devices() groups a Stratis device into its
+    /// pool, and records a device with no signature and a Stratis device
+    /// whose static header is corrupt as rejected, for those reasons.
+    /// find_all() refuses to return pools while a device is corrupt.
+    fn test_discover_devices(paths: &[&Path]) -> () {
+        let device = |path: &Path| Device::from(devnode_to_devno(path).unwrap().unwrap());
+
+        let uuid = Uuid::new_v4();
+        BlockDevMgr::initialize(uuid,
+                                &paths[..1],
+                                &[],
+                                MIN_MDA_SECTORS,
+                                CreateForce::default()).unwrap();
+        BlockDevMgr::initialize(Uuid::new_v4(),
+                                &paths[2..],
+                                &[],
+                                MIN_MDA_SECTORS,
+                                CreateForce::default())
+            .unwrap();
+
+        // Corrupt the CRC of both sigblocks of the last device, which are
+        // the second and tenth sectors of its static header.
+        let (sigblock1, sigblock2) = (Sectors(1), Sectors(9));
+        let mut buf = [0u8; SECTOR_SIZE];
+        {
+            let mut f = OpenOptions::new().read(true).open(paths[2]).unwrap();
+            f.seek(SeekFrom::Start(*sigblock1.bytes())).unwrap();
+            f.read_exact(&mut buf).unwrap();
+        }
+        buf[0] = !buf[0];
+        write_sectors(paths[2], sigblock1, Sectors(1), &buf).unwrap();
+        write_sectors(paths[2], sigblock2, Sectors(1), &buf).unwrap();
+
+        let discovery = discover_devices().unwrap();
+        assert_eq!(discovery.pools.len(), 1);
+        assert!(discovery
+                    .pools
+                    .get(&uuid)
+                    .unwrap()
+                    .contains_key(&device(paths[0])));
+
+        let reason = |path: &Path| {
+            discovery
+                .rejected
+                .iter()
+                .find(|rejected| rejected.device == device(path))
+                .map(|rejected| &rejected.reason)
+        };
+        assert!(match reason(paths[1]) {
+                    Some(&RejectReason::Unowned) => true,
+                    _ => false,
+                });
+        assert!(match reason(paths[2]) {
+                    Some(&RejectReason::IncompleteMetadata(_)) => true,
+                    _ => false,
+                });
+
+        assert!(find_all().is_err());
+    }
+
+    #[test]
+    pub fn loop_test_discover_devices() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Exactly(3), test_discover_devices);
+    }
+
+    /// Verify that a device which can not be read is recorded as unreadable,
+    /// and that the scan goes on to examine all the other devices, so that
+    /// find_all() still finds the pools.
+    fn test_discover_unreadable(paths: &[&Path]) -> () {
+        let uuid = Uuid::new_v4();
+        BlockDevMgr::initialize(uuid,
+                                paths,
+                                &[],
+                                MIN_MDA_SECTORS,
+                                CreateForce::default()).unwrap();
+
+        let unreadable = |discovery: &DeviceDiscovery| {
+            discovery
+                .rejected
+                .iter()
+                .filter(|rejected| match rejected.reason {
+                            RejectReason::Unreadable(_) => true,
+                            _ => false,
+                        })
+                .count()
+        };
+        let examined = |discovery: &DeviceDiscovery| {
+            discovery.rejected.len() +
+            discovery
+                .pools
+                .values()
+                .map(|devices| devices.len())
+                .sum::<usize>()
+        };
+
+        let before = discover_devices().unwrap();
+        // Fail the read of the first device examined.
+        arm("discover_devices", 0);
+        let after = discover_devices().unwrap();
+        assert_eq!(unreadable(&after), unreadable(&before) + 1);
+        assert_eq!(examined(&after), examined(&before));
+
+        // The pool is found, without the failed device, if it is one of
+        // the pool's.
+        arm("discover_devices", 0);
+        let pools = find_all().unwrap();
+        assert!(pools.get(&uuid).map_or(0, |devices| devices.len()) >= paths.len() - 1);
+    }
+
+    #[test]
+    pub fn loop_test_discover_unreadable() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3),
+                                   test_discover_unreadable);
+    }
+
     /// Test that initialing devices claims all and that destroying
     /// them releases all.
     fn test_ownership(paths: &[&Path]) -> () {
         let pool_uuid = Uuid::new_v4();
-        let bd_mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
+        let bd_mgr = BlockDevMgr::initialize(pool_uuid,
+                                             paths,
+                                             &[],
+                                             MIN_MDA_SECTORS,
+                                             CreateForce::default())
+            .unwrap();
 
         assert!(paths
                     .iter()
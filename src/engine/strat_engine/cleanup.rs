@@ -4,10 +4,18 @@
 
 // Code to handle cleanup after a failed operation.
 
-use super::super::engine::HasUuid;
+use std::cmp::Reverse;
+
+use devicemapper::{DM, DM_DEFERRED_REMOVE, DevId, DmFlags};
+
+use super::super::engine::{HasUuid, Pool};
 use super::super::errors::{EngineResult, EngineError, ErrorEnum};
+use super::super::types::{FilesystemUuid, PoolUuid};
 
 use super::blockdev::StratBlockDev;
+use super::dmdevice::{FlexRole, ThinPoolRole, ThinRole, format_flex_name, format_thin_name,
+                      format_thinpool_name};
+use super::mount::{mount_points_of, unmount_fs_lazy};
 use super::pool::StratPool;
 
 /// Wipe some blockdevs of their identifying headers.
@@ -31,19 +39,154 @@ pub fn wipe_blockdevs(blockdevs: &[StratBlockDev]) -> EngineResult<()> {
     }
 }
 
-/// Teardown pools.
-pub fn teardown_pools(pools: Vec<StratPool>) -> EngineResult<()> {
-    let mut untorndown_pools = Vec::new();
-    for pool in pools {
+/// The outcome of tearing down a number of pools.
+#[derive(Debug)]
+pub struct TeardownReport {
+    /// The pools which were torn down.
+    pub torn_down: Vec<PoolUuid>,
+    /// The pools which could not be torn down, each with the error that
+    /// prevented it.
+    pub failed: Vec<(PoolUuid, EngineError)>,
+}
+
+/// Order pools for teardown: in reverse order of creation, so that a pool is
+/// torn down before any pool created before it, which it may depend on.
+/// Pools whose creation times do not tell them apart are in reverse of the
+/// order in which they are given.
+fn teardown_order(mut pools: Vec<StratPool>) -> Vec<StratPool> {
+    pools.reverse();
+    // The sort is stable, so pools created at the same time keep their
+    // order.
+    pools.sort_by_key(|pool| Reverse(pool.created()));
+    pools
+}
+
+/// Teardown pools, in reverse order of creation.
+/// Attempt to tear down every pool, even if some can not be torn down.
+pub fn teardown_pools_detailed(pools: Vec<StratPool>) -> TeardownReport {
+    let mut report = TeardownReport {
+        torn_down: Vec::new(),
+        failed: Vec::new(),
+    };
+    for pool in teardown_order(pools) {
         let pool_uuid = pool.uuid();
-        pool.teardown()
-            .unwrap_or_else(|_| untorndown_pools.push(pool_uuid));
+        match pool.teardown() {
+            Ok(_) => report.torn_down.push(pool_uuid),
+            Err(err) => report.failed.push((pool_uuid, err)),
+        }
+    }
+    report
+}
+
+/// Teardown pools, in reverse order of creation.
+/// Every pool is torn down if possible, even if some can not be.
+/// Return an error listing every pool which could not be torn down, with
+/// the error which prevented it.
+pub fn teardown_pools(pools: Vec<StratPool>) -> EngineResult<()> {
+    let report = teardown_pools_detailed(pools);
+    if report.failed.is_empty() {
+        Ok(())
+    } else {
+        let failures: Vec<_> = report
+            .failed
+            .iter()
+            .map(|&(uuid, ref err)| format!("{}: {}", uuid, err))
+            .collect();
+        let err_msg = format!("Failed to teardown already set up pools: {}",
+                              failures.join("; "));
+        Err(EngineError::Engine(ErrorEnum::Error, err_msg))
     }
-    if untorndown_pools.is_empty() {
+}
+
+/// Teardown pools, in reverse order of creation, even if some of their
+/// filesystems are in use, e.g., on system shutdown. Failures to tear down
+/// individual devices are logged, and devices still in use are removed
+/// once they are no longer in use.
+pub fn teardown_pools_force(dm: &DM, pools: Vec<StratPool>) {
+    for pool in teardown_order(pools) {
+        pool.teardown_force(dm);
+    }
+}
+
+/// Remove the devicemapper devices of the pool with pool_uuid, which has the
+/// filesystems fs_uuids, deferring the removal of any device which is in
+/// use until it is no longer in use. Devices are removed before the
+/// devices they depend on. The MDV is first detached from any mount point,
+/// since its removal would otherwise wait on a mount which nothing will
+/// ever unmount. Failures are logged.
+pub fn remove_pool_devs_deferred(dm: &DM, pool_uuid: PoolUuid, fs_uuids: &[FilesystemUuid]) {
+    let existing = match dm.list_devices() {
+        Ok(devices) => devices,
+        Err(err) => {
+            warn!("Could not list devicemapper devices of pool {}: {}",
+                  pool_uuid,
+                  err);
+            return;
+        }
+    };
+
+    let mdv_name = format_flex_name(pool_uuid, FlexRole::MetadataVolume);
+    if let Some(&(_, mdv_device, _)) = existing.iter().find(|d| d.0 == mdv_name) {
+        let result = mount_points_of(mdv_device).and_then(|mount_points| {
+            for mount_point in mount_points.iter().rev() {
+                unmount_fs_lazy(mount_point)?;
+            }
+            Ok(())
+        });
+        if let Err(err) = result {
+            warn!("Could not unmount the MDV of pool {}: {}", pool_uuid, err);
+        }
+    }
+
+    let existing = existing.into_iter().map(|d| d.0).collect::<Vec<_>>();
+
+    let names = fs_uuids
+        .iter()
+        .map(|&fs_uuid| format_thin_name(pool_uuid, ThinRole::Filesystem(fs_uuid)))
+        .chain(vec![format_thinpool_name(pool_uuid, ThinPoolRole::Pool),
+                    format_flex_name(pool_uuid, FlexRole::MetadataVolume),
+                    format_flex_name(pool_uuid, FlexRole::ThinData),
+                    format_flex_name(pool_uuid, FlexRole::ThinDataCrypt),
+                    format_flex_name(pool_uuid, FlexRole::ThinDataRaw),
+                    format_flex_name(pool_uuid, FlexRole::ThinMeta)]);
+
+    for name in names.filter(|n| existing.contains(n)) {
+        if let Err(err) = dm.device_remove(&DevId::Name(&name), DM_DEFERRED_REMOVE) {
+            warn!("Could not remove devicemapper device {}: {}", name.to_string(), err);
+        }
+    }
+}
+
+/// Remove any devicemapper devices belonging to the thinpool of the pool
+/// with pool_uuid, e.g., those left behind by a partially completed
+/// thinpool setup. The thinpool device is removed before the devices it
+/// depends on.
+/// Return an error if any of the devices that exist could not be removed.
+pub fn remove_thinpool_devs(dm: &DM, pool_uuid: PoolUuid) -> EngineResult<()> {
+    let names = [format_thinpool_name(pool_uuid, ThinPoolRole::Pool),
+                 format_flex_name(pool_uuid, FlexRole::MetadataVolume),
+                 format_flex_name(pool_uuid, FlexRole::ThinData),
+                 format_flex_name(pool_uuid, FlexRole::ThinDataCrypt),
+                 format_flex_name(pool_uuid, FlexRole::ThinDataRaw),
+                 format_flex_name(pool_uuid, FlexRole::ThinMeta)];
+
+    let existing = dm.list_devices()?
+        .into_iter()
+        .map(|d| d.0)
+        .collect::<Vec<_>>();
+
+    let mut unremoved_devs = Vec::new();
+    for name in names.iter().filter(|n| existing.contains(n)) {
+        if dm.device_remove(&DevId::Name(name), DmFlags::empty())
+               .is_err() {
+            unremoved_devs.push(name.to_string());
+        }
+    }
+
+    if unremoved_devs.is_empty() {
         Ok(())
     } else {
-        let err_msg = format!("Failed to teardown already set up pools: {:?}",
-                              untorndown_pools);
+        let err_msg = format!("Failed to remove devicemapper devices: {:?}", unremoved_devs);
         Err(EngineError::Engine(ErrorEnum::Error, err_msg))
     }
 }
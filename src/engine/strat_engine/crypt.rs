@@ -0,0 +1,363 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Encryption of the data of a pool.
+//
+// The data of an encrypted pool passes through a dm-crypt device. The
+// dm-crypt device lies on a linear device, which maps the pool's data
+// segments, and the thinpool's data device is a linear device which maps
+// the whole of the dm-crypt device. The thinpool's data device is thus a
+// linear device whether the pool is encrypted or not.
+//
+// The key is not stored by Stratis. It is kept in the kernel keyring,
+// where dm-crypt finds it by the description recorded in the pool's
+// metadata. An encrypted pool can not be set up unless its key is in the
+// keyring. The metadata also records a check value computed from the key's
+// data, by which a key of the right description but the wrong data is
+// detected before the pool's data is decrypted with it.
+
+use std::ffi::CString;
+use std::io;
+use std::ptr;
+
+use hmac::{Hmac, Mac};
+use libc;
+use sha2::Sha256;
+
+use devicemapper::{DM, DM_SUSPEND, DevId, Device, DmDevice, DmFlags, DmName, DmNameBuf,
+                   LinearDev, Sectors, Segment, TargetLine, TargetTypeBuf, device_exists};
+
+use super::super::errors::{EngineError, EngineResult, ErrorEnum};
+use super::super::types::{KeyDescription, PoolUuid};
+
+use super::dmdevice::{FlexRole, format_flex_name, retry_dm};
+
+/// The cipher with which a pool's data is encrypted.
+const CIPHER: &str = "aes-xts-plain64";
+
+/// The type of the key in the kernel keyring.
+const KEY_TYPE: &str = "user";
+
+/// The sizes, in bytes, of the keys which CIPHER accepts.
+const KEY_SIZES: [usize; 2] = [32, 64];
+
+/// The message, followed by the pool's UUID, of which the key check value
+/// is the MAC.
+const KEY_CHECK_MESSAGE: &[u8] = b"stratis key check";
+
+// Keyring constants from linux/keyctl.h, which libc does not define.
+#[cfg(test)]
+const KEY_SPEC_SESSION_KEYRING: libc::c_long = -3;
+const KEYCTL_READ: libc::c_long = 11;
+#[cfg(test)]
+const KEYCTL_INVALIDATE: libc::c_long = 21;
+
+/// The dm-crypt device of an encrypted pool's data, and the linear device
+/// beneath it.
+#[derive(Debug)]
+pub struct CryptDev {
+    raw_dev: LinearDev,
+    name: DmNameBuf,
+    device: Device,
+    key: KeyDescription,
+    key_size: usize,
+}
+
+impl CryptDev {
+    /// Set up the dm-crypt device of the data of pool pool_uuid on
+    /// segments, encrypting with key, or reload it if it already exists.
+    /// Returns a KeyNotFound error if key is not in the kernel keyring.
+    pub fn setup(dm: &DM,
+                 pool_uuid: PoolUuid,
+                 segments: &[Segment],
+                 key: &KeyDescription)
+                 -> EngineResult<CryptDev> {
+        let key_size = check_key(key)?;
+        let raw_name = format_flex_name(pool_uuid, FlexRole::ThinDataRaw);
+        let raw_dev = retry_dm(|| LinearDev::setup(dm, &raw_name, None, segments))?;
+
+        let name = format_flex_name(pool_uuid, FlexRole::ThinDataCrypt);
+        let table = crypt_table(&raw_dev, key, key_size);
+        let device = match load_table(dm, &name, &table) {
+            Ok(device) => device,
+            Err(err) => {
+                if let Err(teardown_err) = raw_dev.teardown(dm) {
+                    warn!("Could not tear down {} after failed encryption setup: {}",
+                          raw_name.to_string(),
+                          teardown_err);
+                }
+                return Err(err);
+            }
+        };
+
+        Ok(CryptDev {
+               raw_dev: raw_dev,
+               name: name,
+               device: device,
+               key: key.clone(),
+               key_size: key_size,
+           })
+    }
+
+    /// The single segment which maps the whole of the decrypted data.
+    pub fn segment(&self) -> Segment {
+        Segment::new(self.device, Sectors(0), self.raw_dev.size())
+    }
+
+    /// Map the encrypted data onto segments, and resize the dm-crypt device
+    /// to match. The data must already have been copied to any new
+    /// segments.
+    pub fn set_segments(&mut self, dm: &DM, segments: &[Segment]) -> EngineResult<()> {
+        self.raw_dev.set_segments(dm, segments)?;
+        load_table(dm,
+                   &self.name,
+                   &crypt_table(&self.raw_dev, &self.key, self.key_size))?;
+        Ok(())
+    }
+
+    /// Tear down the dm-crypt device, then the device beneath it.
+    pub fn teardown(self, dm: &DM) -> EngineResult<()> {
+        dm.device_remove(&DevId::Name(&self.name), DmFlags::empty())?;
+        self.raw_dev.teardown(dm)?;
+        Ok(())
+    }
+}
+
+/// The table of a dm-crypt device over raw_dev, with a key of key_size
+/// bytes found in the kernel keyring by key.
+fn crypt_table(raw_dev: &LinearDev, key: &KeyDescription, key_size: usize) -> Vec<TargetLine> {
+    let params = format!("{} :{}:{}:{} 0 {} 0",
+                         CIPHER,
+                         key_size,
+                         KEY_TYPE,
+                         key.as_str(),
+                         raw_dev.device());
+    vec![TargetLine {
+             start: Sectors(0),
+             length: raw_dev.size(),
+             target_type: TargetTypeBuf::new("crypt".into()).expect("valid target type"),
+             params: params,
+         }]
+}
+
+/// Load table into the device name, creating the device if it does not
+/// exist, and make the table live.
+/// Returns the device number of the device.
+fn load_table(dm: &DM, name: &DmName, table: &[TargetLine]) -> EngineResult<Device> {
+    let id = DevId::Name(name);
+    if device_exists(dm, name)? {
+        let dev_info = dm.table_load(&id, table)?;
+        dm.device_suspend(&id, DM_SUSPEND)?;
+        dm.device_suspend(&id, DmFlags::empty())?;
+        return Ok(dev_info.device());
+    }
+
+    dm.device_create(name, None, DmFlags::empty())?;
+    let dev_info = match dm.table_load(&id, table) {
+        Ok(dev_info) => dev_info,
+        Err(err) => {
+            dm.device_remove(&id, DmFlags::empty())?;
+            return Err(From::from(err));
+        }
+    };
+    dm.device_suspend(&id, DmFlags::empty())?;
+    Ok(dev_info.device())
+}
+
+/// The key description as a C string; it has no NUL bytes, since it has
+/// no control characters.
+fn c_description(key: &KeyDescription) -> CString {
+    CString::new(key.as_str()).expect("key description contains no NUL bytes")
+}
+
+/// Find key in the kernel keyring.
+/// Returns the serial number of the key, or a KeyNotFound error if it is
+/// not there.
+fn find_key(key: &KeyDescription) -> EngineResult<libc::c_long> {
+    let key_type = CString::new(KEY_TYPE).expect("no NUL bytes");
+    let description = c_description(key);
+    let serial = unsafe {
+        libc::syscall(libc::SYS_request_key,
+                      key_type.as_ptr(),
+                      description.as_ptr(),
+                      ptr::null::<libc::c_char>(),
+                      0)
+    };
+    if serial < 0 {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+                   Some(libc::ENOKEY) |
+                   Some(libc::EKEYEXPIRED) |
+                   Some(libc::EKEYREVOKED) => {
+                       let err_msg = format!("key {:?} is not in the kernel keyring: {}",
+                                             key.as_str(),
+                                             err);
+                       Err(EngineError::Engine(ErrorEnum::KeyNotFound, err_msg))
+                   }
+                   _ => Err(From::from(err)),
+               };
+    }
+    Ok(serial)
+}
+
+/// Read the data of key from the kernel keyring.
+/// Returns a KeyNotFound error if the key is not in the keyring.
+fn read_key(key: &KeyDescription) -> EngineResult<Vec<u8>> {
+    let serial = find_key(key)?;
+    let size = unsafe {
+        libc::syscall(libc::SYS_keyctl,
+                      KEYCTL_READ,
+                      serial,
+                      ptr::null_mut::<libc::c_char>(),
+                      0)
+    };
+    if size < 0 {
+        return Err(From::from(io::Error::last_os_error()));
+    }
+
+    let mut data = vec![0u8; size as usize];
+    let read = unsafe {
+        libc::syscall(libc::SYS_keyctl,
+                      KEYCTL_READ,
+                      serial,
+                      data.as_mut_ptr(),
+                      data.len())
+    };
+    if read < 0 {
+        return Err(From::from(io::Error::last_os_error()));
+    }
+    if read as usize != data.len() {
+        let err_msg = format!("key {:?} changed size while it was read", key.as_str());
+        return Err(EngineError::Engine(ErrorEnum::Error, err_msg));
+    }
+    Ok(data)
+}
+
+/// Check that key is in the kernel keyring, and is of a size the cipher
+/// accepts.
+/// Returns the size of the key in bytes.
+/// Returns a KeyNotFound error if the key is not in the keyring, or an
+/// Invalid error if it is of the wrong size.
+pub fn check_key(key: &KeyDescription) -> EngineResult<usize> {
+    let size = read_key(key)?.len();
+    if !KEY_SIZES.contains(&size) {
+        let err_msg = format!("key {:?} is {} bytes, but must be one of {:?}",
+                              key.as_str(),
+                              size,
+                              KEY_SIZES);
+        return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+    Ok(size)
+}
+
+/// The check value of key as the key of pool pool_uuid: the MAC, keyed
+/// with the key's data, of KEY_CHECK_MESSAGE and the pool's UUID, in hex.
+/// Returns a KeyNotFound error if the key is not in the keyring.
+pub fn key_check_value(key: &KeyDescription, pool_uuid: PoolUuid) -> EngineResult<String> {
+    let data = read_key(key)?;
+    let mut mac = Hmac::<Sha256>::new_from_slice(&data).expect("HMAC accepts keys of any size");
+    mac.update(KEY_CHECK_MESSAGE);
+    mac.update(pool_uuid.as_bytes());
+    Ok(mac.finalize()
+           .into_bytes()
+           .iter()
+           .map(|b| format!("{:02x}", b))
+           .collect())
+}
+
+/// Add data to the session keyring as the key described by key, replacing
+/// any key which has the same description.
+#[cfg(test)]
+pub fn add_key(key: &KeyDescription, data: &[u8]) -> EngineResult<()> {
+    let key_type = CString::new(KEY_TYPE).expect("no NUL bytes");
+    let description = c_description(key);
+    let serial = unsafe {
+        libc::syscall(libc::SYS_add_key,
+                      key_type.as_ptr(),
+                      description.as_ptr(),
+                      data.as_ptr(),
+                      data.len(),
+                      KEY_SPEC_SESSION_KEYRING)
+    };
+    if serial < 0 {
+        return Err(From::from(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+/// Remove the key described by key from the kernel keyring.
+#[cfg(test)]
+pub fn remove_key(key: &KeyDescription) -> EngineResult<()> {
+    let serial = find_key(key)?;
+    if unsafe { libc::syscall(libc::SYS_keyctl, KEYCTL_INVALIDATE, serial) } < 0 {
+        return Err(From::from(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    /// A key is found once it is added, and not once it is removed; only
+    /// keys of the sizes the cipher accepts are accepted.
+    fn key_lifecycle() {
+        let key = KeyDescription::new(&format!("stratis-test-{}", Uuid::new_v4())).unwrap();
+        assert!(match check_key(&key) {
+                    Err(EngineError::Engine(ErrorEnum::KeyNotFound, _)) => true,
+                    _ => false,
+                });
+
+        add_key(&key, &[0; 64]).unwrap();
+        assert_eq!(check_key(&key).unwrap(), 64);
+
+        add_key(&key, &[0; 20]).unwrap();
+        assert!(match check_key(&key) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        remove_key(&key).unwrap();
+        assert!(match check_key(&key) {
+                    Err(EngineError::Engine(ErrorEnum::KeyNotFound, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// The check value of a key depends on the key's data and the pool,
+    /// not on the key's description.
+    fn key_check_values() {
+        let key = KeyDescription::new(&format!("stratis-test-{}", Uuid::new_v4())).unwrap();
+        let other_key = KeyDescription::new(&format!("stratis-test-{}", Uuid::new_v4())).unwrap();
+        let pool_uuid = Uuid::new_v4();
+        assert!(match key_check_value(&key, pool_uuid) {
+                    Err(EngineError::Engine(ErrorEnum::KeyNotFound, _)) => true,
+                    _ => false,
+                });
+
+        add_key(&key, &[0; 64]).unwrap();
+        add_key(&other_key, &[0; 64]).unwrap();
+        let value = key_check_value(&key, pool_uuid).unwrap();
+        assert_eq!(value.len(), 64);
+        assert_eq!(key_check_value(&other_key, pool_uuid).unwrap(), value);
+        assert_ne!(key_check_value(&key, Uuid::new_v4()).unwrap(), value);
+
+        add_key(&key, &[1; 64]).unwrap();
+        assert_ne!(key_check_value(&key, pool_uuid).unwrap(), value);
+
+        // The check value is the HMAC-SHA256 of the message and the pool's
+        // UUID, as recorded in the metadata of existing pools.
+        add_key(&key, &[0; 64]).unwrap();
+        let pool_uuid = Uuid::parse_str("2f1f6f8a-37b5-4c3a-9d0e-5b7c0a3e9f41").unwrap();
+        assert_eq!(key_check_value(&key, pool_uuid).unwrap(),
+                   "5a164f10c9421ae313bdfcae61bc05ab41ce349b128bcb07fae4433052ff955e");
+
+        remove_key(&key).unwrap();
+        remove_key(&other_key).unwrap();
+    }
+}
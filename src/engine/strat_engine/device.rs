@@ -4,21 +4,47 @@
 
 // Functions for dealing with devices.
 
+use std::cmp::{max, min};
 use std::collections::HashMap;
+use std::env;
+use std::fs;
 use std::fs::File;
-use std::io::{BufWriter, ErrorKind, Seek, SeekFrom, Write};
+use std::io::{BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
 use std::fs::OpenOptions;
 use std::os::linux::fs::MetadataExt;
 use std::os::unix::prelude::AsRawFd;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
+use libc;
 use nix::sys::stat::{S_IFBLK, S_IFMT};
 
 use devicemapper::{Bytes, Device, IEC, SECTOR_SIZE, Sectors};
 
 use super::super::errors::{EngineResult, EngineError, ErrorEnum};
 
+use super::util::signature_offsets;
+
+/// The number of sectors at each end of a device in which partition tables
+/// and the superblocks of filesystems, volume managers, and RAID are
+/// usually found.
+pub const SIGNATURE_AREA_SECTORS: Sectors = Sectors(2048); // 1 MiB
+
+/// The number of sectors, aligned to their own size, zeroed around any
+/// other signature found.
+const SIGNATURE_BLOCK_SECTORS: u64 = 8; // 4 KiB
+
+/// The directory of links to block devices by their stable identifiers,
+/// which udev maintains.
+const DISK_BY_ID_PATH: &str = "/dev/disk/by-id";
+
+/// An environment variable which, if set, overrides DISK_BY_ID_PATH.
+pub const DISK_BY_ID_ENV: &str = "STRATIS_DISK_BY_ID_PATH";
+
 ioctl!(read blkgetsize64 with 0x12, 114; u64);
+ioctl!(none blkflsbuf with 0x12, 97);
+ioctl!(bad write_ptr blkroset with io!(0x12, 93); libc::c_int);
+#[cfg(test)]
+ioctl!(bad read blkroget with io!(0x12, 94); libc::c_int);
 
 pub fn blkdev_size(file: &File) -> EngineResult<Bytes> {
     let mut val: u64 = 0;
@@ -29,6 +55,158 @@ pub fn blkdev_size(file: &File) -> EngineResult<Bytes> {
     }
 }
 
+/// Make the block device at path read-only, or writable again. The kernel
+/// refuses writes to a read-only block device, and the device can not be
+/// opened for writing.
+pub fn set_blkdev_read_only(path: &Path, read_only: bool) -> EngineResult<()> {
+    let f = File::open(path)?;
+    let flag = read_only as libc::c_int;
+    unsafe { blkroset(f.as_raw_fd(), &flag) }?;
+    Ok(())
+}
+
+/// Whether the block device at path is read-only.
+#[cfg(test)]
+pub fn blkdev_read_only(path: &Path) -> EngineResult<bool> {
+    let f = File::open(path)?;
+    let mut flag: libc::c_int = 0;
+    unsafe { blkroget(f.as_raw_fd(), &mut flag) }?;
+    Ok(flag != 0)
+}
+
+/// The path of the attribute attr of the request queue of the block device
+/// device, in sysfs.
+fn queue_attr(device: Device, attr: &str) -> PathBuf {
+    PathBuf::from(format!("/sys/dev/block/{}/queue/{}", device, attr))
+}
+
+/// Read the attribute attr of the request queue of the block device
+/// device, which is a number.
+fn read_queue_attr(device: Device, attr: &str) -> EngineResult<u64> {
+    let path = queue_attr(device, attr);
+    let mut value = String::new();
+    File::open(&path)?.read_to_string(&mut value)?;
+    value
+        .trim()
+        .parse()
+        .map_err(|_| {
+                     let err_msg = format!("{} is not a number: {:?}", path.display(), value);
+                     EngineError::Engine(ErrorEnum::Invalid, err_msg)
+                 })
+}
+
+/// Read the sysfs attribute at path, trimmed of whitespace. None if there
+/// is no such attribute, or if it is empty.
+fn read_sysfs_attr(path: &Path) -> EngineResult<Option<String>> {
+    let mut value = String::new();
+    match File::open(path) {
+        Ok(mut f) => f.read_to_string(&mut value)?,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let value = value.trim();
+    Ok(if value.is_empty() {
+           None
+       } else {
+           Some(value.to_owned())
+       })
+}
+
+/// The model and serial number of the block device device, as reported in
+/// sysfs by the hardware beneath it. A partition is identified by the disk
+/// which contains it. Neither is found for a device which has no hardware
+/// beneath it, e.g., a loop device.
+pub fn blkdev_identity(device: Device) -> EngineResult<(Option<String>, Option<String>)> {
+    let mut disk_dir = fs::canonicalize(format!("/sys/dev/block/{}", device))?;
+    if disk_dir.join("partition").exists() {
+        disk_dir.pop();
+    }
+    let hw_dir = disk_dir.join("device");
+    let model = read_sysfs_attr(&hw_dir.join("model"))?;
+    let serial = match read_sysfs_attr(&hw_dir.join("serial"))? {
+        Some(serial) => Some(serial),
+        None => read_sysfs_attr(&disk_dir.join("serial"))?,
+    };
+    Ok((model, serial))
+}
+
+/// The directory of links to block devices by their stable identifiers.
+fn disk_by_id_dir() -> PathBuf {
+    env::var_os(DISK_BY_ID_ENV).map_or_else(|| PathBuf::from(DISK_BY_ID_PATH), PathBuf::from)
+}
+
+/// Whether a link to a device named name is a better identifier for the
+/// device than a link named other. A WWN, which names the device itself
+/// rather than the path to it, is best; otherwise, the first name in order.
+fn better_stable_id(name: &str, other: &str) -> bool {
+    match (name.starts_with("wwn-"), other.starts_with("wwn-")) {
+        (true, false) => true,
+        (false, true) => false,
+        _ => name < other,
+    }
+}
+
+/// A stable identifier for the block device device, which does not change
+/// across reboots as its device node may: the name of its link in
+/// /dev/disk/by-id, preferring a WWN. None if the device has no such link,
+/// e.g., a loop device.
+pub fn blkdev_stable_id(device: Device) -> EngineResult<Option<String>> {
+    let entries = match fs::read_dir(disk_by_id_dir()) {
+        Ok(entries) => entries,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut stable_id: Option<String> = None;
+    for entry in entries {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if devnode_to_devno(&entry.path())? != Some(device.into()) {
+            continue;
+        }
+        let better = match stable_id {
+            Some(ref current) => better_stable_id(&name, current),
+            None => true,
+        };
+        if better {
+            stable_id = Some(name);
+        }
+    }
+    Ok(stable_id)
+}
+
+/// The block device whose stable identifier is stable_id, and its current
+/// device node. None if no device has the identifier.
+pub fn resolve_stable_id(stable_id: &str) -> EngineResult<Option<(Device, PathBuf)>> {
+    let devnode = match fs::canonicalize(disk_by_id_dir().join(stable_id)) {
+        Ok(devnode) => devnode,
+        Err(ref err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    Ok(devnode_to_devno(&devnode)?.map(|devno| (Device::from(devno), devnode)))
+}
+
+/// Let the block device device accept discards, as large as the device
+/// beneath it accepts, or refuse them, so that they are not passed down.
+pub fn set_blkdev_discards(device: Device, enable: bool) -> EngineResult<()> {
+    let max_bytes = if enable {
+        read_queue_attr(device, "discard_max_hw_bytes")?
+    } else {
+        0
+    };
+    let mut f = OpenOptions::new()
+        .write(true)
+        .open(queue_attr(device, "discard_max_bytes"))?;
+    write!(f, "{}", max_bytes)?;
+    Ok(())
+}
+
+/// The largest discard which the block device device accepts; none if it
+/// refuses them.
+#[cfg(test)]
+pub fn blkdev_discard_max(device: Device) -> EngineResult<Bytes> {
+    Ok(Bytes(read_queue_attr(device, "discard_max_bytes")?))
+}
+
 /// Write buf at offset length times.
 pub fn write_sectors<P: AsRef<Path>>(path: P,
                                      offset: Sectors,
@@ -38,7 +216,7 @@ pub fn write_sectors<P: AsRef<Path>>(path: P,
     let mut f = BufWriter::with_capacity(IEC::Mi as usize,
                                          OpenOptions::new().write(true).open(path)?);
 
-    f.seek(SeekFrom::Start(*offset))?;
+    f.seek(SeekFrom::Start(*offset.bytes()))?;
     for _ in 0..*length {
         f.write_all(buf)?;
     }
@@ -52,6 +230,111 @@ pub fn wipe_sectors<P: AsRef<Path>>(path: P, offset: Sectors, length: Sectors) -
     write_sectors(path, offset, length, &[0u8; SECTOR_SIZE])
 }
 
+/// Remove what any previous user of the device at path left in its first
+/// length sectors.
+/// If thorough, zero all length sectors. Otherwise, zero only the first and
+/// last SIGNATURE_AREA_SECTORS sectors, and the block containing any other
+/// signature which wipefs finds. This is enough that nothing recognizes the
+/// device's previous contents, and is much faster for a large device.
+/// Returns the number of sectors zeroed.
+pub fn wipe_device<P: AsRef<Path>>(path: P,
+                                   length: Sectors,
+                                   thorough: bool)
+                                   -> EngineResult<Sectors> {
+    let path = path.as_ref();
+    if thorough {
+        wipe_sectors(path, Sectors(0), length)?;
+        return Ok(length);
+    }
+
+    // The (offset, length) of each region to zero; regions may overlap.
+    let mut regions = vec![(Sectors(0), min(SIGNATURE_AREA_SECTORS, length))];
+    if length > SIGNATURE_AREA_SECTORS {
+        regions.push((length - SIGNATURE_AREA_SECTORS, SIGNATURE_AREA_SECTORS));
+    }
+    for offset in signature_offsets(path)? {
+        let start = Sectors(*offset.sectors() / SIGNATURE_BLOCK_SECTORS * SIGNATURE_BLOCK_SECTORS);
+        if start < length {
+            regions.push((start, min(Sectors(SIGNATURE_BLOCK_SECTORS), length - start)));
+        }
+    }
+    regions.sort();
+
+    let mut wiped = Sectors(0);
+    let mut next = Sectors(0);
+    for (offset, len) in regions {
+        let start = max(offset, next);
+        let end = offset + len;
+        if end > start {
+            wipe_sectors(path, start, end - start)?;
+            wiped += end - start;
+            next = end;
+        }
+    }
+    Ok(wiped)
+}
+
+/// Copy length sectors at src_offset on the device src to dst_offset on
+/// the device dst. Any of src's buffers are flushed before reading, as src
+/// may have been written through a devicemapper device stacked on it.
+pub fn copy_sectors(src: &Path,
+                    src_offset: Sectors,
+                    dst: &Path,
+                    dst_offset: Sectors,
+                    length: Sectors)
+                    -> EngineResult<()> {
+    let mut src_f = File::open(src)?;
+    if let Err(err) = unsafe { blkflsbuf(src_f.as_raw_fd()) } {
+        return Err(EngineError::Nix(err));
+    }
+    let mut dst_f = OpenOptions::new().write(true).open(dst)?;
+
+    src_f.seek(SeekFrom::Start(*src_offset.bytes()))?;
+    dst_f.seek(SeekFrom::Start(*dst_offset.bytes()))?;
+
+    let mut buf = vec![0u8; IEC::Mi as usize];
+    let mut remaining = *length.bytes();
+    while remaining > 0 {
+        let chunk = ::std::cmp::min(remaining, buf.len() as u64) as usize;
+        src_f.read_exact(&mut buf[..chunk])?;
+        dst_f.write_all(&buf[..chunk])?;
+        remaining -= chunk as u64;
+    }
+
+    dst_f.sync_all()?;
+    Ok(())
+}
+
+/// Copy the first length sectors of the device src to the device dst,
+/// which must read as zeros where it has not been written, as a new thin
+/// device does. Chunks of src which are all zeros are not written, so that
+/// no space is allocated for them on dst.
+/// Returns the number of sectors written.
+pub fn copy_device_sparse(src: &Path, dst: &Path, length: Sectors) -> EngineResult<Sectors> {
+    let mut src_f = File::open(src)?;
+    if let Err(err) = unsafe { blkflsbuf(src_f.as_raw_fd()) } {
+        return Err(EngineError::Nix(err));
+    }
+    let mut dst_f = OpenOptions::new().write(true).open(dst)?;
+
+    let mut buf = vec![0u8; IEC::Mi as usize];
+    let mut written = Bytes(0);
+    let mut offset = 0;
+    while offset < *length.bytes() {
+        let chunk = min(*length.bytes() - offset, buf.len() as u64) as usize;
+        src_f.read_exact(&mut buf[..chunk])?;
+        if buf[..chunk].iter().any(|b| *b != 0) {
+            dst_f.seek(SeekFrom::Start(offset))?;
+            dst_f.write_all(&buf[..chunk])?;
+            written += Bytes(chunk as u64);
+        }
+        offset += chunk as u64;
+    }
+
+    dst_f.sync_all()?;
+    Ok(written.sectors())
+}
+
 /// Get a device number from a device node.
 /// Return None if the device is not a block device; devicemapper is not
 /// interested in other sorts of devices.
@@ -76,14 +359,22 @@ pub fn devnode_to_devno(path: &Path) -> EngineResult<Option<u64>> {
 
 /// Resolve a list of Paths of some sort to a set of unique Devices.
 /// Return an IOError if there was a problem resolving any particular device.
-/// The set of devices maps each device to one of the paths passed.
+/// The set of devices maps each device to the path passed for it.
 /// Returns an error if any path does not correspond to a block device.
+/// Returns an Invalid error if two paths correspond to the same device,
+/// whether they are the same path, or, e.g., a device node and a symbolic
+/// link to it, since a device must not be claimed twice.
 pub fn resolve_devices<'a>(paths: &'a [&Path]) -> EngineResult<HashMap<Device, &'a Path>> {
     let mut map = HashMap::new();
     for path in paths {
         match devnode_to_devno(path)? {
             Some(devno) => {
-                let _ = map.insert(Device::from(devno), *path);
+                if let Some(other) = map.insert(Device::from(devno), *path) {
+                    let err_msg = format!("paths {} and {} refer to the same device",
+                                          other.display(),
+                                          path.display());
+                    return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+                }
             }
             None => {
                 let err_msg = format!("path {} does not refer to a block device", path.display());
@@ -93,3 +384,75 @@ pub fn resolve_devices<'a>(paths: &'a [&Path]) -> EngineResult<HashMap<Device, &
     }
     Ok(map)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::super::tests::{loopbacked, real};
+    use super::super::util::signature_offsets;
+
+    use super::*;
+
+    #[test]
+    /// A WWN is preferred to any other identifier; otherwise, the first
+    /// identifier in order is.
+    fn stable_id_preference() {
+        assert!(better_stable_id("wwn-0x5000c500a1b2c3d4", "ata-DISK_123"));
+        assert!(!better_stable_id("ata-DISK_123", "wwn-0x5000c500a1b2c3d4"));
+        assert!(better_stable_id("ata-DISK_123", "scsi-DISK_123"));
+        assert!(!better_stable_id("scsi-DISK_123", "ata-DISK_123"));
+    }
+
+    /// Whether the sector at offset on the device at path is all zeros.
+    fn is_zeroed(path: &Path, offset: Sectors) -> bool {
+        let mut f = File::open(path).unwrap();
+        let mut buf = [1u8; SECTOR_SIZE];
+        f.seek(SeekFrom::Start(*offset.bytes())).unwrap();
+        f.read_exact(&mut buf).unwrap();
+        buf.iter().all(|b| *b == 0)
+    }
+
+    /// Verify that a fast wipe zeroes much less than a thorough wipe, but
+    /// still zeroes both ends of the device, where signatures are found.
+    fn test_wipe_device(paths: &[&Path]) {
+        let path = paths[0];
+        let size = blkdev_size(&File::open(path).unwrap())
+            .unwrap()
+            .sectors();
+        let middle = Sectors(*size / 2);
+        let last = size - Sectors(1);
+
+        // Make the device look like it has an ext2 filesystem, whose
+        // signature is in its second KiB, and scribble over the other
+        // places checked.
+        let mut superblock = [0u8; SECTOR_SIZE];
+        superblock[0x38] = 0x53;
+        superblock[0x39] = 0xef;
+        write_sectors(path, Sectors(2), Sectors(1), &superblock).unwrap();
+        for offset in &[middle, last] {
+            write_sectors(path, *offset, Sectors(1), &[1u8; SECTOR_SIZE]).unwrap();
+        }
+        assert!(!signature_offsets(path).unwrap().is_empty());
+
+        let fast = wipe_device(path, size, false).unwrap();
+        assert_eq!(fast, SIGNATURE_AREA_SECTORS + SIGNATURE_AREA_SECTORS);
+        assert!(signature_offsets(path).unwrap().is_empty());
+        assert!(is_zeroed(path, Sectors(2)));
+        assert!(is_zeroed(path, last));
+        assert!(!is_zeroed(path, middle));
+
+        let thorough = wipe_device(path, size, true).unwrap();
+        assert_eq!(thorough, size);
+        assert!(fast < thorough);
+        assert!(is_zeroed(path, middle));
+    }
+
+    #[test]
+    pub fn loop_test_wipe_device() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Exactly(1), test_wipe_device);
+    }
+
+    #[test]
+    pub fn real_test_wipe_device() {
+        real::test_with_spec(real::DeviceLimits::Exactly(1), test_wipe_device);
+    }
+}
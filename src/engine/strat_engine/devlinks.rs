@@ -0,0 +1,184 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Links to the device nodes of filesystems, by pool and filesystem name.
+//
+// Each pool has a directory, named for the pool, in the Stratis /dev
+// directory, and each active filesystem has a symbolic link, named for the
+// filesystem, in its pool's directory. The links follow the pools and
+// filesystems through renames. The MDV mount points, which are also in the
+// Stratis /dev directory, are hidden and named for the pool's UUID, and so
+// are unaffected.
+//
+// The links are a convenience. A failure to maintain them is logged, but
+// does not cause the operation which changed them to fail.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use super::super::engine::StratisPaths;
+use super::super::errors::EngineResult;
+
+// Each function takes the paths of the Stratis /dev directory in which the
+// links are kept.
+
+/// Create the directory of pool pool_name, replacing any links already in
+/// it with links to the device nodes of its active filesystems, given as
+/// (name, device node) pairs.
+pub fn setup_pool_devlinks(paths: &StratisPaths,
+                           pool_name: &str,
+                           filesystems: &[(String, PathBuf)]) {
+    if let Err(err) = remove_dir(&paths.pool_dir(pool_name)) {
+        warn!("Could not remove stale links of pool {}: {}", pool_name, err);
+    }
+    for fs in filesystems {
+        filesystem_added(paths, pool_name, &fs.0, &fs.1);
+    }
+}
+
+/// Remove the directory of pool pool_name, and all the links in it.
+pub fn pool_removed(paths: &StratisPaths, pool_name: &str) {
+    if let Err(err) = remove_dir(&paths.pool_dir(pool_name)) {
+        warn!("Could not remove links of pool {}: {}", pool_name, err);
+    }
+}
+
+/// Move the links of the active filesystems of a pool which was renamed
+/// from old_name to new_name.
+pub fn pool_renamed(paths: &StratisPaths,
+                    old_name: &str,
+                    new_name: &str,
+                    filesystems: &[(String, PathBuf)]) {
+    pool_removed(paths, old_name);
+    setup_pool_devlinks(paths, new_name, filesystems);
+}
+
+/// Add, or replace, the link to devnode for filesystem fs_name of pool
+/// pool_name.
+pub fn filesystem_added(paths: &StratisPaths, pool_name: &str, fs_name: &str, devnode: &Path) {
+    if let Err(err) = make_link(&paths.filesystem_devnode(pool_name, fs_name), devnode) {
+        warn!("Could not link {} for filesystem {} of pool {}: {}",
+              devnode.display(),
+              fs_name,
+              pool_name,
+              err);
+    }
+}
+
+/// Remove the link for filesystem fs_name of pool pool_name.
+pub fn filesystem_removed(paths: &StratisPaths, pool_name: &str, fs_name: &str) {
+    if let Err(err) = remove_link(&paths.filesystem_devnode(pool_name, fs_name)) {
+        warn!("Could not remove link for filesystem {} of pool {}: {}",
+              fs_name,
+              pool_name,
+              err);
+    }
+}
+
+/// Move the link for a filesystem which was renamed from old_name to
+/// new_name. The link of the new name is made before the link of the old
+/// name is removed, so that a mounted filesystem, which may have been
+/// mounted by either, can always be found by one of them.
+pub fn filesystem_renamed(paths: &StratisPaths,
+                          pool_name: &str,
+                          old_name: &str,
+                          new_name: &str,
+                          devnode: &Path) {
+    filesystem_added(paths, pool_name, new_name, devnode);
+    filesystem_removed(paths, pool_name, old_name);
+}
+
+/// Make a link at link to target, creating the directory which contains
+/// it if necessary. A link already at link is replaced atomically, by
+/// renaming a new link over it, so that there is always a link at link.
+fn make_link(link: &Path, target: &Path) -> EngineResult<()> {
+    let dir = link.parent().unwrap_or_else(|| Path::new("/"));
+    fs::create_dir_all(dir)?;
+    let tmp_link = dir.join(format!(".{}.new",
+                                    link.file_name()
+                                        .map_or_else(Default::default,
+                                                     |name| name.to_string_lossy())));
+    remove_link(&tmp_link)?;
+    symlink(target, &tmp_link)?;
+    if let Err(err) = fs::rename(&tmp_link, link) {
+        let _ = remove_link(&tmp_link);
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+/// Remove the link at link, if there is one.
+fn remove_link(link: &Path) -> EngineResult<()> {
+    match fs::remove_file(link) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+/// Remove the directory dir and its contents, if it exists.
+fn remove_dir(dir: &Path) -> EngineResult<()> {
+    match fs::remove_dir_all(dir) {
+        Err(ref err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        result => Ok(result?),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::super::tests::tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    /// Links are made, moved when their pool or filesystem is renamed, and
+    /// removed; each link resolves to its filesystem's device node.
+    fn links_follow_names() {
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let paths = StratisPaths::new(tmp_dir.path());
+
+        let devnode = tmp_dir.path().join("dm-7");
+        fs::File::create(&devnode).unwrap();
+        let filesystems = [("fs".to_owned(), devnode.clone())];
+
+        setup_pool_devlinks(&paths, "pool", &filesystems);
+        assert_eq!(fs::canonicalize(paths.filesystem_devnode("pool", "fs")).unwrap(),
+                   fs::canonicalize(&devnode).unwrap());
+
+        pool_renamed(&paths, "pool", "renamed", &filesystems);
+        assert!(!paths.pool_dir("pool").exists());
+        assert_eq!(fs::read_link(paths.filesystem_devnode("renamed", "fs")).unwrap(),
+                   devnode);
+
+        filesystem_renamed(&paths, "renamed", "fs", "fs2", &devnode);
+        assert!(fs::symlink_metadata(paths.filesystem_devnode("renamed", "fs")).is_err());
+        assert_eq!(fs::read_link(paths.filesystem_devnode("renamed", "fs2")).unwrap(),
+                   devnode);
+
+        filesystem_removed(&paths, "renamed", "fs2");
+        assert!(fs::symlink_metadata(paths.filesystem_devnode("renamed", "fs2")).is_err());
+
+        pool_removed(&paths, "renamed");
+        assert!(!paths.pool_dir("renamed").exists());
+        assert!(devnode.exists());
+    }
+
+    #[test]
+    /// A link which already exists is replaced by a link to the new
+    /// target, and the temporary link used to replace it is not left
+    /// behind.
+    fn make_link_replaces() {
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let link = tmp_dir.path().join("dir").join("link");
+
+        make_link(&link, Path::new("/dev/dm-1")).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("/dev/dm-1"));
+        make_link(&link, Path::new("/dev/dm-2")).unwrap();
+        assert_eq!(fs::read_link(&link).unwrap(), Path::new("/dev/dm-2"));
+        assert_eq!(fs::read_dir(tmp_dir.path().join("dir")).unwrap().count(), 1);
+    }
+}
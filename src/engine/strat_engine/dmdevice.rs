@@ -6,8 +6,14 @@
 
 use std::fmt;
 use std::fmt::Display;
+use std::io;
+use std::ops::Deref;
+use std::thread;
+use std::time::Duration;
 
-use devicemapper::{DmNameBuf, ThinDevId};
+use nix::Errno;
+
+use devicemapper::{DM, DmError, DmNameBuf, DmResult, ThinDevId};
 
 use super::super::errors::EngineResult;
 
@@ -15,10 +21,23 @@ use super::super::super::engine::{FilesystemUuid, PoolUuid};
 
 const FORMAT_VERSION: u16 = 1;
 
+/// The maximum number of times a device-mapper operation which fails
+/// transiently is attempted.
+const DM_RETRY_ATTEMPTS: u32 = 4;
+
+/// The delay before the first retry of a device-mapper operation, in
+/// milliseconds. The delay is doubled before each subsequent retry.
+const DM_RETRY_BASE_DELAY_MS: u64 = 10;
+
 #[derive(Clone, Copy)]
 pub enum FlexRole {
     MetadataVolume,
     ThinData,
+    /// The dm-crypt device of an encrypted pool's data.
+    ThinDataCrypt,
+    /// The device which maps the segments of an encrypted pool's data,
+    /// beneath its dm-crypt device.
+    ThinDataRaw,
     ThinMeta,
     ThinMetaSpare,
 }
@@ -28,6 +47,8 @@ impl Display for FlexRole {
         match *self {
             FlexRole::MetadataVolume => write!(f, "mdv"),
             FlexRole::ThinData => write!(f, "thindata"),
+            FlexRole::ThinDataCrypt => write!(f, "thindatacrypt"),
+            FlexRole::ThinDataRaw => write!(f, "thindataraw"),
             FlexRole::ThinMeta => write!(f, "thinmeta"),
             FlexRole::ThinMetaSpare => write!(f, "thinmetaspare"),
         }
@@ -116,3 +137,139 @@ impl ThinDevIdPool {
         Ok(next_id)
     }
 }
+
+/// A handle to devicemapper, which an engine opens once and shares with its
+/// pools, rather than each operation opening a handle of its own.
+pub struct SharedDM(DM);
+
+impl SharedDM {
+    pub fn new() -> EngineResult<SharedDM> {
+        Ok(SharedDM(DM::new()?))
+    }
+}
+
+impl fmt::Debug for SharedDM {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SharedDM")
+    }
+}
+
+impl Deref for SharedDM {
+    type Target = DM;
+
+    fn deref(&self) -> &DM {
+        &self.0
+    }
+}
+
+/// Retry a device-mapper operation while it fails with an errno that may be
+/// only transient, e.g., EBUSY on a busy system. Returns the result of the
+/// last attempt.
+pub fn retry_dm<T, F>(f: F) -> DmResult<T>
+    where F: FnMut() -> DmResult<T>
+{
+    retry(f, dm_errno)
+}
+
+/// Obtain the errno with which a device-mapper ioctl failed, if any.
+fn dm_errno(err: &DmError) -> Option<i32> {
+    match *err {
+        // The cause of a failed ioctl is the OS error, which is the next
+        // error in the error chain.
+        DmError::Core(ref err) => {
+            (err.1)
+                .next_error
+                .as_ref()
+                .and_then(|cause| cause.downcast_ref::<io::Error>())
+                .and_then(|cause| cause.raw_os_error())
+        }
+        DmError::Dm(..) => None,
+    }
+}
+
+/// Call f until it succeeds, fails with an error for which errno does not
+/// return a retryable value, or DM_RETRY_ATTEMPTS attempts have been made,
+/// backing off exponentially between attempts.
+fn retry<T, E, F, G>(mut f: F, errno: G) -> Result<T, E>
+    where F: FnMut() -> Result<T, E>,
+          G: Fn(&E) -> Option<i32>
+{
+    let mut delay = DM_RETRY_BASE_DELAY_MS;
+    let mut attempt = 1;
+    loop {
+        match f() {
+            Err(ref err) if attempt < DM_RETRY_ATTEMPTS &&
+                            errno(err).map_or(false, is_transient) => {}
+            result => return result,
+        }
+        warn!("device-mapper operation failed on attempt {}, retrying in {} ms",
+              attempt,
+              delay);
+        thread::sleep(Duration::from_millis(delay));
+        delay *= 2;
+        attempt += 1;
+    }
+}
+
+/// Whether a failure with this errno may succeed if retried.
+fn is_transient(errno: i32) -> bool {
+    match Errno::from_i32(errno) {
+        Errno::EBUSY | Errno::EAGAIN => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+
+    use nix::Errno;
+
+    use super::*;
+
+    #[test]
+    /// An operation which fails transiently twice succeeds on the third
+    /// attempt.
+    fn retry_transient() {
+        let mut attempts = 0;
+        let result = retry(|| {
+                               attempts += 1;
+                               if attempts < 3 {
+                                   Err(io::Error::from_raw_os_error(Errno::EBUSY as i32))
+                               } else {
+                                   Ok(attempts)
+                               }
+                           },
+                           |err: &io::Error| err.raw_os_error());
+        assert_eq!(result.unwrap(), 3);
+    }
+
+    #[test]
+    /// An operation which fails with a non-transient error is not retried.
+    fn retry_permanent() {
+        let mut attempts = 0;
+        let result: Result<(), io::Error> =
+            retry(|| {
+                      attempts += 1;
+                      Err(io::Error::from_raw_os_error(Errno::EINVAL as i32))
+                  },
+                  |err: &io::Error| err.raw_os_error());
+        assert!(result.is_err());
+        assert_eq!(attempts, 1);
+    }
+
+    #[test]
+    /// An operation which always fails transiently is attempted only a
+    /// bounded number of times.
+    fn retry_bounded() {
+        let mut attempts = 0;
+        let result: Result<(), io::Error> =
+            retry(|| {
+                      attempts += 1;
+                      Err(io::Error::from_raw_os_error(Errno::EBUSY as i32))
+                  },
+                  |err: &io::Error| err.raw_os_error());
+        assert!(result.is_err());
+        assert_eq!(attempts, DM_RETRY_ATTEMPTS);
+    }
+}
@@ -0,0 +1,179 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Parsing of the status lines reported by devicemapper targets.
+//
+// The thin-pool status line is documented in the kernel's
+// Documentation/device-mapper/thin-provisioning.txt. It is:
+//
+// <transaction id> <used metadata blocks>/<total metadata blocks>
+// <used data blocks>/<total data blocks> <held metadata root>
+// ro|rw|out_of_data_space [no_]discard_passdown [error|queue]_if_no_space
+// needs_check|- [metadata_low_watermark]
+//
+// or just "Fail" if the pool has failed.
+
+use devicemapper::{DataBlocks, MetaBlocks};
+
+use super::super::errors::{EngineError, EngineResult, ErrorEnum};
+
+/// The health of a working thin pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ThinPoolHealth {
+    /// The pool is working normally.
+    Good,
+    /// The pool has been forced into read-only mode.
+    ReadOnly,
+    /// The pool has run out of data space.
+    OutOfDataSpace,
+    /// The pool's metadata must be checked, e.g., with thin_check.
+    NeedsCheck,
+}
+
+/// The status of a working thin pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ThinPoolStatus {
+    pub transaction_id: u64,
+    pub used_meta: MetaBlocks,
+    pub total_meta: MetaBlocks,
+    pub used_data: DataBlocks,
+    pub total_data: DataBlocks,
+    pub health: ThinPoolHealth,
+}
+
+/// Parse the status line of a thin-pool target.
+/// Returns None if the status line reports that the pool has failed.
+/// Returns an error if the status line can not be parsed.
+pub fn parse_thinpool_status(status_line: &str) -> EngineResult<Option<ThinPoolStatus>> {
+    let parse_err = || {
+        EngineError::Engine(ErrorEnum::Error,
+                            format!("could not parse thin pool status {:?}", status_line))
+    };
+
+    let status_vals = status_line.split_whitespace().collect::<Vec<_>>();
+    if status_vals.first() == Some(&"Fail") {
+        return Ok(None);
+    }
+    if status_vals.len() < 8 {
+        return Err(parse_err());
+    }
+
+    // Parse a "used/total" pair of block counts.
+    let parse_usage = |usage: &str| -> EngineResult<(u64, u64)> {
+        let vals = usage.split('/').collect::<Vec<_>>();
+        if vals.len() != 2 {
+            return Err(parse_err());
+        }
+        Ok((vals[0].parse::<u64>().map_err(|_| parse_err())?,
+            vals[1].parse::<u64>().map_err(|_| parse_err())?))
+    };
+
+    let transaction_id = status_vals[0]
+        .parse::<u64>()
+        .map_err(|_| parse_err())?;
+    let (used_meta, total_meta) = parse_usage(status_vals[1])?;
+    let (used_data, total_data) = parse_usage(status_vals[2])?;
+
+    let mode_health = match status_vals[4] {
+        "rw" => ThinPoolHealth::Good,
+        "ro" => ThinPoolHealth::ReadOnly,
+        "out_of_data_space" => ThinPoolHealth::OutOfDataSpace,
+        _ => return Err(parse_err()),
+    };
+
+    // A pool whose metadata needs checking is not healthy, whatever its
+    // mode.
+    let health = match status_vals[7] {
+        "-" => mode_health,
+        "needs_check" => ThinPoolHealth::NeedsCheck,
+        _ => return Err(parse_err()),
+    };
+
+    Ok(Some(ThinPoolStatus {
+                transaction_id: transaction_id,
+                used_meta: MetaBlocks(used_meta),
+                total_meta: MetaBlocks(total_meta),
+                used_data: DataBlocks(used_data),
+                total_data: DataBlocks(total_data),
+                health: health,
+            }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    /// Verify that the fields of a working pool's status are parsed.
+    fn parse_good() {
+        let status = parse_thinpool_status("0 10/4096 20/32768 - rw discard_passdown \
+                                            queue_if_no_space - ")
+                .unwrap()
+                .unwrap();
+        assert_eq!(status,
+                   ThinPoolStatus {
+                       transaction_id: 0,
+                       used_meta: MetaBlocks(10),
+                       total_meta: MetaBlocks(4096),
+                       used_data: DataBlocks(20),
+                       total_data: DataBlocks(32768),
+                       health: ThinPoolHealth::Good,
+                   });
+
+        // Newer kernels append the metadata low watermark.
+        let status = parse_thinpool_status("17 3585/4096 32700/32768 5 rw no_discard_passdown \
+                                            error_if_no_space - 1024")
+                .unwrap()
+                .unwrap();
+        assert_eq!(status.transaction_id, 17);
+        assert_eq!(status.used_meta, MetaBlocks(3585));
+        assert_eq!(status.used_data, DataBlocks(32700));
+        assert_eq!(status.health, ThinPoolHealth::Good);
+    }
+
+    #[test]
+    /// Verify that each mode and the needs_check flag are parsed, and that
+    /// needs_check takes precedence over the mode.
+    fn parse_health() {
+        let health = |line: &str| parse_thinpool_status(line).unwrap().unwrap().health;
+        assert_eq!(health("7 4096/4096 20/32768 - ro discard_passdown queue_if_no_space -"),
+                   ThinPoolHealth::ReadOnly);
+        assert_eq!(health("9 100/8192 32768/32768 - out_of_data_space discard_passdown \
+                           queue_if_no_space -"),
+                   ThinPoolHealth::OutOfDataSpace);
+        assert_eq!(health("3 100/8192 20/32768 - rw discard_passdown queue_if_no_space \
+                           needs_check"),
+                   ThinPoolHealth::NeedsCheck);
+        assert_eq!(health("3 100/8192 20/32768 - ro discard_passdown queue_if_no_space \
+                           needs_check"),
+                   ThinPoolHealth::NeedsCheck);
+    }
+
+    #[test]
+    /// Verify that a failed pool has no status.
+    fn parse_fail() {
+        assert_eq!(parse_thinpool_status("Fail").unwrap(), None);
+    }
+
+    #[test]
+    /// Verify that malformed status lines are errors.
+    fn parse_malformed() {
+        let bad = ["",
+                   "0 10/4096 20/32768 - rw",
+                   "x 10/4096 20/32768 - rw discard_passdown queue_if_no_space -",
+                   "0 10-4096 20/32768 - rw discard_passdown queue_if_no_space -",
+                   "0 10/4096 20/32768/1 - rw discard_passdown queue_if_no_space -",
+                   "0 10/4096 20/x - rw discard_passdown queue_if_no_space -",
+                   "0 10/4096 20/32768 - rx discard_passdown queue_if_no_space -",
+                   "0 10/4096 20/32768 - rw discard_passdown queue_if_no_space check"];
+        for line in &bad {
+            assert!(match parse_thinpool_status(line) {
+                        Err(EngineError::Engine(ErrorEnum::Error, _)) => true,
+                        _ => false,
+                    },
+                    "{:?}",
+                    line);
+        }
+    }
+}
@@ -2,61 +2,629 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::path::Path;
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::env;
+use std::ffi::CString;
+use std::fs::{self, DirBuilder, File, OpenOptions};
+use std::io::{self, ErrorKind};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{DirBuilderExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
 
-use devicemapper::DM;
+use libc;
+use nix;
+use nix::Errno;
+use nix::fcntl::{FlockArg, flock};
 
-use super::super::engine::{Engine, HasName, HasUuid, Pool};
+use devicemapper::{Device, Sectors};
+
+use super::super::engine::{Engine, HasName, HasUuid, Pool, StratisPaths, check_unclaimed,
+                           validate_name};
 use super::super::errors::{EngineError, EngineResult, ErrorEnum};
-use super::super::structures::Table;
-use super::super::types::{DevUuid, PoolUuid, Redundancy, RenameAction};
+use super::super::event::{EngineEvent, EventQueue};
+use super::super::structures::{SetName, Table};
+use super::super::types::{AvailableBlockDev, CheckReport, CreateAction, CreateForce,
+                          DestroyAction, DevUuid, DeviceMetadata, EngineLimits, FilesystemUuid,
+                          KeyDescription, PoolUuid, Redundancy, RenameAction};
 
-use super::cleanup::teardown_pools;
+use super::blockdevmgr::probe_blockdev;
+use super::cleanup::{TeardownReport, remove_thinpool_devs, teardown_pools,
+                     teardown_pools_detailed, teardown_pools_force};
+use super::device::resolve_devices;
+use super::dmdevice::SharedDM;
+use super::inflight::{InFlight, Resource};
+use super::oplog::{LogValue, logged};
 use super::pool::StratPool;
-use super::setup::find_all;
+use super::relabel::{relabel_device, relabel_pool};
+use super::serde_structs::{METADATA_VERSION, PoolSave};
+use super::setup::{RejectReason, discover_devices, find_all, get_metadata, match_devices,
+                   missing_blockdevs, read_device_metadata};
 
-#[derive(Debug, PartialEq, Eq)]
-pub enum DevOwnership {
-    Ours(PoolUuid, DevUuid),
-    Unowned,
-    Theirs,
+/// Why a pool whose devices were found could not be set up.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IncompleteReason {
+    /// More of the pool's blockdevs, which are listed, were missing than
+    /// its redundancy allows it to be set up without.
+    MissingDevices(Vec<DevUuid>),
+    /// Setting up the pool failed with the error given.
+    Error(String),
 }
 
 #[derive(Debug)]
 pub struct StratEngine {
     pools: Table<StratPool>,
+    // Pools whose devices were found but which could not be set up
+    incomplete_pools: HashMap<PoolUuid, IncompleteReason>,
+    // Encrypted pools which could not be set up because their keys were
+    // not in the kernel keyring
+    locked_pools: HashSet<PoolUuid>,
+    // Pools which failed their integrity probe or setup in safe mode, with
+    // the error; they are left alone until released
+    quarantined_pools: HashMap<PoolUuid, String>,
+    events: Rc<RefCell<EventQueue>>,
+    // The handle to devicemapper, shared by all the engine's pools
+    dm: Rc<SharedDM>,
+    // The pools and devices which operations in flight are changing
+    in_flight: InFlight,
+    limits: EngineLimits,
+    // Whether the periodic check of the pools is paused
+    check_paused: bool,
+    // The paths in the Stratis /dev directory, resolved once when the
+    // engine is set up
+    paths: StratisPaths,
+    // The open lock file; the lock is released when it is closed
+    _lock: File,
+}
+
+/// The default path of the file locked by a running engine.
+pub const LOCK_PATH: &str = "/run/stratisd.lock";
+
+/// The environment variable which, if set, overrides LOCK_PATH.
+pub const LOCK_PATH_ENV: &str = "STRATIS_LOCK_PATH";
+
+/// The path of the file locked by a running engine.
+pub fn lock_path() -> PathBuf {
+    env::var_os(LOCK_PATH_ENV).map_or_else(|| PathBuf::from(LOCK_PATH), PathBuf::from)
+}
+
+/// Take an exclusive lock on the file at path, creating the file if it
+/// does not exist. The lock is held until the returned file is closed.
+/// Returns a Busy error if the lock is already held, i.e., another engine
+/// is running.
+fn lock_engine(path: &Path) -> EngineResult<File> {
+    let file = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    match flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+        Ok(_) => Ok(file),
+        Err(nix::Error::Sys(Errno::EAGAIN)) => {
+            let err_msg = format!("another engine holds the lock on {}", path.display());
+            Err(EngineError::Engine(ErrorEnum::Busy, err_msg))
+        }
+        Err(err) => Err(err.into()),
+    }
+}
+
+/// The permissions of the Stratis /dev directory, if it must be created.
+const DEV_PATH_MODE: u32 = 0o755;
+
+/// Make sure that the Stratis /dev directory dir exists and is writable,
+/// creating it, and any missing parents, if it does not exist.
+/// Returns an Invalid error if dir exists but is not a directory or is not
+/// writable.
+fn setup_dev_path(dir: &Path) -> EngineResult<()> {
+    match fs::metadata(dir) {
+        Ok(metadata) => {
+            if !metadata.is_dir() {
+                let err_msg = format!("Stratis /dev directory {} is not a directory",
+                                      dir.display());
+                return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        Err(ref err) if err.kind() == ErrorKind::NotFound => {
+            DirBuilder::new()
+                .recursive(true)
+                .mode(DEV_PATH_MODE)
+                .create(dir)?;
+            // The mode given to DirBuilder is restricted by the umask.
+            fs::set_permissions(dir, fs::Permissions::from_mode(DEV_PATH_MODE))?;
+        }
+        Err(err) => return Err(From::from(err)),
+    }
+
+    let c_dir = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| {
+                     EngineError::Engine(ErrorEnum::Invalid,
+                                         format!("Stratis /dev directory {} is not a valid path",
+                                                 dir.display()))
+                 })?;
+    if unsafe { libc::access(c_dir.as_ptr(), libc::W_OK) } != 0 {
+        let err_msg = format!("Stratis /dev directory {} is not writable: {}",
+                              dir.display(),
+                              io::Error::last_os_error());
+        return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+
+    Ok(())
+}
+
+/// The blockdevs of pool pool_uuid which are not among devices, if more of
+/// them are missing than the pool's redundancy allows it to be set up
+/// without; otherwise, none.
+fn missing_devices(pool_uuid: PoolUuid,
+                   devices: &HashMap<Device, PathBuf>)
+                   -> EngineResult<Vec<DevUuid>> {
+    let metadata = match get_metadata(pool_uuid, devices)? {
+        Some(metadata) => metadata,
+        None => return Ok(vec![]),
+    };
+    let devices = match_devices(pool_uuid, &metadata, devices)?;
+    // Pools are set up without redundancy.
+    let missing = missing_blockdevs(pool_uuid, &metadata, &devices)?;
+    if missing.len() > Redundancy::NONE.max_missing_devices() {
+        Ok(missing)
+    } else {
+        Ok(vec![])
+    }
+}
+
+/// A lightweight check, which activates nothing, that pool pool_uuid can be
+/// set up from devices: that the static header and metadata area of each
+/// device can be read and belong to the pool, and that the pool's most
+/// recent metadata can be read and parsed.
+fn probe_pool(pool_uuid: PoolUuid, devices: &HashMap<Device, PathBuf>) -> EngineResult<()> {
+    for devnode in devices.values() {
+        match read_device_metadata(devnode)? {
+            Some(ref metadata) if metadata.pool_uuid == pool_uuid => {}
+            _ => {
+                let err_msg = format!("device {} has no Stratis header for pool {}",
+                                      devnode.display(),
+                                      pool_uuid);
+                return Err(EngineError::Engine(ErrorEnum::Corrupt, err_msg));
+            }
+        }
+    }
+    match get_metadata(pool_uuid, devices)? {
+        Some(_) => Ok(()),
+        None => {
+            Err(EngineError::Engine(ErrorEnum::NotFound,
+                                    format!("no metadata for pool {}", pool_uuid)))
+        }
+    }
+}
+
+/// The UUIDs of devs, separated by commas, for messages.
+fn format_dev_uuids(devs: &[DevUuid]) -> String {
+    devs.iter()
+        .map(|uuid| uuid.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// The first of name_1, name_2, ... which is not in use.
+fn unused_name<F>(name: &str, in_use: F) -> String
+    where F: Fn(&str) -> bool
+{
+    (1..)
+        .map(|n| format!("{}_{}", name, n))
+        .find(|name| !in_use(name))
+        .expect("only finitely many names are in use")
 }
 
 impl StratEngine {
     /// Setup a StratEngine.
-    /// 1. Verify the existance of Stratis /dev directory.
-    /// 2. Setup all the pools belonging to the engine.
+    /// 1. Lock the engine's lock file, so that no other engine can run.
+    /// 2. Create the Stratis /dev directory if it does not exist.
+    /// 3. Setup all the pools belonging to the engine.
+    ///
+    /// The lock is released when the engine is torn down or dropped.
+    ///
+    /// Returns a Busy error if another engine is running.
+    /// Returns an error if the Stratis /dev directory can not be created,
+    /// or exists but is not a writable directory.
+    /// Devices which can not be read are logged and skipped; see
+    /// DeviceDiscovery::into_pools().
+    /// Returns an error if there was an error setting up any of the pools,
+    /// unless the error was that the pool's MDV was corrupted. Such pools
+    /// are recorded as incomplete and are not set up, as are pools more of
+    /// whose blockdevs are missing than their redundancy allows them to be
+    /// set up without; the missing blockdevs are recorded.
+    /// If two pools have the same name, the newer is renamed by appending
+    /// a numeric suffix, e.g., "name_1", and its metadata is updated.
+    /// Returns an error if two pools have the same UUID.
+    /// If repair_mdv is true, attempt to repair any MDV which can not be
+    /// mounted.
+    /// No more pools or filesystems may be created than limits allows.
+    pub fn initialize(repair_mdv: bool, limits: EngineLimits) -> EngineResult<StratEngine> {
+        let discovery = discover_devices()?;
+        for rejected in &discovery.rejected {
+            match rejected.reason {
+                // Unreadable devices are logged by into_pools().
+                RejectReason::Unowned | RejectReason::Unreadable(_) => {}
+                ref reason => {
+                    info!("Device {} at {} belongs to no pool: {}",
+                          rejected.device,
+                          rejected.devnode.display(),
+                          reason)
+                }
+            }
+        }
+        StratEngine::setup_pools(&discovery.into_pools()?, repair_mdv, true, limits, false)
+    }
+
+    /// Setup a StratEngine in safe mode, in which no pool can prevent the
+    /// others from being set up.
+    /// Before each pool is set up, it is probed: the headers and metadata
+    /// of its blockdevs must be readable, and its metadata must parse.
+    /// Setting it up then checks its thin pool's metadata and mounts its
+    /// MDV. A pool which fails the probe or its setup is quarantined, with
+    /// the error, rather than causing setup of the engine to fail; see
+    /// quarantined_pools().
+    /// Devices which could not be examined are logged and ignored.
+    /// Otherwise, as initialize(), without attempting to repair any MDV.
+    pub fn initialize_safe(limits: EngineLimits) -> EngineResult<StratEngine> {
+        let discovery = discover_devices()?;
+        for rejected in &discovery.rejected {
+            match rejected.reason {
+                RejectReason::Unowned => {}
+                ref reason => {
+                    warn!("Device {} at {} belongs to no pool: {}",
+                          rejected.device,
+                          rejected.devnode.display(),
+                          reason)
+                }
+            }
+        }
+        StratEngine::setup_pools(&discovery.pools, false, true, limits, true)
+    }
+
+    /// Setup a StratEngine with only the pool designated by pool_uuid.
+    /// Devices belonging to other pools are left untouched.
+    ///
+    /// If activate_filesystems is false, the pool's filesystems are not
+    /// activated; see activate_filesystem().
     ///
-    /// Returns an error if there was an error reading device nodes.
-    /// Returns an error if there was an error setting up any of the pools.
-    pub fn initialize() -> EngineResult<StratEngine> {
-        let pools = find_all()?;
+    /// Returns a NotFound error if no devices belonging to the pool are found.
+    pub fn initialize_one(pool_uuid: PoolUuid,
+                          activate_filesystems: bool)
+                          -> EngineResult<StratEngine> {
+        let devices = find_all()?
+            .remove(&pool_uuid)
+            .ok_or_else(|| {
+                            EngineError::Engine(ErrorEnum::NotFound,
+                                                format!("no devices found for pool {}",
+                                                        pool_uuid))
+                        })?;
+
+        let mut pools = HashMap::new();
+        pools.insert(pool_uuid, devices);
+        StratEngine::setup_pools(&pools,
+                                 false,
+                                 activate_filesystems,
+                                 EngineLimits::default(),
+                                 false)
+    }
+
+    /// Setup a StratEngine with the given pools.
+    /// If safe is true, a pool which fails its probe or its setup is
+    /// quarantined instead of causing an error. Otherwise, a pool whose
+    /// blockdevs can not be matched against its metadata is recorded as
+    /// incomplete, and any other failure of its setup is an error.
+    fn setup_pools(pools: &HashMap<PoolUuid, HashMap<Device, PathBuf>>,
+                   repair_mdv: bool,
+                   activate_filesystems: bool,
+                   limits: EngineLimits,
+                   safe: bool)
+                   -> EngineResult<StratEngine> {
+        let lock = lock_engine(&lock_path())?;
+        let paths = StratisPaths::from_env();
+        setup_dev_path(paths.base())?;
+
+        let events = Rc::default();
+        let dm = Rc::new(SharedDM::new()?);
+        let mut setup = Vec::new();
+        let mut incomplete_pools = HashMap::new();
+        let mut locked_pools = HashSet::new();
+        let mut quarantined_pools = HashMap::new();
+        for (pool_uuid, devices) in pools {
+            let missing = if safe {
+                match probe_pool(*pool_uuid, devices)
+                          .and_then(|_| missing_devices(*pool_uuid, devices)) {
+                    Ok(missing) => missing,
+                    Err(err) => {
+                        warn!("Pool {} is quarantined: {}", pool_uuid, err);
+                        quarantined_pools.insert(*pool_uuid, err.to_string());
+                        continue;
+                    }
+                }
+            } else {
+                match missing_devices(*pool_uuid, devices) {
+                    Ok(missing) => missing,
+                    Err(err) => {
+                        warn!("Pool {} is incomplete: {}", pool_uuid, err);
+                        incomplete_pools.insert(*pool_uuid,
+                                                IncompleteReason::Error(err.to_string()));
+                        continue;
+                    }
+                }
+            };
+            if !missing.is_empty() {
+                warn!("Pool {} is incomplete: blockdevs {} are missing",
+                      pool_uuid,
+                      format_dev_uuids(&missing));
+                incomplete_pools.insert(*pool_uuid, IncompleteReason::MissingDevices(missing));
+                continue;
+            }
+
+            match StratPool::setup(*pool_uuid,
+                                   &dm,
+                                   &paths,
+                                   devices,
+                                   repair_mdv,
+                                   activate_filesystems) {
+                Ok(mut pool) => {
+                    pool.set_event_queue(&events);
+                    pool.set_max_filesystems(limits.max_filesystems_per_pool);
+                    setup.push(pool);
+                }
+                Err(EngineError::Engine(ErrorEnum::Corrupt, msg)) => {
+                    warn!("Pool {} is incomplete: {}", pool_uuid, msg);
+                    if let Err(cleanup_err) = remove_thinpool_devs(&dm, *pool_uuid) {
+                        warn!("Could not clean up after failed setup of pool {}: {}",
+                              pool_uuid,
+                              cleanup_err);
+                    }
+                    incomplete_pools.insert(*pool_uuid, IncompleteReason::Error(msg));
+                }
+                Err(EngineError::Engine(ErrorEnum::KeyNotFound, msg)) |
+                Err(EngineError::Engine(ErrorEnum::WrongKey, msg)) => {
+                    warn!("Pool {} is locked: {}", pool_uuid, msg);
+                    locked_pools.insert(*pool_uuid);
+                }
+                Err(err) => {
+                    if !safe {
+                        return Err(err);
+                    }
+                    warn!("Pool {} is quarantined: {}", pool_uuid, err);
+                    if let Err(cleanup_err) = remove_thinpool_devs(&dm, *pool_uuid) {
+                        warn!("Could not clean up after failed setup of pool {}: {}",
+                              pool_uuid,
+                              cleanup_err);
+                    }
+                    quarantined_pools.insert(*pool_uuid, err.to_string());
+                }
+            }
+        }
+
+        // The oldest of several pools with the same name keeps the name.
+        setup.sort_by_key(|pool| (pool.created(), pool.uuid()));
 
         let mut table = Table::default();
-        for (pool_uuid, devices) in &pools {
-            let evicted = table.insert(StratPool::setup(*pool_uuid, devices)?);
-            if !evicted.is_empty() {
+        while !setup.is_empty() {
+            let mut pool = setup.remove(0);
+            let result = if table.contains_uuid(pool.uuid()) {
+                let err_msg = format!("found two pools with the id {}", pool.uuid());
+                Err(EngineError::Engine(ErrorEnum::Invalid, err_msg))
+            } else if table.contains_name(pool.name()) {
+                let old_name = pool.name().to_owned();
+                let new_name = unused_name(&old_name, |name| {
+                    table.contains_name(name) || setup.iter().any(|p| p.name() == name)
+                });
+                warn!("Pool {} is named {}, which is already in use; renaming it to {}",
+                      pool.uuid(),
+                      old_name,
+                      new_name);
+                pool.set_name(&new_name);
+                pool.write_metadata()
+                    .map(|_| {
+                             events
+                                 .borrow_mut()
+                                 .push(EngineEvent::PoolRenamed(pool.uuid()))
+                         })
+            } else {
+                Ok(())
+            };
 
+            if let Err(err) = result {
                 // TODO: update state machine on failure.
-                let _ = teardown_pools(table.empty());
+                let mut all_pools = table.empty();
+                all_pools.push(pool);
+                all_pools.extend(setup);
+                let _ = teardown_pools(all_pools);
+                return Err(err);
+            }
+
+            table.insert(pool);
+        }
 
-                let err_msg = "found two pools with the same id or name";
-                return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+        Ok(StratEngine {
+               pools: table,
+               incomplete_pools: incomplete_pools,
+               locked_pools: locked_pools,
+               quarantined_pools: quarantined_pools,
+               events: events,
+               dm: dm,
+               in_flight: InFlight::default(),
+               limits: limits,
+               check_paused: false,
+               paths: paths,
+               _lock: lock,
+           })
+    }
+
+    /// Look for pool devices again, e.g., after devices have been
+    /// hot-plugged, and set up each pool which is not already set up and
+    /// all of whose devices are now present. Pools which are already set up
+    /// are not disturbed. A pool which still can not be set up is recorded
+    /// as incomplete, or as locked if its key is not in the kernel keyring.
+    /// A pool whose name is already in use is renamed, as when the engine is
+    /// initialized.
+    /// Returns the UUIDs of the pools which were set up.
+    pub fn rescan(&mut self) -> EngineResult<Vec<PoolUuid>> {
+        logged("rescan", &[], || {
+            let mut activated = Vec::new();
+            for (pool_uuid, devices) in find_all()? {
+                if self.pools.contains_uuid(pool_uuid) ||
+                   self.quarantined_pools.contains_key(&pool_uuid) {
+                    continue;
+                }
+
+                let reason = match missing_devices(pool_uuid, &devices) {
+                    Ok(ref missing) if missing.is_empty() => None,
+                    Ok(missing) => Some(IncompleteReason::MissingDevices(missing)),
+                    Err(err) => Some(IncompleteReason::Error(err.to_string())),
+                };
+                if let Some(reason) = reason {
+                    if self.incomplete_pools
+                           .insert(pool_uuid, reason.clone())
+                           .is_none() {
+                        warn!("Pool {} is incomplete: {:?}", pool_uuid, reason);
+                    }
+                    continue;
+                }
+
+                let pool = match StratPool::setup(pool_uuid,
+                                                  &self.dm,
+                                                  &self.paths,
+                                                  &devices,
+                                                  false,
+                                                  true) {
+                    Ok(pool) => pool,
+                    Err(EngineError::Engine(ErrorEnum::KeyNotFound, msg)) |
+                    Err(EngineError::Engine(ErrorEnum::WrongKey, msg)) => {
+                        if self.locked_pools.insert(pool_uuid) {
+                            warn!("Pool {} is locked: {}", pool_uuid, msg);
+                        }
+                        continue;
+                    }
+                    Err(err) => {
+                        if self.incomplete_pools
+                               .insert(pool_uuid, IncompleteReason::Error(err.to_string()))
+                               .is_none() {
+                            warn!("Pool {} is incomplete: {}", pool_uuid, err);
+                        }
+                        continue;
+                    }
+                };
+
+                if let Err(err) = self.add_pool(pool) {
+                    warn!("Could not rename pool {}: {}", pool_uuid, err);
+                    self.incomplete_pools
+                        .insert(pool_uuid, IncompleteReason::Error(err.to_string()));
+                    continue;
+                }
+                activated.push(pool_uuid);
+            }
+            Ok(activated)
+        })
+    }
+
+    /// Add a pool which has just been set up to the engine. If the pool's
+    /// name is already in use, it is renamed, as when the engine is
+    /// initialized; if it can not be renamed, it is torn down and the error
+    /// returned.
+    fn add_pool(&mut self, mut pool: StratPool) -> EngineResult<()> {
+        let pool_uuid = pool.uuid();
+        if self.pools.contains_name(pool.name()) {
+            let old_name = pool.name().to_owned();
+            let new_name = unused_name(&old_name, |name| self.pools.contains_name(name));
+            warn!("Pool {} is named {}, which is already in use; renaming it to {}",
+                  pool_uuid,
+                  old_name,
+                  new_name);
+            pool.set_name(&new_name);
+            if let Err(err) = pool.write_metadata() {
+                if let Err(teardown_err) = pool.teardown() {
+                    warn!("Could not tear down pool {}: {}", pool_uuid, teardown_err);
+                }
+                return Err(err);
             }
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::PoolRenamed(pool_uuid));
+        }
+
+        pool.set_event_queue(&self.events);
+        pool.set_max_filesystems(self.limits.max_filesystems_per_pool);
+        self.incomplete_pools.remove(&pool_uuid);
+        self.locked_pools.remove(&pool_uuid);
+        self.pools.insert(pool);
+        Ok(())
+    }
+
+    /// The pools which could not be set up, and why: because too many of
+    /// their blockdevs were missing, because their MDV appeared to be
+    /// corrupted, or, when found by a rescan, for any reason.
+    pub fn incomplete_pools(&self) -> &HashMap<PoolUuid, IncompleteReason> {
+        &self.incomplete_pools
+    }
+
+    /// The pools which failed their probe or their setup when the engine
+    /// was set up in safe mode, with the error. A quarantined pool is left
+    /// alone, even by a rescan, until it is released.
+    pub fn quarantined_pools(&self) -> &HashMap<PoolUuid, String> {
+        &self.quarantined_pools
+    }
+
+    /// Release the quarantined pool pool_uuid, e.g., once it has been
+    /// repaired, so that the next rescan attempts to set it up.
+    /// Returns true if the pool was quarantined, false if it was not.
+    pub fn release_quarantined_pool(&mut self, pool_uuid: PoolUuid) -> bool {
+        self.quarantined_pools.remove(&pool_uuid).is_some()
+    }
+
+    /// Activate the filesystem fs_uuid in the pool pool_uuid, if the pool
+    /// was set up without activating its filesystems.
+    /// Returns a NotFound error if there is no such pool or filesystem.
+    pub fn activate_filesystem(&mut self,
+                               pool_uuid: PoolUuid,
+                               fs_uuid: FilesystemUuid)
+                               -> EngineResult<()> {
+        match self.pools.get_mut_by_uuid(pool_uuid) {
+            Some(pool) => pool.activate_filesystem(fs_uuid),
+            None => Err(EngineError::Engine(ErrorEnum::NotFound, pool_uuid.to_string())),
         }
+    }
 
-        Ok(StratEngine { pools: table })
+    /// The resources which an operation on the pool uuid changes: the pool
+    /// and its blockdevs.
+    fn pool_resources(&self, uuid: PoolUuid) -> Vec<Resource> {
+        let mut resources = vec![Resource::Pool(uuid)];
+        if let Some(pool) = self.pools.get_by_uuid(uuid) {
+            resources.extend(pool.blockdevs()
+                                 .iter()
+                                 .map(|bd| Resource::device(&bd.devnode())));
+        }
+        resources
     }
 
     /// Teardown Stratis, preparatory to a shutdown.
+    /// Returns an error if any pool could not be torn down.
     pub fn teardown(self) -> EngineResult<()> {
         teardown_pools(self.pools.empty())
     }
+
+    /// Teardown Stratis on a system shutdown, when the daemon must stop
+    /// even if some filesystems are in use. Busy filesystems are detached
+    /// from their mount points and their devices are removed once they are
+    /// no longer in use. Failures to tear down individual devices are
+    /// logged.
+    pub fn teardown_force(self) {
+        teardown_pools_force(&self.dm, self.pools.empty())
+    }
+
+    /// Teardown Stratis, preparatory to a shutdown.
+    /// Every pool is torn down if possible, and the report lists which
+    /// pools were torn down and which were not, and why.
+    pub fn teardown_detailed(self) -> TeardownReport {
+        teardown_pools_detailed(self.pools.empty())
+    }
 }
 
 impl Engine for StratEngine {
@@ -68,43 +636,144 @@ impl Engine for StratEngine {
                    name: &str,
                    blockdev_paths: &[&Path],
                    redundancy: Option<u16>,
-                   force: bool)
-                   -> EngineResult<PoolUuid> {
+                   force: CreateForce,
+                   mdv_size: Option<Sectors>,
+                   encryption: Option<KeyDescription>,
+                   metadata_paths: &[&Path])
+                   -> EngineResult<CreateAction<PoolUuid>> {
 
-        let redundancy = calculate_redundancy!(redundancy);
+        let devices = blockdev_paths
+            .iter()
+            .map(|p| p.display().to_string())
+            .collect::<Vec<_>>();
+        let fields = [("name", name.to_owned()), ("devices", devices.join(","))];
+        logged("create_pool", &fields, || {
+            let _guard = self.in_flight
+                .claim(blockdev_paths.iter().map(|p| Resource::device(p)).collect())?;
+            let redundancy = calculate_redundancy!(redundancy);
+            validate_name(name)?;
 
-        if self.pools.contains_name(name) {
-            return Err(EngineError::Engine(ErrorEnum::AlreadyExists, name.into()));
-        }
+            if let Some(pool) = self.pools.get_by_name(name) {
+                let devices: HashSet<Device> = resolve_devices(blockdev_paths)?
+                    .keys()
+                    .cloned()
+                    .collect();
+                if pool.devices() == devices {
+                    return Ok(CreateAction::Identity(pool.uuid()));
+                }
+                return Err(EngineError::Engine(ErrorEnum::AlreadyExists, name.into()));
+            }
+            if self.pools.len() >= self.limits.max_pools {
+                let err_msg = format!("the limit of {} pools has been reached",
+                                      self.limits.max_pools);
+                return Err(EngineError::Engine(ErrorEnum::LimitReached, err_msg));
+            }
 
-        let dm = DM::new()?;
-        let pool = StratPool::initialize(name, &dm, blockdev_paths, redundancy, force)?;
+            let mut pool = StratPool::initialize(name,
+                                                 &self.dm,
+                                                 &self.paths,
+                                                 blockdev_paths,
+                                                 redundancy,
+                                                 force,
+                                                 mdv_size,
+                                                 encryption,
+                                                 metadata_paths)?;
+            pool.set_event_queue(&self.events);
+            pool.set_max_filesystems(self.limits.max_filesystems_per_pool);
 
-        let uuid = pool.uuid();
-        self.pools.insert(pool);
-        Ok(uuid)
+            let uuid = pool.uuid();
+            self.pools.insert(pool);
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::PoolCreated(uuid));
+            Ok(CreateAction::Created(uuid))
+        })
+    }
+
+    fn destroy_pool(&mut self,
+                    uuid: PoolUuid,
+                    force: bool)
+                    -> EngineResult<DestroyAction<PoolUuid>> {
+        let fields = [("pool", uuid.log_value()), ("force", force.to_string())];
+        logged("destroy_pool", &fields, || {
+            let _guard = self.in_flight.claim(self.pool_resources(uuid))?;
+            destroy_pool!{self; uuid; force}
+        })
+    }
+
+    fn unlock_pool(&mut self, uuid: PoolUuid, key: &KeyDescription) -> EngineResult<bool> {
+        logged("unlock_pool", &[("pool", uuid.log_value())], || {
+            if self.pools.contains_uuid(uuid) {
+                return Ok(false);
+            }
+            if !self.locked_pools.contains(&uuid) {
+                return Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string()));
+            }
+
+            let devices = find_all()?
+                .remove(&uuid)
+                .ok_or_else(|| {
+                                EngineError::Engine(ErrorEnum::NotFound,
+                                                    format!("no devices found for pool {}",
+                                                            uuid))
+                            })?;
+            match get_metadata(uuid, &devices)? {
+                Some(PoolSave { key_description: Some(ref description), .. }) => {
+                    if description != key.as_str() {
+                        let err_msg = format!("pool {} is encrypted with key {:?}, not {:?}",
+                                              uuid,
+                                              description,
+                                              key.as_str());
+                        return Err(EngineError::Engine(ErrorEnum::WrongKey, err_msg));
+                    }
+                }
+                _ => {
+                    let err_msg = format!("no key is recorded for pool {}", uuid);
+                    return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+                }
+            }
+
+            let pool = StratPool::setup(uuid, &self.dm, &self.paths, &devices, false, true)?;
+            self.add_pool(pool)?;
+            Ok(true)
+        })
     }
 
-    fn destroy_pool(&mut self, uuid: PoolUuid) -> EngineResult<bool> {
-        destroy_pool!{self; uuid}
+    fn locked_pools(&self) -> Vec<PoolUuid> {
+        self.locked_pools.iter().cloned().collect()
     }
 
     fn rename_pool(&mut self, uuid: PoolUuid, new_name: &str) -> EngineResult<RenameAction> {
-        let old_name = rename_pool_pre!(self; uuid; new_name);
+        let fields = [("pool", uuid.log_value()), ("new_name", new_name.to_owned())];
+        logged("rename_pool", &fields, || {
+            validate_name(new_name)?;
 
-        let mut pool = self.pools
-            .remove_by_uuid(uuid)
-            .expect("Must succeed since self.pools.get_by_uuid() returned a value");
-        pool.rename(new_name);
+            let old_name = match self.pools.get_by_uuid(uuid) {
+                Some(pool) => pool.name().to_owned(),
+                None => return Ok(RenameAction::NoSource),
+            };
 
-        if let Err(err) = pool.write_metadata() {
-            pool.rename(&old_name);
-            self.pools.insert(pool);
-            Err(err)
-        } else {
-            self.pools.insert(pool);
+            let action = self.pools.rename(uuid, new_name)?;
+            if action != RenameAction::Renamed {
+                return Ok(action);
+            }
+
+            let result = self.pools
+                .get_mut_by_uuid(uuid)
+                .expect("the pool was just renamed")
+                .write_metadata();
+            if let Err(err) = result {
+                self.pools
+                    .rename(uuid, &old_name)
+                    .expect("the old name was freed by the rename");
+                return Err(err);
+            }
+
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::PoolRenamed(uuid));
             Ok(RenameAction::Renamed)
-        }
+        })
     }
 
     fn get_pool(&self, uuid: PoolUuid) -> Option<&Pool> {
@@ -115,28 +784,351 @@ impl Engine for StratEngine {
         get_mut_pool!(self; uuid)
     }
 
-    fn check(&mut self) -> () {
-        check_engine!(self);
+    fn check(&mut self) -> EngineResult<CheckReport> {
+        check_engine!(self)
+    }
+
+    fn set_check_paused(&mut self, paused: bool) {
+        if paused != self.check_paused {
+            info!("{} the periodic check of the pools",
+                  if paused { "Pausing" } else { "Resuming" });
+        }
+        self.check_paused = paused;
+    }
+
+    fn check_paused(&self) -> bool {
+        self.check_paused
+    }
+
+    fn paths(&self) -> &StratisPaths {
+        &self.paths
     }
 
     fn pools(&self) -> Vec<&Pool> {
         self.pools.into_iter().map(|x| x as &Pool).collect()
     }
+
+    fn drain_events(&mut self) -> Vec<EngineEvent> {
+        self.events.borrow_mut().drain()
+    }
+
+    fn recent_events(&self) -> Vec<EngineEvent> {
+        self.events.borrow().recent()
+    }
+
+    fn read_device_metadata(&self, path: &Path) -> EngineResult<Option<DeviceMetadata>> {
+        read_device_metadata(path)
+    }
+
+    fn available_blockdevs(&self, paths: &[&Path]) -> Vec<EngineResult<AvailableBlockDev>> {
+        paths.iter().map(|path| probe_blockdev(path)).collect()
+    }
+
+    fn relabel_pool(&self, paths: &[&Path], new_pool_uuid: PoolUuid) -> EngineResult<()> {
+        let fields = [("pool", new_pool_uuid.log_value()),
+                      ("devices",
+                       paths
+                           .iter()
+                           .map(|p| p.display().to_string())
+                           .collect::<Vec<_>>()
+                           .join(","))];
+        logged("relabel_pool", &fields, || {
+            check_unclaimed(&self.claimed_devices(), paths)?;
+            let _guard = self.in_flight
+                .claim(paths.iter().map(|p| Resource::device(p)).collect())?;
+            relabel_pool(paths, new_pool_uuid)
+        })
+    }
+
+    fn relabel_device(&self, path: &Path, new_dev_uuid: DevUuid) -> EngineResult<()> {
+        let fields = [("device", path.display().to_string()),
+                      ("uuid", new_dev_uuid.log_value())];
+        logged("relabel_device", &fields, || {
+            check_unclaimed(&self.claimed_devices(), &[path])?;
+            let _guard = self.in_flight.claim(vec![Resource::device(path)])?;
+            relabel_device(path, new_dev_uuid)
+        })
+    }
+
+    fn metadata_version(&self) -> u32 {
+        METADATA_VERSION
+    }
 }
 
 #[cfg(test)]
 mod test {
+    use std::env;
+    use std::fs::File;
+    use std::io::Read;
+    use std::thread;
+    use std::time::Duration;
+
+    use nix::mount::{MsFlags, mount, umount};
+    use uuid::Uuid;
+
+    use devicemapper::{DM, SECTOR_SIZE, device_exists};
+
+    use super::super::crypt::{add_key, remove_key};
+    use super::super::device::write_sectors;
+    use super::super::dmdevice::{FlexRole, format_flex_name};
     use super::super::tests::{loopbacked, real};
+    use super::super::tests::tempdir::TempDir;
+    use super::super::tests::logger::capture_logs;
+
+    use super::super::super::engine::DEV_PATH_ENV;
+    use super::super::super::types::DevOwnership;
 
     use super::*;
 
+    #[test]
+    /// Verify that a missing Stratis /dev directory is created, with any
+    /// missing parents, and that an existing one is accepted.
+    fn dev_path_created() {
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let dir = tmp_dir.path().join("dev").join("stratis");
+
+        setup_dev_path(&dir).unwrap();
+        assert!(dir.is_dir());
+        assert_eq!(fs::metadata(&dir).unwrap().permissions().mode() & 0o777,
+                   DEV_PATH_MODE);
+
+        setup_dev_path(&dir).unwrap();
+    }
+
+    #[test]
+    /// Verify that a Stratis /dev directory which is not a directory is
+    /// rejected.
+    fn dev_path_not_dir() {
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let path = tmp_dir.path().join("stratis");
+        fs::File::create(&path).unwrap();
+
+        assert!(match setup_dev_path(&path) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// Verify that the engine lock can be held by only one holder at a
+    /// time, and is released when its file is closed.
+    fn lock_exclusive() {
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let path = tmp_dir.path().join("stratisd.lock");
+
+        let lock = lock_engine(&path).unwrap();
+        assert!(match lock_engine(&path) {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+
+        drop(lock);
+        lock_engine(&path).unwrap();
+    }
+
+    /// Verify that a second engine can not be initialized while the first
+    /// is running, but can be once the first is torn down.
+    fn test_lock(paths: &[&Path]) {
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        env::set_var(LOCK_PATH_ENV, tmp_dir.path().join("stratisd.lock"));
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid = engine
+            .create_pool("name", paths, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+
+        assert!(match StratEngine::initialize(false, EngineLimits::default()) {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+
+        engine.teardown().unwrap();
+
+        let engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        assert!(engine.get_pool(uuid).is_some());
+        engine.teardown().unwrap();
+
+        env::remove_var(LOCK_PATH_ENV);
+    }
+
+    #[test]
+    pub fn loop_test_lock() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_lock);
+    }
+
+    #[test]
+    pub fn real_test_lock() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_lock);
+    }
+
+    /// Verify that initializing the engine recreates a Stratis /dev
+    /// directory which has been removed.
+    fn test_dev_path_recreated(paths: &[&Path]) {
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let dir = tmp_dir.path().join("stratis");
+        env::set_var(DEV_PATH_ENV, &dir);
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        engine.create_pool("name", paths, None, CreateForce::default(), None, None, &[]).unwrap();
+        engine.teardown().unwrap();
+
+        fs::remove_dir_all(&dir).unwrap();
+
+        let engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        assert!(dir.is_dir());
+        engine.teardown().unwrap();
+
+        env::remove_var(DEV_PATH_ENV);
+    }
+
+    #[test]
+    pub fn loop_test_dev_path_recreated() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_dev_path_recreated);
+    }
+
+    #[test]
+    pub fn real_test_dev_path_recreated() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_dev_path_recreated);
+    }
+
+    /// Verify that every pool the engine creates uses the engine's handle to
+    /// devicemapper, rather than one of its own.
+    fn test_shared_dm(paths: &[&Path]) {
+        let (paths1, paths2) = paths.split_at(1);
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid1 = engine
+            .create_pool("name1", paths1, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let uuid2 = engine
+            .create_pool("name2", paths2, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+
+        for uuid in &[uuid1, uuid2] {
+            let pool = engine.pools.get_by_uuid(*uuid).unwrap();
+            assert!(Rc::ptr_eq(pool.dm(), &engine.dm));
+        }
+        assert_eq!(Rc::strong_count(&engine.dm), 3);
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_shared_dm() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_shared_dm);
+    }
+
+    #[test]
+    pub fn real_test_shared_dm() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_shared_dm);
+    }
+
+    /// Verify that a pool can not be created on a device which another
+    /// operation in flight is changing, nor destroyed while another
+    /// operation is changing it, and that both succeed once the other
+    /// operation has completed.
+    fn test_in_flight(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+
+        let guard = engine.in_flight.claim(vec![Resource::device(paths[0])]).unwrap();
+        assert!(match engine.create_pool("name",
+                                         paths,
+                                         None,
+                                         CreateForce::default(),
+                                         None,
+                                         None,
+                                         &[]) {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+        drop(guard);
+        let uuid = engine
+            .create_pool("name", paths, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+
+        let guard = engine.in_flight.claim(vec![Resource::Pool(uuid)]).unwrap();
+        assert!(match engine.destroy_pool(uuid, false) {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+        assert!(engine.get_pool(uuid).is_some());
+        drop(guard);
+        assert_eq!(engine.destroy_pool(uuid, false).unwrap(),
+                   DestroyAction::Destroyed(uuid));
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_in_flight() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_in_flight);
+    }
+
+    #[test]
+    pub fn real_test_in_flight() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_in_flight);
+    }
+
+    /// Verify that a rescan sets up a pool whose devices are present but
+    /// which is not set up, without disturbing a pool which is, and that
+    /// a second rescan finds nothing more to set up.
+    fn test_rescan(paths: &[&Path]) {
+        let (paths1, paths2) = paths.split_at(1);
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid1 = engine
+            .create_pool("name1", paths1, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        engine.teardown().unwrap();
+
+        let mut engine = StratEngine::setup_pools(&HashMap::new(),
+                                                  false,
+                                                  true,
+                                                  EngineLimits::default(),
+                                                  false)
+                .unwrap();
+        let uuid2 = engine
+            .create_pool("name2", paths2, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        engine
+            .get_mut_pool(uuid2)
+            .unwrap()
+            .create_filesystems(&[("fs", None, None)])
+            .unwrap()
+            .into_inner();
+
+        assert_eq!(engine.rescan().unwrap(), vec![uuid1]);
+        assert_eq!(engine.get_pool(uuid1).unwrap().name(), "name1");
+        assert_eq!(engine.get_pool(uuid2).unwrap().filesystems().len(), 1);
+
+        assert_eq!(engine.rescan().unwrap(), vec![]);
+        assert_eq!(engine.pools().len(), 2);
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_rescan() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_rescan);
+    }
+
+    #[test]
+    pub fn real_test_rescan() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_rescan);
+    }
 
     /// Verify that a pool rename causes the pool metadata to get the new name.
     fn test_pool_rename(paths: &[&Path]) {
-        let mut engine = StratEngine::initialize().unwrap();
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
 
         let name1 = "name1";
-        let uuid1 = engine.create_pool(&name1, paths, None, false).unwrap();
+        let uuid1 = engine
+            .create_pool(&name1, paths, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
 
         let name2 = "name2";
         let action = engine.rename_pool(uuid1, name2).unwrap();
@@ -144,7 +1136,7 @@ mod test {
         assert_eq!(action, RenameAction::Renamed);
         engine.teardown().unwrap();
 
-        let engine = StratEngine::initialize().unwrap();
+        let engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
         let pool_name: String = engine.get_pool(uuid1).unwrap().name().into();
         assert_eq!(pool_name, name2);
     }
@@ -159,6 +1151,221 @@ mod test {
         real::test_with_spec(real::DeviceLimits::AtLeast(1), test_pool_rename);
     }
 
+    /// Verify that creating a pool again, with the same name and devices,
+    /// changes nothing, but that a pool of the same name with other devices
+    /// can not be created.
+    fn test_create_pool_twice(paths: &[&Path]) {
+        let (paths1, _) = paths.split_at(1);
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+
+        let name = "name";
+        let uuid = engine
+            .create_pool(name, paths1, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        assert_eq!(engine
+                       .create_pool(name, paths1, None, CreateForce::default(), None, None, &[])
+                       .unwrap(),
+                   CreateAction::Identity(uuid));
+        assert!(match engine.create_pool(name,
+                                         paths,
+                                         None,
+                                         CreateForce::default(),
+                                         None,
+                                         None,
+                                         &[]) {
+                    Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(engine.get_pool(uuid).unwrap().blockdevs().len(), paths1.len());
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_create_pool_twice() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_create_pool_twice);
+    }
+
+    #[test]
+    pub fn real_test_create_pool_twice() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_create_pool_twice);
+    }
+
+    /// Verify that no more pools can be created than the engine's limit
+    /// allows, and that the limit on filesystems applies to each pool.
+    fn test_limits(paths: &[&Path]) {
+        let (paths1, paths2) = paths.split_at(1);
+        let limits = EngineLimits {
+            max_pools: 1,
+            max_filesystems_per_pool: 1,
+        };
+        let mut engine = StratEngine::initialize(false, limits).unwrap();
+        let uuid = engine
+            .create_pool("name1", paths1, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        assert!(match engine.create_pool("name2",
+                                         paths2,
+                                         None,
+                                         CreateForce::default(),
+                                         None,
+                                         None,
+                                         &[]) {
+                    Err(EngineError::Engine(ErrorEnum::LimitReached, _)) => true,
+                    _ => false,
+                });
+
+        {
+            let pool = engine.get_mut_pool(uuid).unwrap();
+            pool.create_filesystems(&[("fs1", None, None)]).unwrap();
+            assert!(match pool.create_filesystems(&[("fs2", None, None)]) {
+                        Err(EngineError::Engine(ErrorEnum::LimitReached, _)) => true,
+                        _ => false,
+                    });
+        }
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_limits() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_limits);
+    }
+
+    #[test]
+    pub fn real_test_limits() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_limits);
+    }
+
+    /// Verify that renaming a pool advances its last modification time but
+    /// not its creation time, and that both persist when it is set up again.
+    fn test_pool_last_modified(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid = engine
+            .create_pool("name1", paths, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+
+        let (created, last_modified) = {
+            let pool = engine.get_pool(uuid).unwrap();
+            (pool.created(), pool.last_modified())
+        };
+        assert!(created <= last_modified);
+
+        // Times are recorded to the second.
+        thread::sleep(Duration::from_secs(1));
+        engine.rename_pool(uuid, "name2").unwrap();
+
+        let renamed = engine.get_pool(uuid).unwrap().last_modified();
+        assert!(renamed > last_modified);
+        assert_eq!(engine.get_pool(uuid).unwrap().created(), created);
+        engine.teardown().unwrap();
+
+        let engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let pool = engine.get_pool(uuid).unwrap();
+        assert_eq!(pool.created(), created);
+        assert_eq!(pool.last_modified(), renamed);
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_pool_last_modified() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_pool_last_modified);
+    }
+
+    #[test]
+    pub fn real_test_pool_last_modified() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_pool_last_modified);
+    }
+
+    /// Verify that the metadata of a pool's device can be read, and that
+    /// a device which does not belong to a pool has none and is left
+    /// unclaimed.
+    fn test_read_device_metadata(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(1);
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid = engine
+            .create_pool("name1", paths1, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+
+        let metadata = engine.read_device_metadata(paths1[0]).unwrap().unwrap();
+        {
+            let pool = engine.get_pool(uuid).unwrap();
+            let blockdev = pool.blockdevs()[0];
+            assert_eq!(metadata.pool_uuid, uuid);
+            assert_eq!(metadata.dev_uuid, blockdev.uuid());
+            assert_eq!(metadata.initialization_time, blockdev.initialization_time());
+            assert!(metadata.mda_offset < metadata.data_offset);
+            assert!(metadata.data_offset < metadata.dev_size);
+            assert!(metadata.last_update_time.is_some());
+        }
+
+        assert_eq!(engine.read_device_metadata(paths2[0]).unwrap(), None);
+        engine.create_pool("name2",
+                           paths2,
+                           None,
+                           CreateForce::default(),
+                           None,
+                           None,
+                           &[]).unwrap();
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_read_device_metadata() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3),
+                                   test_read_device_metadata);
+    }
+
+    #[test]
+    pub fn real_test_read_device_metadata() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_read_device_metadata);
+    }
+
+    /// Verify that a device of a pool is found to be ours, and one which
+    /// is not to be unowned, and that a path which can not be examined
+    /// has an error without preventing the others from being examined.
+    fn test_available_blockdevs(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(1);
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid = engine
+            .create_pool("name", paths1, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let dev_uuid = engine.get_pool(uuid).unwrap().blockdevs()[0].uuid();
+
+        let available = engine.available_blockdevs(&[paths1[0],
+                                                     Path::new("/dev/stratis-no-such-device"),
+                                                     paths2[0]]);
+        assert_eq!(available.len(), 3);
+        let ours = available[0].as_ref().unwrap();
+        assert_eq!(ours.ownership, DevOwnership::Ours(uuid, dev_uuid));
+        assert!(ours.meets_min_size);
+        assert!(available[1].is_err());
+        let unowned = available[2].as_ref().unwrap();
+        assert_eq!(unowned.ownership, DevOwnership::Unowned);
+        assert!(unowned.meets_min_size);
+
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_available_blockdevs() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3),
+                                   test_available_blockdevs);
+    }
+
+    #[test]
+    pub fn real_test_available_blockdevs() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_available_blockdevs);
+    }
+
     /// Test engine setup.
     /// 1. Create two pools.
     /// 2. Verify that both exist.
@@ -171,20 +1378,26 @@ mod test {
 
         let (paths1, paths2) = paths.split_at(paths.len() / 2);
 
-        let mut engine = StratEngine::initialize().unwrap();
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
 
         let name1 = "name1";
-        let uuid1 = engine.create_pool(&name1, paths1, None, false).unwrap();
+        let uuid1 = engine
+            .create_pool(&name1, paths1, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
 
         let name2 = "name2";
-        let uuid2 = engine.create_pool(&name2, paths2, None, false).unwrap();
+        let uuid2 = engine
+            .create_pool(&name2, paths2, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
 
         assert!(engine.get_pool(uuid1).is_some());
         assert!(engine.get_pool(uuid2).is_some());
 
         engine.teardown().unwrap();
 
-        let engine = StratEngine::initialize().unwrap();
+        let engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
 
         assert!(engine.get_pool(uuid1).is_some());
         assert!(engine.get_pool(uuid2).is_some());
@@ -199,4 +1412,641 @@ mod test {
     pub fn real_test_setup() {
         real::test_with_spec(real::DeviceLimits::AtLeast(2), test_setup);
     }
+
+    /// Verify that a pool one of whose blockdevs is missing is recorded as
+    /// incomplete, with the missing blockdev, rather than causing setup of
+    /// the engine to fail, and that a rescan sets it up once the blockdev
+    /// is found again.
+    fn test_setup_missing_device(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid = engine
+            .create_pool("name", paths, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let (dev_uuid, devnode) = {
+            let blockdev = engine.get_pool(uuid).unwrap().blockdevs()[0];
+            (blockdev.uuid(), blockdev.devnode())
+        };
+        engine.teardown().unwrap();
+
+        let mut pools = find_all().unwrap();
+        pools
+            .get_mut(&uuid)
+            .unwrap()
+            .retain(|_, path| *path != devnode);
+
+        let mut engine =
+            StratEngine::setup_pools(&pools, false, true, EngineLimits::default(), false)
+                .unwrap();
+        assert!(engine.get_pool(uuid).is_none());
+        assert_eq!(engine.incomplete_pools().get(&uuid),
+                   Some(&IncompleteReason::MissingDevices(vec![dev_uuid])));
+
+        assert_eq!(engine.rescan().unwrap(), vec![uuid]);
+        assert!(engine.incomplete_pools().is_empty());
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_setup_missing_device() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3),
+                                   test_setup_missing_device);
+    }
+
+    #[test]
+    pub fn real_test_setup_missing_device() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_setup_missing_device);
+    }
+
+    /// Verify that in safe mode a pool whose metadata areas are corrupt is
+    /// quarantined, while a healthy pool is set up, that a rescan leaves
+    /// the quarantined pool alone, and that it can be released.
+    fn test_initialize_safe(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(paths.len() / 2);
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let good_uuid = engine
+            .create_pool("good", paths1, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let bad_uuid = engine
+            .create_pool("bad", paths2, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        engine.teardown().unwrap();
+
+        // Overwrite everything between the static header and the data, so
+        // that the devices are still found, but their metadata is not.
+        for path in paths2 {
+            let metadata = read_device_metadata(path).unwrap().unwrap();
+            write_sectors(path,
+                          metadata.mda_offset,
+                          metadata.data_offset - metadata.mda_offset,
+                          &[0xffu8; SECTOR_SIZE])
+                    .unwrap();
+            assert!(read_device_metadata(path).is_err());
+        }
+        assert!(discover_devices().unwrap().pools.contains_key(&bad_uuid));
+
+        let mut engine = StratEngine::initialize_safe(EngineLimits::default()).unwrap();
+        assert!(engine.get_pool(good_uuid).is_some());
+        assert!(engine.get_pool(bad_uuid).is_none());
+        assert!(match engine.quarantined_pools().get(&bad_uuid) {
+                    Some(err) => err.contains("MDA"),
+                    None => false,
+                });
+        assert!(!engine.quarantined_pools().contains_key(&good_uuid));
+
+        assert!(engine.rescan().unwrap().is_empty());
+        assert!(engine.incomplete_pools().is_empty());
+        assert!(engine.release_quarantined_pool(bad_uuid));
+        assert!(!engine.release_quarantined_pool(bad_uuid));
+        assert!(engine.quarantined_pools().is_empty());
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_initialize_safe() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_initialize_safe);
+    }
+
+    #[test]
+    pub fn real_test_initialize_safe() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_initialize_safe);
+    }
+
+    /// Verify that a check reports the removal of a temp file left in a
+    /// pool's MDV, and that a second check finds nothing to remove.
+    fn test_check_temp_files(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid = engine
+            .create_pool("name", paths, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+
+        let mdv_devnode = Path::new("/dev/mapper")
+            .join(format_flex_name(uuid, FlexRole::MetadataVolume).to_string());
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&mdv_devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        File::create(tmp_dir.path().join("filesystems").join("foo.temp")).unwrap();
+        umount(tmp_dir.path()).unwrap();
+
+        let report = engine.check().unwrap();
+        assert!(report.failed.is_empty());
+        assert_eq!(report.pools.len(), 1);
+        assert_eq!(report.pools[0].0, uuid);
+        assert_eq!(report.pools[0].1.temp_files_removed, 1);
+
+        let report = engine.check().unwrap();
+        assert_eq!(report.pools[0].1.temp_files_removed, 0);
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_check_temp_files() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_check_temp_files);
+    }
+
+    #[test]
+    pub fn real_test_check_temp_files() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_check_temp_files);
+    }
+
+    /// Verify that an encrypted pool is not set up while its key is not in
+    /// the kernel keyring, but is locked, that it can be unlocked only with
+    /// its key once the key is in the keyring, and that its filesystems can
+    /// then be mounted.
+    fn test_encrypted_pool(paths: &[&Path]) {
+        let key = KeyDescription::new(&format!("stratis-test-{}", Uuid::new_v4())).unwrap();
+        let key_data = [0x5a; 64];
+        add_key(&key, &key_data).unwrap();
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid = engine
+            .create_pool("name", paths, None, CreateForce::default(), None, Some(key.clone()), &[])
+            .unwrap()
+            .into_inner();
+        {
+            let pool = engine.get_mut_pool(uuid).unwrap();
+            assert_eq!(pool.key_description(), Some(&key));
+            pool.create_filesystems(&[("fsname", None, None)]).unwrap();
+        }
+        engine.teardown().unwrap();
+
+        remove_key(&key).unwrap();
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        assert!(engine.get_pool(uuid).is_none());
+        assert_eq!(engine.locked_pools(), vec![uuid]);
+
+        assert!(match engine.unlock_pool(uuid, &key) {
+                    Err(EngineError::Engine(ErrorEnum::KeyNotFound, _)) => true,
+                    _ => false,
+                });
+        add_key(&key, &key_data).unwrap();
+        let other_key = KeyDescription::new("stratis-test-other").unwrap();
+        assert!(match engine.unlock_pool(uuid, &other_key) {
+                    Err(EngineError::Engine(ErrorEnum::WrongKey, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(engine.locked_pools(), vec![uuid]);
+
+        add_key(&key, &[0xa5; 64]).unwrap();
+        assert!(match engine.unlock_pool(uuid, &key) {
+                    Err(EngineError::Engine(ErrorEnum::WrongKey, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(engine.locked_pools(), vec![uuid]);
+
+        add_key(&key, &key_data).unwrap();
+        assert!(engine.unlock_pool(uuid, &key).unwrap());
+        assert!(!engine.unlock_pool(uuid, &key).unwrap());
+        assert!(engine.locked_pools().is_empty());
+
+        let devnode = {
+            let pool = engine.get_pool(uuid).unwrap();
+            let filesystems = pool.filesystems();
+            assert_eq!(filesystems.len(), 1);
+            filesystems[0].devnode()
+        };
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        umount(tmp_dir.path()).unwrap();
+
+        engine.teardown().unwrap();
+        remove_key(&key).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_encrypted_pool() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_encrypted_pool);
+    }
+
+    #[test]
+    pub fn real_test_encrypted_pool() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_encrypted_pool);
+    }
+
+    /// Verify that when two pools with the same name are set up, the newer
+    /// is renamed, and that the new name persists.
+    /// 1. Create two pools with different names.
+    /// 2. Set up only the second pool and give it the first pool's name.
+    /// 3. Initialize the engine with both pools.
+    /// 4. Verify that both pools were set up with distinct names.
+    fn test_setup_name_collision(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(paths.len() / 2);
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid1 = engine
+            .create_pool("name1", paths1, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let uuid2 = engine
+            .create_pool("name2", paths2, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        engine.teardown().unwrap();
+
+        let mut engine = StratEngine::initialize_one(uuid2, true).unwrap();
+        engine.rename_pool(uuid2, "name1").unwrap();
+        engine.teardown().unwrap();
+
+        let engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let mut names = vec![engine.get_pool(uuid1).unwrap().name().to_owned(),
+                             engine.get_pool(uuid2).unwrap().name().to_owned()];
+        names.sort();
+        assert_eq!(names, vec!["name1", "name1_1"]);
+        engine.teardown().unwrap();
+
+        let engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let mut persisted = vec![engine.get_pool(uuid1).unwrap().name().to_owned(),
+                                 engine.get_pool(uuid2).unwrap().name().to_owned()];
+        persisted.sort();
+        assert_eq!(persisted, names);
+    }
+
+    #[test]
+    pub fn loop_test_setup_name_collision() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3),
+                                   test_setup_name_collision);
+    }
+
+    #[test]
+    pub fn real_test_setup_name_collision() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_setup_name_collision);
+    }
+
+    /// Test setting up only one of two pools.
+    /// 1. Create two pools.
+    /// 2. Teardown the engine.
+    /// 3. Initialize the engine with only the first pool.
+    /// 4. Verify that only the first pool was set up.
+    /// 5. Verify that a pool with no devices can not be set up.
+    fn test_setup_one(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(paths.len() / 2);
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+
+        let uuid1 = engine
+            .create_pool("name1", paths1, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let uuid2 = engine
+            .create_pool("name2", paths2, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+
+        engine.teardown().unwrap();
+
+        let engine = StratEngine::initialize_one(uuid1, true).unwrap();
+
+        assert!(engine.get_pool(uuid1).is_some());
+        assert!(engine.get_pool(uuid2).is_none());
+
+        engine.teardown().unwrap();
+
+        assert!(match StratEngine::initialize_one(Uuid::new_v4(), true) {
+                    Err(EngineError::Engine(ErrorEnum::NotFound, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    pub fn loop_test_setup_one() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_setup_one);
+    }
+
+    #[test]
+    pub fn real_test_setup_one() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_setup_one);
+    }
+
+    /// Verify that the attempt and outcome of each operation in a
+    /// create/destroy sequence, including a failed one, are logged.
+    fn test_operation_log(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+
+        let (uuid, messages) = capture_logs(|| {
+            let uuid = engine
+                .create_pool("name", paths, None, CreateForce::default(), None, None, &[])
+                .unwrap()
+                .into_inner();
+            assert!(engine.create_pool("name",
+                                       paths,
+                                       None,
+                                       CreateForce::default(),
+                                       None,
+                                       None,
+                                       &[]).is_err());
+            assert_eq!(engine.destroy_pool(uuid, false).unwrap(), DestroyAction::Destroyed(uuid));
+            uuid
+        });
+
+        let ops: Vec<&String> = messages.iter().filter(|m| m.starts_with("op=")).collect();
+        let create_fields = format!("name=\"name\" devices={:?}",
+                                    paths
+                                        .iter()
+                                        .map(|p| p.display().to_string())
+                                        .collect::<Vec<_>>()
+                                        .join(","));
+        let destroy_fields = format!("pool=\"{}\" force=\"false\"", uuid.simple());
+        assert_eq!(ops.len(), 6);
+        assert_eq!(*ops[0],
+                   format!("op=create_pool {} outcome=attempt", create_fields));
+        assert_eq!(*ops[1],
+                   format!("op=create_pool {} outcome=success result=\"{}\"",
+                           create_fields,
+                           uuid.simple()));
+        assert_eq!(*ops[2],
+                   format!("op=create_pool {} outcome=attempt", create_fields));
+        assert!(ops[3].starts_with(&format!("op=create_pool {} outcome=failure error=",
+                                            create_fields)));
+        assert_eq!(*ops[4],
+                   format!("op=destroy_pool {} outcome=attempt", destroy_fields));
+        assert_eq!(*ops[5],
+                   format!("op=destroy_pool {} outcome=success result=\"true\"",
+                           destroy_fields));
+    }
+
+    #[test]
+    pub fn loop_test_operation_log() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_operation_log);
+    }
+
+    #[test]
+    pub fn real_test_operation_log() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_operation_log);
+    }
+
+    /// Verify that teardown of a busy pool does not prevent teardown of
+    /// the others, and that the report says which pool failed.
+    /// 1. Create two pools, and mount a filesystem of the second.
+    /// 2. Teardown the engine and verify the report.
+    fn test_teardown_detailed(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(paths.len() / 2);
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid1 = engine
+            .create_pool("name1", paths1, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+        let uuid2 = engine
+            .create_pool("name2", paths2, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+
+        let devnode = {
+            let pool = engine.get_mut_pool(uuid2).unwrap();
+            let fs_uuid = pool.create_filesystems(&[("fsname", None, None)])
+                .unwrap()
+                .into_inner()[0]
+                .1;
+            pool.get_filesystem(fs_uuid).unwrap().devnode()
+        };
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+
+        let report = engine.teardown_detailed();
+        assert_eq!(report.torn_down, vec![uuid1]);
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(report.failed[0].0, uuid2);
+
+        umount(tmp_dir.path()).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_teardown_detailed() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_teardown_detailed);
+    }
+
+    #[test]
+    pub fn real_test_teardown_detailed() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_teardown_detailed);
+    }
+
+    /// Verify that a pool which can not be torn down does not prevent the
+    /// pools created before or after it from being torn down, and that the
+    /// error names it, with the error which prevented its teardown.
+    /// 1. Create three pools, and mount a filesystem of the second.
+    /// 2. Teardown the engine and verify the error and the devices left.
+    fn test_teardown_failure(paths: &[&Path]) {
+        assert!(paths.len() > 2);
+
+        let (paths1, paths23) = paths.split_at(1);
+        let (paths2, paths3) = paths23.split_at(1);
+
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuids = [("name1", paths1), ("name2", paths2), ("name3", paths3)]
+            .iter()
+            .map(|&(name, paths)| {
+                     engine
+                         .create_pool(name, paths, None, CreateForce::default(), None, None, &[])
+                         .unwrap()
+                         .into_inner()
+                 })
+            .collect::<Vec<_>>();
+
+        let devnode = {
+            let pool = engine.get_mut_pool(uuids[1]).unwrap();
+            let fs_uuid = pool.create_filesystems(&[("fsname", None, None)])
+                .unwrap()
+                .into_inner()[0]
+                .1;
+            pool.get_filesystem(fs_uuid).unwrap().devnode()
+        };
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+
+        let err_msg = match engine.teardown() {
+            Err(EngineError::Engine(ErrorEnum::Error, err_msg)) => err_msg,
+            _ => panic!("teardown of a busy pool must fail"),
+        };
+        assert!(err_msg.contains(&format!("{}:", uuids[1])));
+        assert!(!err_msg.contains(&uuids[0].to_string()));
+        assert!(!err_msg.contains(&uuids[2].to_string()));
+
+        let dm = DM::new().unwrap();
+        for (uuid, left) in uuids.iter().zip(&[false, true, false]) {
+            let mdv_name = format_flex_name(*uuid, FlexRole::MetadataVolume);
+            assert_eq!(device_exists(&dm, &mdv_name).unwrap(), *left);
+        }
+
+        umount(tmp_dir.path()).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_teardown_failure() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(3, 3), test_teardown_failure);
+    }
+
+    #[test]
+    pub fn real_test_teardown_failure() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(3), test_teardown_failure);
+    }
+
+    /// Verify that a forced teardown completes even though a filesystem and
+    /// the MDV are in use, that both are detached from their mount points,
+    /// and that the pool's devices are removed once they are no longer in
+    /// use.
+    fn test_teardown_force(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid = engine
+            .create_pool("name", paths, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+
+        let devnode = {
+            let pool = engine.get_mut_pool(uuid).unwrap();
+            let fs_uuid = pool.create_filesystems(&[("fsname", None, None)])
+                .unwrap()
+                .into_inner()[0]
+                .1;
+            pool.get_filesystem(fs_uuid).unwrap().devnode()
+        };
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        let file = File::create(tmp_dir.path().join("stratis_test.txt")).unwrap();
+
+        let mdv_dir = TempDir::new("stratis_testing").unwrap();
+        let mdv_devnode =
+            PathBuf::from("/dev/mapper")
+                .join(format_flex_name(uuid, FlexRole::MetadataVolume).to_string());
+        mount(Some(&mdv_devnode),
+              mdv_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        let mdv_file = File::open(mdv_dir.path()).unwrap();
+
+        engine.teardown_force();
+
+        let mut mounts = String::new();
+        File::open("/proc/self/mounts")
+            .unwrap()
+            .read_to_string(&mut mounts)
+            .unwrap();
+        assert!(!mounts.contains(&format!("{} ", tmp_dir.path().display())));
+        assert!(!mounts.contains(&format!("{} ", mdv_dir.path().display())));
+
+        drop(file);
+        drop(mdv_file);
+
+        let pool_name = uuid.simple().to_string();
+        let mut remaining = vec![];
+        for _ in 0..50 {
+            remaining = DM::new()
+                .unwrap()
+                .list_devices()
+                .unwrap()
+                .into_iter()
+                .filter(|d| d.0.to_string().contains(&pool_name))
+                .collect::<Vec<_>>();
+            if remaining.is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(100));
+        }
+        assert_eq!(remaining, vec![]);
+    }
+
+    #[test]
+    pub fn loop_test_teardown_force() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_teardown_force);
+    }
+
+    #[test]
+    pub fn real_test_teardown_force() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_teardown_force);
+    }
+
+    /// Verify that a file on a mounted filesystem, and the filesystem's
+    /// mount point, resolve to the filesystem and its pool, and that a
+    /// file which is not on a Stratis filesystem resolves to nothing.
+    fn test_resolve_path(paths: &[&Path]) {
+        let mut engine = StratEngine::initialize(false, EngineLimits::default()).unwrap();
+        let uuid = engine
+            .create_pool("name", paths, None, CreateForce::default(), None, None, &[])
+            .unwrap()
+            .into_inner();
+
+        let (fs_uuid, devnode) = {
+            let pool = engine.get_mut_pool(uuid).unwrap();
+            let fs_uuids: Vec<_> = pool.create_filesystems(&[("fsname1", None, None),
+                                                              ("fsname2", None, None)])
+                .unwrap()
+                .into_inner()
+                .into_iter()
+                .map(|(_, fs_uuid)| fs_uuid)
+                .collect();
+            (fs_uuids[1], pool.get_filesystem(fs_uuids[1]).unwrap().devnode())
+        };
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let file_path = tmp_dir.path().join("stratis_test.txt");
+        File::create(&file_path).unwrap();
+        assert_eq!(engine.resolve_path(&file_path).unwrap(), None);
+
+        mount(Some(&devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        File::create(&file_path).unwrap();
+        assert_eq!(engine.resolve_path(&file_path).unwrap(),
+                   Some((uuid, fs_uuid)));
+        assert_eq!(engine.resolve_path(tmp_dir.path()).unwrap(),
+                   Some((uuid, fs_uuid)));
+
+        umount(tmp_dir.path()).unwrap();
+        engine.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_resolve_path() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_resolve_path);
+    }
+
+    #[test]
+    pub fn real_test_resolve_path() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_resolve_path);
+    }
 }
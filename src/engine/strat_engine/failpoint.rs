@@ -0,0 +1,62 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Points at which a failure can be injected by tests, in order to exercise
+// cleanup paths which are otherwise hard to reach. Outside of tests, a
+// failure point never fails.
+
+use super::super::errors::EngineResult;
+
+#[cfg(test)]
+use std::cell::RefCell;
+#[cfg(test)]
+use std::collections::HashMap;
+
+#[cfg(test)]
+use super::super::errors::{EngineError, ErrorEnum};
+
+#[cfg(test)]
+thread_local! {
+    // Armed failure points, and the number of times each is to be passed
+    // before it fails.
+    static ARMED: RefCell<HashMap<&'static str, usize>> = RefCell::new(HashMap::new());
+}
+
+/// Return an error if the failure point name is armed and has been
+/// passed as often as specified when it was armed.
+#[cfg(not(test))]
+pub fn fail_point(_name: &str) -> EngineResult<()> {
+    Ok(())
+}
+
+/// Return an error if the failure point name is armed and has been
+/// passed as often as specified when it was armed.
+#[cfg(test)]
+pub fn fail_point(name: &str) -> EngineResult<()> {
+    ARMED.with(|armed| {
+        let mut armed = armed.borrow_mut();
+        let fire = match armed.get_mut(name) {
+            Some(&mut 0) => true,
+            Some(remaining) => {
+                *remaining -= 1;
+                false
+            }
+            None => false,
+        };
+        if fire {
+            armed.remove(name);
+            let err_msg = format!("injected failure at {}", name);
+            Err(EngineError::Engine(ErrorEnum::Error, err_msg))
+        } else {
+            Ok(())
+        }
+    })
+}
+
+/// Arm the failure point name, so that it fails after being passed
+/// skip times. A failure point fails only once.
+#[cfg(test)]
+pub fn arm(name: &'static str, skip: usize) {
+    ARMED.with(|armed| armed.borrow_mut().insert(name, skip));
+}
@@ -2,33 +2,66 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::cmp::min;
 use std::path::{Path, PathBuf};
 
-use devicemapper::{Bytes, DmDevice, DmName, DM, IEC, SECTOR_SIZE, Sectors, ThinDev, ThinDevId,
-                   ThinStatus, ThinPoolDev};
+use chrono::{DateTime, TimeZone, Utc};
+use devicemapper::{Bytes, DataBlocks, DevId, DmDevice, DmFlags, DmName, DmNameBuf, DM,
+                   DM_DEFERRED_REMOVE, DM_STATUS_TABLE, DM_SUSPEND, IEC, SECTOR_SIZE, Sectors,
+                   TargetLine, TargetTypeBuf, ThinDev, ThinDevId, ThinStatus, ThinPoolDev,
+                   device_exists};
 
 use mnt::{MountParam, MountIter};
+use nix;
+use nix::Errno;
 use nix::sys::statvfs::statvfs;
 use nix::sys::statvfs::vfs::Statvfs;
-use nix::mount::{mount, MsFlags, umount};
 use tempdir::TempDir;
 
 use super::super::engine::{Filesystem, HasName, HasUuid};
 use super::super::errors::{EngineError, EngineResult, ErrorEnum};
-use super::super::types::FilesystemUuid;
+use super::super::structures::SetName;
+use super::super::types::{AllocationInfo, FilesystemType, FilesystemUuid, FsOptions};
 
+use super::device::{copy_device_sparse, set_blkdev_discards, set_blkdev_read_only};
+use super::failpoint::fail_point;
+use super::mount::{freeze_fs, mount_fs, mount_points_of, remount_fs, thaw_fs, unmount_fs,
+                   unmount_fs_lazy};
+use super::openers::{PidInfo, openers_of};
 use super::serde_structs::{FilesystemSave, Recordable};
-use super::util::{create_fs, set_uuid, xfs_growfs};
+use super::util::{create_fs, fstrim, grow_fs, set_uuid};
 
 /// TODO: confirm that 256 MiB leaves enough time for stratisd to respond and extend before
 /// the filesystem is out of space.
 pub const FILESYSTEM_LOWATER: Sectors = Sectors(256 * IEC::Mi / (SECTOR_SIZE as u64)); // = 256 MiB
 
+/// The thin device on which a filesystem resides.
+#[derive(Debug)]
+enum FilesystemDev {
+    Active(ThinDev),
+    /// A thin device which has not been activated, with the name, thin id,
+    /// and size it will have when it is.
+    Inactive(DmNameBuf, ThinDevId, Sectors),
+}
+
 #[derive(Debug)]
 pub struct StratFilesystem {
     fs_id: FilesystemUuid,
     name: String,
-    thin_dev: ThinDev,
+    thin_dev: FilesystemDev,
+    // The time of creation, in seconds since the Unix epoch
+    created: u64,
+    // The filesystem of which this filesystem is a snapshot, if any
+    origin: Option<FilesystemUuid>,
+    // The options the filesystem was made with
+    options: FsOptions,
+    // Whether writes to the filesystem's thin device are refused
+    read_only: bool,
+    // Whether the filesystem's thin device passes discards to the pool;
+    // None if never set, so that the kernel's default holds
+    discards: Option<bool>,
+    // The size past which the filesystem is never grown, if any
+    quota: Option<Sectors>,
 }
 
 pub enum FilesystemStatus {
@@ -38,27 +71,236 @@ pub enum FilesystemStatus {
     Failed,
 }
 
+/// Discrepancies between a filesystem's recorded state and the state of its
+/// thin device.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FilesystemCheckReport {
+    /// The thin device was activated, but no longer exists.
+    pub device_missing: bool,
+    /// Where the filesystem is still mounted, although its thin device no
+    /// longer exists.
+    pub orphaned_mounts: Vec<PathBuf>,
+    /// Where the filesystem is mounted on a directory which can no longer
+    /// be reached.
+    pub unreachable_mounts: Vec<PathBuf>,
+    /// The thin device exists, but has failed.
+    pub device_failed: bool,
+    /// The size of the thin device, if it is not the recorded size.
+    pub size_mismatch: Option<Sectors>,
+    /// Where the filesystem is mounted, if it is mounted although its thin
+    /// device was never activated.
+    pub unexpected_mount: Option<PathBuf>,
+}
+
+impl FilesystemCheckReport {
+    /// Whether no discrepancy was found.
+    pub fn is_consistent(&self) -> bool {
+        *self == FilesystemCheckReport::default()
+    }
+}
+
 impl StratFilesystem {
-    /// Create a StratFilesystem on top of the given ThinDev.
+    /// Create a StratFilesystem on top of the given ThinDev, making the
+    /// filesystem with options.
+    /// If the filesystem can not be created, destroy the ThinDev.
     pub fn initialize(fs_id: FilesystemUuid,
                       name: &str,
-                      thin_dev: ThinDev)
+                      dm: &DM,
+                      thin_pool: &ThinPoolDev,
+                      thin_dev: ThinDev,
+                      options: FsOptions)
                       -> EngineResult<StratFilesystem> {
-        let fs = StratFilesystem::setup(fs_id, name, thin_dev);
+        let created = Utc::now().timestamp() as u64;
+        let fs = StratFilesystem::setup(fs_id, name, thin_dev, created, None, options);
 
-        create_fs(&fs.devnode(), fs_id)?;
+        if let Err(err) = create_fs(&fs.devnode(), fs_id, &options) {
+            if let Err(destroy_err) = fs.destroy(dm, thin_pool) {
+                warn!("Could not destroy thin device of filesystem {}: {}",
+                      fs_id,
+                      destroy_err);
+            }
+            return Err(err);
+        }
         Ok(fs)
     }
 
     /// Build a StratFilesystem that includes the ThinDev and related info.
-    pub fn setup(fs_id: FilesystemUuid, name: &str, thin_dev: ThinDev) -> StratFilesystem {
+    /// created is the time of creation in seconds since the Unix epoch.
+    pub fn setup(fs_id: FilesystemUuid,
+                 name: &str,
+                 thin_dev: ThinDev,
+                 created: u64,
+                 origin: Option<FilesystemUuid>,
+                 options: FsOptions)
+                 -> StratFilesystem {
+        StratFilesystem {
+            fs_id: fs_id,
+            name: name.to_owned(),
+            thin_dev: FilesystemDev::Active(thin_dev),
+            created: created,
+            origin: origin,
+            options: options,
+            read_only: false,
+            discards: None,
+            quota: None,
+        }
+    }
+
+    /// Build a StratFilesystem whose thin device has not been activated.
+    /// It must be activated with activate() before it can be used.
+    #[allow(too_many_arguments)]
+    pub fn setup_inactive(fs_id: FilesystemUuid,
+                          name: &str,
+                          dm_name: &DmName,
+                          thin_id: ThinDevId,
+                          size: Sectors,
+                          created: u64,
+                          origin: Option<FilesystemUuid>,
+                          options: FsOptions)
+                          -> StratFilesystem {
         StratFilesystem {
             fs_id: fs_id,
             name: name.to_owned(),
-            thin_dev: thin_dev,
+            thin_dev: FilesystemDev::Inactive(dm_name.to_owned(), thin_id, size),
+            created: created,
+            origin: origin,
+            options: options,
+            read_only: false,
+            discards: None,
+            quota: None,
+        }
+    }
+
+    /// Activate the filesystem's thin device, if it is not already active.
+    pub fn activate(&mut self, dm: &DM, thin_pool: &ThinPoolDev) -> EngineResult<()> {
+        let thin_dev = match self.thin_dev {
+            FilesystemDev::Active(_) => return Ok(()),
+            FilesystemDev::Inactive(ref dm_name, thin_id, size) => {
+                ThinDev::setup(dm, dm_name, None, thin_pool, thin_id, size)?
+            }
+        };
+        if self.read_only {
+            set_blkdev_read_only(&thin_dev.devnode(), true)?;
+        }
+        if let Some(discards) = self.discards {
+            set_blkdev_discards(thin_dev.device(), discards)?;
+        }
+        self.thin_dev = FilesystemDev::Active(thin_dev);
+        Ok(())
+    }
+
+    /// Make the filesystem read-only, or writable again, by setting the
+    /// read-only flag of its thin device, remounting it wherever it is
+    /// mounted to match. The flag is set on a thin device which has not
+    /// been activated when it is activated.
+    /// Returns true if the filesystem was changed, false if it was already
+    /// as requested.
+    pub fn set_read_only(&mut self, read_only: bool) -> EngineResult<bool> {
+        if self.read_only == read_only {
+            return Ok(false);
+        }
+        if let FilesystemDev::Active(ref thin_dev) = self.thin_dev {
+            set_thin_dev_read_only(thin_dev, read_only)?;
+        }
+        self.read_only = read_only;
+        Ok(true)
+    }
+
+    /// Let the filesystem's thin device pass discards down to the pool, so
+    /// that the space of deleted files is returned to it, or refuse them.
+    /// The setting is applied to a thin device which has not been
+    /// activated when it is activated.
+    /// Returns true if the filesystem was changed, false if it was already
+    /// as requested.
+    pub fn set_discards(&mut self, enable: bool) -> EngineResult<bool> {
+        if self.discards == Some(enable) {
+            return Ok(false);
+        }
+        if let FilesystemDev::Active(ref thin_dev) = self.thin_dev {
+            set_blkdev_discards(thin_dev.device(), enable)?;
+        }
+        self.discards = Some(enable);
+        Ok(true)
+    }
+
+    /// Cap the size of the filesystem at limit, so that it is never grown
+    /// past it, or lift the cap if limit is None. Since the filesystem can
+    /// not be bigger than its thin device, writes which would take more
+    /// space than that fail.
+    /// Returns true if the filesystem was changed, false if it was already
+    /// as requested.
+    /// Returns an Invalid error if limit is less than the filesystem's size,
+    /// since the filesystem can not be shrunk to meet it.
+    pub fn set_quota(&mut self, limit: Option<Sectors>) -> EngineResult<bool> {
+        if self.quota == limit {
+            return Ok(false);
+        }
+        if let Some(limit) = limit {
+            if limit < self.size() {
+                let err_msg = format!("filesystem {} of size {} can not be limited to {}",
+                                      self.name,
+                                      self.size(),
+                                      limit);
+                return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        self.quota = limit;
+        Ok(true)
+    }
+
+    /// Mount the filesystem at mount_point with the comma-separated mount
+    /// options data, read-only if its thin device is.
+    /// Returns an Invalid error if the filesystem has not been activated.
+    pub fn mount(&self, mount_point: &Path, data: &str) -> EngineResult<()> {
+        let thin_dev = self.active_thin_dev()?;
+        let data = if data.is_empty() { None } else { Some(data) };
+        mount_fs(&thin_dev.devnode(),
+                 mount_point,
+                 self.options.fs_type,
+                 self.read_only,
+                 data)
+    }
+
+    /// Discard the unused blocks of the filesystem, returning their space to
+    /// the pool.
+    /// Returns an Invalid error if the filesystem is not mounted, since
+    /// only a mounted filesystem can be trimmed.
+    pub fn trim(&self) -> EngineResult<()> {
+        match self.mount_points()?.first() {
+            Some(mount_point) => fstrim(mount_point),
+            None => {
+                let err_msg = format!("filesystem {} is not mounted", self.name);
+                Err(EngineError::Engine(ErrorEnum::Invalid, err_msg))
+            }
+        }
+    }
+
+    /// Whether the filesystem's thin device has been activated.
+    pub fn is_active(&self) -> bool {
+        match self.thin_dev {
+            FilesystemDev::Active(_) => true,
+            FilesystemDev::Inactive(..) => false,
         }
     }
 
+    /// The filesystem's thin device.
+    /// Returns an Invalid error if it has not been activated.
+    fn active_thin_dev(&self) -> EngineResult<&ThinDev> {
+        match self.thin_dev {
+            FilesystemDev::Active(ref thin_dev) => Ok(thin_dev),
+            FilesystemDev::Inactive(..) => {
+                let err_msg = format!("filesystem {} has not been activated", self.name);
+                Err(EngineError::Engine(ErrorEnum::Invalid, err_msg))
+            }
+        }
+    }
+
+    /// The options the filesystem was made with. A snapshot shares the
+    /// options of its origin.
+    pub fn options(&self) -> FsOptions {
+        self.options
+    }
+
     /// Create a snapshot of the filesystem. Return the resulting filesystem/ThinDev
     /// to the caller.  Use snapshot_name for the Stratis filesytem name.  Use
     /// snapshot_dmname for the new name of the ThinDev allocated for the snapshot.
@@ -74,7 +316,7 @@ impl StratFilesystem {
                     snapshot_thin_id: ThinDevId)
                     -> EngineResult<StratFilesystem> {
 
-        match self.thin_dev
+        match self.active_thin_dev()?
                   .snapshot(dm, thin_pool, snapshot_dmname, snapshot_thin_id) {
             Ok(thin_dev) => {
                 // If the source is mounted, XFS puts a dummy record in the
@@ -85,20 +327,18 @@ impl StratFilesystem {
                 // we mount/unmount the filesystem before updating the UUID.
                 //
                 // If the source is unmounted the XFS log will be clean so
-                // we can skip the mount/unmount.
-                if self.get_mount_point()?.is_some() {
-                    let tmp_dir = TempDir::new("stratis_mp_")?;
-                    // Mount the snapshot with the "nouuid" option. mount
-                    // will fail due to duplicate UUID otherwise.
-                    mount(Some(&thin_dev.devnode()),
-                          tmp_dir.path(),
-                          Some("xfs"),
-                          MsFlags::empty(),
-                          Some("nouuid"))?;
-                    umount(tmp_dir.path())?;
+                // we can skip the mount/unmount. The same holds for the
+                // ext4 journal, which tune2fs requires to be recovered.
+                if self.is_mounted()? {
+                    replay_log(&thin_dev.devnode(), self.options.fs_type)?;
                 }
-                set_uuid(&thin_dev.devnode(), snapshot_fs_uuid)?;
-                Ok(StratFilesystem::setup(snapshot_fs_uuid, snapshot_name, thin_dev))
+                set_uuid(&thin_dev.devnode(), self.options.fs_type, snapshot_fs_uuid)?;
+                Ok(StratFilesystem::setup(snapshot_fs_uuid,
+                                          snapshot_name,
+                                          thin_dev,
+                                          Utc::now().timestamp() as u64,
+                                          Some(self.fs_id),
+                                          self.options))
             }
             Err(e) => {
                 Err(EngineError::Engine(ErrorEnum::Error,
@@ -110,20 +350,123 @@ impl StratFilesystem {
         }
     }
 
+    /// Create a copy of the filesystem on a new thin device, with the
+    /// given name, dm name, UUID, and thin id. Unlike a snapshot, the copy
+    /// shares no blocks with the filesystem, and has no origin.
+    /// The copy is read from a temporary snapshot, with source_dmname and
+    /// source_thin_id, so that the filesystem is only suspended for as long
+    /// as it takes to make the snapshot. Only the range of the snapshot
+    /// which is mapped is read.
+    /// If the copy can not be completed, its thin device is destroyed.
+    #[allow(too_many_arguments)]
+    pub fn copy(&self,
+                dm: &DM,
+                thin_pool: &ThinPoolDev,
+                copy_name: &str,
+                copy_dmname: &DmName,
+                copy_fs_uuid: FilesystemUuid,
+                copy_thin_id: ThinDevId,
+                source_dmname: &DmName,
+                source_thin_id: ThinDevId)
+                -> EngineResult<StratFilesystem> {
+        let origin = self.active_thin_dev()?;
+        let mounted = self.is_mounted()?;
+        let source = origin.snapshot(dm, thin_pool, source_dmname, source_thin_id)?;
+        let thin_dev = match ThinDev::new(dm,
+                                          copy_dmname,
+                                          None,
+                                          thin_pool,
+                                          copy_thin_id,
+                                          origin.size()) {
+            Ok(thin_dev) => thin_dev,
+            Err(err) => {
+                if let Err(destroy_err) = source.destroy(dm, thin_pool) {
+                    warn!("Could not destroy temporary snapshot of filesystem {}: {}",
+                          self.name,
+                          destroy_err);
+                }
+                return Err(err.into());
+            }
+        };
+
+        let copied = source
+            .status(dm)
+            .map_err(EngineError::from)
+            .and_then(|status| match status {
+                          ThinStatus::Good((_, Some(highest))) => {
+                              Ok(min(highest + Sectors(1), source.size()))
+                          }
+                          ThinStatus::Good((_, None)) => Ok(Sectors(0)),
+                          ThinStatus::Fail => {
+                              let err_msg = format!("thin device {} failed", source_dmname);
+                              Err(EngineError::Engine(ErrorEnum::Error, err_msg))
+                          }
+                      })
+            .and_then(|length| {
+                          copy_device_sparse(&source.devnode(), &thin_dev.devnode(), length)
+                      });
+        if let Err(destroy_err) = source.destroy(dm, thin_pool) {
+            warn!("Could not destroy temporary snapshot of filesystem {}: {}",
+                  self.name,
+                  destroy_err);
+        }
+
+        let result = copied.and_then(|_| {
+            // As for a snapshot, the log of a copy of a mounted
+            // filesystem must be replayed before its UUID can be set.
+            if mounted {
+                replay_log(&thin_dev.devnode(), self.options.fs_type)?;
+            }
+            set_uuid(&thin_dev.devnode(), self.options.fs_type, copy_fs_uuid)
+        });
+        if let Err(err) = result {
+            if let Err(destroy_err) = thin_dev.destroy(dm, thin_pool) {
+                warn!("Could not destroy thin device of copy {} of filesystem {}: {}",
+                      copy_name,
+                      self.name,
+                      destroy_err);
+            }
+            return Err(EngineError::Engine(ErrorEnum::Error,
+                                           format!("failed to copy {} to {} - {}",
+                                                   self.name,
+                                                   copy_name,
+                                                   err)));
+        }
+
+        Ok(StratFilesystem::setup(copy_fs_uuid,
+                                  copy_name,
+                                  thin_dev,
+                                  Utc::now().timestamp() as u64,
+                                  None,
+                                  self.options))
+    }
+
     /// check if filesystem is getting full and needs to be extended
     /// TODO: deal with the thindev in a Fail state.
+    /// A filesystem which has not been activated is not checked.
     pub fn check(&mut self, dm: &DM) -> EngineResult<FilesystemStatus> {
-        match self.thin_dev.status(dm)? {
+        let thin_dev = match self.thin_dev {
+            FilesystemDev::Active(ref mut thin_dev) => thin_dev,
+            FilesystemDev::Inactive(..) => return Ok(FilesystemStatus::Good),
+        };
+        match thin_dev.status(dm)? {
             ThinStatus::Good(_) => {
-                if let Some(mount_point) = self.get_mount_point()? {
+                if let Some(mount_point) = mount_points_of(thin_dev.device())?.pop() {
                     let (fs_total_bytes, fs_total_used_bytes) = fs_usage(&mount_point)?;
                     let free_bytes = fs_total_bytes - fs_total_used_bytes;
                     if free_bytes.sectors() < FILESYSTEM_LOWATER {
-                        let extend_size = self.extend_size(self.thin_dev.size());
-                        if self.thin_dev.extend(dm, extend_size).is_err() {
+                        let size = thin_dev.size();
+                        // A filesystem is not extended past its quota.
+                        let extend_size = match self.quota {
+                            Some(quota) if quota <= size => return Ok(FilesystemStatus::Good),
+                            Some(quota) => min(extend_size(size), quota - size),
+                            None => extend_size(size),
+                        };
+                        if thin_dev.extend(dm, extend_size).is_err() {
                             return Ok(FilesystemStatus::ThinDevExtendFailed);
                         }
-                        if xfs_growfs(&mount_point).is_err() {
+                        if grow_fs(self.options.fs_type, &thin_dev.devnode(), &mount_point)
+                               .is_err() {
                             return Ok(FilesystemStatus::XfsGrowFailed);
                         }
                     }
@@ -136,63 +479,334 @@ impl StratFilesystem {
         Ok(FilesystemStatus::Good)
     }
 
+    /// The percentage of the filesystem's space which is used, or None if
+    /// the filesystem is not active or not mounted, so that its usage can
+    /// not be found.
+    pub fn used_percent(&self) -> EngineResult<Option<u8>> {
+        let thin_dev = match self.thin_dev {
+            FilesystemDev::Active(ref thin_dev) => thin_dev,
+            FilesystemDev::Inactive(..) => return Ok(None),
+        };
+        match mount_points_of(thin_dev.device())?.pop() {
+            Some(mount_point) => {
+                let (total, used) = fs_usage(&mount_point)?;
+                if *total == 0 {
+                    return Ok(None);
+                }
+                Ok(Some((*used * 100 / *total) as u8))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// How much of the filesystem's thin device is backed by the pool's
+    /// data blocks, of data_block_size sectors each, according to the
+    /// mapping status of the device; None if the filesystem has not been
+    /// activated, so that its status can not be found.
+    /// Returns an error if the thin device has failed.
+    pub fn allocation_info(&self,
+                           dm: &DM,
+                           data_block_size: Sectors)
+                           -> EngineResult<Option<AllocationInfo>> {
+        let thin_dev = match self.thin_dev {
+            FilesystemDev::Active(ref thin_dev) => thin_dev,
+            FilesystemDev::Inactive(..) => return Ok(None),
+        };
+        let (mapped, highest_mapped) = match thin_dev.status(dm)? {
+            ThinStatus::Good(status) => status,
+            ThinStatus::Fail => {
+                let err_msg = format!("thin device of filesystem {} has failed", self.name);
+                return Err(EngineError::Engine(ErrorEnum::Error, err_msg));
+            }
+        };
+        let allocated_blocks = *mapped / *data_block_size;
+        // The blocks from the first up to the highest allocated block
+        let spanned_blocks = highest_mapped.map_or(0, |highest| *highest / *data_block_size + 1);
+        let fragmentation = match (allocated_blocks * 100).checked_div(spanned_blocks) {
+            Some(percent) => (100 - percent.min(100)) as u8,
+            None => 0,
+        };
+        Ok(Some(AllocationInfo {
+                    size: thin_dev.size(),
+                    allocated_blocks: DataBlocks(allocated_blocks),
+                    allocated: mapped,
+                    fragmentation: fragmentation,
+                }))
+    }
+
+    /// Check that the filesystem's thin device is as recorded. A thin device
+    /// which was activated must exist, must not have failed, and must have
+    /// the recorded size; a filesystem whose thin device was never
+    /// activated must not be mounted. The filesystem's mount points must
+    /// be reachable, and it must not remain mounted once its thin device
+    /// is gone.
+    /// Discrepancies are reported, not repaired.
+    pub fn check_consistency(&self, dm: &DM) -> EngineResult<FilesystemCheckReport> {
+        let mut report = FilesystemCheckReport::default();
+        match self.thin_dev {
+            FilesystemDev::Active(ref thin_dev) => {
+                let mount_points = mount_points_of(thin_dev.device())?;
+                if !device_exists(dm, thin_dev.name())? {
+                    report.device_missing = true;
+                    report.orphaned_mounts = mount_points;
+                    return Ok(report);
+                }
+                report.unreachable_mounts = mount_points
+                    .into_iter()
+                    .filter(|mount_point| !mount_point.is_dir())
+                    .collect();
+                if let ThinStatus::Fail = thin_dev.status(dm)? {
+                    report.device_failed = true;
+                }
+                let size = table_size(dm, thin_dev)?;
+                if size != thin_dev.size() {
+                    report.size_mismatch = Some(size);
+                }
+            }
+            FilesystemDev::Inactive(..) => {
+                report.unexpected_mount = mount_point_of(&self.devnode())?;
+            }
+        }
+        Ok(report)
+    }
+
+    /// The size of the filesystem's thin device, as devicemapper reports
+    /// it, or None if the device has not been activated or no longer
+    /// exists.
+    pub fn device_size(&self, dm: &DM) -> EngineResult<Option<Sectors>> {
+        match self.thin_dev {
+            FilesystemDev::Active(ref thin_dev) => {
+                if device_exists(dm, thin_dev.name())? {
+                    Ok(Some(table_size(dm, thin_dev)?))
+                } else {
+                    Ok(None)
+                }
+            }
+            FilesystemDev::Inactive(..) => Ok(None),
+        }
+    }
+
+    /// Grow the filesystem, and the thin device on which it resides, to
+    /// size. If the filesystem is not mounted, it is mounted on a temporary
+    /// directory while it is grown.
+    /// The new size is saved with save once the thin device has been
+    /// extended, and before the filesystem is grown, so that the size saved
+    /// is never less than the size of the filesystem. If the size can not
+    /// be saved, or the filesystem can not be grown, the thin device is
+    /// reduced to its former size, and that size is saved again.
+    /// Returns true if the filesystem was grown, false if it was already
+    /// size. Returns an Invalid error if size is less than the filesystem's
+    /// size, since filesystems are never shrunk, if size is more than the
+    /// filesystem's quota, or if the filesystem has not been activated.
+    pub fn grow(&mut self,
+                dm: &DM,
+                thin_pool: &ThinPoolDev,
+                size: Sectors,
+                save: &Fn(&StratFilesystem) -> EngineResult<()>)
+                -> EngineResult<bool> {
+        let current_size = self.active_thin_dev()?.size();
+        if size < current_size {
+            let err_msg = format!("filesystem {} can not be shrunk from {} to {}",
+                                  self.name,
+                                  current_size,
+                                  size);
+            return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+        if let Some(quota) = self.quota {
+            if size > quota {
+                let err_msg = format!("filesystem {} can not be grown to {} past its quota {}",
+                                      self.name,
+                                      size,
+                                      quota);
+                return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+        }
+        if size == current_size {
+            return Ok(false);
+        }
+
+        let fs_type = self.options.fs_type;
+        let devnode = self.devnode();
+        let mut tmp_dir = None;
+        let mount_point = match self.mount_points()?.pop() {
+            Some(mount_point) => mount_point,
+            None => {
+                let dir = TempDir::new("stratis_mp_")?;
+                mount_fs(&devnode, dir.path(), fs_type, false, None)?;
+                let mount_point = dir.path().to_owned();
+                tmp_dir = Some(dir);
+                mount_point
+            }
+        };
+
+        let mut result = Ok(());
+        if let FilesystemDev::Active(ref mut thin_dev) = self.thin_dev {
+            result = thin_dev.extend(dm, size - current_size).map_err(EngineError::from);
+        }
+        if result.is_ok() {
+            result = save(self)
+                .and_then(|_| fail_point("StratFilesystem::grow"))
+                .and_then(|_| grow_fs(fs_type, &devnode, &mount_point));
+            if result.is_err() {
+                if let Err(err) = self.reduce(dm, thin_pool, current_size)
+                       .and_then(|_| save(self)) {
+                    warn!("Could not restore size {} of filesystem {}: {}",
+                          current_size,
+                          self.name,
+                          err);
+                }
+            }
+        }
+
+        if let Some(tmp_dir) = tmp_dir {
+            unmount_fs(tmp_dir.path())?;
+        }
+        result?;
+        Ok(true)
+    }
+
+    /// Reduce the thin device of the filesystem to size, which must be no
+    /// less than the size of the filesystem on it, by reloading its table.
+    fn reduce(&mut self, dm: &DM, thin_pool: &ThinPoolDev, size: Sectors) -> EngineResult<()> {
+        if let FilesystemDev::Active(ref mut thin_dev) = self.thin_dev {
+            let table = vec![TargetLine {
+                                 start: Sectors(0),
+                                 length: size,
+                                 target_type: TargetTypeBuf::new("thin".into())
+                                     .expect("valid target type"),
+                                 params: format!("{} {}", thin_pool.device(), thin_dev.id()),
+                             }];
+            let id = DevId::Name(thin_dev.name());
+            dm.table_load(&id, &table)?;
+            dm.device_suspend(&id, DM_SUSPEND)?;
+            dm.device_suspend(&id, DmFlags::empty())?;
+            *thin_dev = ThinDev::setup(dm, thin_dev.name(), None, thin_pool, thin_dev.id(), size)?;
+        }
+        Ok(())
+    }
+
     /// The thin id for the thin device that backs this filesystem.
-    #[cfg(test)]
     pub fn thin_id(&self) -> ThinDevId {
-        self.thin_dev.id()
-    }
-
-    /// Return an extend size for the thindev under the filesystem
-    /// TODO: returning the current size will double the space provisoned to
-    /// the thin device.  We should determine if this is a reasonable value.
-    fn extend_size(&self, current_size: Sectors) -> Sectors {
-        current_size
-    }
-
-    /// Get one (non-deterministic in the presence of errors) of the mount_point(s) for the file
-    /// system that is contained on the block device referred to as self.devnode(), i.e. the device
-    /// node, while ignoring parse errors as long as at least one mount point is found.
-    pub fn get_mount_point(&self) -> EngineResult<Option<PathBuf>> {
-        let device_node = self.devnode();
-        let search = device_node.to_str().ok_or_else(|| EngineError::Engine(ErrorEnum::Error,
-                                    format!("Unable to represent devnode as string {:?}", *self)))?;
-
-        let m_iter = MountIter::new_from_proc()
-            .map_err(|e| {
-                         EngineError::Engine(ErrorEnum::Error,
-                                             format!("Error reading /proc/mounts {:?}", e))
-                     })?;
-
-        let mut last_error: Option<String> = None;
-        for mp in m_iter {
-            match mp {
-                Ok(mount) => {
-                    if mount.contains(&MountParam::Spec(search)) {
-                        return Ok(Some(mount.file));
+        match self.thin_dev {
+            FilesystemDev::Active(ref thin_dev) => thin_dev.id(),
+            FilesystemDev::Inactive(_, thin_id, _) => thin_id,
+        }
+    }
+
+    /// The mount points of the filesystem, in the order in which the mounts
+    /// were made, as recorded by the kernel. A filesystem may be mounted in
+    /// several places, including by others than stratisd.
+    /// A filesystem which has not been activated can not be mounted.
+    pub fn mount_points(&self) -> EngineResult<Vec<PathBuf>> {
+        match self.thin_dev {
+            FilesystemDev::Active(ref thin_dev) => mount_points_of(thin_dev.device()),
+            FilesystemDev::Inactive(..) => Ok(vec![]),
+        }
+    }
+
+    /// The processes which hold files open on the filesystem, and so
+    /// prevent it from being unmounted.
+    pub fn list_openers(&self) -> EngineResult<Vec<PidInfo>> {
+        match self.thin_dev {
+            FilesystemDev::Active(ref thin_dev) => openers_of(thin_dev.device()),
+            FilesystemDev::Inactive(..) => Ok(vec![]),
+        }
+    }
+
+    /// Unmount the filesystem from all its mount points.
+    /// Returns a Busy error if the filesystem is in use and can not be
+    /// unmounted; its message names the processes which are using it.
+    pub fn unmount(&self) -> EngineResult<()> {
+        while let Some(mount_point) = self.mount_points()?.pop() {
+            match unmount_fs(&mount_point) {
+                Ok(_) => {}
+                Err(EngineError::Nix(nix::Error::Sys(Errno::EBUSY))) => {
+                    let mut err_msg = format!("filesystem {} is busy at {:?}",
+                                              self.name,
+                                              mount_point);
+                    match self.list_openers() {
+                        Ok(ref openers) if !openers.is_empty() => {
+                            let openers = openers
+                                .iter()
+                                .map(|info| info.to_string())
+                                .collect::<Vec<_>>();
+                            err_msg.push_str(&format!(", held open by {}", openers.join(", ")));
+                        }
+                        Ok(_) => {}
+                        Err(err) => {
+                            warn!("Could not find openers of filesystem {}: {}", self.name, err)
+                        }
                     }
+                    return Err(EngineError::Engine(ErrorEnum::Busy, err_msg));
                 }
-                Err(e) => {
-                    last_error = Some(format!("Error during parsing {:?} {:?}", *self, e));
-                }
+                Err(err) => return Err(err),
             }
         }
+        Ok(())
+    }
 
-        last_error.map_or(Ok(None), |e| Err(EngineError::Engine(ErrorEnum::Error, e)))
+    /// Detach the filesystem from all its mount points, even if it is in
+    /// use. The unmounts complete once it is no longer in use.
+    pub fn unmount_lazy(&self) -> EngineResult<()> {
+        while let Some(mount_point) = self.mount_points()?.pop() {
+            unmount_fs_lazy(&mount_point)?;
+        }
+        Ok(())
+    }
+
+    /// Freeze the filesystem, if it is mounted, so that it is consistent on
+    /// disk and writes to it block until it is thawed.
+    /// Returns true if the filesystem was frozen, false if it is not
+    /// mounted.
+    pub fn freeze(&self) -> EngineResult<bool> {
+        match self.mount_points()?.pop() {
+            Some(mount_point) => {
+                freeze_fs(&mount_point)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Thaw the filesystem, if it is mounted and frozen.
+    pub fn thaw(&self) -> EngineResult<()> {
+        match self.mount_points()?.pop() {
+            Some(mount_point) => {
+                match thaw_fs(&mount_point) {
+                    Err(EngineError::Nix(nix::Error::Sys(Errno::EINVAL))) => Ok(()),
+                    result => result,
+                }
+            }
+            None => Ok(()),
+        }
     }
 
     /// Tear down the filesystem.
     pub fn teardown(self, dm: &DM) -> EngineResult<()> {
-        Ok(self.thin_dev.teardown(dm)?)
+        match self.thin_dev {
+            FilesystemDev::Active(thin_dev) => Ok(thin_dev.teardown(dm)?),
+            FilesystemDev::Inactive(..) => Ok(()),
+        }
     }
 
-    /// Set the name of this filesystem to name.
-    pub fn rename(&mut self, name: &str) {
-        self.name = name.to_owned();
+    /// Remove the thin device of the filesystem once it is no longer in use,
+    /// without deleting it from the thin pool.
+    pub fn remove_deferred(self, dm: &DM) -> EngineResult<()> {
+        if let FilesystemDev::Active(thin_dev) = self.thin_dev {
+            dm.device_remove(&DevId::Name(thin_dev.name()), DM_DEFERRED_REMOVE)?;
+        }
+        Ok(())
     }
 
     /// Destroy the filesystem.
+    /// Precondition: the filesystem is not mounted.
     pub fn destroy(self, dm: &DM, thin_pool: &ThinPoolDev) -> EngineResult<()> {
-        Ok(self.thin_dev.destroy(dm, thin_pool)?)
+        match self.thin_dev {
+            FilesystemDev::Active(thin_dev) => Ok(thin_dev.destroy(dm, thin_pool)?),
+            FilesystemDev::Inactive(_, thin_id, _) => {
+                Ok(thin_pool.message(dm, &format!("delete {}", thin_id))?)
+            }
+        }
     }
 }
 
@@ -202,6 +816,12 @@ impl HasName for StratFilesystem {
     }
 }
 
+impl SetName for StratFilesystem {
+    fn set_name(&mut self, name: &str) {
+        self.name = name.to_owned();
+    }
+}
+
 impl HasUuid for StratFilesystem {
     fn uuid(&self) -> FilesystemUuid {
         self.fs_id
@@ -209,8 +829,47 @@ impl HasUuid for StratFilesystem {
 }
 
 impl Filesystem for StratFilesystem {
+    /// The device node of a filesystem which has not been activated is the
+    /// one it will have when it is.
     fn devnode(&self) -> PathBuf {
-        self.thin_dev.devnode()
+        match self.thin_dev {
+            FilesystemDev::Active(ref thin_dev) => thin_dev.devnode(),
+            FilesystemDev::Inactive(ref dm_name, _, _) => {
+                Path::new("/dev/mapper").join(dm_name.to_string())
+            }
+        }
+    }
+
+    fn created(&self) -> DateTime<Utc> {
+        Utc.timestamp(self.created as i64, 0)
+    }
+
+    fn is_mounted(&self) -> EngineResult<bool> {
+        Ok(!self.mount_points()?.is_empty())
+    }
+
+    fn read_only(&self) -> bool {
+        self.read_only
+    }
+
+    fn discards(&self) -> Option<bool> {
+        self.discards
+    }
+
+    fn snapshot_of(&self) -> Option<FilesystemUuid> {
+        self.origin
+    }
+
+    /// The size of the filesystem's thin device, as the engine knows it.
+    fn size(&self) -> Sectors {
+        match self.thin_dev {
+            FilesystemDev::Active(ref thin_dev) => thin_dev.size(),
+            FilesystemDev::Inactive(_, _, size) => size,
+        }
+    }
+
+    fn quota(&self) -> Option<Sectors> {
+        self.quota
     }
 }
 
@@ -219,10 +878,104 @@ impl Recordable<FilesystemSave> for StratFilesystem {
         FilesystemSave {
             name: self.name.clone(),
             uuid: self.fs_id,
-            thin_id: self.thin_dev.id(),
-            size: self.thin_dev.size(),
+            thin_id: self.thin_id(),
+            size: self.size(),
+            created: self.created,
+            origin: self.origin,
+            fs_type: match self.options.fs_type {
+                FilesystemType::Xfs => None,
+                fs_type => Some(fs_type.as_str().to_owned()),
+            },
+            block_size: self.options.block_size,
+            inode_size: self.options.inode_size,
+            reflink: self.options.reflink,
+            read_only: self.read_only,
+            discards: self.discards,
+            quota: self.quota,
+        }
+    }
+}
+
+/// Make thin_dev read-only, or writable again, remounting it wherever it is
+/// mounted to match.
+fn set_thin_dev_read_only(thin_dev: &ThinDev, read_only: bool) -> EngineResult<()> {
+    let mount_points = mount_points_of(thin_dev.device())?;
+    // A mounted filesystem must be read-only before its device is, and its
+    // device writable before it is.
+    if read_only {
+        for mount_point in &mount_points {
+            remount_fs(mount_point, true)?;
+        }
+        set_blkdev_read_only(&thin_dev.devnode(), true)?;
+    } else {
+        set_blkdev_read_only(&thin_dev.devnode(), false)?;
+        for mount_point in &mount_points {
+            remount_fs(mount_point, false)?;
         }
     }
+    Ok(())
+}
+
+/// The size of thin_dev according to its devicemapper table.
+fn table_size(dm: &DM, thin_dev: &ThinDev) -> EngineResult<Sectors> {
+    let (_, table) = dm.table_status(&DevId::Name(thin_dev.name()), DM_STATUS_TABLE)?;
+    Ok(table.iter().map(|line| line.length).sum::<Sectors>())
+}
+
+/// Return an extend size for the thindev under the filesystem
+/// TODO: returning the current size will double the space provisoned to
+/// the thin device.  We should determine if this is a reasonable value.
+fn extend_size(current_size: Sectors) -> Sectors {
+    current_size
+}
+
+/// Mount the filesystem of type fs_type on devnode on a temporary directory,
+/// and unmount it again, so that its log is replayed.
+fn replay_log(devnode: &Path, fs_type: FilesystemType) -> EngineResult<()> {
+    let tmp_dir = TempDir::new("stratis_mp_")?;
+    // An XFS filesystem must be mounted with the "nouuid" option, since
+    // mount fails due to duplicate UUID otherwise.
+    let data = match fs_type {
+        FilesystemType::Xfs => Some("nouuid"),
+        FilesystemType::Ext4 => None,
+    };
+    mount_fs(devnode, tmp_dir.path(), fs_type, false, data)?;
+    unmount_fs(tmp_dir.path())
+}
+
+/// Get one (non-deterministic in the presence of errors) of the mount points
+/// of the device node devnode, while ignoring parse errors as long as at
+/// least one mount point is found.
+fn mount_point_of(devnode: &Path) -> EngineResult<Option<PathBuf>> {
+    let search = devnode
+        .to_str()
+        .ok_or_else(|| {
+                        EngineError::Engine(ErrorEnum::Error,
+                                            format!("Unable to represent devnode as string {:?}",
+                                                    devnode))
+                    })?;
+
+    let m_iter = MountIter::new_from_proc()
+        .map_err(|e| {
+                     EngineError::Engine(ErrorEnum::Error,
+                                         format!("Error reading /proc/mounts {:?}", e))
+                 })?;
+
+    let mut last_error: Option<String> = None;
+    for mp in m_iter {
+        match mp {
+            Ok(mount) => {
+                if mount.contains(&MountParam::Spec(search)) {
+                    return Ok(Some(mount.file));
+                }
+            }
+            Err(e) => {
+                last_error = Some(format!("Error during parsing {:?} {:?}", devnode, e));
+            }
+        }
+    }
+
+    last_error.map_or(Ok(None), |e| Err(EngineError::Engine(ErrorEnum::Error, e)))
 }
 
 /// Return total bytes allocated to the filesystem, total bytes used by data/metadata
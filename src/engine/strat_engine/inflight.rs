@@ -0,0 +1,122 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Tracking of the pools and devices which operations in flight are
+// changing, so that an operation which would change any of them while
+// another operation is still changing it is refused, rather than racing
+// with the other operation.
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use super::super::errors::{EngineError, EngineResult, ErrorEnum};
+use super::super::types::PoolUuid;
+
+/// A pool or device which an operation changes.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub enum Resource {
+    Pool(PoolUuid),
+    Device(PathBuf),
+}
+
+impl Resource {
+    /// The device at path. A path which is a symbolic link is resolved to
+    /// its target, if it exists, so that a device is the same resource by
+    /// whichever path it is given.
+    pub fn device(path: &Path) -> Resource {
+        Resource::Device(fs::canonicalize(path).unwrap_or_else(|_| path.to_owned()))
+    }
+}
+
+/// The resources claimed by the operations in flight.
+#[derive(Debug, Default)]
+pub struct InFlight {
+    claimed: Rc<RefCell<HashSet<Resource>>>,
+}
+
+impl InFlight {
+    /// Claim resources for an operation, until the returned guard is
+    /// dropped, which happens however the operation ends.
+    /// Returns a Busy error, and claims nothing, if any of the resources is
+    /// already claimed.
+    pub fn claim(&self, resources: Vec<Resource>) -> EngineResult<InFlightGuard> {
+        let mut claimed = self.claimed.borrow_mut();
+        if let Some(resource) = resources.iter().find(|r| claimed.contains(r)) {
+            let err_msg = format!("{:?} is being changed by another operation", resource);
+            return Err(EngineError::Engine(ErrorEnum::Busy, err_msg));
+        }
+        claimed.extend(resources.iter().cloned());
+        Ok(InFlightGuard {
+               resources: resources,
+               claimed: Rc::clone(&self.claimed),
+           })
+    }
+}
+
+/// The claim of an operation in flight on its resources, which are
+/// released when it is dropped.
+#[derive(Debug)]
+pub struct InFlightGuard {
+    resources: Vec<Resource>,
+    claimed: Rc<RefCell<HashSet<Resource>>>,
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        let mut claimed = self.claimed.borrow_mut();
+        for resource in &self.resources {
+            claimed.remove(resource);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    #[test]
+    /// A resource which is claimed can not be claimed again until it is
+    /// released; other resources can be.
+    fn claim_conflicts() {
+        let in_flight = InFlight::default();
+        let pool_uuid = Uuid::new_v4();
+        let guard = in_flight
+            .claim(vec![Resource::Pool(pool_uuid), Resource::device(Path::new("/s/d"))])
+            .unwrap();
+
+        assert!(match in_flight.claim(vec![Resource::device(Path::new("/s/e")),
+                                           Resource::device(Path::new("/s/d"))]) {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+        // The refused claim claimed nothing.
+        let other = in_flight
+            .claim(vec![Resource::device(Path::new("/s/e"))])
+            .unwrap();
+
+        drop(guard);
+        in_flight
+            .claim(vec![Resource::Pool(pool_uuid), Resource::device(Path::new("/s/d"))])
+            .unwrap();
+        drop(other);
+    }
+
+    #[test]
+    /// The resources of an operation which fails are released.
+    fn released_on_error() {
+        let in_flight = InFlight::default();
+        let pool_uuid = Uuid::new_v4();
+        let operation = || -> EngineResult<()> {
+            let _guard = in_flight.claim(vec![Resource::Pool(pool_uuid)])?;
+            Err(EngineError::Engine(ErrorEnum::Error, "failed".into()))
+        };
+        assert!(operation().is_err());
+        assert!(in_flight.claim(vec![Resource::Pool(pool_uuid)]).is_ok());
+    }
+}
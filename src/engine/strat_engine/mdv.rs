@@ -5,34 +5,36 @@
 // Manage the linear volume that stores metadata on pool levels 5-7.
 
 use std::convert::From;
-use std::fs::{create_dir, OpenOptions, read_dir, remove_file, rename};
+use std::ffi::OsStr;
+use std::fs::{create_dir, File, OpenOptions, read_dir, remove_file, rename};
 use std::io::ErrorKind;
 use std::io::prelude::*;
 use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
 
 use nix;
-use nix::mount::{MsFlags, mount, umount};
 use nix::unistd::fsync;
 use serde_json;
 
-use devicemapper::{DmDevice, DM, LinearDev};
+use devicemapper::{DmDevice, DM, LinearDev, Segment};
 
-use super::super::engine::HasUuid;
-use super::super::errors::EngineResult;
-use super::super::types::{FilesystemUuid, PoolUuid};
+use super::super::engine::{HasUuid, StratisPaths};
+use super::super::errors::{EngineError, EngineResult, ErrorEnum};
+use super::super::types::{FilesystemType, FilesystemUuid, FsOptions, PoolUuid};
 
 use super::filesystem::StratFilesystem;
+use super::mount::{mount_fs, unmount_fs};
 use super::serde_structs::{FilesystemSave, Recordable};
-use super::util::create_fs;
+use super::util::{create_fs, xfs_is_damaged, xfs_repair};
 
 // TODO: Monitor fs size and extend linear and fs if needed
 // TODO: Document format of stuff on MDV in SWDD (currently ad-hoc)
 
-const DEV_PATH: &str = "/dev/stratis";
-
 const FILESYSTEM_DIR: &str = "filesystems";
 
+/// The extension of the temp files written while saving.
+const TEMP_EXTENSION: &str = "temp";
+
 #[derive(Debug)]
 pub struct MetadataVol {
     dev: LinearDev,
@@ -49,12 +51,8 @@ struct MountedMDV<'a> {
 impl<'a> MountedMDV<'a> {
     /// Borrow the MDV and ensure it's mounted.
     fn mount(mdv: &MetadataVol) -> EngineResult<MountedMDV> {
-        match mount(Some(&mdv.dev.devnode()),
-                    &mdv.mount_pt,
-                    Some("xfs"),
-                    MsFlags::empty(),
-                    None as Option<&str>) {
-            Err(nix::Error::Sys(nix::Errno::EBUSY)) => {
+        match mount_fs(&mdv.dev.devnode(), &mdv.mount_pt, FilesystemType::Xfs, false, None) {
+            Err(EngineError::Nix(nix::Error::Sys(nix::Errno::EBUSY))) => {
                 // The device is already mounted at the specified mountpoint
                 Ok(())
             }
@@ -72,29 +70,40 @@ impl<'a> MountedMDV<'a> {
 
 impl<'a> Drop for MountedMDV<'a> {
     fn drop(&mut self) {
-        if let Err(err) = umount(&self.mdv.mount_pt) {
+        if let Err(err) = unmount_fs(&self.mdv.mount_pt) {
             warn!("Could not unmount MDV: {}", err)
         }
     }
 }
 
 impl MetadataVol {
-    /// Initialize a new Metadata Volume.
-    pub fn initialize(pool_uuid: PoolUuid, dev: LinearDev) -> EngineResult<MetadataVol> {
-        create_fs(&dev.devnode(), pool_uuid)?;
-        MetadataVol::setup(pool_uuid, dev)
+    /// Initialize a new Metadata Volume, which is mounted in the Stratis
+    /// /dev directory of paths.
+    pub fn initialize(pool_uuid: PoolUuid,
+                      dev: LinearDev,
+                      paths: &StratisPaths)
+                      -> EngineResult<MetadataVol> {
+        create_fs(&dev.devnode(), pool_uuid, &FsOptions::default())?;
+        MetadataVol::setup(pool_uuid, dev, paths, false)
     }
 
-    /// Set up an existing Metadata Volume.
-    pub fn setup(pool_uuid: PoolUuid, dev: LinearDev) -> EngineResult<MetadataVol> {
-        if let Err(err) = create_dir(DEV_PATH) {
+    /// Set up an existing Metadata Volume, which is mounted in the Stratis
+    /// /dev directory of paths.
+    /// If the MDV can not be mounted and xfs_repair finds its filesystem
+    /// damaged, return an ErrorEnum::Corrupt error. If repair is true,
+    /// first attempt to repair the filesystem and mount it again.
+    pub fn setup(pool_uuid: PoolUuid,
+                 dev: LinearDev,
+                 paths: &StratisPaths,
+                 repair: bool)
+                 -> EngineResult<MetadataVol> {
+        if let Err(err) = create_dir(paths.base()) {
             if err.kind() != ErrorKind::AlreadyExists {
                 return Err(From::from(err));
             }
         }
 
-        let filename = format!(".mdv-{}", pool_uuid.simple());
-        let mount_pt: PathBuf = vec![DEV_PATH, &filename].iter().collect();
+        let mount_pt = paths.mdv_dir(pool_uuid);
 
         if let Err(err) = create_dir(&mount_pt) {
             if err.kind() != ErrorKind::AlreadyExists {
@@ -105,7 +114,7 @@ impl MetadataVol {
         let mdv = MetadataVol { dev, mount_pt };
 
         {
-            let mount = MountedMDV::mount(&mdv)?;
+            let mount = mdv.mount_for_setup(pool_uuid, repair)?;
             let filesystem_path = mount.mount_pt().join(FILESYSTEM_DIR);
 
             if let Err(err) = create_dir(&filesystem_path) {
@@ -120,11 +129,49 @@ impl MetadataVol {
         Ok(mdv)
     }
 
+    /// Mount the MDV during setup, distinguishing a damaged filesystem from
+    /// other mount failures.
+    fn mount_for_setup<'a>(&'a self,
+                           pool_uuid: PoolUuid,
+                           repair: bool)
+                           -> EngineResult<MountedMDV<'a>> {
+        let err = match MountedMDV::mount(self) {
+            Ok(mount) => return Ok(mount),
+            Err(err) => err,
+        };
+
+        match xfs_is_damaged(&self.dev.devnode()) {
+            Ok(true) => {}
+            Ok(false) => return Err(err),
+            Err(check_err) => {
+                warn!("Could not check MDV for pool {}: {}", pool_uuid, check_err);
+                return Err(err);
+            }
+        }
+
+        if repair {
+            warn!("Could not mount MDV for pool {}: {}, attempting repair",
+                  pool_uuid,
+                  err);
+            if let Err(repair_err) = xfs_repair(&self.dev.devnode()) {
+                warn!("Could not repair MDV for pool {}: {}", pool_uuid, repair_err);
+            } else if let Ok(mount) = MountedMDV::mount(self) {
+                return Ok(mount);
+            }
+        }
+
+        let err_msg = format!("MDV for pool {} could not be mounted, it may be corrupted: {}",
+                              pool_uuid,
+                              err);
+        Err(EngineError::Engine(ErrorEnum::Corrupt, err_msg))
+    }
+
     /// Save info on a new filesystem to persistent storage, or update
     /// the existing info on a filesystem.
     // Write to a temp file and then rename to actual filename, to
     // ensure file contents are not truncated if operation is
-    // interrupted.
+    // interrupted. Sync the directory afterwards, so that the rename
+    // itself is durable.
     pub fn save_fs(&self, fs: &StratFilesystem) -> EngineResult<()> {
         let data = serde_json::to_string(&fs.record())?;
         let path = self.mount_pt
@@ -132,15 +179,18 @@ impl MetadataVol {
             .join(fs.uuid().simple().to_string())
             .with_extension("json");
 
-        let temp_path = path.clone().with_extension("temp");
+        let temp_path = path.clone().with_extension(TEMP_EXTENSION);
 
         let _mount = MountedMDV::mount(self)?;
 
         // Braces to ensure f is closed before renaming
         {
+            // A temp file left by an interrupted save may be longer than
+            // data, so it must be truncated.
             let mut f = OpenOptions::new()
                 .write(true)
                 .create(true)
+                .truncate(true)
                 .open(&temp_path)?;
             f.write_all(data.as_bytes())?;
 
@@ -150,6 +200,7 @@ impl MetadataVol {
         }
 
         rename(temp_path, path)?;
+        sync_dir(&self.mount_pt.join(FILESYSTEM_DIR))?;
 
         Ok(())
     }
@@ -169,30 +220,31 @@ impl MetadataVol {
             }
         }
 
+        sync_dir(&self.mount_pt.join(FILESYSTEM_DIR))?;
+
         Ok(())
     }
 
     /// Get list of filesystems stored on the MDV.
-    pub fn filesystems(&self) -> EngineResult<Vec<FilesystemSave>> {
-        let mut filesystems = Vec::new();
-
+    /// A record which can not be read is skipped, so that it does not
+    /// prevent the others from being found; see read_records().
+    pub fn filesystems(&self) -> EngineResult<(Vec<FilesystemSave>, Vec<PathBuf>)> {
         let mount = MountedMDV::mount(self)?;
+        read_records(&mount.mount_pt().join(FILESYSTEM_DIR))
+    }
 
-        for dir_e in read_dir(mount.mount_pt().join(FILESYSTEM_DIR))? {
-            let dir_e = dir_e?;
-
-            if dir_e.path().ends_with(".temp") {
-                continue;
-            }
-
-            let mut f = OpenOptions::new().read(true).open(&dir_e.path())?;
-            let mut data = Vec::new();
-            f.read_to_end(&mut data)?;
-
-            filesystems.push(serde_json::from_slice(&data)?);
-        }
+    /// Remove the temp files left by saves which were interrupted.
+    /// Returns the number of temp files found, and the paths of any which
+    /// could not be removed.
+    pub fn check(&self) -> EngineResult<(u64, Vec<PathBuf>)> {
+        let mount = MountedMDV::mount(self)?;
+        remove_temp_files(&mount.mount_pt().join(FILESYSTEM_DIR))
+    }
 
-        Ok(filesystems)
+    /// Move the MDV's linear device onto segments.
+    pub fn set_segments(&mut self, dm: &DM, segments: &[Segment]) -> EngineResult<()> {
+        self.dev.set_segments(dm, segments)?;
+        Ok(())
     }
 
     /// Tear down a Metadata Volume.
@@ -201,6 +253,67 @@ impl MetadataVol {
 
         Ok(())
     }
+
+    /// The device node of the MDV's linear device.
+    #[cfg(test)]
+    pub fn devnode(&self) -> PathBuf {
+        self.dev.devnode()
+    }
+}
+
+/// Sync the directory dir, so that changes to its entries are durable.
+fn sync_dir(dir: &Path) -> EngineResult<()> {
+    let f = File::open(dir)?;
+    fsync(f.as_raw_fd())?;
+    Ok(())
+}
+
+/// Whether path is that of a temp file written while saving.
+fn is_temp_file(path: &Path) -> bool {
+    path.extension() == Some(OsStr::new(TEMP_EXTENSION))
+}
+
+/// Read the filesystem records in the designated directory.
+/// Returns an error if the directory can not be read.
+/// A record which can not be opened, read, or parsed is logged and skipped,
+/// as is a directory entry which can not be read.
+/// Returns the following summary values:
+///  * the records which were read
+///  * paths of those which could not be, if any
+fn read_records(dir: &Path) -> EngineResult<(Vec<FilesystemSave>, Vec<PathBuf>)> {
+    fn read_record(path: &Path) -> EngineResult<FilesystemSave> {
+        let mut f = OpenOptions::new().read(true).open(path)?;
+        let mut data = Vec::new();
+        f.read_to_end(&mut data)?;
+        Ok(serde_json::from_slice(&data)?)
+    }
+
+    let mut records = Vec::new();
+    let mut failed = Vec::new();
+    for dir_e in read_dir(dir)? {
+        let path = match dir_e {
+            Ok(dir_e) => dir_e.path(),
+            Err(err) => {
+                warn!("An entry in {} could not be read: {}", dir.display(), err);
+                continue;
+            }
+        };
+
+        if is_temp_file(&path) {
+            continue;
+        }
+
+        match read_record(&path) {
+            Ok(record) => records.push(record),
+            Err(err) => {
+                warn!("Filesystem record {} could not be read, skipping it: {}",
+                      path.display(),
+                      err);
+                failed.push(path);
+            }
+        }
+    }
+    Ok((records, failed))
 }
 
 /// Remove temp files from the designated directory.
@@ -216,9 +329,158 @@ fn remove_temp_files(dir: &Path) -> EngineResult<(u64, Vec<PathBuf>)> {
     for path in read_dir(dir)?
     .filter_map(|e| e.ok()) // Just ignore entry on intermittent IO error
     .map(|e| e.path())
-    .filter(|p| p.ends_with(".temp")) {
+    .filter(|p| is_temp_file(p)) {
         found += 1;
         remove_file(&path).unwrap_or_else(|_| failed.push(path));
     }
     Ok((found, failed))
 }
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use devicemapper::DmName;
+
+    use super::super::super::types::{BlockDevTier, CreateForce, DEFAULT_MDV_SIZE};
+
+    use super::super::blockdevmgr::{BlockDevMgr, map_to_dm};
+    use super::super::metadata::MIN_MDA_SECTORS;
+    use super::super::tests::{loopbacked, real};
+    use super::super::tests::tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    /// A record which can not be parsed is skipped, and reported, and does
+    /// not prevent a valid record from being read; temp files are ignored.
+    fn read_records_skips_unreadable() {
+        let tmp_dir = TempDir::new("stratis_test_mdv").unwrap();
+        let dir = tmp_dir.path();
+        let fs_uuid = Uuid::new_v4();
+
+        let valid = dir.join(format!("{}.json", fs_uuid.simple()));
+        let mut f = File::create(&valid).unwrap();
+        write!(f,
+               r#"{{"name": "fs", "uuid": "{}", "thin_id": 1, "size": 2048}}"#,
+               fs_uuid)
+                .unwrap();
+
+        let broken = dir.join(format!("{}.json", Uuid::new_v4().simple()));
+        File::create(&broken)
+            .unwrap()
+            .write_all(b"{\"name\": ")
+            .unwrap();
+
+        File::create(dir.join(format!("{}.temp", Uuid::new_v4().simple()))).unwrap();
+
+        let (records, failed) = read_records(dir).unwrap();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].uuid, fs_uuid);
+        assert_eq!(records[0].name, "fs");
+        assert_eq!(failed, vec![broken]);
+
+        assert!(read_records(&dir.join("missing")).is_err());
+    }
+
+    #[test]
+    /// Temp files are recognized by their extension, not by the whole of
+    /// their final component.
+    fn temp_file_extension() {
+        assert!(is_temp_file(Path::new("/mdv/filesystems/foo.temp")));
+        assert!(!is_temp_file(Path::new("/mdv/filesystems/foo.json")));
+        assert!(!is_temp_file(Path::new("/mdv/filesystems/.temp")));
+        assert!(!is_temp_file(Path::new("/mdv/filesystems/foo.temp.json")));
+    }
+
+    /// Verify that a check of the MDV removes a temp file left in its
+    /// filesystems directory, but leaves the filesystems' records alone.
+    fn test_check_removes_temp_files(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let segments = mgr.alloc_space(BlockDevTier::Data, &[DEFAULT_MDV_SIZE]).unwrap().pop()
+            .unwrap();
+        let name = format!("stratis-test-mdv-{}", pool_uuid.simple());
+        let dev = LinearDev::setup(&dm, DmName::new(&name).unwrap(), None, &map_to_dm(&segments))
+            .unwrap();
+        let mdv = MetadataVol::initialize(pool_uuid, dev, &StratisPaths::from_env()).unwrap();
+
+        {
+            let mount = MountedMDV::mount(&mdv).unwrap();
+            let dir = mount.mount_pt().join(FILESYSTEM_DIR);
+            File::create(dir.join("foo.temp")).unwrap();
+            File::create(dir.join("foo.json")).unwrap();
+        }
+
+        assert_eq!(mdv.check().unwrap(), (1, vec![]));
+        assert_eq!(mdv.check().unwrap(), (0, vec![]));
+        {
+            let mount = MountedMDV::mount(&mdv).unwrap();
+            let dir = mount.mount_pt().join(FILESYSTEM_DIR);
+            assert!(!dir.join("foo.temp").exists());
+            assert!(dir.join("foo.json").exists());
+        }
+
+        mdv.teardown(&dm).unwrap();
+        mgr.destroy_all().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_check_removes_temp_files() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_check_removes_temp_files);
+    }
+
+    #[test]
+    pub fn real_test_check_removes_temp_files() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_check_removes_temp_files);
+    }
+
+    /// Verify that if the Stratis /dev directory is overridden, the MDV is
+    /// mounted in the overriding directory.
+    fn test_dev_path(paths: &[&Path]) {
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let stratis_paths = StratisPaths::new(tmp_dir.path());
+
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let segments = mgr.alloc_space(BlockDevTier::Data, &[DEFAULT_MDV_SIZE]).unwrap().pop()
+            .unwrap();
+        let name = format!("stratis-test-mdv-{}", pool_uuid.simple());
+        let dev = LinearDev::setup(&dm, DmName::new(&name).unwrap(), None, &map_to_dm(&segments))
+            .unwrap();
+        let mdv = MetadataVol::initialize(pool_uuid, dev, &stratis_paths).unwrap();
+
+        assert!(stratis_paths.mdv_dir(pool_uuid).is_dir());
+        {
+            let mount = MountedMDV::mount(&mdv).unwrap();
+            assert_eq!(mount.mount_pt(), stratis_paths.mdv_dir(pool_uuid));
+            assert!(mount.mount_pt().join(FILESYSTEM_DIR).is_dir());
+        }
+
+        mdv.teardown(&dm).unwrap();
+        mgr.destroy_all().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_dev_path() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_dev_path);
+    }
+
+    #[test]
+    pub fn real_test_dev_path() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_dev_path);
+    }
+}
@@ -13,9 +13,8 @@ use uuid::Uuid;
 use devicemapper::{Bytes, IEC, SECTOR_SIZE, Sectors};
 
 use super::super::errors::{EngineResult, EngineError, ErrorEnum};
-use super::super::types::{DevUuid, PoolUuid};
+use super::super::types::{DevOwnership, DevUuid, PoolUuid};
 
-use super::engine::DevOwnership;
 
 pub use self::mda::{MIN_MDA_SECTORS, validate_mda_size};
 
@@ -24,7 +23,7 @@ pub const BDA_STATIC_HDR_SECTORS: Sectors = Sectors(_BDA_STATIC_HDR_SECTORS as u
 const _BDA_STATIC_HDR_SIZE: usize = _BDA_STATIC_HDR_SECTORS * SECTOR_SIZE;
 const BDA_STATIC_HDR_SIZE: Bytes = Bytes(_BDA_STATIC_HDR_SIZE as u64);
 
-const MDA_RESERVED_SECTORS: Sectors = Sectors(3 * IEC::Mi / (SECTOR_SIZE as u64)); // = 3 MiB
+pub const MDA_RESERVED_SECTORS: Sectors = Sectors(3 * IEC::Mi / (SECTOR_SIZE as u64)); // = 3 MiB
 
 const STRAT_MAGIC: &'static [u8] = b"!Stra0tis\x86\xff\x02^\x41rh";
 
@@ -103,6 +102,34 @@ impl BDA {
         Ok(())
     }
 
+    /// Give the device the pool UUID pool_uuid and the device UUID dev_uuid,
+    /// rewriting both copies of its static header. The metadata saved in
+    /// its MDA is left alone.
+    pub fn relabel<F>(&mut self,
+                      pool_uuid: PoolUuid,
+                      dev_uuid: DevUuid,
+                      f: &mut F)
+                      -> EngineResult<()>
+        where F: Seek + Write
+    {
+        let header = StaticHeader {
+            pool_uuid: pool_uuid,
+            dev_uuid: dev_uuid,
+            ..self.header
+        };
+        let hdr_buf = header.sigblock_to_buf();
+
+        // Static_Header copies are in sectors 1 and 9.
+        for sector in &[1, 9] {
+            f.seek(SeekFrom::Start((sector * SECTOR_SIZE) as u64))?;
+            f.write_all(&hdr_buf)?;
+            f.flush()?;
+        }
+
+        self.header = header;
+        Ok(())
+    }
+
     /// Save metadata to the disk
     pub fn save_state<F>(&mut self,
                          time: &DateTime<Utc>,
@@ -116,10 +143,24 @@ impl BDA {
     }
 
     /// Read latest metadata from the disk
-    pub fn load_state<F>(&self, mut f: &mut F) -> EngineResult<Option<Vec<u8>>>
+    #[cfg(test)]
+    pub fn load_state<F>(&self, f: &mut F) -> EngineResult<Option<Vec<u8>>>
+        where F: Read + Seek
+    {
+        self.load_state_with(f, &|_| true)
+    }
+
+    /// Read latest metadata from the disk which accept accepts. Metadata
+    /// which it does not accept is treated as if it failed its checksum,
+    /// so that the copy of it, or the previous metadata, is read instead.
+    pub fn load_state_with<F>(&self,
+                              mut f: &mut F,
+                              accept: &Fn(&[u8]) -> bool)
+                              -> EngineResult<Option<Vec<u8>>>
         where F: Read + Seek
     {
-        self.regions.load_state(BDA_STATIC_HDR_SIZE, &mut f)
+        self.regions
+            .load_state(BDA_STATIC_HDR_SIZE, &mut f, accept)
     }
 
     /// The time when the most recent metadata was written to the BDA,
@@ -456,6 +497,8 @@ mod mda {
                 Ok(())
             };
 
+            // Only the older regions are written, so if writing is
+            // interrupted the newer regions still hold the previous data.
             // TODO: Consider if there is an action that should be taken if
             // saving to one or the other region fails.
             let older_region = self.older();
@@ -469,32 +512,62 @@ mod mda {
 
         /// Load metadata from the newer MDA region.
         /// In case there is no record of metadata in regions, return None.
-        /// If there is a record of metadata, and there is a failure to read
-        /// the metadata, return an error.
-        pub fn load_state<F>(&self, header_size: Bytes, f: &mut F) -> EngineResult<Option<Vec<u8>>>
+        /// If the newer region's metadata can not be read, as happens if
+        /// writing it was interrupted, load the previous metadata from the
+        /// older region instead. If that fails too, return an error.
+        /// Metadata which accept does not accept can not be read.
+        pub fn load_state<F>(&self,
+                             header_size: Bytes,
+                             f: &mut F,
+                             accept: &Fn(&[u8]) -> bool)
+                             -> EngineResult<Option<Vec<u8>>>
             where F: Read + Seek
         {
-            let newer_region = self.newer();
-            let mda = match self.mdas[newer_region] {
-                None => return Ok(None),
-                Some(ref mda) => mda,
-            };
             let region_size = self.region_size.bytes();
 
-            // Load the metadata region specified by index.
-            // It is an error if the metadata can not be found.
-            let mut load_region = |index: usize| -> EngineResult<Vec<u8>> {
-                let offset = MDARegions::mda_offset(header_size, index, region_size) +
-                             _MDA_REGION_HDR_SIZE as u64;
-                f.seek(SeekFrom::Start(offset))?;
-                mda.load_region(f)
+            // Load the metadata in the primary region specified by index,
+            // falling back on its copy.
+            let mut load_primary = |index: usize| -> EngineResult<Option<Vec<u8>>> {
+                let mda = match self.mdas[index] {
+                    None => return Ok(None),
+                    Some(ref mda) => mda,
+                };
+
+                let mut load_region = |index: usize| -> EngineResult<Vec<u8>> {
+                    let offset = MDARegions::mda_offset(header_size, index, region_size) +
+                                 _MDA_REGION_HDR_SIZE as u64;
+                    f.seek(SeekFrom::Start(offset))?;
+                    let data = mda.load_region(f)?;
+                    if !accept(&data) {
+                        let err_msg = "MDA region data could not be interpreted";
+                        return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+                    }
+                    Ok(data)
+                };
+
+                load_region(index)
+                    .or_else(|err| {
+                                 let data = load_region(index + 2)?;
+                                 warn!("Metadata in MDA region {} could not be read: {}; \
+                                        using its copy in region {}",
+                                       index,
+                                       err,
+                                       index + 2);
+                                 Ok(data)
+                             })
+                    .map(Some)
             };
 
-            // TODO: Figure out if there is an action to take if the
-            // first read returns an error.
-            load_region(newer_region)
-                .or_else(|_| load_region(newer_region + 2))
-                .map(Some)
+            load_primary(self.newer()).or_else(|err| match load_primary(self.older()) {
+                                                    Ok(Some(data)) => {
+                                                        warn!("Newer metadata could not be \
+                                                               read: {}; using the previous \
+                                                               metadata",
+                                                              err);
+                                                        Ok(Some(data))
+                                                    }
+                                                    _ => Err(err),
+                                                })
         }
 
         /// The index of the older region, or 0 if there is a tie.
@@ -758,13 +831,14 @@ mod mda {
 
 #[cfg(test)]
 mod tests {
+    use std::cmp;
+    use std::io;
     use std::io::Cursor;
 
     use devicemapper::{Bytes, IEC, Sectors};
     use quickcheck::{QuickCheck, TestResult};
     use uuid::Uuid;
 
-    use super::super::engine::DevOwnership;
     use super::*;
 
     /// Return a static header with random block device and MDA size.
@@ -919,6 +993,102 @@ mod tests {
     }
 
 
+    /// A writer which fails once it has written limit bytes.
+    struct FailingWriter {
+        inner: Cursor<Vec<u8>>,
+        limit: usize,
+    }
+
+    impl Write for FailingWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.limit == 0 {
+                return Err(io::Error::new(io::ErrorKind::Other, "write limit reached"));
+            }
+            let len = cmp::min(buf.len(), self.limit);
+            let written = self.inner.write(&buf[..len])?;
+            self.limit -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.inner.flush()
+        }
+    }
+
+    impl Seek for FailingWriter {
+        fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+            self.inner.seek(pos)
+        }
+    }
+
+    #[test]
+    /// Verify that if saving metadata is interrupted at any point, the
+    /// previously saved metadata is loaded from the device.
+    fn test_interrupted_save() {
+        let sh = random_static_header(0, 0);
+        let state = vec![1u8; 100];
+        let next_state = vec![2u8; 1000];
+
+        for &limit in &[0, 1, 16, 100, 1000] {
+            let mut buf = Cursor::new(vec![0; *sh.blkdev_size.bytes() as usize]);
+            let mut bda = BDA::initialize(&mut buf,
+                                          sh.pool_uuid,
+                                          sh.dev_uuid,
+                                          sh.mda_size,
+                                          sh.blkdev_size,
+                                          Utc::now().timestamp() as u64)
+                    .unwrap();
+            bda.save_state(&Utc::now(), &state, &mut buf).unwrap();
+
+            let mut writer = FailingWriter {
+                inner: buf,
+                limit: limit,
+            };
+            assert!(bda.save_state(&Utc::now(), &next_state, &mut writer)
+                        .is_err());
+
+            let mut buf = writer.inner;
+            let bda = BDA::load(&mut buf).unwrap().unwrap();
+            assert_eq!(bda.load_state(&mut buf).unwrap(), Some(state.clone()));
+        }
+    }
+
+    #[test]
+    /// Verify that metadata whose primary region is corrupt, or is not
+    /// accepted, is loaded from its copy, or, if that is corrupt too, that
+    /// the previous metadata is loaded.
+    fn test_corrupt_primary() {
+        let sh = random_static_header(0, 0);
+        let state = vec![1u8; 100];
+        let next_state = vec![2u8; 100];
+
+        let mut buf = Cursor::new(vec![0; *sh.blkdev_size.bytes() as usize]);
+        let mut bda = BDA::initialize(&mut buf,
+                                      sh.pool_uuid,
+                                      sh.dev_uuid,
+                                      sh.mda_size,
+                                      sh.blkdev_size,
+                                      Utc::now().timestamp() as u64)
+                .unwrap();
+        bda.save_state(&Utc::now(), &state, &mut buf).unwrap();
+        bda.save_state(&Utc::now(), &next_state, &mut buf).unwrap();
+
+        // The second save is to region 1, of which region 3 is the copy.
+        let region_size = *(sh.mda_size / 4usize).bytes();
+        let data_offset =
+            |index: u64| (*BDA_STATIC_HDR_SIZE + index * region_size) as usize + 32;
+
+        buf.get_mut()[data_offset(1)] ^= 0xff;
+        let bda = BDA::load(&mut buf).unwrap().unwrap();
+        assert_eq!(bda.load_state(&mut buf).unwrap(), Some(next_state.clone()));
+        assert_eq!(bda.load_state_with(&mut buf, &|data| data != &next_state[..])
+                       .unwrap(),
+                   Some(state.clone()));
+
+        buf.get_mut()[data_offset(3)] ^= 0xff;
+        assert_eq!(bda.load_state(&mut buf).unwrap(), Some(state.clone()));
+    }
+
     #[test]
     /// Construct an arbitrary StaticHeader object.
     /// Initialize a BDA.
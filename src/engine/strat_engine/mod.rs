@@ -5,20 +5,34 @@
 mod blockdev;
 mod blockdevmgr;
 mod cleanup;
+mod crypt;
+mod devlinks;
 mod device;
 mod dmdevice;
+mod dmstatus;
 mod engine;
+mod failpoint;
+mod inflight;
 mod metadata;
 mod mdv;
+mod mount;
 mod filesystem;
+mod openers;
+mod oplog;
 mod pool;
 mod serde_structs;
 mod setup;
 mod range_alloc;
+mod relabel;
 mod thinpool;
 pub mod util;
 
-pub use self::engine::StratEngine;
+pub use self::cleanup::TeardownReport;
+pub use self::engine::{IncompleteReason, StratEngine};
+pub use self::mount::set_mount_timeout;
+pub use self::openers::PidInfo;
+pub use self::serde_structs::METADATA_VERSION;
+pub use self::setup::{DeviceDiscovery, RejectReason, RejectedDevice, discover_devices};
 
 #[cfg(test)]
 mod tests;
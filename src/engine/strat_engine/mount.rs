@@ -0,0 +1,304 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Mounting and unmounting with a bound on the time taken.
+//
+// A mount or unmount of a wedged filesystem may never return. Each is
+// therefore done on a helper thread, and abandoned if it does not complete
+// in time. An abandoned thread remains blocked in the kernel, but the
+// engine is free to carry on.
+//
+// Whether, and where, a device is mounted is found from
+// /proc/self/mountinfo, which identifies the device of each mount by its
+// device number, so that a mount is found whatever path was used to mount
+// it.
+//
+// A mounted filesystem may also be frozen, which blocks writes to it and
+// leaves it consistent on disk, and thawed again. Like a mount, a freeze
+// may wait on a wedged filesystem, and so is bounded in the same way.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{RecvTimeoutError, channel};
+use std::thread;
+use std::time::Duration;
+
+use devicemapper::Device;
+use libc;
+use nix;
+use nix::fcntl::{O_DIRECTORY, O_RDONLY, open};
+use nix::mount::{MNT_DETACH, MS_RDONLY, MS_REMOUNT, MsFlags, mount, umount, umount2};
+use nix::sys::stat::Mode;
+use nix::unistd::close;
+
+use super::super::errors::{EngineError, EngineResult, ErrorEnum};
+use super::super::types::FilesystemType;
+
+ioctl!(readwrite fifreeze with b'X', 119; libc::c_int);
+ioctl!(readwrite fithaw with b'X', 120; libc::c_int);
+
+/// The timeout used until one is set with set_mount_timeout().
+const DEFAULT_MOUNT_TIMEOUT_MS: usize = 30_000;
+
+// The current timeout in milliseconds, 0 if it has never been set.
+static MOUNT_TIMEOUT_MS: AtomicUsize = AtomicUsize::new(0);
+
+/// Set the time allowed for each mount or unmount to complete.
+pub fn set_mount_timeout(timeout: Duration) {
+    let millis = timeout.as_secs() as usize * 1000 + timeout.subsec_nanos() as usize / 1_000_000;
+    MOUNT_TIMEOUT_MS.store(::std::cmp::max(millis, 1), Ordering::SeqCst);
+}
+
+/// The time allowed for each mount or unmount to complete.
+pub fn mount_timeout() -> Duration {
+    match MOUNT_TIMEOUT_MS.load(Ordering::SeqCst) {
+        0 => Duration::from_millis(DEFAULT_MOUNT_TIMEOUT_MS as u64),
+        millis => Duration::from_millis(millis as u64),
+    }
+}
+
+/// Run f on a helper thread, waiting at most timeout for it to complete.
+/// Returns a Busy error if it does not complete in time; what describes
+/// the operation in the error message.
+/// An error returned by f is returned as an EngineError::Nix.
+fn with_timeout<T, F>(what: String, timeout: Duration, f: F) -> EngineResult<T>
+    where T: Send + 'static,
+          F: FnOnce() -> nix::Result<T> + Send + 'static
+{
+    let (sender, receiver) = channel();
+    thread::spawn(move || {
+                      // The receiver is gone if the operation timed out.
+                      let _ = sender.send(f());
+                  });
+
+    match receiver.recv_timeout(timeout) {
+        Ok(result) => Ok(result?),
+        Err(RecvTimeoutError::Timeout) => {
+            let err_msg = format!("{} did not complete within {:?}", what, timeout);
+            Err(EngineError::Engine(ErrorEnum::Busy, err_msg))
+        }
+        Err(RecvTimeoutError::Disconnected) => {
+            let err_msg = format!("{} failed to complete", what);
+            Err(EngineError::Engine(ErrorEnum::Error, err_msg))
+        }
+    }
+}
+
+/// Mount the filesystem of type fs_type on source at target, read-only if
+/// read_only, with the mount options data, if any.
+pub fn mount_fs(source: &Path,
+                target: &Path,
+                fs_type: FilesystemType,
+                read_only: bool,
+                data: Option<&str>)
+                -> EngineResult<()> {
+    let what = format!("mount of {} at {}", source.display(), target.display());
+    let source = source.to_owned();
+    let target = target.to_owned();
+    let flags = if read_only {
+        MS_RDONLY
+    } else {
+        MsFlags::empty()
+    };
+    let data = data.map(|d| d.to_owned());
+    with_timeout(what, mount_timeout(), move || {
+        mount(Some(&source),
+              &target,
+              Some(fs_type.as_str()),
+              flags,
+              data.as_deref())
+    })
+}
+
+/// Remount the filesystem mounted at target read-only, or read-write.
+pub fn remount_fs(target: &Path, read_only: bool) -> EngineResult<()> {
+    let what = format!("remount of {}", target.display());
+    let target = target.to_owned();
+    let flags = if read_only {
+        MS_REMOUNT | MS_RDONLY
+    } else {
+        MS_REMOUNT
+    };
+    with_timeout(what, mount_timeout(), move || {
+        mount(None as Option<&Path>,
+              &target,
+              None as Option<&str>,
+              flags,
+              None as Option<&str>)
+    })
+}
+
+/// Unmount the filesystem mounted at target.
+pub fn unmount_fs(target: &Path) -> EngineResult<()> {
+    let what = format!("unmount of {}", target.display());
+    let target = target.to_owned();
+    with_timeout(what, mount_timeout(), move || umount(&target))
+}
+
+/// Detach the filesystem mounted at target, even if it is busy. The kernel
+/// completes the unmount once the filesystem is no longer in use.
+pub fn unmount_fs_lazy(target: &Path) -> EngineResult<()> {
+    let what = format!("lazy unmount of {}", target.display());
+    let target = target.to_owned();
+    with_timeout(what, mount_timeout(), move || umount2(&target, MNT_DETACH))
+}
+
+/// Freeze the filesystem mounted at target, blocking writes to it until it
+/// is thawed. A filesystem mounted in several places is frozen through any
+/// of them.
+/// Returns an EngineError::Nix EBUSY error if it is already frozen.
+pub fn freeze_fs(target: &Path) -> EngineResult<()> {
+    let what = format!("freeze of {}", target.display());
+    let target = target.to_owned();
+    with_timeout(what,
+                 mount_timeout(),
+                 move || with_dir_fd(&target, |fd| unsafe { fifreeze(fd, &mut 0) }))
+}
+
+/// Thaw the frozen filesystem mounted at target.
+/// Returns an EngineError::Nix EINVAL error if it is not frozen.
+pub fn thaw_fs(target: &Path) -> EngineResult<()> {
+    let what = format!("thaw of {}", target.display());
+    let target = target.to_owned();
+    with_timeout(what,
+                 mount_timeout(),
+                 move || with_dir_fd(&target, |fd| unsafe { fithaw(fd, &mut 0) }))
+}
+
+/// Apply f to a file descriptor of the directory dir.
+fn with_dir_fd<F>(dir: &Path, f: F) -> nix::Result<()>
+    where F: FnOnce(libc::c_int) -> nix::Result<libc::c_int>
+{
+    let fd = open(dir, O_RDONLY | O_DIRECTORY, Mode::empty())?;
+    let result = f(fd);
+    close(fd)?;
+    result.map(|_| ())
+}
+
+/// The mount points of the device, in the order in which the mounts were
+/// made. A device may be mounted in several places, or several times in the
+/// same place.
+pub fn mount_points_of(device: Device) -> EngineResult<Vec<PathBuf>> {
+    let mut mountinfo = String::new();
+    File::open("/proc/self/mountinfo")?
+        .read_to_string(&mut mountinfo)?;
+    parse_mount_points(&mountinfo, device)
+}
+
+/// Find the mount points of device in the contents of a mountinfo file.
+/// The format of each line is documented in the kernel's
+/// Documentation/filesystems/proc.txt; the third field is the device
+/// number, "major:minor", and the fifth is the mount point.
+fn parse_mount_points(mountinfo: &str, device: Device) -> EngineResult<Vec<PathBuf>> {
+    let search = format!("{}:{}", device.major, device.minor);
+    let mut mount_points = Vec::new();
+    for line in mountinfo.lines().filter(|l| !l.trim().is_empty()) {
+        let fields = line.split_whitespace().collect::<Vec<_>>();
+        if fields.len() < 5 {
+            let err_msg = format!("could not parse mountinfo line {:?}", line);
+            return Err(EngineError::Engine(ErrorEnum::Error, err_msg));
+        }
+        if fields[2] == search {
+            mount_points.push(PathBuf::from(unescape_mount_point(fields[4])));
+        }
+    }
+    Ok(mount_points)
+}
+
+/// Undo the octal escaping of space, tab, newline and backslash which the
+/// kernel applies to paths in mountinfo.
+fn unescape_mount_point(escaped: &str) -> String {
+    let mut unescaped = String::with_capacity(escaped.len());
+    let mut rest = escaped;
+    while let Some(pos) = rest.find('\\') {
+        unescaped.push_str(&rest[..pos]);
+        let code = rest.get(pos + 1..pos + 4)
+            .and_then(|digits| u8::from_str_radix(digits, 8).ok());
+        match code {
+            Some(code) => {
+                unescaped.push(code as char);
+                rest = &rest[pos + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[pos + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use nix;
+    use nix::Errno;
+
+    use super::super::super::errors::{EngineError, ErrorEnum};
+
+    use super::*;
+
+    #[test]
+    /// An operation which takes longer than the timeout fails with a Busy
+    /// error; one which completes in time returns its own result.
+    fn slow_operation_times_out() {
+        let slow_mount = || {
+            thread::sleep(Duration::from_secs(5));
+            Ok(())
+        };
+        assert!(match with_timeout("slow mount".into(), Duration::from_millis(10), slow_mount) {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+
+        assert!(with_timeout("mount".into(), Duration::from_secs(5), || Ok(())).is_ok());
+        assert!(match with_timeout("mount".into(),
+                                   Duration::from_secs(5),
+                                   || -> nix::Result<()> { Err(nix::Error::Sys(Errno::EBUSY)) }) {
+                    Err(EngineError::Nix(nix::Error::Sys(Errno::EBUSY))) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    /// The timeout can be changed from its default.
+    fn set_timeout() {
+        set_mount_timeout(Duration::from_millis(1500));
+        assert_eq!(mount_timeout(), Duration::from_millis(1500));
+        set_mount_timeout(Duration::from_millis(DEFAULT_MOUNT_TIMEOUT_MS as u64));
+    }
+
+    #[test]
+    /// Every mount of the device is found, including one at a path with
+    /// escaped characters, and mounts of other devices are not.
+    fn parse_mountinfo() {
+        let mountinfo = "22 1 253:0 / / rw,relatime shared:1 - xfs /dev/dm-0 rw\n\
+                         40 22 253:4 / /mnt/a rw,relatime shared:20 - xfs /dev/dm-4 rw\n\
+                         41 22 253:40 / /mnt/b rw,relatime shared:21 - xfs /dev/dm-40 rw\n\
+                         42 22 253:4 / /mnt/with\\040space rw shared:22 - xfs /dev/dm-4 rw\n";
+        let device = Device {
+            major: 253,
+            minor: 4,
+        };
+        assert_eq!(parse_mount_points(mountinfo, device).unwrap(),
+                   vec![PathBuf::from("/mnt/a"), PathBuf::from("/mnt/with space")]);
+
+        let device = Device {
+            major: 253,
+            minor: 5,
+        };
+        assert_eq!(parse_mount_points(mountinfo, device).unwrap(),
+                   Vec::<PathBuf>::new());
+
+        assert!(match parse_mount_points("22 1 253:0\n", device) {
+                    Err(EngineError::Engine(ErrorEnum::Error, _)) => true,
+                    _ => false,
+                });
+    }
+}
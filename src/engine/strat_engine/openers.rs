@@ -0,0 +1,107 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Finding the processes which hold files open on a device.
+//
+// A filesystem which has files open on it can not be unmounted. The
+// processes which hold them are found by looking at each open file
+// descriptor in /proc/<pid>/fd, and comparing the device number of the
+// file it refers to with that of the filesystem's device.
+//
+// Processes come and go while /proc is read, and the file descriptors of
+// processes belonging to other users can only be read with privilege, so a
+// process which can not be inspected is passed over.
+
+use std::fmt;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+use devicemapper::Device;
+
+use super::super::errors::EngineResult;
+
+/// A process which holds files open.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PidInfo {
+    pub pid: u32,
+    /// The name of the process's command, as given in /proc/<pid>/comm.
+    pub command: String,
+}
+
+impl fmt::Display for PidInfo {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.command, self.pid)
+    }
+}
+
+/// The processes which hold files open on device, in order of pid.
+pub fn openers_of(device: Device) -> EngineResult<Vec<PidInfo>> {
+    let mut openers = Vec::new();
+    for entry in fs::read_dir("/proc")? {
+        let entry = entry?;
+        let pid = match entry.file_name().to_str().and_then(|n| n.parse::<u32>().ok()) {
+            Some(pid) => pid,
+            None => continue,
+        };
+        if holds_open(&entry.path(), device) {
+            openers.push(PidInfo {
+                             pid: pid,
+                             command: command_of(&entry.path()),
+                         });
+        }
+    }
+    openers.sort_by_key(|info| info.pid);
+    Ok(openers)
+}
+
+/// Whether the process whose /proc directory is proc_dir has a file open
+/// on device. False if the process's file descriptors can not be read.
+fn holds_open(proc_dir: &Path, device: Device) -> bool {
+    let fds = match fs::read_dir(proc_dir.join("fd")) {
+        Ok(fds) => fds,
+        Err(_) => return false,
+    };
+    fds.filter_map(|fd| fd.ok())
+        .filter_map(|fd| fs::metadata(fd.path()).ok())
+        .any(|metadata| Device::from(metadata.dev()) == device)
+}
+
+/// The command name of the process whose /proc directory is proc_dir, or
+/// "?" if it can not be read.
+fn command_of(proc_dir: &Path) -> String {
+    let mut command = String::new();
+    match fs::File::open(proc_dir.join("comm")).and_then(|mut f| f.read_to_string(&mut command)) {
+        Ok(_) => command.trim().to_owned(),
+        Err(_) => "?".into(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::process;
+
+    use super::super::tests::tempdir::TempDir;
+
+    use super::*;
+
+    #[test]
+    /// A process which holds a file open on a device is among its openers.
+    fn finds_self() {
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let path = tmp_dir.path().join("stratis_test.txt");
+        let _file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(&path)
+            .unwrap();
+        let device = Device::from(fs::metadata(&path).unwrap().dev());
+
+        let openers = openers_of(device).unwrap();
+        assert!(openers.iter().any(|info| info.pid == process::id()));
+        assert!(openers.windows(2).all(|w| w[0].pid < w[1].pid));
+    }
+}
@@ -0,0 +1,120 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Logging of state-changing engine operations.
+//
+// Every operation is logged twice at info level, once when it is attempted
+// and once with its outcome, as a sequence of key=value pairs, e.g.:
+//
+// op=create_pool name="pool" outcome=attempt
+// op=create_pool name="pool" outcome=success result="<pool uuid>"
+//
+// The op and outcome values are bare words, all other values are quoted and
+// escaped, so that a line can always be split unambiguously.
+
+use std::fmt;
+
+use uuid::Uuid;
+
+use devicemapper::Sectors;
+
+use super::super::types::{CreateAction, DestroyAction, RenameAction};
+
+/// A value which can be recorded as the result of an operation.
+pub trait LogValue {
+    fn log_value(&self) -> String;
+}
+
+impl LogValue for () {
+    fn log_value(&self) -> String {
+        String::new()
+    }
+}
+
+impl LogValue for bool {
+    fn log_value(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl LogValue for Uuid {
+    fn log_value(&self) -> String {
+        self.simple().to_string()
+    }
+}
+
+impl LogValue for Sectors {
+    fn log_value(&self) -> String {
+        self.to_string()
+    }
+}
+
+impl LogValue for RenameAction {
+    fn log_value(&self) -> String {
+        format!("{:?}", self)
+    }
+}
+
+impl<T: LogValue> LogValue for CreateAction<T> {
+    fn log_value(&self) -> String {
+        match *self {
+            CreateAction::Identity(ref t) => format!("Identity({})", t.log_value()),
+            CreateAction::Created(ref t) => format!("Created({})", t.log_value()),
+        }
+    }
+}
+
+impl<T: LogValue> LogValue for DestroyAction<T> {
+    fn log_value(&self) -> String {
+        match *self {
+            DestroyAction::Destroyed(ref t) => format!("Destroyed({})", t.log_value()),
+            DestroyAction::NotFound => "NotFound".into(),
+        }
+    }
+}
+
+impl<'a, T: LogValue> LogValue for (&'a str, T) {
+    fn log_value(&self) -> String {
+        format!("{}:{}", self.0, self.1.log_value())
+    }
+}
+
+impl<T: LogValue> LogValue for Vec<T> {
+    fn log_value(&self) -> String {
+        self.iter().map(|v| v.log_value()).collect::<Vec<_>>().join(",")
+    }
+}
+
+fn format_line(op: &str, fields: &[(&str, String)], outcome: &str) -> String {
+    let mut line = format!("op={}", op);
+    for &(key, ref value) in fields {
+        line.push_str(&format!(" {}={:?}", key, value));
+    }
+    line.push_str(&format!(" outcome={}", outcome));
+    line
+}
+
+/// Run the operation op, logging the attempt and then its outcome.
+/// fields identify the objects the operation acts on.
+pub fn logged<T, E, F>(op: &str, fields: &[(&str, String)], f: F) -> Result<T, E>
+    where T: LogValue,
+          E: fmt::Display,
+          F: FnOnce() -> Result<T, E>
+{
+    info!("{}", format_line(op, fields, "attempt"));
+    let result = f();
+    match result {
+        Ok(ref value) => {
+            info!("{} result={:?}",
+                  format_line(op, fields, "success"),
+                  value.log_value())
+        }
+        Err(ref err) => {
+            info!("{} error={:?}",
+                  format_line(op, fields, "failure"),
+                  err.to_string())
+        }
+    }
+    result
+}
@@ -2,25 +2,46 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
-use std::collections::HashMap;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::iter::FromIterator;
 use std::path::Path;
 use std::path::PathBuf;
+use std::rc::Rc;
 use std::vec::Vec;
 
+use chrono::{DateTime, TimeZone, Utc};
 use serde_json;
 use uuid::Uuid;
 
-use devicemapper::{Device, DM, Sectors};
+use devicemapper::{Device, DM, Sectors, ThinDevId};
 
-use super::super::engine::{Filesystem, BlockDev, HasName, HasUuid, Pool};
+use super::super::engine::{Filesystem, BlockDev, HasName, HasUuid, Pool, StratisPaths,
+                           check_max_fs_size, check_space_reserve, merge_mount_options,
+                           validate_data_low_water, validate_fs_space_watermark, validate_label,
+                           validate_max_fs_size, validate_mdv_size, validate_metadata_paths,
+                           validate_mount_options, validate_name, validate_space_reserve,
+                           validate_fs_options};
 use super::super::errors::{EngineError, EngineResult, ErrorEnum};
-use super::super::types::{DevUuid, FilesystemUuid, PoolUuid, RenameAction, Redundancy};
+use super::super::event::{EngineEvent, EventQueue, fs_space_low_events};
+use super::super::structures::SetName;
+use super::super::types::{AllocationInfo, AuditReport, CreateAction, CreateForce,
+                          DEFAULT_DATA_LOW_WATER, DEFAULT_FS_SIZE, DEFAULT_FS_SPACE_WATERMARK,
+                          DEFAULT_MAX_FILESYSTEMS_PER_POOL, DestroyAction, DestroyError,
+                          DataLowWater, DevUuid, FilesystemUuid, KeyDescription, PoolCheckReport,
+                          PoolExtendState, PoolUuid, RenameAction, Redundancy, FsOptions};
 
 use super::blockdevmgr::BlockDevMgr;
+use super::cleanup::{remove_pool_devs_deferred, remove_thinpool_devs};
+use super::crypt::{check_key, key_check_value};
+use super::devlinks;
+use super::dmdevice::SharedDM;
+use super::failpoint::fail_point;
+use super::filesystem::FilesystemCheckReport;
 use super::metadata::MIN_MDA_SECTORS;
-use super::serde_structs::{PoolSave, Recordable};
-use super::setup::{get_blockdevs, get_metadata};
+use super::oplog::{LogValue, logged};
+use super::serde_structs::{METADATA_VERSION, PoolSave, Recordable};
+use super::setup::{get_blockdevs, get_metadata, match_devices};
 use super::thinpool::ThinPool;
 
 pub use super::thinpool::{DATA_BLOCK_SIZE, DATA_LOWATER, INITIAL_DATA_SIZE};
@@ -32,27 +53,113 @@ pub struct StratPool {
     block_devs: BlockDevMgr,
     redundancy: Redundancy,
     thin_pool: ThinPool,
+    // The key with which the pool's data is encrypted, if it is encrypted
+    key_description: Option<KeyDescription>,
+    // The check value of that key, by which the wrong key is detected
+    key_check: Option<String>,
+    extend_state: PoolExtendState,
+    metadata_low: bool,
+    mount_options: Vec<String>,
+    default_fs_size: Sectors,
+    fs_space_watermark: u8,
+    space_reserve: Sectors,
+    data_low_water: DataLowWater,
+    max_fs_size: Option<Sectors>,
+    labels: BTreeMap<String, String>,
+    // The filesystems which were above the watermark when last checked
+    space_low_filesystems: HashSet<FilesystemUuid>,
+    // The times of creation and of the last metadata write, in seconds
+    // since the Unix epoch
+    created: u64,
+    last_modified: u64,
+    // The number of times the pool's metadata has been written
+    generation: u64,
+    // The queue to which the pool's events are pushed
+    events: Rc<RefCell<EventQueue>>,
+    // The handle to devicemapper, shared with the engine which owns the pool
+    dm: Rc<SharedDM>,
+    // The filesystems found to be inconsistent by the most recent check
+    inconsistent_filesystems: HashMap<FilesystemUuid, FilesystemCheckReport>,
+    max_filesystems: usize,
+    // The filesystems frozen when the pool was quiesced, None if the pool
+    // is not quiesced
+    quiesced: Option<Vec<FilesystemUuid>>,
+    // The paths in the Stratis /dev directory, those of the engine which
+    // owns the pool
+    paths: StratisPaths,
 }
 
 impl StratPool {
     /// Initialize a Stratis Pool.
     /// 1. Initialize the block devices specified by paths.
     /// 2. Set up thinpool device to back filesystems.
+    /// 3. Write the pool's metadata.
+    ///
+    /// If any step fails, undo all the previous steps, so that the devices
+    /// are left unowned and no devicemapper devices remain.
+    ///
+    /// The filesystem metadata volume is mdv_size, if specified; its
+    /// segments are recorded with the pool's other flex devices, so setup
+    /// restores it at the same size.
+    ///
+    /// If encryption is given, the pool's data is encrypted with the key in
+    /// the kernel keyring which it describes. Returns a KeyNotFound error if
+    /// the key is not in the keyring.
+    ///
+    /// The devices of metadata_paths, which must be among paths, are placed
+    /// in the metadata tier, and hold the thinpool's metadata device and its
+    /// spare. Returns an Invalid error if they can not hold them.
+    ///
+    /// The checks on the devices which force overrides are skipped.
+    ///
+    /// The pool's MDV and the links to its filesystems are made in the
+    /// Stratis /dev directory of stratis_paths.
+    #[allow(too_many_arguments)]
     pub fn initialize(name: &str,
-                      dm: &DM,
+                      dm: &Rc<SharedDM>,
+                      stratis_paths: &StratisPaths,
                       paths: &[&Path],
                       redundancy: Redundancy,
-                      force: bool)
+                      force: CreateForce,
+                      mdv_size: Option<Sectors>,
+                      encryption: Option<KeyDescription>,
+                      metadata_paths: &[&Path])
                       -> EngineResult<StratPool> {
+        let mdv_size = validate_mdv_size(mdv_size)?;
+        validate_metadata_paths(paths, metadata_paths)?;
+        if let Some(ref key) = encryption {
+            check_key(key)?;
+        }
         let pool_uuid = Uuid::new_v4();
+        let key_check = match encryption {
+            Some(ref key) => Some(key_check_value(key, pool_uuid)?),
+            None => None,
+        };
 
-        let mut block_mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, force)?;
+        // BlockDevMgr::initialize wipes any devices it has claimed if it fails.
+        let mut block_mgr =
+            BlockDevMgr::initialize(pool_uuid, paths, metadata_paths, MIN_MDA_SECTORS, force)?;
 
-        let thinpool = ThinPool::new(pool_uuid, dm, DATA_BLOCK_SIZE, DATA_LOWATER, &mut block_mgr);
+        let thinpool = ThinPool::new(pool_uuid,
+                                     dm,
+                                     stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     mdv_size,
+                                     encryption.as_ref(),
+                                     &mut block_mgr);
         let thinpool = match thinpool {
             Ok(thinpool) => thinpool,
             Err(err) => {
-                let _ = block_mgr.destroy_all();
+                // ThinPool::new may fail after setting up some of its devices.
+                if let Err(cleanup_err) = remove_thinpool_devs(dm, pool_uuid) {
+                    warn!("Could not clean up after failed pool initialization: {}",
+                          cleanup_err);
+                }
+                if let Err(cleanup_err) = block_mgr.destroy_all() {
+                    warn!("Could not clean up after failed pool initialization: {}",
+                          cleanup_err);
+                }
                 return Err(err);
             }
         };
@@ -63,121 +170,799 @@ impl StratPool {
             block_devs: block_mgr,
             redundancy: redundancy,
             thin_pool: thinpool,
+            key_description: encryption,
+            key_check: key_check,
+            extend_state: PoolExtendState::Good,
+            metadata_low: false,
+            mount_options: vec![],
+            default_fs_size: DEFAULT_FS_SIZE,
+            fs_space_watermark: DEFAULT_FS_SPACE_WATERMARK,
+            space_reserve: Sectors(0),
+            data_low_water: DEFAULT_DATA_LOW_WATER,
+            max_fs_size: None,
+            labels: BTreeMap::new(),
+            space_low_filesystems: HashSet::new(),
+            created: Utc::now().timestamp() as u64,
+            last_modified: 0,
+            generation: 0,
+            events: Rc::default(),
+            dm: Rc::clone(dm),
+            inconsistent_filesystems: HashMap::new(),
+            max_filesystems: DEFAULT_MAX_FILESYSTEMS_PER_POOL,
+            quiesced: None,
+            paths: stratis_paths.clone(),
         };
 
-        pool.write_metadata()?;
+        if let Err(err) = fail_point("StratPool::initialize").and_then(|_| pool.write_metadata()) {
+            if let Err(cleanup_err) = pool.destroy() {
+                warn!("Could not clean up after failed pool initialization: {}",
+                      cleanup_err);
+            }
+            return Err(err);
+        }
 
+        devlinks::setup_pool_devlinks(&pool.paths, &pool.name, &[]);
         Ok(pool)
     }
 
     /// Setup a StratPool using its UUID and the list of devnodes it has.
-    pub fn setup(uuid: PoolUuid, devnodes: &HashMap<Device, PathBuf>) -> EngineResult<StratPool> {
+    /// A pool whose creation time was not recorded is taken to have been
+    /// created when its earliest blockdev was initialized.
+    /// If repair_mdv is true, attempt to repair the pool's MDV if it can not
+    /// be mounted.
+    /// If activate_filesystems is false, the pool's filesystems are known
+    /// but their devices are not activated until activate_filesystem() is
+    /// called, e.g., when inspecting a pool for recovery.
+    /// Returns a KeyNotFound error, and sets up nothing, if the pool is
+    /// encrypted and its key is not in the kernel keyring, or a WrongKey
+    /// error if the key's data does not match the recorded check value.
+    /// The pool's MDV and the links to its filesystems are made in the
+    /// Stratis /dev directory of stratis_paths.
+    pub fn setup(uuid: PoolUuid,
+                 dm: &Rc<SharedDM>,
+                 stratis_paths: &StratisPaths,
+                 devnodes: &HashMap<Device, PathBuf>,
+                 repair_mdv: bool,
+                 activate_filesystems: bool)
+                 -> EngineResult<StratPool> {
         let metadata = get_metadata(uuid, devnodes)?
             .ok_or_else(|| {
                             EngineError::Engine(ErrorEnum::NotFound,
                                                 format!("no metadata for pool {}", uuid))
                         })?;
-        let bd_mgr = BlockDevMgr::new(uuid, get_blockdevs(uuid, &metadata, devnodes)?);
+        let (key_description, key_check) = match metadata.key_description {
+            Some(ref description) => {
+                let key = KeyDescription::new(description)?;
+                check_key(&key)?;
+                let key_check = key_check_value(&key, uuid)?;
+                if let Some(ref recorded) = metadata.key_check {
+                    if *recorded != key_check {
+                        let err_msg = format!("key {:?} in the kernel keyring is not the key \
+                                               of pool {}",
+                                              description,
+                                              uuid);
+                        return Err(EngineError::Engine(ErrorEnum::WrongKey, err_msg));
+                    }
+                }
+                (Some(key), Some(key_check))
+            }
+            None => (None, None),
+        };
+        let devnodes = match_devices(uuid, &metadata, devnodes)?;
+        let blockdevs = get_blockdevs(uuid, &metadata, &devnodes, Redundancy::NONE)?;
+        let bd_mgr = BlockDevMgr::new(uuid, blockdevs);
+        let created = if metadata.created != 0 {
+            metadata.created
+        } else {
+            bd_mgr
+                .blockdevs()
+                .iter()
+                .map(|bd| bd.initialization_time().timestamp() as u64)
+                .min()
+                .unwrap_or(0)
+        };
         let thinpool = ThinPool::setup(uuid,
-                                       &DM::new()?,
+                                       dm,
+                                       stratis_paths,
                                        metadata.thinpool_dev.data_block_size,
                                        DATA_LOWATER,
                                        &metadata.flex_devs,
-                                       &bd_mgr)?;
-
-        Ok(StratPool {
-               name: metadata.name,
-               pool_uuid: uuid,
-               block_devs: bd_mgr,
-               redundancy: Redundancy::NONE,
-               thin_pool: thinpool,
-           })
+                                       key_description.as_ref(),
+                                       &bd_mgr,
+                                       repair_mdv,
+                                       activate_filesystems)?;
+
+        let mut pool = StratPool {
+            name: metadata.name,
+            pool_uuid: uuid,
+            block_devs: bd_mgr,
+            redundancy: Redundancy::NONE,
+            thin_pool: thinpool,
+            key_description: key_description,
+            key_check: key_check,
+            extend_state: PoolExtendState::Good,
+            metadata_low: false,
+            mount_options: metadata.mount_options,
+            default_fs_size: metadata.default_fs_size,
+            fs_space_watermark: metadata.fs_space_watermark,
+            space_reserve: metadata.space_reserve,
+            data_low_water: metadata
+                .data_low_water
+                .map_or(DEFAULT_DATA_LOW_WATER, DataLowWater::from),
+            max_fs_size: metadata.max_fs_size,
+            labels: metadata.labels,
+            space_low_filesystems: HashSet::new(),
+            created: created,
+            last_modified: metadata.last_modified,
+            generation: metadata.generation,
+            events: Rc::default(),
+            dm: Rc::clone(dm),
+            inconsistent_filesystems: HashMap::new(),
+            max_filesystems: DEFAULT_MAX_FILESYSTEMS_PER_POOL,
+            quiesced: None,
+            paths: stratis_paths.clone(),
+        };
+
+        if let Err(err) = pool.migrate(metadata.version) {
+            if let Err(teardown_err) = pool.teardown() {
+                warn!("Could not tear down pool {} after failed migration: {}",
+                      uuid,
+                      teardown_err);
+            }
+            return Err(err);
+        }
+
+        devlinks::setup_pool_devlinks(&pool.paths,
+                                      &pool.name,
+                                      &pool.thin_pool.filesystem_devnodes());
+        Ok(pool)
+    }
+
+    /// The handle to devicemapper which the pool uses.
+    #[cfg(test)]
+    pub fn dm(&self) -> &Rc<SharedDM> {
+        &self.dm
+    }
+
+    /// Report the pool's events to events, the queue of the engine which
+    /// owns the pool. Until this is called, the pool's events are not
+    /// reported to any engine.
+    pub fn set_event_queue(&mut self, events: &Rc<RefCell<EventQueue>>) {
+        self.events = Rc::clone(events);
+    }
+
+    /// Set the number of filesystems, including snapshots, beyond which
+    /// no more may be created.
+    pub fn set_max_filesystems(&mut self, max_filesystems: usize) {
+        self.max_filesystems = max_filesystems;
+    }
+
+    /// Return a LimitReached error if creating count more filesystems would
+    /// exceed the pool's limit.
+    fn check_filesystem_limit(&self, count: usize) -> EngineResult<()> {
+        if self.thin_pool.filesystems().len() + count > self.max_filesystems {
+            let err_msg = format!("the limit of {} filesystems in pool {} has been reached",
+                                  self.max_filesystems,
+                                  self.name);
+            return Err(EngineError::Engine(ErrorEnum::LimitReached, err_msg));
+        }
+        Ok(())
+    }
+
+    /// Return a NoSpace error if the pool's free space is within its
+    /// space reserve.
+    fn check_space_reserve(&self) -> EngineResult<()> {
+        check_space_reserve(self.total_physical_size(),
+                            self.total_physical_used()?,
+                            self.space_reserve)
+    }
+
+    /// Return a Busy error if the pool is quiesced.
+    fn check_not_quiesced(&self) -> EngineResult<()> {
+        if self.quiesced.is_some() {
+            let err_msg = format!("pool {} is quiesced", self.name);
+            return Err(EngineError::Engine(ErrorEnum::Busy, err_msg));
+        }
+        Ok(())
+    }
+
+    /// Upgrade the pool's metadata from format version from_version to the
+    /// current version, by writing all of it again. Fields which are new
+    /// since from_version take their default values.
+    /// Does nothing if from_version is the current version.
+    fn migrate(&mut self, from_version: u32) -> EngineResult<()> {
+        if from_version >= METADATA_VERSION {
+            return Ok(());
+        }
+
+        self.thin_pool.save_filesystems()?;
+        self.write_metadata()
     }
 
-    /// Write current metadata to pool members.
+    /// Write current metadata to pool members, recording the time of the
+    /// write as the pool's last modification time.
+    // Each blockdev holds two copies of the metadata, and only the older
+    // is overwritten, so an interrupted write leaves the previous metadata
+    // intact. This serves the same purpose as writing a temp file and
+    // renaming it.
+    // Each write advances the metadata's generation, by which the newest
+    // metadata is found when the pool is set up.
     pub fn write_metadata(&mut self) -> EngineResult<()> {
-        let data = serde_json::to_string(&self.record())?;
-        self.block_devs.save_state(data.as_bytes())
+        let old_last_modified = self.last_modified;
+        self.last_modified = Utc::now().timestamp() as u64;
+        self.generation += 1;
+        let result = serde_json::to_string(&self.record())
+            .map_err(From::from)
+            .and_then(|data| self.block_devs.save_state(data.as_bytes()));
+        if result.is_err() {
+            self.last_modified = old_last_modified;
+            self.generation -= 1;
+        }
+        result
+    }
+
+    /// Remove the temp files left in the pool's MDV by saves which were
+    /// interrupted, logging what was found.
+    /// Returns the number of temp files removed.
+    /// Returns a Busy error, and checks nothing, if the pool is quiesced.
+    pub fn check_mdv(&self) -> EngineResult<u64> {
+        self.check_not_quiesced()?;
+        let (found, failed) = self.thin_pool.check_mdv()?;
+        let removed = found - failed.len() as u64;
+        if removed > 0 {
+            info!("Removed {} stale temp files from the MDV of pool {}",
+                  removed,
+                  self.pool_uuid);
+        }
+        for path in failed {
+            warn!("Could not remove stale temp file {} from the MDV of pool {}",
+                  path.display(),
+                  self.pool_uuid);
+        }
+        Ok(removed)
     }
 
-    pub fn check(&mut self) -> EngineResult<()> {
-        // FIXME: The context should not be created here as this is not
-        // a public method. Ideally the context should be created in the
-        // invoking method, Engine::check(). However, since we hope that
-        // method will go away entirely, we just fix half of the problem
-        // with this method, and leave the rest alone.
-        self.thin_pool.check(&DM::new()?, &mut self.block_devs)
+    /// The filesystems which the most recent check found to be inconsistent
+    /// with their devices, and what was found.
+    #[cfg(test)]
+    pub fn inconsistent_filesystems(&self) -> &HashMap<FilesystemUuid, FilesystemCheckReport> {
+        &self.inconsistent_filesystems
     }
 
     /// Teardown a pool.
     pub fn teardown(self) -> EngineResult<()> {
-        self.thin_pool.teardown(&DM::new()?)
+        self.thin_pool.teardown(&self.dm)?;
+        devlinks::pool_removed(&self.paths, &self.name);
+        Ok(())
+    }
+
+    /// Teardown a pool, even if some of its filesystems are in use.
+    /// Filesystems are detached from their mount points, and devices which
+    /// are still in use are removed once they are no longer in use.
+    /// Failures are logged rather than returned.
+    pub fn teardown_force(self, dm: &DM) {
+        let fs_uuids = self.thin_pool.unmount_filesystems_lazy();
+        remove_pool_devs_deferred(dm, self.pool_uuid, &fs_uuids);
+        devlinks::pool_removed(&self.paths, &self.name);
     }
 
     pub fn has_filesystems(&self) -> bool {
         self.thin_pool.has_filesystems()
     }
+
+    /// The devices of the pool's blockdevs.
+    pub fn devices(&self) -> HashSet<Device> {
+        self.block_devs.devices()
+    }
+
+    /// Activate the device of the filesystem uuid, if the pool was set up
+    /// without activating its filesystems.
+    pub fn activate_filesystem(&mut self, uuid: FilesystemUuid) -> EngineResult<()> {
+        self.thin_pool.activate_filesystem(&self.dm, uuid)?;
+        self.link_filesystem(uuid);
+        Ok(())
+    }
+
+    /// Add, or replace, the link to the device node of filesystem uuid,
+    /// if it is active.
+    fn link_filesystem(&self, uuid: FilesystemUuid) {
+        if let Some(fs) = self.thin_pool.get_filesystem_by_uuid(uuid) {
+            if fs.is_active() {
+                devlinks::filesystem_added(&self.paths, &self.name, fs.name(), &fs.devnode());
+            }
+        }
+    }
 }
 
 impl Pool for StratPool {
     fn create_filesystems<'a, 'b>(&'a mut self,
-                                  specs: &[(&'b str, Option<Sectors>)])
-                                  -> EngineResult<Vec<(&'b str, FilesystemUuid)>> {
-        let names: HashMap<_, _> = HashMap::from_iter(specs.iter().map(|&tup| (tup.0, tup.1)));
-        for name in names.keys() {
-            if self.thin_pool
-                   .get_mut_filesystem_by_name(*name)
-                   .is_some() {
-                return Err(EngineError::Engine(ErrorEnum::AlreadyExists, name.to_string()));
+                                  specs: &[(&'b str, Option<Sectors>, Option<FsOptions>)])
+                                  -> EngineResult<CreateAction<Vec<(&'b str, FilesystemUuid)>>> {
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("names",
+                       specs
+                           .iter()
+                           .map(|&(name, _, _)| name)
+                           .collect::<Vec<_>>()
+                           .join(","))];
+        logged("create_filesystems", &fields, || {
+            self.check_not_quiesced()?;
+            let names: HashMap<_, _> =
+                HashMap::from_iter(specs.iter().map(|&tup| (tup.0, (tup.1, tup.2))));
+            for (name, &(size, options)) in &names {
+                validate_name(name)?;
+                if self.thin_pool
+                       .get_mut_filesystem_by_name(*name)
+                       .is_some() {
+                    return Err(EngineError::Engine(ErrorEnum::AlreadyExists, name.to_string()));
+                }
+                if size == Some(Sectors(0)) {
+                    let err_msg = format!("filesystem {} must have a non-zero size", name);
+                    return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+                }
+                if let Some(ref options) = options {
+                    validate_fs_options(options)?;
+                }
+                check_max_fs_size(name, size.unwrap_or(self.default_fs_size), self.max_fs_size)?;
             }
-        }
+            if names.is_empty() {
+                return Ok(CreateAction::Identity(vec![]));
+            }
+            self.check_filesystem_limit(names.len())?;
+            self.check_space_reserve()?;
 
-        // TODO: Roll back on filesystem initialization failure.
-        let dm = DM::new()?;
-        let mut result = Vec::new();
-        for (name, size) in names {
-            let fs_uuid = self.thin_pool.create_filesystem(name, &dm, size)?;
-            result.push((name, fs_uuid));
-        }
+            let specs: Vec<_> = names
+                .into_iter()
+                .map(|(name, (size, options))| {
+                         (name,
+                          Some(size.unwrap_or(self.default_fs_size)),
+                          options.unwrap_or_default())
+                     })
+                .collect();
+            let fs_uuids = self.thin_pool.create_filesystems(&self.dm, &specs)?;
+            for fs_uuid in &fs_uuids {
+                self.link_filesystem(*fs_uuid);
+                self.events
+                    .borrow_mut()
+                    .push(EngineEvent::FilesystemCreated(self.pool_uuid, *fs_uuid));
+            }
+
+            Ok(CreateAction::Created(specs
+                                         .iter()
+                                         .map(|&(name, _, _)| name)
+                                         .zip(fs_uuids)
+                                         .collect()))
+        })
+    }
 
-        Ok(result)
+    fn add_blockdevs(&mut self,
+                     paths: &[&Path],
+                     force: CreateForce)
+                     -> EngineResult<Vec<DevUuid>> {
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("devices",
+                       paths
+                           .iter()
+                           .map(|p| p.display().to_string())
+                           .collect::<Vec<_>>()
+                           .join(","))];
+        logged("add_blockdevs", &fields, || {
+            self.check_not_quiesced()?;
+            let bdev_info = self.block_devs.add(paths, force)?;
+            self.write_metadata()?;
+            for dev_uuid in &bdev_info {
+                self.events
+                    .borrow_mut()
+                    .push(EngineEvent::BlockdevAdded(self.pool_uuid, *dev_uuid));
+            }
+            Ok(bdev_info)
+        })
+    }
+
+    fn extend_thinpool(&mut self) -> EngineResult<Sectors> {
+        let fields = [("pool", self.pool_uuid.log_value())];
+        logged("extend_thinpool", &fields, || {
+            self.check_not_quiesced()?;
+            let added = self.thin_pool
+                .extend_all(&self.dm, &mut self.block_devs)?;
+            if added != Sectors(0) {
+                self.write_metadata()?;
+            }
+            Ok(added)
+        })
     }
 
-    fn add_blockdevs(&mut self, paths: &[&Path], force: bool) -> EngineResult<Vec<DevUuid>> {
-        let bdev_info = self.block_devs.add(paths, force)?;
-        self.write_metadata()?;
-        Ok(bdev_info)
+    fn remove_blockdev(&mut self, dev_uuid: DevUuid) -> EngineResult<()> {
+        let fields = [("pool", self.pool_uuid.log_value()), ("device", dev_uuid.log_value())];
+        logged("remove_blockdev", &fields, || {
+            self.check_not_quiesced()?;
+            if self.block_devs.get_blockdev_by_uuid(dev_uuid).is_none() {
+                let err_msg = format!("No blockdev with uuid {} in pool", dev_uuid);
+                return Err(EngineError::Engine(ErrorEnum::NotFound, err_msg));
+            }
+
+            // Redundancy::NONE, the only redundancy, requires one blockdev.
+            if self.block_devs.blockdevs().len() == 1 {
+                let err_msg = "A pool must have at least one blockdev";
+                return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+            }
+
+            let dm = Rc::clone(&self.dm);
+            let allocated = self.thin_pool.allocated_on(dev_uuid);
+            let bd = self.block_devs
+                .remove(dev_uuid)
+                .expect("checked for presence above");
+
+            if self.block_devs.avail_space() < allocated {
+                self.block_devs.insert(bd);
+                let err_msg = format!("Other blockdevs in pool can not hold the {} allocated on \
+                                       blockdev {}",
+                                      allocated,
+                                      dev_uuid);
+                return Err(EngineError::Engine(ErrorEnum::NoSpace, err_msg));
+            }
+
+            // The blockdev stays in the pool's metadata until it is
+            // written again, so it is returned to the pool on failure.
+            let evacuation = match self.thin_pool
+                      .evacuate(&dm, dev_uuid, &bd.devnode, &mut self.block_devs) {
+                Ok(evacuation) => evacuation,
+                Err(err) => {
+                    self.block_devs.insert(bd);
+                    return Err(err);
+                }
+            };
+
+            if let Err(err) = self.write_metadata() {
+                self.thin_pool
+                    .undo_evacuation(&dm, evacuation, &bd.devnode, &mut self.block_devs);
+                self.block_devs.insert(bd);
+                return Err(err);
+            }
+
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::BlockdevRemoved(self.pool_uuid, dev_uuid));
+            bd.wipe_metadata()
+        })
     }
 
     fn destroy(self) -> EngineResult<()> {
-        self.thin_pool.teardown(&DM::new()?)?;
+        self.thin_pool.teardown(&self.dm)?;
+        devlinks::pool_removed(&self.paths, &self.name);
         self.block_devs.destroy_all()?;
         Ok(())
     }
 
     fn destroy_filesystems<'a>(&'a mut self,
-                               fs_uuids: &[FilesystemUuid])
-                               -> EngineResult<Vec<FilesystemUuid>> {
-        let dm = DM::new()?;
+                               fs_uuids: &[FilesystemUuid],
+                               force: bool)
+                               -> Result<DestroyAction<Vec<FilesystemUuid>>,
+                                         DestroyError<Vec<FilesystemUuid>>> {
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("filesystems", fs_uuids.to_vec().log_value()),
+                      ("force", force.to_string())];
+        logged("destroy_filesystems", &fields, || {
+            let dm = Rc::clone(&self.dm);
 
-        let mut removed = Vec::new();
-        for &uuid in fs_uuids {
-            self.thin_pool.destroy_filesystem(&dm, uuid)?;
-            removed.push(uuid);
-        }
+            let mut removed = Vec::new();
+            if let Err(err) = self.check_not_quiesced() {
+                return Err(DestroyError {
+                               destroyed: removed,
+                               error: err,
+                           });
+            }
+            for &uuid in fs_uuids {
+                let name = match self.thin_pool.get_filesystem_by_uuid(uuid) {
+                    Some(fs) => fs.name().to_owned(),
+                    None => continue,
+                };
+                if let Err(err) = self.thin_pool.destroy_filesystem(&dm, uuid, force) {
+                    return Err(DestroyError {
+                                   destroyed: removed,
+                                   error: err,
+                               });
+                }
+                devlinks::filesystem_removed(&self.paths, &self.name, &name);
+                self.events
+                    .borrow_mut()
+                    .push(EngineEvent::FilesystemDestroyed(self.pool_uuid, uuid));
+                removed.push(uuid);
+            }
 
-        Ok(removed)
+            if removed.is_empty() {
+                Ok(DestroyAction::NotFound)
+            } else {
+                Ok(DestroyAction::Destroyed(removed))
+            }
+        })
     }
 
     fn rename_filesystem(&mut self,
                          uuid: FilesystemUuid,
                          new_name: &str)
                          -> EngineResult<RenameAction> {
-        self.thin_pool.rename_filesystem(uuid, new_name)
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("filesystem", uuid.log_value()),
+                      ("new_name", new_name.to_owned())];
+        logged("rename_filesystem", &fields, || {
+            self.check_not_quiesced()?;
+            let old_name = self.thin_pool
+                .get_filesystem_by_uuid(uuid)
+                .map(|fs| fs.name().to_owned());
+            let action = self.thin_pool.rename_filesystem(uuid, new_name)?;
+            if action == RenameAction::Renamed {
+                let fs = self.thin_pool
+                    .get_filesystem_by_uuid(uuid)
+                    .expect("the filesystem was just renamed");
+                if let (Some(old_name), true) = (old_name, fs.is_active()) {
+                    devlinks::filesystem_renamed(&self.paths,
+                                                 &self.name,
+                                                 &old_name,
+                                                 new_name,
+                                                 &fs.devnode());
+                }
+            }
+            Ok(action)
+        })
+    }
+
+    fn set_filesystem_size(&mut self,
+                           uuid: FilesystemUuid,
+                           size: Sectors)
+                           -> EngineResult<Sectors> {
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("filesystem", uuid.log_value()),
+                      ("size", size.to_string())];
+        logged("set_filesystem_size", &fields, || {
+            self.check_not_quiesced()?;
+            if let Some(fs) = self.thin_pool.get_filesystem_by_uuid(uuid) {
+                if size > fs.size() {
+                    check_max_fs_size(fs.name(), size, self.max_fs_size)?;
+                    self.check_space_reserve()?;
+                }
+            }
+            self.thin_pool
+                .set_filesystem_size(&self.dm, uuid, size)
+        })
+    }
+
+    fn set_filesystem_read_only(&mut self,
+                                uuid: FilesystemUuid,
+                                read_only: bool)
+                                -> EngineResult<()> {
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("filesystem", uuid.log_value()),
+                      ("read_only", read_only.to_string())];
+        logged("set_filesystem_read_only", &fields, || {
+            self.check_not_quiesced()?;
+            self.thin_pool.set_filesystem_read_only(uuid, read_only)
+        })
+    }
+
+    fn filesystem_allocation_info(&self,
+                                  fs_uuid: FilesystemUuid)
+                                  -> EngineResult<Option<AllocationInfo>> {
+        self.thin_pool
+            .filesystem_allocation_info(&self.dm, fs_uuid)
+    }
+
+    fn set_filesystem_quota(&mut self,
+                            fs_uuid: FilesystemUuid,
+                            limit: Option<Sectors>)
+                            -> EngineResult<()> {
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("filesystem", fs_uuid.log_value()),
+                      ("limit", limit.map_or_else(|| "none".into(), |l| l.to_string()))];
+        logged("set_filesystem_quota", &fields, || {
+            self.check_not_quiesced()?;
+            self.thin_pool.set_filesystem_quota(fs_uuid, limit)
+        })
+    }
+
+    fn enable_discards(&mut self, fs_uuid: FilesystemUuid, enable: bool) -> EngineResult<()> {
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("filesystem", fs_uuid.log_value()),
+                      ("enable", enable.to_string())];
+        logged("enable_discards", &fields, || {
+            self.check_not_quiesced()?;
+            self.thin_pool.set_filesystem_discards(fs_uuid, enable)
+        })
+    }
+
+    fn trim_filesystem(&mut self, fs_uuid: FilesystemUuid) -> EngineResult<()> {
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("filesystem", fs_uuid.log_value())];
+        logged("trim_filesystem", &fields, || {
+            self.check_not_quiesced()?;
+            self.thin_pool.trim_filesystem(fs_uuid)
+        })
+    }
+
+    fn quiesce(&mut self) -> EngineResult<()> {
+        let fields = [("pool", self.pool_uuid.log_value())];
+        logged("quiesce", &fields, || {
+            if self.quiesced.is_none() {
+                self.quiesced = Some(self.thin_pool.freeze_filesystems()?);
+            }
+            Ok(())
+        })
+    }
+
+    fn unquiesce(&mut self) -> EngineResult<()> {
+        let fields = [("pool", self.pool_uuid.log_value())];
+        logged("unquiesce", &fields, || {
+            if let Some(frozen) = self.quiesced.take() {
+                if let Err(err) = self.thin_pool.thaw_filesystems(&frozen) {
+                    self.quiesced = Some(frozen);
+                    return Err(err);
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn is_quiesced(&self) -> bool {
+        self.quiesced.is_some()
+    }
+
+    fn set_default_mount_options(&mut self, opts: &[&str]) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_mount_options(opts)?;
+        let old_opts = self.mount_options.clone();
+        self.mount_options = opts.iter().map(|o| o.to_string()).collect();
+        if let Err(err) = self.write_metadata() {
+            self.mount_options = old_opts;
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn default_mount_options(&self) -> &[String] {
+        &self.mount_options
+    }
+
+    fn mount_filesystem(&self,
+                        fs_uuid: FilesystemUuid,
+                        mount_point: &Path,
+                        opts: &[&str])
+                        -> EngineResult<()> {
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("filesystem", fs_uuid.log_value()),
+                      ("mount_point", mount_point.display().to_string()),
+                      ("options", opts.join(","))];
+        logged("mount_filesystem", &fields, || {
+            self.check_not_quiesced()?;
+            let data = merge_mount_options(&self.mount_options, opts)?;
+            self.thin_pool.mount_filesystem(fs_uuid, mount_point, &data)
+        })
+    }
+
+    fn set_default_fs_size(&mut self, size: Sectors) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        if size == Sectors(0) {
+            let err_msg = "default filesystem size must be non-zero";
+            return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+        }
+        let old_size = self.default_fs_size;
+        self.default_fs_size = size;
+        if let Err(err) = self.write_metadata() {
+            self.default_fs_size = old_size;
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn default_fs_size(&self) -> Sectors {
+        self.default_fs_size
+    }
+
+    fn set_fs_space_watermark(&mut self, percent: u8) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_fs_space_watermark(percent)?;
+        let old_percent = self.fs_space_watermark;
+        self.fs_space_watermark = percent;
+        if let Err(err) = self.write_metadata() {
+            self.fs_space_watermark = old_percent;
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn fs_space_watermark(&self) -> u8 {
+        self.fs_space_watermark
+    }
+
+    fn set_space_reserve(&mut self, reserve: Sectors) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_space_reserve(reserve, self.total_physical_size())?;
+        let old_reserve = self.space_reserve;
+        self.space_reserve = reserve;
+        if let Err(err) = self.write_metadata() {
+            self.space_reserve = old_reserve;
+            Err(err)
+        } else {
+            Ok(())
+        }
     }
 
-    fn rename(&mut self, name: &str) {
-        self.name = name.to_owned();
+    fn space_reserve(&self) -> Sectors {
+        self.space_reserve
+    }
+
+    fn set_data_low_water(&mut self, low_water: DataLowWater) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_data_low_water(low_water)?;
+        let old_low_water = self.data_low_water;
+        self.data_low_water = low_water;
+        if let Err(err) = self.write_metadata() {
+            self.data_low_water = old_low_water;
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn data_low_water(&self) -> DataLowWater {
+        self.data_low_water
+    }
+
+    fn set_max_fs_size(&mut self, max: Option<Sectors>) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_max_fs_size(max)?;
+        let old_max = self.max_fs_size;
+        self.max_fs_size = max;
+        if let Err(err) = self.write_metadata() {
+            self.max_fs_size = old_max;
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn max_fs_size(&self) -> Option<Sectors> {
+        self.max_fs_size
+    }
+
+    fn set_label(&mut self, key: &str, value: Option<&str>) -> EngineResult<()> {
+        self.check_not_quiesced()?;
+        validate_label(key, value)?;
+        let old_value = match value {
+            Some(value) => self.labels.insert(key.to_owned(), value.to_owned()),
+            None => self.labels.remove(key),
+        };
+        if let Err(err) = self.write_metadata() {
+            match old_value {
+                Some(old_value) => self.labels.insert(key.to_owned(), old_value),
+                None => self.labels.remove(key),
+            };
+            Err(err)
+        } else {
+            Ok(())
+        }
+    }
+
+    fn labels(&self) -> &BTreeMap<String, String> {
+        &self.labels
+    }
+
+    fn created(&self) -> DateTime<Utc> {
+        Utc.timestamp(self.created as i64, 0)
+    }
+
+    fn last_modified(&self) -> DateTime<Utc> {
+        Utc.timestamp(self.last_modified as i64, 0)
+    }
+
+    fn key_description(&self) -> Option<&KeyDescription> {
+        self.key_description.as_ref()
+    }
+
+    fn redundancy(&self) -> Redundancy {
+        self.redundancy
     }
 
     fn total_physical_size(&self) -> Sectors {
@@ -186,10 +971,18 @@ impl Pool for StratPool {
 
     fn total_physical_used(&self) -> EngineResult<Sectors> {
         self.thin_pool
-            .total_physical_used()
+            .total_physical_used(&self.dm)
             .and_then(|v| Ok(v + self.block_devs.metadata_size()))
     }
 
+    fn extend_state(&self) -> PoolExtendState {
+        self.extend_state
+    }
+
+    fn metadata_low(&self) -> bool {
+        self.metadata_low
+    }
+
     fn filesystems(&self) -> Vec<&Filesystem> {
         self.thin_pool.filesystems()
     }
@@ -198,8 +991,76 @@ impl Pool for StratPool {
                            origin_uuid: FilesystemUuid,
                            snapshot_name: &str)
                            -> EngineResult<FilesystemUuid> {
-        self.thin_pool
-            .snapshot_filesystem(&DM::new()?, origin_uuid, snapshot_name)
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("origin", origin_uuid.log_value()),
+                      ("name", snapshot_name.to_owned())];
+        logged("snapshot_filesystem", &fields, || {
+            validate_name(snapshot_name)?;
+            self.check_filesystem_limit(1)?;
+            self.check_space_reserve()?;
+            let snapshot_uuid =
+                self.thin_pool
+                    .snapshot_filesystem(&self.dm, origin_uuid, snapshot_name)?;
+            self.link_filesystem(snapshot_uuid);
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::FilesystemCreated(self.pool_uuid, snapshot_uuid));
+            Ok(snapshot_uuid)
+        })
+    }
+
+    fn clone_filesystem(&mut self,
+                        origin_uuid: FilesystemUuid,
+                        new_name: &str)
+                        -> EngineResult<FilesystemUuid> {
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("origin", origin_uuid.log_value()),
+                      ("name", new_name.to_owned())];
+        logged("clone_filesystem", &fields, || {
+            self.check_not_quiesced()?;
+            validate_name(new_name)?;
+            if self.thin_pool
+                   .get_mut_filesystem_by_name(new_name)
+                   .is_some() {
+                return Err(EngineError::Engine(ErrorEnum::AlreadyExists, new_name.to_string()));
+            }
+            self.check_filesystem_limit(1)?;
+            let dm = Rc::clone(&self.dm);
+            let needed = match self.thin_pool.filesystem_allocation_info(&dm, origin_uuid)? {
+                Some(info) => info.allocated,
+                None => {
+                    let err_msg = format!("filesystem {} has not been activated", origin_uuid);
+                    return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+                }
+            };
+            check_space_reserve(self.total_physical_size(),
+                                self.total_physical_used()? + needed,
+                                self.space_reserve)?;
+            let clone_uuid = self.thin_pool
+                .clone_filesystem(&dm, origin_uuid, new_name)?;
+            self.link_filesystem(clone_uuid);
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::FilesystemCreated(self.pool_uuid, clone_uuid));
+            Ok(clone_uuid)
+        })
+    }
+
+    fn revert_to_snapshot(&mut self,
+                          fs_uuid: FilesystemUuid,
+                          snapshot_uuid: FilesystemUuid)
+                          -> EngineResult<()> {
+        let fields = [("pool", self.pool_uuid.log_value()),
+                      ("filesystem", fs_uuid.log_value()),
+                      ("snapshot", snapshot_uuid.log_value())];
+        logged("revert_to_snapshot", &fields, || {
+            self.check_not_quiesced()?;
+            self.thin_pool
+                .revert_to_snapshot(&self.dm, fs_uuid, snapshot_uuid)?;
+            // The filesystem now has the device node of the copy.
+            self.link_filesystem(fs_uuid);
+            Ok(())
+        })
     }
 
     fn get_filesystem(&self, uuid: FilesystemUuid) -> Option<&Filesystem> {
@@ -226,6 +1087,82 @@ impl Pool for StratPool {
         self.block_devs.get_mut_blockdev_by_uuid(uuid)
     }
 
+    fn audit(&self) -> EngineResult<AuditReport> {
+        self.thin_pool.audit(&self.dm)
+    }
+
+    fn delete_orphaned_thin_devs(&mut self) -> EngineResult<Vec<ThinDevId>> {
+        self.check_not_quiesced()?;
+        self.thin_pool.delete_orphaned_thin_devs(&self.dm)
+    }
+
+    fn check(&mut self) -> EngineResult<PoolCheckReport> {
+        self.check_not_quiesced()?;
+
+        let dm = Rc::clone(&self.dm);
+
+        let reports: HashMap<_, _> = HashMap::from_iter(self.thin_pool.check_filesystems(&dm));
+        for (fs_uuid, report) in &reports {
+            if self.inconsistent_filesystems.get(fs_uuid) != Some(report) {
+                warn!("Filesystem {} of pool {} is inconsistent with its device: {:?}",
+                      fs_uuid,
+                      self.pool_uuid,
+                      report);
+            }
+        }
+        let mut inconsistent_filesystems: Vec<_> = reports.keys().cloned().collect();
+        inconsistent_filesystems.sort();
+        self.inconsistent_filesystems = reports;
+
+        let (extend_state, meta_extended, metadata_low) =
+            self.thin_pool
+                .check(&dm, &mut self.block_devs, self.data_low_water)?;
+        if extend_state == PoolExtendState::Extended || meta_extended {
+            self.write_metadata()?;
+        }
+        if extend_state == PoolExtendState::Failed && self.extend_state != PoolExtendState::Failed {
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::CapacityLow(self.pool_uuid));
+        }
+        if metadata_low && !self.metadata_low {
+            self.events
+                .borrow_mut()
+                .push(EngineEvent::MetadataLow(self.pool_uuid));
+        }
+        self.extend_state = extend_state;
+        self.metadata_low = metadata_low;
+
+        let usage = self.thin_pool.filesystem_usage()?;
+        for event in fs_space_low_events(self.pool_uuid,
+                                         &usage,
+                                         self.fs_space_watermark,
+                                         &mut self.space_low_filesystems) {
+            self.events.borrow_mut().push(event);
+        }
+
+        let mut space_low_filesystems: Vec<_> =
+            self.space_low_filesystems.iter().cloned().collect();
+        space_low_filesystems.sort();
+
+        let temp_files_removed = match self.check_mdv() {
+            Ok(removed) => removed,
+            Err(err) => {
+                warn!("Could not check the MDV of pool {}: {}", self.pool_uuid, err);
+                0
+            }
+        };
+
+        Ok(PoolCheckReport {
+               extend_state: extend_state,
+               metadata_low: metadata_low,
+               inconsistent_filesystems: inconsistent_filesystems,
+               space_low_filesystems: space_low_filesystems,
+               temp_files_removed: temp_files_removed,
+               unreadable_records: self.thin_pool.unreadable_records().to_vec(),
+           })
+    }
+
     fn save_state(&mut self) -> EngineResult<()> {
         self.write_metadata()
     }
@@ -243,10 +1180,38 @@ impl HasName for StratPool {
     }
 }
 
+impl SetName for StratPool {
+    /// Rename the pool. The links to the device nodes of its filesystems
+    /// are moved to the directory of the new name.
+    fn set_name(&mut self, name: &str) {
+        let old_name = ::std::mem::replace(&mut self.name, name.to_owned());
+        devlinks::pool_renamed(&self.paths,
+                               &old_name,
+                               &self.name,
+                               &self.thin_pool.filesystem_devnodes());
+    }
+}
+
 impl Recordable<PoolSave> for StratPool {
     fn record(&self) -> PoolSave {
         PoolSave {
+            version: METADATA_VERSION,
             name: self.name.clone(),
+            mount_options: self.mount_options.clone(),
+            default_fs_size: self.default_fs_size,
+            fs_space_watermark: self.fs_space_watermark,
+            space_reserve: self.space_reserve,
+            data_low_water: Some(self.data_low_water.into()),
+            max_fs_size: self.max_fs_size,
+            labels: self.labels.clone(),
+            created: self.created,
+            last_modified: self.last_modified,
+            generation: self.generation,
+            key_description: self
+                .key_description
+                .as_ref()
+                .map(|key| key.as_str().to_owned()),
+            key_check: self.key_check.clone(),
             block_devs: self.block_devs.record(),
             flex_devs: self.thin_pool.record(),
             thinpool_dev: self.thin_pool.record(),
@@ -256,13 +1221,158 @@ impl Recordable<PoolSave> for StratPool {
 
 #[cfg(test)]
 mod tests {
-    use super::super::super::types::Redundancy;
+    use chrono;
+    use devicemapper::{Bytes, DataBlocks, DevId, DmFlags, IEC, MetaBlocks, device_exists};
+    use nix::mount::{MsFlags, mount, umount};
+    use nix::sys::stat::{S_IFBLK, S_IRUSR, S_IWUSR, mknod, stat};
+
+    use std::env;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::{Read, Seek, SeekFrom, Write};
+    use std::os::linux::fs::MetadataExt;
+    use std::os::unix::fs::symlink;
+    use std::process::Command;
+    use std::sync::mpsc::{RecvTimeoutError, channel};
+    use std::thread;
+    use std::time::Duration;
+
+    use super::super::super::engine::StratisPaths;
+    use super::super::super::types::{BlockDevTier, DEFAULT_MDV_SIZE, DevOwnership, FilesystemType,
+                                     MIN_MDV_SIZE, Redundancy};
 
+    use super::super::device::{DISK_BY_ID_ENV, blkdev_discard_max, blkdev_read_only};
+    use super::super::dmdevice::{ThinRole, format_thin_name};
+    use super::super::filesystem::fs_usage;
+    use super::super::failpoint::arm;
+    use super::super::metadata::{BDA_STATIC_HDR_SECTORS, MDA_RESERVED_SECTORS, StaticHeader};
     use super::super::setup::find_all;
     use super::super::tests::{loopbacked, real};
+    use super::super::tests::tempdir::TempDir;
 
     use super::*;
 
+    /// Verify that manually extending the thinpool after adding a blockdev
+    /// allocates the new space to the thinpool, and that a second
+    /// extension adds nothing.
+    fn test_extend_thinpool(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let (paths1, paths2) = paths.split_at(1);
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths1,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let total_data = |pool: &StratPool| pool.thin_pool.status(&dm).unwrap().unwrap().total_data;
+
+        pool.add_blockdevs(paths2, CreateForce::default()).unwrap();
+        let before = total_data(&pool);
+        let avail = pool.block_devs.avail_space();
+
+        let added = pool.extend_thinpool().unwrap();
+        assert!(added > Sectors(0));
+        assert!(added <= avail);
+        assert!(total_data(&pool) > before);
+        assert!(pool.block_devs.avail_space() < DATA_BLOCK_SIZE + MetaBlocks(1).sectors());
+
+        assert_eq!(pool.extend_thinpool().unwrap(), Sectors(0));
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_extend_thinpool() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_extend_thinpool);
+    }
+
+    #[test]
+    pub fn real_test_extend_thinpool() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_extend_thinpool);
+    }
+
+    /// Verify that the thinpool's metadata device and its spare are placed
+    /// on the metadata tier, and its data device and the MDV on the other
+    /// devices, when the pool is created, when it is extended, and when it
+    /// is set up again.
+    fn test_metadata_tier(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &paths[..1])
+            .unwrap();
+        let uuid = pool.uuid();
+
+        let check_tiers = |pool: &StratPool| {
+            let meta_uuids: Vec<DevUuid> = pool.blockdevs()
+                .iter()
+                .filter(|bd| bd.tier() == BlockDevTier::Metadata)
+                .map(|bd| bd.uuid())
+                .collect();
+            assert_eq!(meta_uuids.len(), 1);
+            assert_eq!(pool.blockdevs()
+                           .iter()
+                           .find(|bd| bd.uuid() == meta_uuids[0])
+                           .unwrap()
+                           .devnode(),
+                       paths[0]);
+
+            let flex_devs = pool.record().flex_devs;
+            let on_meta = |segs: &[(Uuid, Sectors, Sectors)]| {
+                segs.iter().all(|seg| seg.0 == meta_uuids[0])
+            };
+            let off_meta = |segs: &[(Uuid, Sectors, Sectors)]| {
+                segs.iter().all(|seg| seg.0 != meta_uuids[0])
+            };
+            assert!(on_meta(&flex_devs.thin_meta_dev));
+            assert!(on_meta(&flex_devs.thin_meta_dev_spare));
+            assert!(off_meta(&flex_devs.thin_data_dev));
+            assert!(off_meta(&flex_devs.meta_dev));
+        };
+
+        check_tiers(&pool);
+        pool.extend_thinpool().unwrap();
+        check_tiers(&pool);
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        check_tiers(&pool);
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_metadata_tier() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_metadata_tier);
+    }
+
+    #[test]
+    pub fn real_test_metadata_tier() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_metadata_tier);
+    }
+
     /// Verify that metadata can be read from pools.
     /// 1. Split paths into two separate sets.
     /// 2. Create pools from the two sets.
@@ -274,15 +1384,34 @@ mod tests {
         assert!(paths.len() > 1);
 
         let (paths1, paths2) = paths.split_at(paths.len() / 2);
-        let dm = DM::new().unwrap();
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
 
         let name1 = "name1";
-        let pool1 = StratPool::initialize(&name1, &dm, paths1, Redundancy::NONE, false).unwrap();
+        let pool1 = StratPool::initialize(&name1,
+                                          &dm,
+                                          &stratis_paths,
+                                          paths1,
+                                          Redundancy::NONE,
+                                          CreateForce::default(),
+                                          None,
+                                          None,
+                                          &[])
+                .unwrap();
         let uuid1 = pool1.uuid();
         let metadata1 = pool1.record();
 
         let name2 = "name2";
-        let pool2 = StratPool::initialize(&name2, &dm, paths2, Redundancy::NONE, false).unwrap();
+        let pool2 = StratPool::initialize(&name2,
+                                          &dm,
+                                          &stratis_paths,
+                                          paths2,
+                                          Redundancy::NONE,
+                                          CreateForce::default(),
+                                          None,
+                                          None,
+                                          &[])
+                .unwrap();
         let uuid2 = pool2.uuid();
         let metadata2 = pool2.record();
 
@@ -294,8 +1423,8 @@ mod tests {
         let pool_save2 = get_metadata(uuid2, devnodes2).unwrap().unwrap();
         assert_eq!(pool_save1, metadata1);
         assert_eq!(pool_save2, metadata2);
-        let blockdevs1 = get_blockdevs(uuid1, &pool_save1, devnodes1).unwrap();
-        let blockdevs2 = get_blockdevs(uuid2, &pool_save2, devnodes2).unwrap();
+        let blockdevs1 = get_blockdevs(uuid1, &pool_save1, devnodes1, Redundancy::NONE).unwrap();
+        let blockdevs2 = get_blockdevs(uuid2, &pool_save2, devnodes2, Redundancy::NONE).unwrap();
         assert_eq!(blockdevs1.len(), pool_save1.block_devs.len());
         assert_eq!(blockdevs2.len(), pool_save2.block_devs.len());
 
@@ -309,8 +1438,8 @@ mod tests {
         let pool_save2 = get_metadata(uuid2, devnodes2).unwrap().unwrap();
         assert_eq!(pool_save1, metadata1);
         assert_eq!(pool_save2, metadata2);
-        let blockdevs1 = get_blockdevs(uuid1, &pool_save1, devnodes1).unwrap();
-        let blockdevs2 = get_blockdevs(uuid2, &pool_save2, devnodes2).unwrap();
+        let blockdevs1 = get_blockdevs(uuid1, &pool_save1, devnodes1, Redundancy::NONE).unwrap();
+        let blockdevs2 = get_blockdevs(uuid2, &pool_save2, devnodes2, Redundancy::NONE).unwrap();
         assert_eq!(blockdevs1.len(), pool_save1.block_devs.len());
         assert_eq!(blockdevs2.len(), pool_save2.block_devs.len());
     }
@@ -324,29 +1453,2080 @@ mod tests {
     pub fn real_test_basic_metadata() {
         real::test_with_spec(real::DeviceLimits::AtLeast(2), test_basic_metadata);
     }
-    /// Verify that a pool with no devices does not have the minimum amount of
-    /// space required.
-    fn test_empty_pool(paths: &[&Path]) -> () {
-        assert_eq!(paths.len(), 0);
-        let dm = DM::new().unwrap();
-        assert!(match StratPool::initialize("stratis_test_pool",
+
+    /// The filesystem-specific options of the filesystem mounted at
+    /// mount_point, which follow the filesystem type and the source in the
+    /// last fields of its mountinfo line.
+    fn fs_mount_options(mount_point: &Path) -> Vec<String> {
+        let mut mountinfo = String::new();
+        fs::File::open("/proc/self/mountinfo")
+            .unwrap()
+            .read_to_string(&mut mountinfo)
+            .unwrap();
+        let mount_point = mount_point.to_str().unwrap();
+        let line = mountinfo
+            .lines()
+            .find(|line| line.split_whitespace().nth(4) == Some(mount_point))
+            .unwrap();
+        line.split_whitespace()
+            .last()
+            .unwrap()
+            .split(',')
+            .map(|opt| opt.to_owned())
+            .collect()
+    }
+
+    /// Verify that default mount options set on a pool are restored when
+    /// the pool is set up again, and that a filesystem is mounted with
+    /// them, and with the options given for the mount, which replace a
+    /// default of the same name. Invalid options are refused.
+    fn test_default_mount_options(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        assert!(pool.default_mount_options().is_empty());
+
+        pool.set_default_mount_options(&["discard", "logbufs=4"]).unwrap();
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let mut pool = StratPool::setup(uuid,
+                                        &dm,
+                                        &stratis_paths,
+                                        pools.get(&uuid).unwrap(),
+                                        false,
+                                        true)
+            .unwrap();
+        let defaults = vec!["discard".to_string(), "logbufs=4".to_string()];
+        assert_eq!(pool.default_mount_options(), defaults.as_slice());
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        pool.mount_filesystem(fs_uuid, tmp_dir.path(), &["logbufs=8"]).unwrap();
+        let options = fs_mount_options(tmp_dir.path());
+        assert!(options.contains(&"discard".to_string()));
+        assert!(options.contains(&"logbufs=8".to_string()));
+        assert!(!options.contains(&"logbufs=4".to_string()));
+        umount(tmp_dir.path()).unwrap();
+
+        assert!(match pool.mount_filesystem(fs_uuid, tmp_dir.path(), &["discard,nouuid"]) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.set_default_mount_options(&[""]) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(pool.default_mount_options(), defaults.as_slice());
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_default_mount_options() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_default_mount_options);
+    }
+
+    #[test]
+    pub fn real_test_default_mount_options() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_default_mount_options);
+    }
+
+    /// Verify that a pool's metadata volume is the size requested when the
+    /// pool is created, and is the same size when the pool is set up again.
+    /// Verify that a metadata volume smaller than the minimum is rejected.
+    fn test_mdv_size(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        assert!(match StratPool::initialize("name",
                                             &dm,
+                                            &stratis_paths,
                                             paths,
                                             Redundancy::NONE,
-                                            true)
-                              .unwrap_err() {
-                    EngineError::Engine(ErrorEnum::Invalid, _) => true,
+                                            CreateForce::default(),
+                                            Some(MIN_MDV_SIZE - Sectors(1)),
+                                            None,
+                                            &[]) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
                     _ => false,
                 });
+
+        let mdv_size = DEFAULT_MDV_SIZE * 4u64;
+        let pool = StratPool::initialize("name",
+                                         &dm,
+                                         &stratis_paths,
+                                         paths,
+                                         Redundancy::NONE,
+                                         CreateForce::default(),
+                                         Some(mdv_size),
+                                         None,
+                                         &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        assert_eq!(pool.thin_pool.mdv_size(), mdv_size);
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        assert_eq!(pool.thin_pool.mdv_size(), mdv_size);
+        pool.teardown().unwrap();
     }
 
     #[test]
-    pub fn loop_test_empty_pool() {
-        loopbacked::test_with_spec(loopbacked::DeviceLimits::Exactly(0), test_empty_pool);
+    pub fn loop_test_mdv_size() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_mdv_size);
     }
 
     #[test]
-    pub fn real_test_empty_pool() {
-        real::test_with_spec(real::DeviceLimits::Exactly(0), test_empty_pool);
+    pub fn real_test_mdv_size() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_mdv_size);
+    }
+
+    /// Verify that a pool set up without activating its filesystems knows
+    /// of them, but has no devices for them until they are activated.
+    fn test_setup_inactive(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        let fs_uuid = pool.create_filesystems(&[("fsname", None, None)])
+            .unwrap()
+            .into_inner()[0]
+            .1;
+        pool.teardown().unwrap();
+
+        let fs_name = format_thin_name(uuid, ThinRole::Filesystem(fs_uuid));
+
+        let pools = find_all().unwrap();
+        let mut pool = StratPool::setup(uuid,
+                                        &dm,
+                                        &stratis_paths,
+                                        pools.get(&uuid).unwrap(),
+                                        false,
+                                        false)
+            .unwrap();
+        assert!(pool.get_filesystem(fs_uuid).is_some());
+        assert!(!device_exists(&dm, fs_name.as_ref()).unwrap());
+
+        pool.activate_filesystem(fs_uuid).unwrap();
+        assert!(device_exists(&dm, fs_name.as_ref()).unwrap());
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_setup_inactive() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_setup_inactive);
+    }
+
+    #[test]
+    pub fn real_test_setup_inactive() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_setup_inactive);
+    }
+
+    /// Verify that the stable ID of each blockdev is recorded, and that
+    /// when the device nodes of the blockdevs are renamed, the pool is set
+    /// up from the devices found by their stable IDs, at their new nodes.
+    fn test_stable_ids(paths: &[&Path]) {
+        let id_dir = TempDir::new("stratis_testing").unwrap();
+        env::set_var(DISK_BY_ID_ENV, id_dir.path());
+        let stable_id = |i: usize| format!("wwn-stratis-test-{}", i);
+        for (i, path) in paths.iter().enumerate() {
+            symlink(path, id_dir.path().join(stable_id(i))).unwrap();
+        }
+
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let pool = StratPool::initialize("name",
+                                         &dm,
+                                         &stratis_paths,
+                                         paths,
+                                         Redundancy::NONE,
+                                         CreateForce::default(),
+                                         None,
+                                         None,
+                                         &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        for (i, path) in paths.iter().enumerate() {
+            let bd = pool.block_devs
+                .blockdevs()
+                .into_iter()
+                .find(|bd| bd.devnode() == *path)
+                .unwrap();
+            assert_eq!(bd.hardware_id(), Some(stable_id(i).as_str()));
+        }
+        pool.teardown().unwrap();
+
+        // Give each device a new node, and point its stable ID at it, as
+        // udev would if the device were renamed.
+        let node_dir = TempDir::new_in("/dev", "stratis_testing").unwrap();
+        let renamed = |i: usize| node_dir.path().join(format!("renamed{}", i));
+        for (i, path) in paths.iter().enumerate() {
+            mknod(&renamed(i),
+                  S_IFBLK,
+                  S_IRUSR | S_IWUSR,
+                  fs::metadata(path).unwrap().st_rdev())
+                    .unwrap();
+            fs::remove_file(id_dir.path().join(stable_id(i))).unwrap();
+            symlink(renamed(i), id_dir.path().join(stable_id(i))).unwrap();
+        }
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        let mut devnodes = pool.block_devs
+            .blockdevs()
+            .iter()
+            .map(|bd| bd.devnode())
+            .collect::<Vec<_>>();
+        devnodes.sort();
+        assert_eq!(devnodes, (0..paths.len()).map(&renamed).collect::<Vec<_>>());
+
+        pool.teardown().unwrap();
+        env::remove_var(DISK_BY_ID_ENV);
+    }
+
+    #[test]
+    pub fn loop_test_stable_ids() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_stable_ids);
+    }
+    /// Verify that a pool with no devices does not have the minimum amount of
+    /// space required.
+    fn test_empty_pool(paths: &[&Path]) -> () {
+        assert_eq!(paths.len(), 0);
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        assert!(match StratPool::initialize("stratis_test_pool",
+                                            &dm,
+                                            &stratis_paths,
+                                            paths,
+                                            Redundancy::NONE,
+                                            CreateForce {
+                                                overwrite_signatures: true,
+                                                ..CreateForce::default()
+                                            },
+                                            None,
+                                            None,
+                                            &[])
+                              .unwrap_err() {
+                    EngineError::Engine(ErrorEnum::Invalid, _) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    pub fn loop_test_empty_pool() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Exactly(0), test_empty_pool);
+    }
+
+    #[test]
+    pub fn real_test_empty_pool() {
+        real::test_with_spec(real::DeviceLimits::Exactly(0), test_empty_pool);
+    }
+
+    /// Verify that a pool whose metadata has a newer version than is
+    /// supported is not set up.
+    fn test_future_version(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+
+        let mut metadata = pool.record();
+        metadata.version = METADATA_VERSION + 1;
+        let data = serde_json::to_string(&metadata).unwrap();
+        pool.block_devs.save_state(data.as_bytes()).unwrap();
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        assert!(match StratPool::setup(uuid,
+                                       &dm,
+                                       &stratis_paths,
+                                       pools.get(&uuid).unwrap(),
+                                       false,
+                                       true) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    pub fn loop_test_future_version() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_future_version);
+    }
+
+    #[test]
+    pub fn real_test_future_version() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_future_version);
+    }
+
+    /// Verify that either all requested filesystems are created or none.
+    /// 1. Create a filesystem.
+    /// 2. Request three filesystems, the second of which has the name of
+    ///    the existing filesystem, and verify that none are created.
+    /// 3. Inject a failure while saving the metadata of the second of three
+    ///    requested filesystems, and verify that none are created.
+    /// 4. Verify that none are found when the pool is set up again.
+    fn test_create_filesystems_atomic(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        pool.create_filesystems(&[("fs2", None, None)]).unwrap();
+
+        let specs = [("fs1", None, None), ("fs2", None, None), ("fs3", None, None)];
+        assert!(match pool.create_filesystems(&specs) {
+                    Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(pool.filesystems().len(), 1);
+
+        arm("ThinPool::create_filesystems", 1);
+        let specs = [("fs1", None, None), ("fs3", None, None), ("fs4", None, None)];
+        assert!(pool.create_filesystems(&specs).is_err());
+        assert_eq!(pool.filesystems().len(), 1);
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        assert_eq!(pool.filesystems().len(), 1);
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_create_filesystems_atomic() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_create_filesystems_atomic);
+    }
+
+    #[test]
+    pub fn real_test_create_filesystems_atomic() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_create_filesystems_atomic);
+    }
+
+    /// Verify that a pool's labels are saved, and restored when the pool is
+    /// set up again, and that a removed label stays removed.
+    fn test_labels(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        pool.set_label("team", Some("infra")).unwrap();
+        pool.set_label("env", Some("prod")).unwrap();
+        let labels = pool.labels().clone();
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let mut pool = StratPool::setup(uuid,
+                                        &dm,
+                                        &stratis_paths,
+                                        pools.get(&uuid).unwrap(),
+                                        false,
+                                        true)
+            .unwrap();
+        assert_eq!(pool.labels(), &labels);
+
+        pool.set_label("team", None).unwrap();
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        assert_eq!(pool.labels().keys().collect::<Vec<_>>(), vec!["env"]);
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_labels() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_labels);
+    }
+
+    #[test]
+    pub fn real_test_labels() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_labels);
+    }
+
+    /// Verify that filesystems created without a size have the pool's
+    /// default filesystem size, and that the default is restored when the
+    /// pool is set up again.
+    fn test_default_fs_size(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        assert_eq!(pool.default_fs_size(), DEFAULT_FS_SIZE);
+
+        let size = Sectors(IEC::Mi);
+        pool.set_default_fs_size(size).unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        assert_eq!(pool.thin_pool
+                       .get_filesystem_by_uuid(fs_uuid)
+                       .unwrap()
+                       .record()
+                       .size,
+                   size);
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        assert_eq!(pool.default_fs_size(), size);
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_default_fs_size() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_default_fs_size);
+    }
+
+    #[test]
+    pub fn real_test_default_fs_size() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_default_fs_size);
+    }
+
+    /// Verify that a read-only filesystem refuses writes, both to its
+    /// device and, once it is remounted, to its files, that it comes back
+    /// read-only when the pool is set up again, and that it can be made
+    /// writable again.
+    fn test_filesystem_read_only(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        let devnode = pool.get_filesystem(fs_uuid).unwrap().devnode();
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        pool.set_filesystem_read_only(fs_uuid, true).unwrap();
+        assert!(pool.get_filesystem(fs_uuid).unwrap().read_only());
+        assert!(fs::File::create(tmp_dir.path().join("stratis_test.txt")).is_err());
+        assert!(OpenOptions::new().write(true).open(&devnode).is_err());
+        umount(tmp_dir.path()).unwrap();
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let mut pool = StratPool::setup(uuid,
+                                        &dm,
+                                        &stratis_paths,
+                                        pools.get(&uuid).unwrap(),
+                                        false,
+                                        true)
+            .unwrap();
+        assert!(pool.get_filesystem(fs_uuid).unwrap().read_only());
+        assert!(blkdev_read_only(&devnode).unwrap());
+        assert!(OpenOptions::new().write(true).open(&devnode).is_err());
+
+        pool.set_filesystem_read_only(fs_uuid, false).unwrap();
+        assert!(!blkdev_read_only(&devnode).unwrap());
+        assert!(!pool.get_filesystem(fs_uuid).unwrap().read_only());
+        assert!(OpenOptions::new().write(true).open(&devnode).is_ok());
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_filesystem_read_only() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_filesystem_read_only);
+    }
+
+    #[test]
+    pub fn real_test_filesystem_read_only() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_filesystem_read_only);
+    }
+
+    /// Verify that when a filesystem with discards enabled is trimmed, the
+    /// space of a file deleted from it is returned to the thin pool, that
+    /// discards stay enabled when the pool is set up again, and that once
+    /// they are disabled the filesystem's thin device refuses them.
+    fn test_trim_filesystem(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        let devnode = pool.get_filesystem(fs_uuid).unwrap().devnode();
+        let device = Device::from(stat(&devnode).unwrap().st_rdev);
+        pool.enable_discards(fs_uuid, true).unwrap();
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().discards(), Some(true));
+
+        assert!(match pool.trim_filesystem(fs_uuid) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        let file_path = tmp_dir.path().join("stratis_test.txt");
+        {
+            let mut f = fs::File::create(&file_path).unwrap();
+            let buf = vec![1u8; IEC::Mi as usize];
+            for _ in 0..64 {
+                f.write_all(&buf).unwrap();
+            }
+            f.sync_all().unwrap();
+        }
+        let used_data = |pool: &StratPool| pool.thin_pool.status(&dm).unwrap().unwrap().used_data;
+        let used_before = used_data(&pool);
+
+        fs::remove_file(&file_path).unwrap();
+        Command::new("sync").output().unwrap();
+        pool.trim_filesystem(fs_uuid).unwrap();
+        assert!(used_data(&pool) < used_before);
+        umount(tmp_dir.path()).unwrap();
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let mut pool = StratPool::setup(uuid,
+                                        &dm,
+                                        &stratis_paths,
+                                        pools.get(&uuid).unwrap(),
+                                        false,
+                                        true)
+            .unwrap();
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().discards(), Some(true));
+        assert!(blkdev_discard_max(device).unwrap() > Bytes(0));
+
+        pool.enable_discards(fs_uuid, false).unwrap();
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().discards(), Some(false));
+        assert_eq!(blkdev_discard_max(device).unwrap(), Bytes(0));
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_trim_filesystem() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_trim_filesystem);
+    }
+
+    #[test]
+    pub fn real_test_trim_filesystem() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_trim_filesystem);
+    }
+
+    /// Verify that after a sparse pattern is written to a filesystem, fewer
+    /// of its sectors are allocated than its logical size, in whole data
+    /// blocks, and that the allocation of a filesystem which has not been
+    /// activated is not found.
+    fn test_allocation_info(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        let devnode = pool.get_filesystem(fs_uuid).unwrap().devnode();
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        {
+            let mut f = fs::File::create(tmp_dir.path().join("stratis_test.txt")).unwrap();
+            let buf = vec![1u8; IEC::Mi as usize];
+            for i in 0..8 {
+                f.seek(SeekFrom::Start(i * 16 * IEC::Mi)).unwrap();
+                f.write_all(&buf).unwrap();
+            }
+            f.sync_all().unwrap();
+        }
+        umount(tmp_dir.path()).unwrap();
+
+        let info = pool.filesystem_allocation_info(fs_uuid)
+            .unwrap()
+            .unwrap();
+        assert_eq!(info.size,
+                   pool.thin_pool
+                       .get_filesystem_by_uuid(fs_uuid)
+                       .unwrap()
+                       .size());
+        assert!(info.allocated_blocks > DataBlocks(0));
+        assert!(info.allocated < info.size);
+        assert_eq!(DATA_BLOCK_SIZE * *info.allocated_blocks, info.allocated);
+        assert!(info.fragmentation <= 100);
+        assert!(match pool.filesystem_allocation_info(Uuid::new_v4()) {
+                    Err(EngineError::Engine(ErrorEnum::NotFound, _)) => true,
+                    _ => false,
+                });
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    false)
+            .unwrap();
+        assert_eq!(pool.filesystem_allocation_info(fs_uuid).unwrap(), None);
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_allocation_info() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_allocation_info);
+    }
+
+    #[test]
+    pub fn real_test_allocation_info() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_allocation_info);
+    }
+
+    /// Verify that a copy of a mounted filesystem has the filesystem's
+    /// contents, is not a snapshot of it, and is unchanged when the
+    /// filesystem is written afterwards.
+    fn test_clone_filesystem(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        let devnode = pool.get_filesystem(fs_uuid).unwrap().devnode();
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        let file_path = tmp_dir.path().join("stratis_test.txt");
+        {
+            let mut f = fs::File::create(&file_path).unwrap();
+            f.write_all(b"origin").unwrap();
+            f.sync_all().unwrap();
+        }
+
+        let clone_uuid = pool.clone_filesystem(fs_uuid, "copy").unwrap();
+        assert!(match pool.clone_filesystem(fs_uuid, "copy") {
+                    Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(pool.thin_pool
+                       .get_filesystem_by_uuid(clone_uuid)
+                       .unwrap()
+                       .snapshot_of(),
+                   None);
+        assert!(pool.filesystem_allocation_info(clone_uuid)
+                    .unwrap()
+                    .unwrap()
+                    .allocated_blocks > DataBlocks(0));
+
+        {
+            let mut f = fs::File::create(&file_path).unwrap();
+            f.write_all(b"changed").unwrap();
+            f.sync_all().unwrap();
+        }
+        umount(tmp_dir.path()).unwrap();
+
+        let clone_devnode = pool.get_filesystem(clone_uuid).unwrap().devnode();
+        mount(Some(&clone_devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        let mut contents = String::new();
+        fs::File::open(&file_path)
+            .unwrap()
+            .read_to_string(&mut contents)
+            .unwrap();
+        assert_eq!(contents, "origin");
+        umount(tmp_dir.path()).unwrap();
+
+        pool.destroy_filesystems(&[fs_uuid], false).unwrap();
+        assert!(pool.get_filesystem(clone_uuid).is_some());
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_clone_filesystem() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_clone_filesystem);
+    }
+
+    #[test]
+    pub fn real_test_clone_filesystem() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_clone_filesystem);
+    }
+
+    /// Verify that if a filesystem can not be destroyed because it is busy,
+    /// the filesystems destroyed before it are reported with the error.
+    fn test_destroy_filesystems_partial(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let fs_uuids = pool.create_filesystems(&[("fs1", None, None), ("fs2", None, None)])
+            .unwrap()
+            .into_inner()
+            .iter()
+            .map(|&(_, uuid)| uuid)
+            .collect::<Vec<_>>();
+        let devnode = pool.get_filesystem(fs_uuids[1]).unwrap().devnode();
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&devnode),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        {
+            let _file = fs::File::create(tmp_dir.path().join("stratis_test.txt")).unwrap();
+            assert!(match pool.destroy_filesystems(&fs_uuids, false) {
+                        Err(DestroyError {
+                                destroyed,
+                                error: EngineError::Engine(ErrorEnum::Busy, _),
+                            }) => destroyed == vec![fs_uuids[0]],
+                        _ => false,
+                    });
+        }
+        assert!(pool.get_filesystem(fs_uuids[0]).is_none());
+        assert!(pool.get_filesystem(fs_uuids[1]).is_some());
+
+        assert_eq!(pool.destroy_filesystems(&fs_uuids, false).unwrap(),
+                   DestroyAction::Destroyed(vec![fs_uuids[1]]));
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_destroy_filesystems_partial() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_destroy_filesystems_partial);
+    }
+
+    #[test]
+    pub fn real_test_destroy_filesystems_partial() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_destroy_filesystems_partial);
+    }
+
+    /// Verify that filesystems can be created until the pool's free space
+    /// is within its space reserve, and that then filesystems can be neither
+    /// created, nor snapshotted, nor grown, even after the pool is set up
+    /// again, until the reserve is lowered.
+    fn test_space_reserve(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        let total = pool.total_physical_size();
+        assert!(match pool.set_space_reserve(total + Sectors(1)) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        // Leave a single data block outside the reserve. Making a
+        // filesystem writes to at least one more block, after which the
+        // free space is within the reserve.
+        let reserve = total - pool.total_physical_used().unwrap() - DATA_BLOCK_SIZE;
+        pool.set_space_reserve(reserve).unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        assert!(pool.total_physical_used().unwrap() + reserve >= total);
+
+        assert!(match pool.create_filesystems(&[("fs2", None, None)]) {
+                    Err(EngineError::Engine(ErrorEnum::NoSpace, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.snapshot_filesystem(fs_uuid, "snapshot") {
+                    Err(EngineError::Engine(ErrorEnum::NoSpace, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.set_filesystem_size(fs_uuid, DEFAULT_FS_SIZE * 2u64) {
+                    Err(EngineError::Engine(ErrorEnum::NoSpace, _)) => true,
+                    _ => false,
+                });
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let mut pool = StratPool::setup(uuid,
+                                        &dm,
+                                        &stratis_paths,
+                                        pools.get(&uuid).unwrap(),
+                                        false,
+                                        true)
+            .unwrap();
+        assert_eq!(pool.space_reserve(), reserve);
+        assert!(match pool.create_filesystems(&[("fs2", None, None)]) {
+                    Err(EngineError::Engine(ErrorEnum::NoSpace, _)) => true,
+                    _ => false,
+                });
+
+        pool.set_space_reserve(Sectors(0)).unwrap();
+        pool.create_filesystems(&[("fs2", None, None)]).unwrap();
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_space_reserve() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_space_reserve);
+    }
+
+    #[test]
+    pub fn real_test_space_reserve() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_space_reserve);
+    }
+
+    /// Verify that a pool can not be made with a device given twice, either
+    /// by the same path or by a path and a symbolic link to it, and that
+    /// the device is not claimed.
+    fn test_duplicate_devices(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let link_dir = TempDir::new("stratis_testing").unwrap();
+        let link = link_dir.path().join("link");
+        symlink(paths[0], &link).unwrap();
+
+        for duplicates in &[[paths[0], paths[0]], [paths[0], link.as_path()]] {
+            assert!(match StratPool::initialize("name",
+                                                &dm,
+                                                &stratis_paths,
+                                                duplicates,
+                                                Redundancy::NONE,
+                                                CreateForce::default(),
+                                                None,
+                                                None,
+                                                &[]) {
+                        Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                        _ => false,
+                    });
+            assert_eq!(StaticHeader::determine_ownership(&mut OpenOptions::new()
+                                                                   .read(true)
+                                                                   .open(paths[0])
+                                                                   .unwrap())
+                               .unwrap(),
+                       DevOwnership::Unowned);
+        }
+    }
+
+    #[test]
+    pub fn loop_test_duplicate_devices() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_duplicate_devices);
+    }
+
+    #[test]
+    pub fn real_test_duplicate_devices() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_duplicate_devices);
+    }
+
+    /// Verify that a filesystem's size is the size it was created with
+    /// until it is grown, that a filesystem which can not be grown keeps
+    /// its size, and that the size it was grown to is kept when the pool is
+    /// set up again.
+    fn test_filesystem_size(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+
+        let size = Sectors(IEC::Gi);
+        let fs_uuid = pool.create_filesystems(&[("fs", Some(size), None)])
+            .unwrap()
+            .into_inner()[0]
+            .1;
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().size(), size);
+
+        arm("StratFilesystem::grow", 0);
+        assert!(pool.set_filesystem_size(fs_uuid, size * 2u64).is_err());
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().size(), size);
+        assert_eq!(pool.thin_pool
+                       .get_filesystem_by_uuid(fs_uuid)
+                       .unwrap()
+                       .device_size(&dm)
+                       .unwrap(),
+                   Some(size));
+
+        pool.set_filesystem_size(fs_uuid, size * 2u64).unwrap();
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().size(), size * 2u64);
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().size(), size * 2u64);
+        pool.teardown().unwrap();
+    }
+
+    /// Verify that filesystems can not be made or grown past the pool's
+    /// maximum filesystem size, and that the maximum is kept when the pool
+    /// is set up again.
+    fn test_max_fs_size(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+
+        let max = Sectors(IEC::Gi);
+        pool.set_max_fs_size(Some(max)).unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", Some(max), None)])
+            .unwrap()
+            .into_inner()[0]
+            .1;
+        assert!(match pool.create_filesystems(&[("fs2", Some(max * 2u64), None)]) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let mut pool = StratPool::setup(uuid,
+                                        &dm,
+                                        &stratis_paths,
+                                        pools.get(&uuid).unwrap(),
+                                        false,
+                                        true)
+            .unwrap();
+        assert_eq!(pool.max_fs_size(), Some(max));
+        assert!(match pool.set_filesystem_size(fs_uuid, max * 2u64) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(pool.get_filesystem(fs_uuid).unwrap().size(), max);
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_max_fs_size() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_max_fs_size);
+    }
+
+    #[test]
+    pub fn real_test_max_fs_size() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_max_fs_size);
+    }
+
+    #[test]
+    pub fn loop_test_filesystem_size() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_filesystem_size);
+    }
+
+    #[test]
+    pub fn real_test_filesystem_size() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_filesystem_size);
+    }
+
+    /// Verify that a filesystem can not be grown past its quota, so that a
+    /// write past its quota fails for lack of space, while another
+    /// filesystem of the pool is unaffected, that it is not grown past its
+    /// quota when the pool is checked, and that the quota is kept when the
+    /// pool is set up again.
+    fn test_filesystem_quota(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        let size = Sectors(IEC::Mi);
+        let fs_uuids = pool.create_filesystems(&[("fs1", Some(size), None),
+                                                 ("fs2", Some(size), None)])
+            .unwrap()
+            .into_inner()
+            .iter()
+            .map(|&(_, fs_uuid)| fs_uuid)
+            .collect::<Vec<_>>();
+
+        pool.set_filesystem_quota(fs_uuids[0], Some(size)).unwrap();
+        assert!(match pool.set_filesystem_size(fs_uuids[0], size * 2u64) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        let tmp_dirs = [TempDir::new("stratis_testing").unwrap(),
+                        TempDir::new("stratis_testing").unwrap()];
+        for (fs_uuid, tmp_dir) in fs_uuids.iter().zip(tmp_dirs.iter()) {
+            mount(Some(&pool.get_filesystem(*fs_uuid).unwrap().devnode()),
+                  tmp_dir.path(),
+                  Some("xfs"),
+                  MsFlags::empty(),
+                  None as Option<&str>)
+                    .unwrap();
+        }
+
+        // Try to write twice the quota. The write fails once the
+        // filesystem is full, without waiting for the pool to be checked.
+        let mut f = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(tmp_dirs[0].path().join("stratis_test.txt"))
+            .unwrap();
+        let chunk = vec![1; IEC::Mi as usize];
+        let err = (0..2 * *size.bytes() / IEC::Mi)
+            .map(|_| f.write_all(&chunk).and_then(|_| f.sync_data()))
+            .find(|result| result.is_err())
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(nix::Errno::ENOSPC as i32));
+
+        // Nor does checking the pool grow the full filesystem.
+        pool.check().unwrap();
+        assert_eq!(pool.get_filesystem(fs_uuids[0]).unwrap().size(), size);
+
+        let mut f = OpenOptions::new()
+            .create(true)
+            .truncate(true)
+            .write(true)
+            .open(tmp_dirs[1].path().join("stratis_test.txt"))
+            .unwrap();
+        f.write_all(&vec![1; 16 * IEC::Mi as usize]).unwrap();
+        f.sync_all().unwrap();
+
+        for tmp_dir in &tmp_dirs {
+            umount(tmp_dir.path()).unwrap();
+        }
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        assert_eq!(pool.get_filesystem(fs_uuids[0]).unwrap().quota(), Some(size));
+        assert_eq!(pool.get_filesystem(fs_uuids[1]).unwrap().quota(), None);
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_filesystem_quota() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_filesystem_quota);
+    }
+
+    #[test]
+    pub fn real_test_filesystem_quota() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_filesystem_quota);
+    }
+
+    /// Verify that a check raises a single event for a mounted filesystem
+    /// which uses more of its space than the pool's watermark, and that the
+    /// watermark is restored when the pool is set up again.
+    fn test_fs_space_low(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        pool.set_fs_space_watermark(1).unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", Some(Sectors(IEC::Mi)), None)])
+            .unwrap()
+            .into_inner()[0]
+            .1;
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&pool.get_filesystem(fs_uuid).unwrap().devnode()),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        let mut f = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .open(tmp_dir.path().join("stratis_test.txt"))
+            .unwrap();
+        f.write_all(&vec![1; 16 * IEC::Mi as usize]).unwrap();
+        f.sync_all().unwrap();
+        pool.events.borrow_mut().drain();
+
+        pool.check().unwrap();
+        pool.check().unwrap();
+        let events = pool.events.borrow_mut().drain();
+        assert_eq!(events.len(), 1);
+        assert!(match events[0] {
+                    EngineEvent::FilesystemSpaceLow(pool_uuid, uuid, percent) => {
+                        pool_uuid == pool.uuid() && uuid == fs_uuid && percent > 1
+                    }
+                    _ => false,
+                });
+
+        umount(tmp_dir.path()).unwrap();
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        assert_eq!(pool.fs_space_watermark(), 1);
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_fs_space_low() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_fs_space_low);
+    }
+
+    #[test]
+    pub fn real_test_fs_space_low() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_fs_space_low);
+    }
+
+    /// Verify that a filesystem is made with the options specified for it,
+    /// and that the options are restored when the pool is set up again.
+    fn test_xfs_options(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+
+        let options = FsOptions {
+            fs_type: FilesystemType::Xfs,
+            block_size: Some(Bytes(2048)),
+            inode_size: Some(Bytes(1024)),
+            reflink: None,
+        };
+        let fs_uuid = pool.create_filesystems(&[("fs", None, Some(options))])
+            .unwrap()
+            .into_inner()[0]
+            .1;
+
+        let output = Command::new("xfs_db")
+            .arg("-r")
+            .arg("-c")
+            .arg("sb 0")
+            .arg("-c")
+            .arg("p blocksize inodesize")
+            .arg(pool.get_filesystem(fs_uuid).unwrap().devnode())
+            .output()
+            .unwrap();
+        let superblock = String::from_utf8_lossy(&output.stdout);
+        assert!(superblock.contains("blocksize = 2048"));
+        assert!(superblock.contains("inodesize = 1024"));
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        assert_eq!(pool.thin_pool
+                       .get_filesystem_by_uuid(fs_uuid)
+                       .unwrap()
+                       .options(),
+                   options);
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_xfs_options() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_xfs_options);
+    }
+
+    #[test]
+    pub fn real_test_xfs_options() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_xfs_options);
+    }
+
+    /// Verify that an ext4 filesystem can be made, mounted, and grown, and
+    /// that its type is restored when the pool is set up again.
+    fn test_ext4(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+
+        let options = FsOptions {
+            fs_type: FilesystemType::Ext4,
+            ..Default::default()
+        };
+        let fs_uuid = pool.create_filesystems(&[("fs", Some(Sectors(IEC::Gi)), Some(options))])
+            .unwrap()
+            .into_inner()[0]
+            .1;
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&pool.get_filesystem(fs_uuid).unwrap().devnode()),
+              tmp_dir.path(),
+              Some("ext4"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        let (total_before, _) = fs_usage(tmp_dir.path()).unwrap();
+        pool.set_filesystem_size(fs_uuid, Sectors(2 * IEC::Gi)).unwrap();
+        let (total_after, _) = fs_usage(tmp_dir.path()).unwrap();
+        assert!(total_after > total_before);
+        umount(tmp_dir.path()).unwrap();
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        assert_eq!(pool.thin_pool
+                       .get_filesystem_by_uuid(fs_uuid)
+                       .unwrap()
+                       .options(),
+                   options);
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_ext4() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_ext4);
+    }
+
+    #[test]
+    pub fn real_test_ext4() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_ext4);
+    }
+
+    /// Verify that a check finds nothing amiss with a new filesystem, but
+    /// reports a filesystem whose device has been removed out-of-band.
+    fn test_check_filesystems(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+
+        pool.check().unwrap();
+        assert!(pool.inconsistent_filesystems().is_empty());
+
+        let fs_name = format_thin_name(pool.uuid(), ThinRole::Filesystem(fs_uuid));
+        dm.device_remove(&DevId::Name(&fs_name), DmFlags::empty())
+            .unwrap();
+
+        // The missing device is recorded, and does not fail the check.
+        pool.check().unwrap();
+        assert_eq!(pool.inconsistent_filesystems().len(), 1);
+        let report = &pool.inconsistent_filesystems()[&fs_uuid];
+        assert!(report.device_missing);
+        assert!(report.orphaned_mounts.is_empty());
+        assert!(!report.is_consistent());
+
+        pool.teardown_force(&dm);
+    }
+
+    #[test]
+    pub fn loop_test_check_filesystems() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_check_filesystems);
+    }
+
+    #[test]
+    pub fn real_test_check_filesystems() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_check_filesystems);
+    }
+
+    /// Verify that metadata in the initial format, which has neither a
+    /// version nor mount options, is upgraded to the current version when
+    /// the pool is set up, and that the pool's filesystems are preserved.
+    fn test_migrate(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+
+        let mut metadata = serde_json::to_value(&pool.record()).unwrap();
+        {
+            let fields = metadata.as_object_mut().unwrap();
+            fields.remove("version");
+            fields.remove("mount_options");
+            fields.remove("default_fs_size");
+            fields.remove("created");
+            fields.remove("last_modified");
+        }
+        let data = serde_json::to_string(&metadata).unwrap();
+        pool.block_devs.save_state(data.as_bytes()).unwrap();
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        assert_eq!(get_metadata(uuid, pools.get(&uuid).unwrap())
+                       .unwrap()
+                       .unwrap()
+                       .version,
+                   1);
+
+        for _ in 0..2 {
+            let pool = StratPool::setup(uuid,
+                                        &dm,
+                                        &stratis_paths,
+                                        pools.get(&uuid).unwrap(),
+                                        false,
+                                        true)
+                .unwrap();
+            assert!(pool.get_filesystem(fs_uuid).is_some());
+            assert!(pool.created().timestamp() > 0);
+            pool.teardown().unwrap();
+
+            assert_eq!(get_metadata(uuid, pools.get(&uuid).unwrap())
+                           .unwrap()
+                           .unwrap()
+                           .version,
+                       METADATA_VERSION);
+        }
+    }
+
+    #[test]
+    pub fn loop_test_migrate() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_migrate);
+    }
+
+    #[test]
+    pub fn real_test_migrate() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_migrate);
+    }
+
+    /// Verify that each write of the metadata advances its generation, and
+    /// that when the copies on two blockdevs disagree, the pool is set up
+    /// from the copy of the higher generation, even though the other was
+    /// written later.
+    fn test_metadata_generation(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        let generation = pool.record().generation;
+        pool.write_metadata().unwrap();
+        assert_eq!(pool.record().generation, generation + 1);
+
+        let dev_uuids: Vec<_> = pool.blockdevs().iter().map(|bd| bd.uuid()).collect();
+        let now = Utc::now();
+        for &(dev_uuid, name, generation, time) in
+            &[(dev_uuids[0], "newer", generation + 10, now + chrono::Duration::minutes(1)),
+              (dev_uuids[1], "older", generation + 5, now + chrono::Duration::minutes(2))] {
+            let mut metadata = pool.record();
+            metadata.name = name.into();
+            metadata.generation = generation;
+            let data = serde_json::to_string(&metadata).unwrap();
+            let mut bd = pool.block_devs.remove(dev_uuid).unwrap();
+            bd.save_state(&time, data.as_bytes()).unwrap();
+            pool.block_devs.insert(bd);
+        }
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let metadata = get_metadata(uuid, pools.get(&uuid).unwrap())
+            .unwrap()
+            .unwrap();
+        assert_eq!(metadata.name, "newer");
+        assert_eq!(metadata.generation, generation + 10);
+
+        let mut pool = StratPool::setup(uuid,
+                                        &dm,
+                                        &stratis_paths,
+                                        pools.get(&uuid).unwrap(),
+                                        false,
+                                        false)
+            .unwrap();
+        assert_eq!(pool.name(), "newer");
+        pool.write_metadata().unwrap();
+        assert_eq!(pool.record().generation, generation + 11);
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_metadata_generation() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3),
+                                   test_metadata_generation);
+    }
+
+    #[test]
+    pub fn real_test_metadata_generation() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_metadata_generation);
+    }
+
+    /// Verify that a pool whose metadata is corrupt in both primary MDA
+    /// regions of every blockdev is set up from the copies of the regions.
+    fn test_corrupt_primary_metadata(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+        let devices: Vec<_> = pool.blockdevs()
+            .iter()
+            .map(|bd| (bd.devnode(), bd.metadata_size()))
+            .collect();
+        pool.teardown().unwrap();
+
+        for &(ref devnode, metadata_size) in &devices {
+            let mda_size = metadata_size - BDA_STATIC_HDR_SECTORS - MDA_RESERVED_SECTORS;
+            let region_size = *(mda_size / 4usize).bytes();
+            let mut f = OpenOptions::new().write(true).open(devnode).unwrap();
+            for index in 0..2 {
+                // Past the 32 byte header of the region, into its data.
+                f.seek(SeekFrom::Start(*BDA_STATIC_HDR_SECTORS.bytes() + index * region_size +
+                                       32))
+                    .unwrap();
+                f.write_all(&[0xffu8; 16]).unwrap();
+            }
+            f.sync_all().unwrap();
+        }
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        assert_eq!(pool.name(), "name");
+        assert!(pool.get_filesystem(fs_uuid).is_some());
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_corrupt_primary_metadata() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_corrupt_primary_metadata);
+    }
+
+    #[test]
+    pub fn real_test_corrupt_primary_metadata() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_corrupt_primary_metadata);
+    }
+
+    /// Verify that a pool can not be created from a device that is too
+    /// small, and that the error names the device.
+    fn test_too_small(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        assert!(match StratPool::initialize("stratis_test_pool",
+                                            &dm,
+                                            &stratis_paths,
+                                            paths,
+                                            Redundancy::NONE,
+                                            CreateForce::default(),
+                                            None,
+                                            None,
+                                            &[]) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, msg)) => {
+                        paths
+                            .iter()
+                            .any(|p| msg.contains(&p.display().to_string()))
+                    }
+                    _ => false,
+                });
+    }
+
+    #[test]
+    pub fn loop_test_too_small() {
+        loopbacked::test_with_size_spec(loopbacked::DeviceLimits::Exactly(1),
+                                        Bytes(IEC::Mi * 512),
+                                        test_too_small);
+    }
+
+    /// Verify that if pool initialization fails at any step, the devices
+    /// are left unowned and no devicemapper devices are left behind.
+    ///
+    /// 1. Inject a failure after the first device is claimed.
+    /// 2. Inject a failure after some thinpool devices are set up.
+    /// 3. Inject a failure before the metadata is written.
+    /// 4. Verify that the devices can then be used to create a pool.
+    fn test_initialize_rollback(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let stratis_devs = || {
+            dm.list_devices()
+                .unwrap()
+                .into_iter()
+                .filter(|d| d.0.to_string().starts_with("stratis-"))
+                .count()
+        };
+        let devs_before = stratis_devs();
+
+        for fail_point in &["BlockDevMgr::initialize", "ThinPool::new", "StratPool::initialize"] {
+            arm(fail_point, 0);
+            assert!(StratPool::initialize("stratis_test_pool",
+                                          &dm,
+                                          &stratis_paths,
+                                          paths,
+                                          Redundancy::NONE,
+                                          CreateForce::default(),
+                                          None,
+                                          None,
+                                          &[])
+                            .is_err());
+
+            assert!(paths
+                        .iter()
+                        .all(|path| {
+                                 StaticHeader::determine_ownership(&mut OpenOptions::new()
+                                                                            .read(true)
+                                                                            .open(path)
+                                                                            .unwrap())
+                                         .unwrap() == DevOwnership::Unowned
+                             }));
+            assert_eq!(stratis_devs(), devs_before);
+        }
+
+        let pool = StratPool::initialize("stratis_test_pool",
+                                         &dm,
+                                         &stratis_paths,
+                                         paths,
+                                         Redundancy::NONE,
+                                         CreateForce::default(),
+                                         None,
+                                         None,
+                                         &[])
+            .unwrap();
+        pool.destroy().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_initialize_rollback() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3),
+                                   test_initialize_rollback);
+    }
+
+    #[test]
+    pub fn real_test_initialize_rollback() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_initialize_rollback);
+    }
+
+    /// Verify that a blockdev holding all of a pool's data can be removed
+    /// once another blockdev is added, and that the data survives.
+    ///
+    /// 1. Create a pool on one device and write a file to a filesystem.
+    /// 2. Add the remaining devices and remove the first device, failing
+    ///    part way at first, and verify that a failed removal leaves the
+    ///    pool's devices and free space as they were.
+    /// 3. Verify that the first device is unowned and that removing the
+    ///    pool's last device fails.
+    /// 4. Set up the pool again and verify the file's contents.
+    fn test_remove_blockdev(paths: &[&Path]) {
+        assert!(paths.len() > 1);
+
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("stratis_test_pool",
+                                             &dm,
+                                             &stratis_paths,
+                                             &paths[..1],
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let uuid = pool.uuid();
+        let first_uuid = pool.blockdevs()[0].uuid();
+        let fs_uuid = pool.create_filesystems(&[("fs", None, None)]).unwrap().into_inner()[0].1;
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let file_path = tmp_dir.path().join("stratis_test.txt");
+        let mount_fs = |pool: &StratPool| {
+            mount(Some(&pool.get_filesystem(fs_uuid).unwrap().devnode()),
+                  tmp_dir.path(),
+                  Some("xfs"),
+                  MsFlags::empty(),
+                  None as Option<&str>)
+                    .unwrap();
+        };
+
+        mount_fs(&pool);
+        {
+            let mut f = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&file_path)
+                .unwrap();
+            f.write_all(b"data").unwrap();
+            f.sync_all().unwrap();
+        }
+        umount(tmp_dir.path()).unwrap();
+
+        pool.add_blockdevs(&paths[1..], CreateForce::default()).unwrap();
+        let avail = pool.block_devs.avail_space();
+        let allocated = pool.thin_pool.allocated_on(first_uuid);
+        arm("ThinPool::evacuate", 0);
+        assert!(pool.remove_blockdev(first_uuid).is_err());
+        assert!(pool.get_blockdev(first_uuid).is_some());
+        assert_eq!(pool.block_devs.avail_space(), avail);
+        assert_eq!(pool.thin_pool.allocated_on(first_uuid), allocated);
+
+        pool.remove_blockdev(first_uuid).unwrap();
+        assert!(pool.get_blockdev(first_uuid).is_none());
+        assert_eq!(StaticHeader::determine_ownership(&mut OpenOptions::new()
+                                                               .read(true)
+                                                               .open(paths[0])
+                                                               .unwrap())
+                           .unwrap(),
+                   DevOwnership::Unowned);
+
+        if paths.len() == 2 {
+            let last_uuid = pool.blockdevs()[0].uuid();
+            assert!(match pool.remove_blockdev(last_uuid) {
+                        Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                        _ => false,
+                    });
+        }
+        pool.teardown().unwrap();
+
+        let pools = find_all().unwrap();
+        let pool = StratPool::setup(uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&uuid).unwrap(),
+                                    false,
+                                    true)
+            .unwrap();
+        assert_eq!(pool.blockdevs().len(), paths.len() - 1);
+
+        mount_fs(&pool);
+        let mut contents = Vec::new();
+        OpenOptions::new()
+            .read(true)
+            .open(&file_path)
+            .unwrap()
+            .read_to_end(&mut contents)
+            .unwrap();
+        umount(tmp_dir.path()).unwrap();
+        assert_eq!(contents, b"data".to_vec());
+
+        pool.destroy().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_remove_blockdev() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_remove_blockdev);
+    }
+
+    #[test]
+    pub fn real_test_remove_blockdev() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_remove_blockdev);
+    }
+
+    /// Verify that renaming a pool with a mounted filesystem moves the link
+    /// to the filesystem's device node to the directory of the new name,
+    /// and that the link resolves to the device node.
+    fn test_rename_devlinks(paths: &[&Path]) {
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let stratis_paths = StratisPaths::new(tmp_dir.path());
+
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let (_, fs_uuid) = pool.create_filesystems(&[("fsname", None, None)])
+            .unwrap()
+            .into_inner()
+            .pop()
+            .unwrap();
+        let devnode = pool.get_filesystem(fs_uuid).unwrap().devnode();
+        assert_eq!(fs::canonicalize(tmp_dir.path().join("name").join("fsname")).unwrap(),
+                   fs::canonicalize(&devnode).unwrap());
+
+        let mnt_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&devnode),
+              mnt_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+
+        pool.set_name("new_name");
+        assert!(!tmp_dir.path().join("name").exists());
+        assert_eq!(fs::canonicalize(tmp_dir.path().join("new_name").join("fsname")).unwrap(),
+                   fs::canonicalize(&devnode).unwrap());
+        // The MDV's mount point is named for the pool's UUID, not its name.
+        assert!(stratis_paths.mdv_dir(pool.uuid()).is_dir());
+
+        umount(mnt_dir.path()).unwrap();
+        pool.teardown().unwrap();
+        assert!(!tmp_dir.path().join("new_name").exists());
+    }
+
+    #[test]
+    pub fn loop_test_rename_devlinks() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_rename_devlinks);
+    }
+
+    #[test]
+    pub fn real_test_rename_devlinks() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_rename_devlinks);
+    }
+
+    /// Verify that renaming a mounted filesystem moves its link, and that
+    /// the filesystem, mounted by its old link, stays mounted.
+    fn test_rename_mounted_filesystem(paths: &[&Path]) {
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let stratis_paths = StratisPaths::new(tmp_dir.path());
+
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let (_, fs_uuid) = pool.create_filesystems(&[("fsname", None, None)])
+            .unwrap()
+            .into_inner()
+            .pop()
+            .unwrap();
+        let devnode = pool.get_filesystem(fs_uuid).unwrap().devnode();
+        let old_link = tmp_dir.path().join("name").join("fsname");
+
+        let mnt_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&old_link),
+              mnt_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+
+        assert_eq!(pool.rename_filesystem(fs_uuid, "new_fsname").unwrap(),
+                   RenameAction::Renamed);
+        assert_eq!(fs::canonicalize(tmp_dir.path().join("name").join("new_fsname")).unwrap(),
+                   fs::canonicalize(&devnode).unwrap());
+        assert!(fs::symlink_metadata(&old_link).is_err());
+        assert!(pool.get_filesystem(fs_uuid).unwrap().is_mounted().unwrap());
+        fs::File::create(mnt_dir.path().join("file")).unwrap();
+
+        umount(mnt_dir.path()).unwrap();
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_rename_mounted_filesystem() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_rename_mounted_filesystem);
+    }
+
+    #[test]
+    pub fn real_test_rename_mounted_filesystem() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_rename_mounted_filesystem);
+    }
+
+    /// Verify that while a pool is quiesced, a write to its mounted
+    /// filesystem blocks and the pool can not be changed, and that once it
+    /// is unquiesced the write completes.
+    fn test_quiesce(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let mut pool = StratPool::initialize("name",
+                                             &dm,
+                                             &stratis_paths,
+                                             paths,
+                                             Redundancy::NONE,
+                                             CreateForce::default(),
+                                             None,
+                                             None,
+                                             &[])
+            .unwrap();
+        let (_, fs_uuid) = pool.create_filesystems(&[("fsname", None, None)])
+            .unwrap()
+            .into_inner()
+            .pop()
+            .unwrap();
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&pool.get_filesystem(fs_uuid).unwrap().devnode()),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+
+        pool.quiesce().unwrap();
+        assert!(pool.is_quiesced());
+        assert!(match pool.check() {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+        assert!(match pool.create_filesystems(&[("fsname2", None, None)]) {
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) => true,
+                    _ => false,
+                });
+
+        let (sender, receiver) = channel();
+        let file_path = tmp_dir.path().join("stratis_test.txt");
+        let writer = thread::spawn(move || {
+            let mut f = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&file_path)
+                .unwrap();
+            f.write_all(b"data").unwrap();
+            f.sync_all().unwrap();
+            sender.send(()).unwrap();
+        });
+        assert_eq!(receiver.recv_timeout(Duration::from_secs(2)),
+                   Err(RecvTimeoutError::Timeout));
+
+        pool.unquiesce().unwrap();
+        assert!(!pool.is_quiesced());
+        receiver.recv_timeout(Duration::from_secs(30)).unwrap();
+        writer.join().unwrap();
+
+        umount(tmp_dir.path()).unwrap();
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_quiesce() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_quiesce);
+    }
+
+    #[test]
+    pub fn real_test_quiesce() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_quiesce);
     }
 }
@@ -0,0 +1,299 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+
+// Code to give copies of Stratis devices identities of their own, so that
+// they do not collide with the devices they were copied from.
+
+use std::collections::HashSet;
+use std::fs::OpenOptions;
+use std::path::Path;
+
+use chrono::{Duration, Utc};
+use serde_json;
+
+use super::super::errors::{EngineResult, EngineError, ErrorEnum};
+use super::super::types::{DevUuid, PoolUuid};
+
+use super::metadata::BDA;
+use super::serde_structs::PoolSave;
+use super::setup::{discover_devices, get_metadata};
+
+/// Load the BDA of the device at devnode.
+/// Returns a NotFound error if the device is not a Stratis device.
+fn load_bda(devnode: &Path) -> EngineResult<BDA> {
+    match BDA::load(&mut OpenOptions::new().read(true).open(devnode)?)? {
+        Some(bda) => Ok(bda),
+        None => {
+            let err_msg = format!("{} is not a Stratis device", devnode.display());
+            Err(EngineError::Engine(ErrorEnum::NotFound, err_msg))
+        }
+    }
+}
+
+/// Replace the device UUID dev_uuid with new_dev_uuid wherever it occurs
+/// in pool_save, and record devnode as the device's device node.
+fn relabel_pool_save(pool_save: &mut PoolSave,
+                     dev_uuid: DevUuid,
+                     new_dev_uuid: DevUuid,
+                     devnode: &Path) {
+    if let Some(mut bd_save) = pool_save.block_devs.remove(&dev_uuid) {
+        bd_save.devnode = Some(devnode.to_owned());
+        pool_save.block_devs.insert(new_dev_uuid, bd_save);
+    }
+
+    let flex_devs = &mut pool_save.flex_devs;
+    for seg in flex_devs
+            .meta_dev
+            .iter_mut()
+            .chain(flex_devs.thin_meta_dev.iter_mut())
+            .chain(flex_devs.thin_data_dev.iter_mut())
+            .chain(flex_devs.thin_meta_dev_spare.iter_mut()) {
+        if seg.0 == dev_uuid {
+            seg.0 = new_dev_uuid;
+        }
+    }
+}
+
+/// Give the devices at devnodes, which must all belong to one pool, the
+/// pool UUID new_pool_uuid, so that they form a pool of their own. This is
+/// for copies of the devices of a pool, made by copying their contents,
+/// which can not be set up alongside the devices they were copied from
+/// while both have the same UUIDs. The devices' own UUIDs are unchanged.
+/// Returns an Invalid error if the devices do not all belong to one pool,
+/// or if two of them have the same UUID, and an AlreadyExists error if a
+/// device of pool new_pool_uuid is present.
+pub fn relabel_pool(devnodes: &[&Path], new_pool_uuid: PoolUuid) -> EngineResult<()> {
+    if discover_devices()?.pools.contains_key(&new_pool_uuid) {
+        let err_msg = format!("devices of pool {} are already present", new_pool_uuid);
+        return Err(EngineError::Engine(ErrorEnum::AlreadyExists, err_msg));
+    }
+
+    let mut bdas = Vec::new();
+    for devnode in devnodes {
+        bdas.push((devnode, load_bda(devnode)?));
+    }
+
+    let pool_uuids: HashSet<_> = bdas.iter().map(|&(_, ref bda)| bda.pool_uuid()).collect();
+    if pool_uuids.len() > 1 {
+        let err_msg = "the devices to relabel belong to different pools";
+        return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+    }
+    let dev_uuids: HashSet<_> = bdas.iter().map(|&(_, ref bda)| bda.dev_uuid()).collect();
+    if dev_uuids.len() != bdas.len() {
+        let err_msg = "some of the devices to relabel have the same UUID";
+        return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+    }
+
+    for (devnode, mut bda) in bdas {
+        let dev_uuid = bda.dev_uuid();
+        bda.relabel(new_pool_uuid,
+                     dev_uuid,
+                     &mut OpenOptions::new().write(true).open(devnode)?)?;
+    }
+    Ok(())
+}
+
+/// Give the device at devnode the device UUID new_dev_uuid, both in its
+/// static header and in the metadata of its pool, which is saved anew on
+/// every device of the pool.
+/// A copy of a device which is to be relabeled must first be given a pool
+/// of its own by relabel_pool(); while the copy and the device it was
+/// copied from are in the same pool, they can not be told apart.
+/// Returns an Invalid error if another device present in the pool has the
+/// same UUID as the device, and an AlreadyExists error if a device present
+/// in the pool, or recorded in the pool's metadata, has the UUID
+/// new_dev_uuid.
+pub fn relabel_device(devnode: &Path, new_dev_uuid: DevUuid) -> EngineResult<()> {
+    let (pool_uuid, dev_uuid) = {
+        let bda = load_bda(devnode)?;
+        (bda.pool_uuid(), bda.dev_uuid())
+    };
+
+    let pool_devnodes = discover_devices()?
+        .pools
+        .remove(&pool_uuid)
+        .unwrap_or_default();
+    let mut bdas = Vec::new();
+    for pool_devnode in pool_devnodes.values() {
+        bdas.push((pool_devnode, load_bda(pool_devnode)?));
+    }
+
+    match bdas.iter()
+              .filter(|&&(_, ref bda)| bda.dev_uuid() == dev_uuid)
+              .count() {
+        0 => {
+            let err_msg = format!("{} was not found among the devices of pool {}",
+                                  devnode.display(),
+                                  pool_uuid);
+            return Err(EngineError::Engine(ErrorEnum::NotFound, err_msg));
+        }
+        1 => {}
+        _ => {
+            let err_msg = format!("several devices of pool {} have UUID {}; give the copies \
+                                   a pool of their own first",
+                                  pool_uuid,
+                                  dev_uuid);
+            return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+    }
+
+    let pool_save = get_metadata(pool_uuid, &pool_devnodes)?;
+    if bdas.iter().any(|&(_, ref bda)| bda.dev_uuid() == new_dev_uuid) ||
+       pool_save
+           .as_ref()
+           .map_or(false, |ps| ps.block_devs.contains_key(&new_dev_uuid)) {
+        let err_msg = format!("pool {} already has a device with UUID {}",
+                              pool_uuid,
+                              new_dev_uuid);
+        return Err(EngineError::Engine(ErrorEnum::AlreadyExists, err_msg));
+    }
+
+    // The metadata is saved before the static header is rewritten; the
+    // device is recorded under its new UUID only once both are done.
+    if let Some(mut pool_save) = pool_save {
+        relabel_pool_save(&mut pool_save, dev_uuid, new_dev_uuid, devnode);
+        pool_save.generation += 1;
+        let data = serde_json::to_string(&pool_save)?;
+
+        let current_time = Utc::now();
+        let stamp_time = match bdas.iter()
+                  .filter_map(|&(_, ref bda)| bda.last_update_time())
+                  .max() {
+            Some(time) if *time >= current_time => {
+                time.checked_add_signed(Duration::nanoseconds(1))
+                    .expect("last update time << maximum representable DateTime")
+            }
+            _ => current_time,
+        };
+
+        for &mut (pool_devnode, ref mut bda) in &mut bdas {
+            let mut f = OpenOptions::new().write(true).open(pool_devnode)?;
+            bda.save_state(&stamp_time, data.as_bytes(), &mut f)?;
+            f.sync_all()?;
+        }
+    }
+
+    for (pool_devnode, mut bda) in bdas {
+        if bda.dev_uuid() == dev_uuid {
+            bda.relabel(pool_uuid,
+                         new_dev_uuid,
+                         &mut OpenOptions::new().write(true).open(pool_devnode)?)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+    use std::rc::Rc;
+
+    use uuid::Uuid;
+
+    use devicemapper::Sectors;
+
+    use super::super::super::engine::{HasUuid, StratisPaths};
+    use super::super::super::types::{CreateForce, Redundancy};
+
+    use super::super::device::copy_sectors;
+    use super::super::dmdevice::SharedDM;
+    use super::super::pool::StratPool;
+    use super::super::setup::find_all;
+    use super::super::tests::{loopbacked, real};
+
+    use super::*;
+
+    /// Verify that a copy of the static header and metadata of the only
+    /// device of a pool can be given a pool and a UUID of its own, after
+    /// which the copy and the original are found in separate pools, each
+    /// recording only its own device, and the original pool can be set up.
+    fn test_relabel(paths: &[&Path]) {
+        let dm = Rc::new(SharedDM::new().unwrap());
+        let stratis_paths = StratisPaths::from_env();
+        let pool = StratPool::initialize("name",
+                                         &dm,
+                                         &stratis_paths,
+                                         &paths[..1],
+                                         Redundancy::NONE,
+                                         CreateForce::default(),
+                                         None,
+                                         None,
+                                         &[])
+            .unwrap();
+        let pool_uuid = pool.uuid();
+        pool.teardown().unwrap();
+
+        let (dev_uuid, bda_size) = {
+            let bda = load_bda(paths[0]).unwrap();
+            (bda.dev_uuid(), bda.size())
+        };
+        copy_sectors(paths[0], Sectors(0), paths[1], Sectors(0), bda_size).unwrap();
+        assert_eq!(discover_devices()
+                       .unwrap()
+                       .pools
+                       .get(&pool_uuid)
+                       .unwrap()
+                       .len(),
+                   2);
+
+        let new_pool_uuid = Uuid::new_v4();
+        let new_dev_uuid = Uuid::new_v4();
+        assert!(match relabel_device(paths[1], new_dev_uuid) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert!(match relabel_pool(&paths[..2], new_pool_uuid) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        relabel_pool(&paths[1..2], new_pool_uuid).unwrap();
+        assert!(match relabel_pool(&paths[1..2], pool_uuid) {
+                    Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
+                    _ => false,
+                });
+        assert!(match relabel_device(paths[1], dev_uuid) {
+                    Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
+                    _ => false,
+                });
+        relabel_device(paths[1], new_dev_uuid).unwrap();
+
+        let bda = load_bda(paths[1]).unwrap();
+        assert_eq!((bda.pool_uuid(), bda.dev_uuid()),
+                   (new_pool_uuid, new_dev_uuid));
+
+        let pools = find_all().unwrap();
+        for &(uuid, expected) in &[(pool_uuid, dev_uuid), (new_pool_uuid, new_dev_uuid)] {
+            let devnodes = pools.get(&uuid).unwrap();
+            assert_eq!(devnodes.len(), 1);
+            let pool_save = get_metadata(uuid, devnodes).unwrap().unwrap();
+            assert_eq!(pool_save.block_devs.keys().collect::<Vec<_>>(),
+                       vec![&expected]);
+            assert!(pool_save
+                        .flex_devs
+                        .thin_data_dev
+                        .iter()
+                        .all(|seg| seg.0 == expected));
+        }
+
+        let pool = StratPool::setup(pool_uuid,
+                                    &dm,
+                                    &stratis_paths,
+                                    pools.get(&pool_uuid).unwrap(),
+                                    false,
+                                    false)
+            .unwrap();
+        pool.teardown().unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_relabel() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_relabel);
+    }
+
+    #[test]
+    pub fn real_test_relabel() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_relabel);
+    }
+}
@@ -12,15 +12,60 @@
 // can convert to or from them when saving our current state, or
 // restoring state from saved metadata.
 
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::path::PathBuf;
 
 use uuid::Uuid;
 use serde::Serialize;
 
-use devicemapper::{Sectors, ThinDevId};
+use devicemapper::{Bytes, Sectors, ThinDevId};
 
-use super::super::types::{DevUuid, FilesystemUuid};
+use super::super::types::{DEFAULT_FS_SIZE, DEFAULT_FS_SPACE_WATERMARK, DataLowWater, DevUuid,
+                          FilesystemUuid};
+
+/// The version of the metadata format which this daemon writes, and the
+/// most recent version which it is able to read. Metadata of an older
+/// version is migrated to this version when its pool is set up.
+/// 1: The initial format.
+/// 2: Adds a pool's default filesystem mount options.
+/// 3: Adds a pool's default filesystem size.
+/// 4: Adds a filesystem's creation time.
+/// 5: Adds a pool's creation and last modification times.
+/// 6: Adds the options a filesystem was made with.
+/// 7: Adds a pool's filesystem space watermark.
+/// 8: Adds whether a filesystem is read-only.
+/// 9: Adds a pool's space reserve.
+/// 10: Adds whether a blockdev is in its pool's metadata tier.
+/// 11: Adds whether a filesystem passes discards to its pool.
+/// 12: Adds a pool's labels.
+/// 13: Adds the generation of a pool's metadata.
+/// 14: Adds the type of a filesystem.
+/// 15: Adds the quota of a filesystem.
+/// 16: Adds a pool's data low water mark.
+/// 17: Adds a pool's maximum filesystem size.
+/// 18: Adds the description of the key with which a pool's data is
+/// encrypted. A daemon which does not know of it would set the pool up over
+/// the encrypted data, so such a daemon must refuse the pool.
+/// 19: Adds the filesystem of which a filesystem is a snapshot.
+/// 20: Adds the check value of the key of an encrypted pool.
+pub const METADATA_VERSION: u32 = 20;
+
+/// Metadata written before the version was recorded is of the first version.
+fn initial_metadata_version() -> u32 {
+    1
+}
+
+/// Metadata written before the default filesystem size was recorded used
+/// the global default.
+fn default_fs_size() -> Sectors {
+    DEFAULT_FS_SIZE
+}
+
+/// Metadata written before the filesystem space watermark was recorded
+/// used the global default.
+fn default_fs_space_watermark() -> u8 {
+    DEFAULT_FS_SPACE_WATERMARK
+}
 
 /// Implements saving struct data to a serializable form. The form should be
 /// sufficient, in conjunction with the environment, to reconstruct the
@@ -31,12 +76,87 @@ pub trait Recordable<T: Serialize> {
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct PoolSave {
+    #[serde(default = "initial_metadata_version")]
+    pub version: u32,
     pub name: String,
+    #[serde(default)]
+    pub mount_options: Vec<String>,
+    #[serde(default = "default_fs_size")]
+    pub default_fs_size: Sectors,
+    /// The percentage of its space a filesystem may use before clients are
+    /// warned
+    #[serde(default = "default_fs_space_watermark")]
+    pub fs_space_watermark: u8,
+    /// The space kept free, beyond which filesystems are not created or
+    /// grown; none if not recorded
+    #[serde(default)]
+    pub space_reserve: Sectors,
+    /// How little of the thinpool's data device may be free before it is
+    /// extended; None if not recorded, in which case the default is used
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data_low_water: Option<DataLowWaterSave>,
+    /// The largest size to which a filesystem may be made or grown; None
+    /// if filesystems may be any size
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_fs_size: Option<Sectors>,
+    /// Arbitrary key/value labels; none if not recorded
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    /// Seconds since the Unix epoch, 0 if not recorded
+    #[serde(default)]
+    pub created: u64,
+    /// Seconds since the Unix epoch, 0 if not recorded
+    #[serde(default)]
+    pub last_modified: u64,
+    /// Incremented each time the metadata is written, 0 if not recorded
+    #[serde(default)]
+    pub generation: u64,
+    /// The description of the key in the kernel keyring with which the
+    /// pool's data is encrypted; None if the pool is not encrypted
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_description: Option<String>,
+    /// The check value of that key; None if the pool is not encrypted, or
+    /// if its metadata predates the check value
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_check: Option<String>,
     pub block_devs: HashMap<DevUuid, BlockDevSave>,
     pub flex_devs: FlexDevsSave,
     pub thinpool_dev: ThinPoolDevSave,
 }
 
+/// A pool's data low water mark; see DataLowWater.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DataLowWaterSave {
+    Percent(u8),
+    Sectors(Sectors),
+}
+
+impl From<DataLowWater> for DataLowWaterSave {
+    fn from(low_water: DataLowWater) -> DataLowWaterSave {
+        match low_water {
+            DataLowWater::Percent(percent) => DataLowWaterSave::Percent(percent),
+            DataLowWater::Sectors(sectors) => DataLowWaterSave::Sectors(sectors),
+        }
+    }
+}
+
+impl From<DataLowWaterSave> for DataLowWater {
+    fn from(low_water: DataLowWaterSave) -> DataLowWater {
+        match low_water {
+            DataLowWaterSave::Percent(percent) => DataLowWater::Percent(percent),
+            DataLowWaterSave::Sectors(sectors) => DataLowWater::Sectors(sectors),
+        }
+    }
+}
+
+/// Only the version of the saved pool metadata. It can be read even if the
+/// remainder of the metadata is in a format that is not understood.
+#[derive(Debug, PartialEq, Eq, Deserialize)]
+pub struct PoolSaveVersion {
+    #[serde(default = "initial_metadata_version")]
+    pub version: u32,
+}
+
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub struct BlockDevSave {
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -45,6 +165,10 @@ pub struct BlockDevSave {
     pub user_info: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub hardware_info: Option<String>,
+    /// Whether the blockdev holds the thinpool's metadata rather than the
+    /// pool's data
+    #[serde(default)]
+    pub metadata_tier: bool,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -53,6 +177,31 @@ pub struct FilesystemSave {
     pub uuid: FilesystemUuid,
     pub thin_id: ThinDevId,
     pub size: Sectors,
+    /// Seconds since the Unix epoch, 0 if not recorded
+    #[serde(default)]
+    pub created: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub origin: Option<FilesystemUuid>,
+    /// The type of the filesystem; None if it is XFS or was not recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fs_type: Option<String>,
+    /// The options the filesystem was made with; None if the default
+    /// was used or the option was not recorded
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_size: Option<Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub inode_size: Option<Bytes>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub reflink: Option<bool>,
+    /// Whether writes to the filesystem are refused; false if not recorded
+    #[serde(default)]
+    pub read_only: bool,
+    /// Whether the filesystem passes discards to its pool; None if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub discards: Option<bool>,
+    /// The size past which the filesystem is never grown; None if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub quota: Option<Sectors>,
 }
 
 #[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
@@ -67,3 +216,22 @@ pub struct FlexDevsSave {
 pub struct ThinPoolDevSave {
     pub data_block_size: Sectors,
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json;
+
+    use super::*;
+
+    #[test]
+    /// Verify that a filesystem saved without a creation time is read with
+    /// a creation time of 0, and without any recorded options.
+    fn filesystem_save_without_created() {
+        let data = "{\"name\":\"fs\",\"uuid\":\"b5ae6bb5-1d39-4356-b8d8-fe8a4a0fc4fb\",\
+                    \"thin_id\":0,\"size\":1024}";
+        let fssave: FilesystemSave = serde_json::from_str(data).unwrap();
+        assert_eq!(fssave.created, 0);
+        assert_eq!((fssave.block_size, fssave.inode_size, fssave.reflink),
+                   (None, None, None));
+    }
+}
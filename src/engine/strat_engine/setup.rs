@@ -6,40 +6,131 @@
 // Initial setup steps are steps that do not alter the environment.
 
 use std::collections::{HashMap, HashSet};
+use std::fmt;
 use std::io::ErrorKind;
 use std::fs::{OpenOptions, read_dir};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 
+use chrono::{DateTime, TimeZone, Utc};
 use nix::Errno;
 use serde_json;
 
 use devicemapper::Device;
 
 use super::super::errors::{EngineResult, EngineError, ErrorEnum};
-use super::super::types::PoolUuid;
+use super::super::types::{BlockDevTier, DevOwnership, DevUuid, DeviceMetadata, PoolUuid,
+                          Redundancy};
 
 use super::blockdev::StratBlockDev;
-use super::device::{blkdev_size, devnode_to_devno};
-use super::engine::DevOwnership;
-use super::metadata::{BDA, StaticHeader};
+use super::device::{blkdev_size, blkdev_stable_id, devnode_to_devno, resolve_stable_id};
+use super::failpoint::fail_point;
+use super::metadata::{BDA, BDA_STATIC_HDR_SECTORS, StaticHeader};
 use super::range_alloc::RangeAllocator;
-use super::serde_structs::PoolSave;
+use super::serde_structs::{METADATA_VERSION, PoolSave, PoolSaveVersion};
 
 
+/// Why a device examined by discover_devices() belongs to no pool.
+#[derive(Debug)]
+pub enum RejectReason {
+    /// The device could not be opened or read.
+    Unreadable(EngineError),
+    /// The device carries no signature at all.
+    Unowned,
+    /// The device carries a signature, but not a Stratis one.
+    Foreign,
+    /// The device appears to be a Stratis device, but no valid static
+    /// header could be read from it.
+    IncompleteMetadata(EngineError),
+}
+
+impl fmt::Display for RejectReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RejectReason::Unreadable(ref err) => write!(f, "unreadable: {}", err),
+            RejectReason::Unowned => write!(f, "no signature"),
+            RejectReason::Foreign => write!(f, "foreign signature"),
+            RejectReason::IncompleteMetadata(ref err) => {
+                write!(f, "incomplete Stratis metadata: {}", err)
+            }
+        }
+    }
+}
+
+/// A device examined by discover_devices() which belongs to no pool.
+#[derive(Debug)]
+pub struct RejectedDevice {
+    pub device: Device,
+    pub devnode: PathBuf,
+    pub reason: RejectReason,
+}
+
+/// The block devices examined by discover_devices().
+#[derive(Debug, Default)]
+pub struct DeviceDiscovery {
+    /// A map of pool uuids to a map of devices to devnodes for each pool
+    pub pools: HashMap<PoolUuid, HashMap<Device, PathBuf>>,
+    /// The devices which belong to no pool, with the reason for each
+    pub rejected: Vec<RejectedDevice>,
+}
+
+impl DeviceDiscovery {
+    /// The devices of each pool.
+    /// A device which could not be read, e.g., because of a transient IO
+    /// error on a disk which has nothing to do with Stratis, is skipped and
+    /// logged, so that it does not prevent the pools from being found. If
+    /// it belongs to a pool, the pool is found without it, and is set up as
+    /// if the device were missing.
+    /// Returns an error if any device appeared to be a Stratis device but
+    /// had no valid static header, since the devices found for a pool might
+    /// then be incomplete.
+    pub fn into_pools(self) -> EngineResult<HashMap<PoolUuid, HashMap<Device, PathBuf>>> {
+        for rejected in self.rejected {
+            match rejected.reason {
+                RejectReason::Unreadable(err) => {
+                    warn!("Skipping device {} at {}, which could not be read: {}",
+                          rejected.device,
+                          rejected.devnode.display(),
+                          err);
+                }
+                RejectReason::IncompleteMetadata(err) => return Err(err),
+                RejectReason::Unowned | RejectReason::Foreign => {}
+            }
+        }
+        Ok(self.pools)
+    }
+}
+
 /// Find all Stratis devices.
 ///
 /// Returns a map of pool uuids to a map of devices to devnodes for each pool.
+/// Devices which could not be read are skipped; see
+/// DeviceDiscovery::into_pools().
 pub fn find_all() -> EngineResult<HashMap<PoolUuid, HashMap<Device, PathBuf>>> {
+    discover_devices()?.into_pools()
+}
+
+/// Examine every block device, grouping the Stratis devices by pool and
+/// recording why each other device belongs to no pool.
+/// Returns an error only if the device directory itself can not be read;
+/// a device which can not be examined is recorded as unreadable, or, if
+/// it can not even be identified, logged and skipped.
+pub fn discover_devices() -> EngineResult<DeviceDiscovery> {
 
-    let mut pool_map = HashMap::new();
+    let mut discovery = DeviceDiscovery::default();
     let mut devno_set = HashSet::new();
     for dir_e in read_dir("/dev")? {
         let dir_e = dir_e?;
         let devnode = dir_e.path();
 
-        let devno = match devnode_to_devno(&devnode)? {
-            None => continue,
-            Some(devno) => {
+        let devno = match devnode_to_devno(&devnode) {
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("Skipping {}, which could not be examined: {}",
+                      devnode.display(),
+                      err);
+                continue;
+            }
+            Ok(Some(devno)) => {
                 // If this device has already been processed, continue.
                 if devno_set.insert(devno) {
                     devno
@@ -48,12 +139,13 @@ pub fn find_all() -> EngineResult<HashMap<PoolUuid, HashMap<Device, PathBuf>>> {
                 }
             }
         };
+        let device = Device::from(devno);
 
         let f = OpenOptions::new().read(true).open(&devnode);
 
         // There are some reasons for OpenOptions::open() to return an error
-        // which are not reasons for this method to return an error.
-        // Try to distinguish. Non-error conditions are:
+        // which are not reasons to consider the device at all.
+        // Try to distinguish. Such conditions are:
         //
         // 1. ENXIO: The device does not exist anymore. This means that the device
         // was volatile for some reason; in that case it can not belong to
@@ -62,45 +154,105 @@ pub fn find_all() -> EngineResult<HashMap<PoolUuid, HashMap<Device, PathBuf>>> {
         // 2. ENOMEDIUM: The device has no medium. An example of this case is an
         // empty optical drive.
         //
-        // Note that it is better to be conservative and return with an
-        // error in any case where failure to read the device could result
-        // in bad data for Stratis. Additional exceptions may be added,
+        // Note that it is better to be conservative and record the device
+        // as unreadable in any case where failure to read the device could
+        // result in bad data for Stratis. Additional exceptions may be added,
         // but only with a complete justification.
-        if f.is_err() {
-            let err = f.unwrap_err();
-            match err.kind() {
-                ErrorKind::NotFound => {
+        let mut f = match f {
+            Ok(f) => f,
+            Err(err) => {
+                if err.kind() == ErrorKind::NotFound {
                     continue;
                 }
-                _ => {
-                    if let Some(errno) = err.raw_os_error() {
-                        match Errno::from_i32(errno) {
-                            Errno::ENXIO | Errno::ENOMEDIUM => continue,
-                            _ => return Err(EngineError::Io(err)),
-                        };
-                    } else {
-                        return Err(EngineError::Io(err));
+                if let Some(errno) = err.raw_os_error() {
+                    match Errno::from_i32(errno) {
+                        Errno::ENXIO | Errno::ENOMEDIUM => continue,
+                        _ => {}
                     }
                 }
+                discovery
+                    .rejected
+                    .push(RejectedDevice {
+                              device: device,
+                              devnode: devnode,
+                              reason: RejectReason::Unreadable(EngineError::Io(err)),
+                          });
+                continue;
             }
+        };
+
+        if let Err(err) = fail_point("discover_devices") {
+            discovery
+                .rejected
+                .push(RejectedDevice {
+                          device: device,
+                          devnode: devnode,
+                          reason: RejectReason::Unreadable(err),
+                      });
+            continue;
         }
 
-        let mut f = f.expect("unreachable if f is err");
-        if let DevOwnership::Ours(pool_uuid, _) = StaticHeader::determine_ownership(&mut f)? {
-            // No value should ever be ejected, because duplicate device nodes
-            // are filtered out above. Therefore, the return value of insert()
-            // might as well be ignored.
-            let _ = pool_map
-                .entry(pool_uuid)
-                .or_insert_with(HashMap::new)
-                .insert(Device::from(devno), devnode);
+        let reason = match StaticHeader::determine_ownership(&mut f) {
+            Ok(DevOwnership::Ours(pool_uuid, _)) => {
+                // No value should ever be ejected, because duplicate device
+                // nodes are filtered out above. Therefore, the return value
+                // of insert() might as well be ignored.
+                let _ = discovery
+                    .pools
+                    .entry(pool_uuid)
+                    .or_insert_with(HashMap::new)
+                    .insert(device, devnode);
+                continue;
+            }
+            Ok(DevOwnership::Unowned) => RejectReason::Unowned,
+            Ok(DevOwnership::Theirs) => RejectReason::Foreign,
+            Err(err @ EngineError::Io(_)) => RejectReason::Unreadable(err),
+            Err(err) => RejectReason::IncompleteMetadata(err),
         };
+        discovery
+            .rejected
+            .push(RejectedDevice {
+                      device: device,
+                      devnode: devnode,
+                      reason: reason,
+                  });
     }
 
-    Ok(pool_map)
+    Ok(discovery)
+}
+
+/// Read the Stratis metadata on the device at devnode. The device is
+/// opened read-only, and nothing is written to it.
+/// Returns None if the device has no Stratis static header.
+pub fn read_device_metadata(devnode: &Path) -> EngineResult<Option<DeviceMetadata>> {
+    let bda = match BDA::load(&mut OpenOptions::new().read(true).open(devnode)?)? {
+        Some(bda) => bda,
+        None => return Ok(None),
+    };
+
+    Ok(Some(DeviceMetadata {
+                pool_uuid: bda.pool_uuid(),
+                dev_uuid: bda.dev_uuid(),
+                dev_size: bda.dev_size(),
+                mda_offset: BDA_STATIC_HDR_SECTORS,
+                data_offset: bda.size(),
+                initialization_time: Utc.timestamp(bda.initialization_time() as i64, 0),
+                last_update_time: bda.last_update_time().cloned(),
+            }))
+}
+
+/// Whether data is pool metadata, or at least of a version newer than this
+/// daemon understands, which is refused only once it has been found.
+fn is_pool_metadata(data: &[u8]) -> bool {
+    match serde_json::from_slice::<PoolSaveVersion>(data) {
+        Ok(PoolSaveVersion { version }) if version > METADATA_VERSION => true,
+        _ => serde_json::from_slice::<PoolSave>(data).is_ok(),
+    }
 }
 
 /// Get the most recent metadata from a set of Devices for a given pool UUID.
+/// The metadata of the highest generation is the most recent; of metadata
+/// of the same generation, that written last.
 /// Returns None if no metadata found for this pool.
 #[allow(implicit_hasher)]
 pub fn get_metadata(pool_uuid: PoolUuid,
@@ -122,52 +274,148 @@ pub fn get_metadata(pool_uuid: PoolUuid,
         }
     }
 
-    // Most recent time should never be None if this was a properly
+    // Some update time should always be found if this was a properly
     // created pool; this allows for the method to be called in other
     // circumstances.
-    let most_recent_time = {
-        match bdas.iter()
-                  .filter_map(|&(_, ref bda)| bda.last_update_time())
-                  .max() {
-            Some(time) => time,
-            None => return Ok(None),
-        }
-    };
+    if bdas.iter().all(|&(_, ref bda)| bda.last_update_time().is_none()) {
+        return Ok(None);
+    }
 
-    // Try to read from all available devnodes that could contain most
-    // recent metadata. In the event of errors, continue to try until all are
+    // Read from all available devnodes that contain metadata, as the copy
+    // written last may not be of the highest generation if the clock was
+    // set back. In the event of errors, continue to try until all are
     // exhausted.
-    for &(devnode, ref bda) in
-        bdas.iter()
-            .filter(|&&(_, ref bda)| bda.last_update_time() == Some(most_recent_time)) {
+    let mut newest: Option<(u64, &DateTime<Utc>, PoolSave)> = None;
+    for &(devnode, ref bda) in &bdas {
+        let update_time = match bda.last_update_time() {
+            Some(update_time) => update_time,
+            None => continue,
+        };
 
-        let poolsave = OpenOptions::new()
+        let data = OpenOptions::new()
             .read(true)
             .open(devnode)
             .ok()
-            .and_then(|mut f| bda.load_state(&mut f).ok())
-            .and_then(|opt| opt)
-            .and_then(|data| serde_json::from_slice(&data).ok());
+            .and_then(|mut f| bda.load_state_with(&mut f, &is_pool_metadata).ok())
+            .and_then(|opt| opt);
+
+        if let Some(data) = data {
+            // Refuse metadata written in a newer format than this daemon
+            // understands, rather than attempting to interpret it.
+            if let Ok(PoolSaveVersion { version }) = serde_json::from_slice(&data) {
+                if version > METADATA_VERSION {
+                    let err_msg = format!("pool {} metadata version {} is newer than {}",
+                                          pool_uuid,
+                                          version,
+                                          METADATA_VERSION);
+                    return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+                }
+            }
 
-        if poolsave.is_some() {
-            return Ok(poolsave);
+            if let Ok(poolsave) = serde_json::from_slice::<PoolSave>(&data) {
+                let is_newer = match newest {
+                    Some((generation, time, _)) => {
+                        (poolsave.generation, update_time) > (generation, time)
+                    }
+                    None => true,
+                };
+                if is_newer {
+                    newest = Some((poolsave.generation, update_time, poolsave));
+                }
+            }
         }
     }
 
+    if let Some((_, _, poolsave)) = newest {
+        return Ok(Some(poolsave));
+    }
+
     // If no data has yet returned, we have an error. That is, we should have
-    // some metadata, because we have a most recent time, but we failed to
+    // some metadata, because we have an update time, but we failed to
     // get any.
     let err_str = "timestamp indicates data was written, but no data succesfully read";
     Err(EngineError::Engine(ErrorEnum::NotFound, err_str.into()))
 }
 
+/// The blockdevs which pool_save, the metadata of pool pool_uuid, records
+/// but which are not among devnodes, in order of UUID.
+#[allow(implicit_hasher)]
+pub fn missing_blockdevs(pool_uuid: PoolUuid,
+                         pool_save: &PoolSave,
+                         devnodes: &HashMap<Device, PathBuf>)
+                         -> EngineResult<Vec<DevUuid>> {
+    let mut found = HashSet::new();
+    for devnode in devnodes.values() {
+        if let Some(bda) = BDA::load(&mut OpenOptions::new().read(true).open(devnode)?)? {
+            if bda.pool_uuid() == pool_uuid {
+                found.insert(bda.dev_uuid());
+            }
+        }
+    }
+
+    let mut missing: Vec<DevUuid> = pool_save
+        .block_devs
+        .keys()
+        .filter(|uuid| !found.contains(uuid))
+        .cloned()
+        .collect();
+    missing.sort();
+    Ok(missing)
+}
+
+/// The devices of pool pool_uuid, whose metadata is pool_save. A blockdev
+/// which has a stable ID recorded is looked for by its ID first, so that it
+/// is found by its current device node, whatever that was when the ID was
+/// recorded. The devices found by scanning, devnodes, are used for the
+/// other blockdevs.
+#[allow(implicit_hasher)]
+pub fn match_devices(pool_uuid: PoolUuid,
+                     pool_save: &PoolSave,
+                     devnodes: &HashMap<Device, PathBuf>)
+                     -> EngineResult<HashMap<Device, PathBuf>> {
+    let mut matched = HashMap::new();
+    for (dev_uuid, bd_save) in &pool_save.block_devs {
+        let (device, devnode) = match bd_save.hardware_info {
+            Some(ref stable_id) => {
+                match resolve_stable_id(stable_id)? {
+                    Some(found) => found,
+                    None => continue,
+                }
+            }
+            None => continue,
+        };
+        // A device which can not be read is left to be found by scanning.
+        let bda = OpenOptions::new()
+            .read(true)
+            .open(&devnode)
+            .ok()
+            .and_then(|mut f| BDA::load(&mut f).ok())
+            .and_then(|bda| bda);
+        match bda {
+            Some(ref bda) if bda.pool_uuid() == pool_uuid && bda.dev_uuid() == *dev_uuid => {
+                matched.insert(device, devnode);
+            }
+            _ => {}
+        }
+    }
+    for (device, devnode) in devnodes {
+        matched
+            .entry(*device)
+            .or_insert_with(|| devnode.clone());
+    }
+    Ok(matched)
+}
+
 /// Get all the blockdevs corresponding to this pool that can be obtained from
 /// the given devices.
-/// Returns an error if the blockdevs obtained do not match the metadata.
+/// Returns an error if the blockdevs obtained do not match the metadata,
+/// unless the only difference is that no more blockdevs are missing than
+/// a pool of the given redundancy can be set up without.
 #[allow(implicit_hasher)]
 pub fn get_blockdevs(pool_uuid: PoolUuid,
                      pool_save: &PoolSave,
-                     devnodes: &HashMap<Device, PathBuf>)
+                     devnodes: &HashMap<Device, PathBuf>,
+                     redundancy: Redundancy)
                      -> EngineResult<Vec<StratBlockDev>> {
     let segments = pool_save
         .flex_devs
@@ -209,25 +457,48 @@ pub fn get_blockdevs(pool_uuid: PoolUuid,
                                     EngineError::Engine(ErrorEnum::NotFound, err_msg)
                                 })?;
 
+                // The stable ID of a blockdev of a pool created before
+                // stable IDs were recorded is found now, to be recorded.
+                let stable_id = match bd_save.hardware_info {
+                    Some(ref stable_id) => Some(stable_id.clone()),
+                    None => blkdev_stable_id(*device)?,
+                };
+
+                let tier = if bd_save.metadata_tier {
+                    BlockDevTier::Metadata
+                } else {
+                    BlockDevTier::Data
+                };
                 blockdevs.push(StratBlockDev::new(*device,
                                                   devnode.to_owned(),
                                                   bda,
                                                   allocator,
                                                   bd_save.user_info.clone(),
-                                                  bd_save.hardware_info.clone()));
+                                                  stable_id,
+                                                  tier));
             }
         }
     }
 
-    // Verify that blockdevs found match blockdevs recorded.
+    // Verify that blockdevs found match blockdevs recorded, but for as many
+    // missing blockdevs as the pool's redundancy allows it to do without.
     let current_uuids: HashSet<_> = blockdevs.iter().map(|b| b.uuid()).collect();
     let recorded_uuids: HashSet<_> = pool_save.block_devs.keys().cloned().collect();
 
-    if current_uuids != recorded_uuids {
-        let err_msg = "Recorded block dev UUIDs != discovered blockdev UUIDs";
+    if !current_uuids.is_subset(&recorded_uuids) {
+        let err_msg = "Discovered blockdev UUIDs which are not recorded";
         return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
     }
 
+    let missing = recorded_uuids.difference(&current_uuids).count();
+    if missing > redundancy.max_missing_devices() {
+        let err_msg = format!("{} recorded blockdevs were not discovered, but the pool can be \
+                               set up without at most {}",
+                              missing,
+                              redundancy.max_missing_devices());
+        return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+    }
+
     if blockdevs.len() != current_uuids.len() {
         let err_msg = "Duplicate block devices found in environment";
         return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
@@ -4,15 +4,59 @@
 
 extern crate env_logger;
 
+use std::cell::RefCell;
 use std::sync::{Once, ONCE_INIT};
 
+use log;
+use log::{Log, LogLevelFilter, LogMetadata, LogRecord};
+
 static LOGGER_INIT: Once = ONCE_INIT;
 
+thread_local! {
+    // Messages logged on this thread, if they are being captured
+    static CAPTURED: RefCell<Option<Vec<String>>> = RefCell::new(None);
+}
+
+/// A logger which writes to stderr as configured by env_logger, but which
+/// also records messages logged on a thread which is capturing them,
+/// regardless of their level.
+struct TestLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for TestLogger {
+    fn enabled(&self, _metadata: &LogMetadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &LogRecord) {
+        CAPTURED.with(|captured| if let Some(ref mut messages) = *captured.borrow_mut() {
+                          messages.push(record.args().to_string());
+                      });
+        self.inner.log(record);
+    }
+}
+
 /// Initialzie the logger once.  More than one init() attempt returns
 /// errors.
 pub fn init_logger() {
     LOGGER_INIT.call_once(|| {
-                              env_logger::init()
+        log::set_logger(|max_level| {
+                            max_level.set(LogLevelFilter::Trace);
+                            Box::new(TestLogger { inner: env_logger::Logger::new() })
+                        })
             .expect("This is the first and only initialization of the logger; it must succeed");
-                          });
+    });
+}
+
+/// Run f, returning its result and all messages logged on this thread
+/// while it ran.
+pub fn capture_logs<F, T>(f: F) -> (T, Vec<String>)
+    where F: FnOnce() -> T
+{
+    init_logger();
+    CAPTURED.with(|captured| *captured.borrow_mut() = Some(Vec::new()));
+    let result = f();
+    let messages = CAPTURED.with(|captured| captured.borrow_mut().take());
+    (result, messages.unwrap_or_default())
 }
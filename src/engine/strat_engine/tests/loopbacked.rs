@@ -4,9 +4,11 @@
 
 extern crate loopdev;
 
+use std::env;
 use std::fs::OpenOptions;
 use std::io::{Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
+use std::thread;
 
 use devicemapper::{Bytes, IEC, Sectors};
 
@@ -27,14 +29,27 @@ pub enum DeviceLimits {
     Range(usize, usize), // inclusive
 }
 
+/// If this environment variable is set, the loop devices and backing files
+/// of a failed test are left in place for inspection.
+const DEBUG_ENV_VAR: &str = "STRATIS_LOOP_DEBUG";
+
 pub struct LoopTestDev {
     ld: LoopDevice,
+    backing_file: PathBuf,
+    // Whether to leave the device attached if the test fails
+    preserve_on_failure: bool,
 }
 
 impl LoopTestDev {
     /// Create a new loopbacked device.
-    /// Create its backing store of 1 GiB wiping the first 1 MiB.
-    pub fn new(lc: &LoopControl, path: &Path) -> LoopTestDev {
+    /// Create its backing store of the given size wiping the first 1 MiB.
+    /// If preserve_on_failure is true, the device is not detached if it is
+    /// dropped while panicking.
+    pub fn new(lc: &LoopControl,
+               path: &Path,
+               size: Bytes,
+               preserve_on_failure: bool)
+               -> LoopTestDev {
         clean_up();
         let mut f = OpenOptions::new()
             .read(true)
@@ -45,7 +60,7 @@ impl LoopTestDev {
 
         // the proper way to do this is fallocate, but nix doesn't implement yet.
         // TODO: see https://github.com/nix-rust/nix/issues/596
-        f.seek(SeekFrom::Start(IEC::Gi)).unwrap();
+        f.seek(SeekFrom::Start(*size)).unwrap();
         f.write(&[0]).unwrap();
         f.flush().unwrap();
 
@@ -57,17 +72,52 @@ impl LoopTestDev {
                      Bytes(IEC::Mi).sectors())
                 .unwrap();
 
-        LoopTestDev { ld: ld }
+        LoopTestDev {
+            ld: ld,
+            backing_file: path.to_owned(),
+            preserve_on_failure: preserve_on_failure,
+        }
     }
 }
 
 impl Drop for LoopTestDev {
     fn drop(&mut self) {
+        if self.preserve_on_failure && thread::panicking() {
+            warn!("Preserving loop device {:?} backed by {:?}",
+                  self.ld.get_path(),
+                  self.backing_file);
+            return;
+        }
         clean_up();
         self.ld.detach().unwrap()
     }
 }
 
+/// A directory holding backing files, which is kept rather than removed if
+/// it is dropped while panicking and preserve_on_failure is true.
+struct BackingDir {
+    dir: Option<TempDir>,
+    preserve_on_failure: bool,
+}
+
+impl BackingDir {
+    fn path(&self) -> &Path {
+        self.dir
+            .as_ref()
+            .expect("dir is only taken on drop")
+            .path()
+    }
+}
+
+impl Drop for BackingDir {
+    fn drop(&mut self) {
+        if self.preserve_on_failure && thread::panicking() {
+            let path = self.dir.take().expect("dir is only taken on drop").into_path();
+            warn!("Preserving backing files in {:?}", path);
+        }
+    }
+}
+
 /// Get a list of counts of devices to use for tests.
 fn get_device_counts(limits: DeviceLimits) -> Vec<usize> {
     match limits {
@@ -79,30 +129,56 @@ fn get_device_counts(limits: DeviceLimits) -> Vec<usize> {
     }
 }
 
-/// Setup count loop backed devices in dir.
-fn get_devices(count: usize, dir: &TempDir) -> Vec<LoopTestDev> {
+/// Setup count loop backed devices of the given size in dir.
+/// If debug is true, log the backing file of each device, and preserve
+/// the devices if the test fails.
+fn get_devices(count: usize, size: Bytes, dir: &Path, debug: bool) -> Vec<LoopTestDev> {
     let lc = LoopControl::open().unwrap();
     let mut loop_devices = Vec::new();
 
     for index in 0..count {
-        let path = dir.path().join(format!("store{}", &index));
-        loop_devices.push(LoopTestDev::new(&lc, &path));
+        let path = dir.join(format!("store{}", &index));
+        let dev = LoopTestDev::new(&lc, &path, size, debug);
+        if debug {
+            info!("Loop device {:?} is backed by {:?}", dev.ld.get_path(), path);
+        }
+        loop_devices.push(dev);
     }
     loop_devices
 }
 
 
 /// Run the designated tests according to the specification.
+/// Each device is 1 GiB.
 pub fn test_with_spec<F>(limits: DeviceLimits, test: F) -> ()
     where F: Fn(&[&Path]) -> ()
+{
+    test_with_size_spec(limits, Bytes(IEC::Gi), test)
+}
+
+/// Run the designated tests according to the specification, using devices
+/// of the given size.
+/// If STRATIS_LOOP_DEBUG is set in the environment, the devices and their
+/// backing files are preserved if the test fails.
+pub fn test_with_size_spec<F>(limits: DeviceLimits, size: Bytes, test: F) -> ()
+    where F: Fn(&[&Path]) -> ()
+{
+    run_with_devices(limits, size, env::var_os(DEBUG_ENV_VAR).is_some(), test)
+}
+
+fn run_with_devices<F>(limits: DeviceLimits, size: Bytes, debug: bool, test: F) -> ()
+    where F: Fn(&[&Path]) -> ()
 {
     let counts = get_device_counts(limits);
 
     init_logger();
 
     for count in counts {
-        let tmpdir = TempDir::new("stratis").unwrap();
-        let loop_devices: Vec<LoopTestDev> = get_devices(count, &tmpdir);
+        let tmpdir = BackingDir {
+            dir: Some(TempDir::new("stratis").unwrap()),
+            preserve_on_failure: debug,
+        };
+        let loop_devices: Vec<LoopTestDev> = get_devices(count, size, tmpdir.path(), debug);
         let device_paths: Vec<PathBuf> = loop_devices
             .iter()
             .map(|x| x.ld.get_path().unwrap())
@@ -111,3 +187,14 @@ pub fn test_with_spec<F>(limits: DeviceLimits, test: F) -> ()
         test(&device_paths);
     }
 }
+
+#[test]
+#[ignore]
+/// A failing test run with debugging on, so that its preserved loop devices
+/// and backing files can be examined. The log records where they are.
+fn loop_test_preserve_on_failure() {
+    run_with_devices(DeviceLimits::Exactly(2),
+                     Bytes(IEC::Gi),
+                     true,
+                     |paths: &[&Path]| assert!(paths.is_empty()));
+}
@@ -6,7 +6,7 @@ extern crate env_logger;
 extern crate log;
 pub extern crate tempdir;
 
-mod logger;
+pub mod logger;
 mod util;
 pub mod loopbacked;
 pub mod real;
@@ -5,24 +5,31 @@
 /// Code to handle management of a pool's thinpool device.
 
 use std::borrow::BorrowMut;
+use std::collections::HashMap;
+use std::mem;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use uuid::Uuid;
 
-use devicemapper as dm;
-use devicemapper::{DM, DataBlocks, DmDevice, DmName, IEC, LinearDev, MetaBlocks, Sectors, Segment,
-                   ThinDev, ThinDevId, ThinPoolDev, ThinPoolWorkingStatus, device_exists};
+use devicemapper::{DM, DM_SUSPEND, DataBlocks, DevId, DmDevice, DmFlags, DmName, LinearDev,
+                   MetaBlocks, Sectors, Segment, ThinDev, ThinDevId, ThinPoolDev, device_exists};
 
-use super::super::engine::{Filesystem, HasName};
+use super::super::engine::{Filesystem, HasName, HasUuid, StratisPaths, validate_name};
 use super::super::errors::{EngineError, EngineResult, ErrorEnum};
 use super::super::structures::Table;
-use super::super::types::{DevUuid, PoolUuid, FilesystemUuid, RenameAction};
+use super::super::types::{AllocationInfo, AuditReport, BlockDevTier, DEFAULT_FS_SIZE,
+                          DataLowWater, DevUuid, KeyDescription, PoolExtendState, PoolUuid,
+                          FilesystemType, FilesystemUuid, RenameAction, FsOptions};
 
 use super::blockdevmgr::{BlockDevMgr, BlkDevSegment, map_to_dm};
-use super::device::wipe_sectors;
+use super::crypt::CryptDev;
+use super::device::{copy_sectors, wipe_device};
+use super::dmstatus::{ThinPoolHealth, ThinPoolStatus, parse_thinpool_status};
 use super::dmdevice::{FlexRole, ThinDevIdPool, ThinPoolRole, ThinRole, format_flex_name,
-                      format_thinpool_name, format_thin_name};
-use super::filesystem::{FilesystemStatus, StratFilesystem};
+                      format_thinpool_name, format_thin_name, retry_dm};
+use super::failpoint::fail_point;
+use super::filesystem::{FilesystemCheckReport, FilesystemStatus, StratFilesystem};
 use super::mdv::MetadataVol;
 use super::serde_structs::{FilesystemSave, FlexDevsSave, Recordable, ThinPoolDevSave};
 
@@ -31,13 +38,29 @@ pub const DATA_BLOCK_SIZE: Sectors = Sectors(2048);
 pub const DATA_LOWATER: DataBlocks = DataBlocks(512);
 const META_LOWATER: MetaBlocks = MetaBlocks(512);
 
-const DEFAULT_THIN_DEV_SIZE: Sectors = Sectors(2 * IEC::Gi); // 1 TiB
 
 const INITIAL_META_SIZE: MetaBlocks = MetaBlocks(4096);
 pub const INITIAL_DATA_SIZE: DataBlocks = DataBlocks(768);
-const INITIAL_MDV_SIZE: Sectors = Sectors(32 * IEC::Ki); // 16 MiB
 
 
+/// The segments of one of a pool's devices before it was moved off a
+/// blockdev, and the moves which were made.
+#[derive(Debug)]
+struct Relocation {
+    old_segs: Vec<BlkDevSegment>,
+    moves: Vec<SegmentMove>,
+}
+
+/// The devices moved off a blockdev by ThinPool::evacuate(), by which the
+/// evacuation can be undone.
+#[derive(Debug, Default)]
+pub struct Evacuation {
+    spare: Option<Relocation>,
+    mdv: Option<Relocation>,
+    meta: Option<Relocation>,
+    data: Option<Relocation>,
+}
+
 /// A ThinPool struct contains the thinpool itself, the spare
 /// segments for its metadata device, and the filesystems and filesystem
 /// metadata associated with it.
@@ -49,24 +72,55 @@ pub struct ThinPool {
     meta_spare_segments: Vec<BlkDevSegment>,
     data_segments: Vec<BlkDevSegment>,
     mdv_segments: Vec<BlkDevSegment>,
+    // The device which encrypts the data, if the pool is encrypted
+    crypt_dev: Option<CryptDev>,
     id_gen: ThinDevIdPool,
+    // Thin devices found at setup which no record accounts for
+    orphaned_thin_ids: Vec<ThinDevId>,
+    // Filesystem records which could not be read at setup
+    unreadable_records: Vec<PathBuf>,
     filesystems: Table<StratFilesystem>,
     mdv: MetadataVol,
 }
 
 impl ThinPool {
-    /// Make a new thin pool.
+    /// Make a new thin pool, with a filesystem metadata volume of mdv_size,
+    /// which is mounted in the Stratis /dev directory of paths.
+    /// If key is given, the thin pool's data is encrypted with the key it
+    /// describes.
+    #[allow(too_many_arguments)]
     pub fn new(pool_uuid: PoolUuid,
                dm: &DM,
+               paths: &StratisPaths,
                data_block_size: Sectors,
                low_water_mark: DataBlocks,
+               mdv_size: Sectors,
+               key: Option<&KeyDescription>,
                block_mgr: &mut BlockDevMgr)
                -> EngineResult<ThinPool> {
-        let mut segments_list =
-            match block_mgr.alloc_space(&[ThinPool::initial_metadata_size(),
-                                          ThinPool::initial_metadata_size(),
-                                          ThinPool::initial_data_size(),
-                                          ThinPool::initial_mdv_size()]) {
+        let meta_needed = ThinPool::initial_metadata_size() * 2u64;
+        let meta_avail = block_mgr.tier_avail_space(BlockDevTier::Metadata);
+        if block_mgr.has_metadata_tier() && meta_avail < meta_needed {
+            let err_msg = format!("The metadata tier devices have {} available, but the \
+                                   thinpool metadata devices need {}",
+                                  meta_avail,
+                                  meta_needed);
+            return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+
+        let mut meta_segments_list =
+            match block_mgr.alloc_space(BlockDevTier::Metadata,
+                                        &[ThinPool::initial_metadata_size(),
+                                          ThinPool::initial_metadata_size()]) {
+                Some(sl) => sl,
+                None => {
+                    let err_msg = "Could not allocate sufficient space for thinpool devices.";
+                    return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
+                }
+            };
+        let mut data_segments_list =
+            match block_mgr.alloc_space(BlockDevTier::Data,
+                                        &[ThinPool::initial_data_size(), mdv_size]) {
                 Some(sl) => sl,
                 None => {
                     let err_msg = "Could not allocate sufficient space for thinpool devices.";
@@ -74,10 +128,10 @@ impl ThinPool {
                 }
             };
 
-        let mdv_segments = segments_list.pop().expect("len(segments_list) == 4");
-        let data_segments = segments_list.pop().expect("len(segments_list) == 3");
-        let spare_segments = segments_list.pop().expect("len(segments_list) == 2");
-        let meta_segments = segments_list.pop().expect("len(segments_list) == 1");
+        let mdv_segments = data_segments_list.pop().expect("len(data_segments_list) == 2");
+        let data_segments = data_segments_list.pop().expect("len(data_segments_list) == 1");
+        let spare_segments = meta_segments_list.pop().expect("len(meta_segments_list) == 2");
+        let meta_segments = meta_segments_list.pop().expect("len(meta_segments_list) == 1");
 
         // When constructing a thin-pool, Stratis reserves the first N
         // sectors on a block device by creating a linear device with a
@@ -87,22 +141,23 @@ impl ThinPool {
         // superblock DM issue error messages because it triggers code paths
         // that are trying to re-adopt the device with the attributes that
         // have been passed.
-        let meta_dev = LinearDev::setup(dm,
-                                        &format_flex_name(pool_uuid, FlexRole::ThinMeta),
-                                        None,
-                                        &map_to_dm(&meta_segments))?;
-        wipe_sectors(&meta_dev.devnode(),
-                     Sectors(0),
-                     ThinPool::initial_metadata_size())?;
+        let meta_dev = retry_dm(|| {
+                                    LinearDev::setup(dm,
+                                                     &format_flex_name(pool_uuid,
+                                                                       FlexRole::ThinMeta),
+                                                     None,
+                                                     &map_to_dm(&meta_segments))
+                                })?;
+        wipe_device(&meta_dev.devnode(), ThinPool::initial_metadata_size(), true)?;
 
-        let data_dev = LinearDev::setup(dm,
-                                        &format_flex_name(pool_uuid, FlexRole::ThinData),
-                                        None,
-                                        &map_to_dm(&data_segments))?;
+        let (data_dev, crypt_dev) = setup_datadev(dm, pool_uuid, &data_segments, key)?;
+
+        fail_point("ThinPool::new")?;
 
         let mdv_name = format_flex_name(pool_uuid, FlexRole::MetadataVolume);
-        let mdv_dev = LinearDev::setup(dm, &mdv_name, None, &map_to_dm(&mdv_segments))?;
-        let mdv = MetadataVol::initialize(pool_uuid, mdv_dev)?;
+        let mdv_dev =
+            retry_dm(|| LinearDev::setup(dm, &mdv_name, None, &map_to_dm(&mdv_segments)))?;
+        let mdv = MetadataVol::initialize(pool_uuid, mdv_dev, paths)?;
 
         let name = format_thinpool_name(pool_uuid, ThinPoolRole::Pool);
         let thinpool_dev = ThinPoolDev::new(dm,
@@ -119,7 +174,10 @@ impl ThinPool {
                meta_spare_segments: spare_segments,
                data_segments: data_segments,
                mdv_segments: mdv_segments,
+               crypt_dev: crypt_dev,
                id_gen: ThinDevIdPool::new_from_ids(&[]),
+               orphaned_thin_ids: vec![],
+               unreadable_records: vec![],
                filesystems: Table::default(),
                mdv: mdv,
            })
@@ -132,12 +190,24 @@ impl ThinPool {
     /// If initial setup fails due to a thin_check failure, attempt to fix
     /// the problem by running thin_repair. If failure recurs, return an
     /// error.
+    /// The MDV is mounted in the Stratis /dev directory of paths.
+    /// If repair_mdv is true, attempt to repair the MDV's filesystem if it
+    /// can not be mounted.
+    /// If activate_filesystems is false, the thin devices of the pool's
+    /// filesystems are not activated; see activate_filesystem().
+    /// If key is given, the thin pool's data is encrypted with the key it
+    /// describes, which must be in the kernel keyring.
+    #[allow(too_many_arguments)]
     pub fn setup(pool_uuid: PoolUuid,
                  dm: &DM,
+                 paths: &StratisPaths,
                  data_block_size: Sectors,
                  low_water_mark: DataBlocks,
                  flex_devs: &FlexDevsSave,
-                 bd_mgr: &BlockDevMgr)
+                 key: Option<&KeyDescription>,
+                 bd_mgr: &BlockDevMgr,
+                 repair_mdv: bool,
+                 activate_filesystems: bool)
                  -> EngineResult<ThinPool> {
         let uuid_to_devno = bd_mgr.uuid_to_devno();
         let mapper = |triple: &(DevUuid, Sectors, Sectors)| -> EngineResult<BlkDevSegment> {
@@ -178,12 +248,7 @@ impl ThinPool {
         let (meta_dev, meta_segments, spare_segments) =
             setup_metadev(dm, pool_uuid, &thinpool_name, meta_segments, spare_segments)?;
 
-        let data_dev = LinearDev::setup(dm,
-                                        &format_flex_name(pool_uuid, FlexRole::ThinData),
-                                        None,
-                                        &map_to_dm(&data_segments))?;
-
-
+        let (data_dev, crypt_dev) = setup_datadev(dm, pool_uuid, &data_segments, key)?;
 
         let thinpool_dev = ThinPoolDev::setup(dm,
                                               &thinpool_name,
@@ -193,25 +258,68 @@ impl ThinPool {
                                               meta_dev,
                                               data_dev)?;
 
-        let mdv_dev = LinearDev::setup(dm,
-                                       &format_flex_name(pool_uuid, FlexRole::MetadataVolume),
-                                       None,
-                                       &map_to_dm(&mdv_segments))?;
-        let mdv = MetadataVol::setup(pool_uuid, mdv_dev)?;
-        let filesystem_metadatas = mdv.filesystems()?;
+        let mdv_dev = retry_dm(|| {
+                                   LinearDev::setup(dm,
+                                                    &format_flex_name(pool_uuid,
+                                                                      FlexRole::MetadataVolume),
+                                                    None,
+                                                    &map_to_dm(&mdv_segments))
+                               })?;
+        let mdv = MetadataVol::setup(pool_uuid, mdv_dev, paths, repair_mdv)?;
+        let (filesystem_metadatas, unreadable_records) = mdv.filesystems()?;
+        for path in &unreadable_records {
+            warn!("Filesystem record {} of pool {} could not be read; its filesystem is not \
+                   set up, and no orphaned thin device of the pool may be deleted",
+                  path.display(),
+                  pool_uuid);
+        }
 
         // TODO: not fail completely if one filesystem setup fails?
         let filesystems = {
             // Set up a filesystem from its metadata.
             let get_filesystem = |fssave: &FilesystemSave| -> EngineResult<StratFilesystem> {
                 let device_name = format_thin_name(pool_uuid, ThinRole::Filesystem(fssave.uuid));
-                let thin_dev = ThinDev::setup(dm,
-                                              device_name.as_ref(),
-                                              None,
-                                              &thinpool_dev,
-                                              fssave.thin_id,
-                                              fssave.size)?;
-                Ok(StratFilesystem::setup(fssave.uuid, &fssave.name, thin_dev))
+                let fs_type = match fssave.fs_type {
+                    Some(ref fs_type) => FilesystemType::new(fs_type)?,
+                    None => FilesystemType::Xfs,
+                };
+                let options = FsOptions {
+                    fs_type: fs_type,
+                    block_size: fssave.block_size,
+                    inode_size: fssave.inode_size,
+                    reflink: fssave.reflink,
+                };
+                let mut fs = if activate_filesystems {
+                    let thin_dev = retry_dm(|| {
+                                                ThinDev::setup(dm,
+                                                               device_name.as_ref(),
+                                                               None,
+                                                               &thinpool_dev,
+                                                               fssave.thin_id,
+                                                               fssave.size)
+                                            })?;
+                    StratFilesystem::setup(fssave.uuid,
+                                           &fssave.name,
+                                           thin_dev,
+                                           fssave.created,
+                                           fssave.origin,
+                                           options)
+                } else {
+                    StratFilesystem::setup_inactive(fssave.uuid,
+                                                    &fssave.name,
+                                                    device_name.as_ref(),
+                                                    fssave.thin_id,
+                                                    fssave.size,
+                                                    fssave.created,
+                                                    fssave.origin,
+                                                    options)
+                };
+                fs.set_read_only(fssave.read_only)?;
+                if let Some(discards) = fssave.discards {
+                    fs.set_discards(discards)?;
+                }
+                fs.set_quota(fssave.quota)?;
+                Ok(fs)
             };
 
             filesystem_metadatas
@@ -229,7 +337,16 @@ impl ThinPool {
             }
         }
 
-        let thin_ids: Vec<ThinDevId> = filesystem_metadatas.iter().map(|x| x.thin_id).collect();
+        let mut thin_ids: Vec<ThinDevId> =
+            filesystem_metadatas.iter().map(|x| x.thin_id).collect();
+        let orphaned_thin_ids = find_orphaned_thin_devs(dm, pool_uuid, &thinpool_dev, &thin_ids)?;
+        for thin_id in &orphaned_thin_ids {
+            warn!("Thin device {} of pool {} has no filesystem recorded for it; it is kept \
+                   until it is explicitly deleted",
+                  thin_id,
+                  pool_uuid);
+        }
+        thin_ids.extend_from_slice(&orphaned_thin_ids);
         Ok(ThinPool {
                pool_uuid: pool_uuid,
                thin_pool: thinpool_dev,
@@ -237,7 +354,10 @@ impl ThinPool {
                meta_spare_segments: spare_segments,
                data_segments: data_segments,
                mdv_segments: mdv_segments,
+               crypt_dev: crypt_dev,
                id_gen: ThinDevIdPool::new_from_ids(&thin_ids),
+               orphaned_thin_ids: orphaned_thin_ids,
+               unreadable_records: unreadable_records,
                filesystems: fs_table,
                mdv: mdv,
            })
@@ -253,78 +373,248 @@ impl ThinPool {
         *INITIAL_DATA_SIZE * DATA_BLOCK_SIZE
     }
 
-    /// Initial size for a pool's filesystem metadata volume.
-    fn initial_mdv_size() -> Sectors {
-        INITIAL_MDV_SIZE
-    }
-
     /// Run status checks and take actions on the thinpool and its components.
-    pub fn check(&mut self, dm: &DM, bd_mgr: &mut BlockDevMgr) -> EngineResult<()> {
+    /// Returns the outcome of extending the thinpool, if that was necessary.
+    /// The data device is extended once less of it is free than low_water.
+    /// Returns the outcome of any extension of the data device, whether
+    /// the metadata device was extended, and whether the metadata device is
+    /// low on space even after any attempt to extend it.
+    pub fn check(&mut self,
+                 dm: &DM,
+                 bd_mgr: &mut BlockDevMgr,
+                 low_water: DataLowWater)
+                 -> EngineResult<(PoolExtendState, bool, bool)> {
         #![allow(match_same_arms)]
-        let thinpool: dm::ThinPoolStatus = self.thin_pool.status(dm)?;
-        match thinpool {
-            dm::ThinPoolStatus::Good(wstatus, usage) => {
-                match wstatus {
-                    ThinPoolWorkingStatus::Good => {}
-                    ThinPoolWorkingStatus::ReadOnly => {
+        let mut extend_state = PoolExtendState::Good;
+        let mut meta_extended = false;
+        let mut meta_low = false;
+        match self.status(dm)? {
+            Some(usage) => {
+                match usage.health {
+                    ThinPoolHealth::Good => {}
+                    ThinPoolHealth::ReadOnly => {
                         // TODO: why is pool r/o and how do we get it
                         // rw again?
                     }
-                    ThinPoolWorkingStatus::OutOfSpace => {
+                    ThinPoolHealth::OutOfDataSpace => {
                         // TODO: Add more space if possible, or
                         // prevent further usage
                         // Should never happen -- we should be extending first!
                     }
-                    ThinPoolWorkingStatus::NeedsCheck => {
+                    ThinPoolHealth::NeedsCheck => {
                         // TODO: Take pool offline?
                         // TODO: run thin_check
                     }
                 }
 
-                if usage.used_meta > usage.total_meta - META_LOWATER {
-                    // TODO: Extend meta device
+                if metadata_low(&usage) {
+                    // Running out of metadata space makes the pool
+                    // unusable, so double the meta device if possible.
+                    match self.extend_thinpool_meta(dm, usage.total_meta, bd_mgr) {
+                        Ok(_) => meta_extended = true,
+                        Err(err) => {
+                            warn!("Thinpool metadata of pool {} is low on space, \
+                                   used {} of {}, and could not be extended: {}",
+                                  self.pool_uuid,
+                                  usage.used_meta,
+                                  usage.total_meta,
+                                  err);
+                            meta_low = true;
+                        }
+                    }
                 }
 
-                if usage.used_data > usage.total_data - DATA_LOWATER {
+                if data_low(&usage, low_water, self.thin_pool.data_block_size()) {
                     // Request expansion of physical space allocated to the pool
                     // TODO: we just request that the space be doubled here.
                     // A more sophisticated approach might be in order.
-                    match self.extend_thinpool(dm, usage.total_data, bd_mgr) {
-                        #![allow(single_match)]
-                        Ok(_) => {}
-                        Err(_) => {} // TODO: Take pool offline?
-                    }
+                    extend_state = match self.extend_thinpool(dm, usage.total_data, bd_mgr) {
+                        Ok(_) => PoolExtendState::Extended,
+                        Err(_) => PoolExtendState::Failed, // TODO: Take pool offline?
+                    };
                 }
             }
-            dm::ThinPoolStatus::Fail => {
+            None => {
                 // TODO: Take pool offline?
                 // TODO: Run thin_check
             }
         };
 
-        let filesystems = self.filesystems
-            .borrow_mut()
+        // A filesystem whose device is missing is reported by
+        // check_filesystems(); it must not prevent the others being checked.
+        for fs in self.filesystems.borrow_mut().into_iter() {
+            match fs.check(dm) {
+                Ok(FilesystemStatus::Failed) => {
+                    // TODO: filesystem failed, how to recover?
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    warn!("Could not check filesystem {} of pool {}: {}",
+                          fs.uuid(),
+                          self.pool_uuid,
+                          err);
+                }
+            }
+        }
+        Ok((extend_state, meta_extended, meta_low))
+    }
+
+    /// Check each filesystem's consistency with its thin device.
+    /// Returns the reports of the filesystems found to be inconsistent.
+    /// A filesystem which can not be checked is logged and skipped, so that
+    /// the rest are still checked.
+    pub fn check_filesystems(&self, dm: &DM) -> Vec<(FilesystemUuid, FilesystemCheckReport)> {
+        let mut reports = Vec::new();
+        for fs in &self.filesystems {
+            match fs.check_consistency(dm) {
+                Ok(report) => {
+                    if !report.is_consistent() {
+                        reports.push((fs.uuid(), report));
+                    }
+                }
+                Err(err) => {
+                    warn!("Could not check filesystem {} of pool {}: {}",
+                          fs.uuid(),
+                          self.pool_uuid,
+                          err);
+                }
+            }
+        }
+        reports
+    }
+
+    /// Remove the temp files left in the MDV by saves which were
+    /// interrupted.
+    /// Returns the number of temp files found, and the paths of any which
+    /// could not be removed.
+    pub fn check_mdv(&self) -> EngineResult<(u64, Vec<PathBuf>)> {
+        self.mdv.check()
+    }
+
+    /// The percentage of its space used by each filesystem whose usage can
+    /// be found.
+    pub fn filesystem_usage(&self) -> EngineResult<Vec<(FilesystemUuid, u8)>> {
+        let mut usage = Vec::new();
+        for fs in &self.filesystems {
+            if let Some(percent) = fs.used_percent()? {
+                usage.push((fs.uuid(), percent));
+            }
+        }
+        Ok(usage)
+    }
+
+    /// Compare the filesystems with their records in the MDV and with their
+    /// thin devices. The size of a filesystem whose thin device has not
+    /// been activated, or no longer exists, is taken to be the size the
+    /// engine knows it to have.
+    pub fn audit(&self, dm: &DM) -> EngineResult<AuditReport> {
+        let (records, unreadable_records) = self.mdv.filesystems()?;
+        let records: HashMap<_, _> = records
             .into_iter()
-            .map(|fs| fs.check(dm))
-            .collect::<EngineResult<Vec<_>>>()?;
+            .map(|record| (record.uuid, record))
+            .collect();
 
-        for fs_status in filesystems {
-            if let FilesystemStatus::Failed = fs_status {
-                // TODO: filesystem failed, how to recover?
+        let mut report = AuditReport {
+            orphaned_thin_devs: self.orphaned_thin_ids.clone(),
+            unreadable_records: unreadable_records,
+            ..AuditReport::default()
+        };
+        for fs in &self.filesystems {
+            match records.get(&fs.uuid()) {
+                Some(record) => {
+                    let size = fs.device_size(dm)?.unwrap_or_else(|| fs.size());
+                    if size != record.size {
+                        report
+                            .size_mismatches
+                            .push((fs.uuid(), record.size, size));
+                    }
+                }
+                None => report.unrecorded_filesystems.push(fs.uuid()),
+            }
+        }
+        report.unknown_records = records
+            .keys()
+            .filter(|uuid| !self.filesystems.contains_uuid(**uuid))
+            .cloned()
+            .collect();
+
+        report.unknown_records.sort();
+        report.unrecorded_filesystems.sort();
+        report.size_mismatches.sort();
+        report.unreadable_records.sort();
+        Ok(report)
+    }
+
+    /// The filesystem records which could not be read when the thinpool was
+    /// set up.
+    pub fn unreadable_records(&self) -> &[PathBuf] {
+        &self.unreadable_records
+    }
+
+    /// Delete the thin devices which were found at setup to have no
+    /// filesystem recorded for them, so that their space is returned to the
+    /// pool.
+    /// Returns the thin ids of the devices deleted. A device which could
+    /// not be deleted is kept, and the error returned.
+    /// Returns an Invalid error, and deletes nothing, if any filesystem
+    /// record can not be read, as the devices may be the filesystems of
+    /// those records.
+    pub fn delete_orphaned_thin_devs(&mut self, dm: &DM) -> EngineResult<Vec<ThinDevId>> {
+        let (_, unreadable_records) = self.mdv.filesystems()?;
+        if !self.unreadable_records.is_empty() || !unreadable_records.is_empty() {
+            let err_msg = format!("filesystem records of pool {} can not be read, so thin \
+                                   devices without a record may belong to filesystems",
+                                  self.pool_uuid);
+            return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+
+        let mut deleted = Vec::new();
+        while let Some(&thin_id) = self.orphaned_thin_ids.first() {
+            self.thin_pool
+                .message(dm, &format!("delete {}", thin_id))?;
+            info!("Deleted orphaned thin device {} of pool {}",
+                  thin_id,
+                  self.pool_uuid);
+            self.orphaned_thin_ids.remove(0);
+            deleted.push(thin_id);
+        }
+        Ok(deleted)
+    }
+
+    /// The current status of the thinpool device, None if it has failed.
+    pub fn status(&self, dm: &DM) -> EngineResult<Option<ThinPoolStatus>> {
+        let (_, mut status) = dm.table_status(&DevId::Name(self.thin_pool.name()),
+                                              DmFlags::empty())?;
+        match status.pop() {
+            Some(line) => parse_thinpool_status(&line.params),
+            None => {
+                let err_msg = format!("no status for thinpool of pool {}", self.pool_uuid);
+                Err(EngineError::Engine(ErrorEnum::Error, err_msg))
             }
         }
-        Ok(())
     }
 
     /// Tear down the components managed here: filesystems, the MDV,
     /// and the actual thinpool device itself.
+    /// Returns a Busy error, and tears down nothing, if any filesystem is
+    /// mounted.
     pub fn teardown(self, dm: &DM) -> EngineResult<()> {
+        for fs in &self.filesystems {
+            if fs.is_mounted()? {
+                let err_msg = format!("filesystem {} is mounted", fs.name());
+                return Err(EngineError::Engine(ErrorEnum::Busy, err_msg));
+            }
+        }
+
         // Must succeed in tearing down all filesystems before the
         // thinpool..
         for fs in self.filesystems.empty() {
             fs.teardown(dm)?;
         }
         self.thin_pool.teardown(dm)?;
+        if let Some(crypt_dev) = self.crypt_dev {
+            crypt_dev.teardown(dm)?;
+        }
 
         // ..but MDV has no DM dependencies with the above
         self.mdv.teardown(dm)?;
@@ -332,6 +622,98 @@ impl ThinPool {
         Ok(())
     }
 
+    /// Detach all filesystems from their mount points, even if they are in
+    /// use, logging any which could not be detached.
+    /// Returns the UUIDs of all the filesystems.
+    pub fn unmount_filesystems_lazy(&self) -> Vec<FilesystemUuid> {
+        self.filesystems
+            .into_iter()
+            .map(|fs| {
+                     if let Err(err) = fs.unmount_lazy() {
+                         warn!("Could not unmount filesystem {}: {}", fs.uuid(), err);
+                     }
+                     fs.uuid()
+                 })
+            .collect()
+    }
+
+    /// Freeze all the mounted filesystems. If any can not be frozen, thaw
+    /// those which were, and return the error.
+    /// Returns the UUIDs of the filesystems which were frozen.
+    pub fn freeze_filesystems(&self) -> EngineResult<Vec<FilesystemUuid>> {
+        let mut frozen = Vec::new();
+        for fs in &self.filesystems {
+            match fs.freeze() {
+                Ok(true) => frozen.push(fs.uuid()),
+                Ok(false) => {}
+                Err(err) => {
+                    if let Err(thaw_err) = self.thaw_filesystems(&frozen) {
+                        warn!("Could not thaw filesystems after failed freeze: {}",
+                              thaw_err);
+                    }
+                    return Err(err);
+                }
+            }
+        }
+        Ok(frozen)
+    }
+
+    /// Thaw the filesystems fs_uuids, any of which may already have been
+    /// thawed. Every filesystem is thawed that can be; the last error, if
+    /// any, is returned.
+    pub fn thaw_filesystems(&self, fs_uuids: &[FilesystemUuid]) -> EngineResult<()> {
+        let mut result = Ok(());
+        for fs in fs_uuids
+                .iter()
+                .filter_map(|&uuid| self.filesystems.get_by_uuid(uuid)) {
+            if let Err(err) = fs.thaw() {
+                result = Err(err);
+            }
+        }
+        result
+    }
+
+    /// Allocate all the space available on the pool's blockdevs to the
+    /// thinpool. If the pool has a metadata tier, the meta device is given
+    /// the space of the metadata tier and the data device that of the data
+    /// tier; otherwise the space is divided between the meta and data
+    /// devices in about the proportion in which their current space is
+    /// divided. Any remainder too small to make a whole block is left
+    /// unallocated.
+    /// Returns the number of sectors added, zero if there was no space to
+    /// add.
+    pub fn extend_all(&mut self, dm: &DM, bd_mgr: &mut BlockDevMgr) -> EngineResult<Sectors> {
+        let (meta_extend, data_avail) = if bd_mgr.has_metadata_tier() {
+            let meta_avail = bd_mgr.tier_avail_space(BlockDevTier::Metadata);
+            (MetaBlocks(*meta_avail / *MetaBlocks(1).sectors()),
+             bd_mgr.tier_avail_space(BlockDevTier::Data))
+        } else {
+            let avail = bd_mgr.avail_space();
+            let meta_size: Sectors = self.meta_segments
+                .iter()
+                .map(|s| s.segment.length)
+                .sum();
+            let data_size: Sectors = self.data_segments
+                .iter()
+                .map(|s| s.segment.length)
+                .sum();
+            let meta_share = avail / ((*meta_size + *data_size) / *meta_size);
+            let meta_extend = MetaBlocks(*meta_share / *MetaBlocks(1).sectors());
+            (meta_extend, avail - meta_extend.sectors())
+        };
+
+        let data_extend = DataBlocks(*data_avail / *DATA_BLOCK_SIZE);
+
+        let mut added = Sectors(0);
+        if meta_extend != MetaBlocks(0) {
+            added += self.extend_thinpool_meta(dm, meta_extend, bd_mgr)?.sectors();
+        }
+        if data_extend != DataBlocks(0) {
+            added += *self.extend_thinpool(dm, data_extend, bd_mgr)? * DATA_BLOCK_SIZE;
+        }
+        Ok(added)
+    }
+
     /// Expand the physical space allocated to a pool by extend_size.
     /// Return the number of DataBlocks added.
     // TODO: Refine this method. A hard fail if the request can not be
@@ -341,7 +723,8 @@ impl ThinPool {
                        extend_size: DataBlocks,
                        bd_mgr: &mut BlockDevMgr)
                        -> EngineResult<DataBlocks> {
-        if let Some(mut new_data_regions) = bd_mgr.alloc_space(&[*extend_size * DATA_BLOCK_SIZE]) {
+        if let Some(mut new_data_regions) =
+            bd_mgr.alloc_space(BlockDevTier::Data, &[*extend_size * DATA_BLOCK_SIZE]) {
             self.extend_data(dm,
                              &new_data_regions
                                   .pop()
@@ -354,42 +737,244 @@ impl ThinPool {
         Ok(extend_size)
     }
 
+    /// Expand the space allocated to the pool's meta device by extend_size.
+    /// Return the number of MetaBlocks added.
+    fn extend_thinpool_meta(&mut self,
+                            dm: &DM,
+                            extend_size: MetaBlocks,
+                            bd_mgr: &mut BlockDevMgr)
+                            -> EngineResult<MetaBlocks> {
+        if let Some(mut new_meta_regions) =
+            bd_mgr.alloc_space(BlockDevTier::Metadata, &[extend_size.sectors()]) {
+            let segments = coalesce_segs(&self.meta_segments,
+                                         &new_meta_regions
+                                              .pop()
+                                              .expect("len(new_meta_regions) == 1"));
+            // TODO: Extend the spare meta device to match, so that the
+            // meta device can still be repaired.
+            self.thin_pool
+                .set_meta_segments(dm, &map_to_dm(&segments))?;
+            self.meta_segments = segments;
+        } else {
+            let err_msg = format!("Insufficient space to accomodate request for {}",
+                                  extend_size);
+            return Err(EngineError::Engine(ErrorEnum::Error, err_msg));
+        }
+        Ok(extend_size)
+    }
+
     /// Extend the thinpool with new data regions.
     fn extend_data(&mut self, dm: &DM, new_segs: &[BlkDevSegment]) -> EngineResult<()> {
-        let mut segments = Vec::with_capacity(self.data_segments.len() + new_segs.len());
-        segments.extend_from_slice(&self.data_segments);
-
-        // Last existing and first new may be contiguous. Coalesce into
-        // a single BlkDevSegment if so.
-        let coalesced_new_first = {
-            match new_segs.first() {
-                Some(new_first) => {
-                    let old_last = segments
-                        .last_mut()
-                        .expect("thin pool must always have some data segments");
-                    if old_last.uuid == new_first.uuid &&
-                       (old_last.segment.start + old_last.segment.length ==
-                        new_first.segment.start) {
-                        old_last.segment.length += new_first.segment.length;
-                        true
-                    } else {
-                        false
-                    }
+        let segments = coalesce_segs(&self.data_segments, new_segs);
+        self.set_data_segments(dm, &segments)?;
+        self.data_segments = segments;
+
+        Ok(())
+    }
+
+    /// Map the thinpool's data onto segments, through the pool's dm-crypt
+    /// device if it is encrypted.
+    fn set_data_segments(&mut self, dm: &DM, segments: &[BlkDevSegment]) -> EngineResult<()> {
+        match self.crypt_dev {
+            Some(ref mut crypt_dev) => {
+                crypt_dev.set_segments(dm, &map_to_dm(segments))?;
+                self.thin_pool
+                    .set_data_segments(dm, &[crypt_dev.segment()])?;
+            }
+            None => {
+                self.thin_pool
+                    .set_data_segments(dm, &map_to_dm(segments))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The number of sectors allocated to this pool's devices on the
+    /// blockdev dev_uuid.
+    pub fn allocated_on(&self, dev_uuid: DevUuid) -> Sectors {
+        [&self.meta_segments,
+         &self.meta_spare_segments,
+         &self.data_segments,
+         &self.mdv_segments]
+                .iter()
+                .flat_map(|segs| segs.iter())
+                .filter(|seg| seg.uuid == dev_uuid)
+                .map(|seg| seg.segment.length)
+                .sum()
+    }
+
+    /// Move all of this pool's devices off the blockdev dev_uuid, whose
+    /// device node is devnode, onto space newly allocated from bd_mgr.
+    /// The blockdev must already have been removed from bd_mgr.
+    /// Returns the moves made, by which they can be undone if the new
+    /// segments can not be persisted. If an error is returned, the devices
+    /// already moved have been moved back, and the space allocated for them
+    /// released.
+    pub fn evacuate(&mut self,
+                    dm: &DM,
+                    dev_uuid: DevUuid,
+                    devnode: &Path,
+                    bd_mgr: &mut BlockDevMgr)
+                    -> EngineResult<Evacuation> {
+        let mut evacuation = Evacuation::default();
+        if let Err(err) = self.evacuate_devs(dm, dev_uuid, devnode, bd_mgr, &mut evacuation) {
+            self.undo_evacuation(dm, evacuation, devnode, bd_mgr);
+            return Err(err);
+        }
+        Ok(evacuation)
+    }
+
+    /// Move the pool's devices one at a time, recording each move in
+    /// evacuation once it is complete. The space allocated for a device
+    /// which could not be moved is released.
+    fn evacuate_devs(&mut self,
+                     dm: &DM,
+                     dev_uuid: DevUuid,
+                     devnode: &Path,
+                     bd_mgr: &mut BlockDevMgr,
+                     evacuation: &mut Evacuation)
+                     -> EngineResult<()> {
+        // The spare holds no data, so it need only be reallocated.
+        let (spare_segs, moves) = relocate_segs(&self.meta_spare_segments,
+                                                dev_uuid,
+                                                BlockDevTier::Metadata,
+                                                bd_mgr)?;
+        if !moves.is_empty() {
+            let old_segs = mem::replace(&mut self.meta_spare_segments, spare_segs);
+            evacuation.spare = Some(Relocation { old_segs, moves });
+        }
+
+        // The MDV is only mounted while it is being read or written, so
+        // nothing is writing to it now.
+        let (mdv_segs, moves) =
+            relocate_segs(&self.mdv_segments, dev_uuid, BlockDevTier::Data, bd_mgr)?;
+        if !moves.is_empty() {
+            if let Err(err) = copy_moves(devnode, &moves, bd_mgr)
+                   .and_then(|_| self.mdv.set_segments(dm, &map_to_dm(&mdv_segs))) {
+                release_moves(&moves, bd_mgr);
+                return Err(err);
+            }
+            let old_segs = mem::replace(&mut self.mdv_segments, mdv_segs);
+            evacuation.mdv = Some(Relocation { old_segs, moves });
+        }
+
+        fail_point("ThinPool::evacuate")?;
+
+        // The meta and data devices are copied while the thinpool is
+        // suspended. Reloading the thinpool's table onto the new segments
+        // resumes it. They are moved one at a time, so that the thinpool
+        // never runs with one device on stale copies of the other's data.
+        let (meta_segs, moves) =
+            relocate_segs(&self.meta_segments, dev_uuid, BlockDevTier::Metadata, bd_mgr)?;
+        if !moves.is_empty() {
+            if let Err(err) = self.suspend_and_copy(dm, &|| copy_moves(devnode, &moves, bd_mgr))
+                   .and_then(|_| {
+                                 self.thin_pool
+                                     .set_meta_segments(dm, &map_to_dm(&meta_segs))
+                                     .map_err(From::from)
+                             }) {
+                release_moves(&moves, bd_mgr);
+                return Err(err);
+            }
+            let old_segs = mem::replace(&mut self.meta_segments, meta_segs);
+            evacuation.meta = Some(Relocation { old_segs, moves });
+        }
+
+        let (data_segs, moves) =
+            relocate_segs(&self.data_segments, dev_uuid, BlockDevTier::Data, bd_mgr)?;
+        if !moves.is_empty() {
+            if let Err(err) = self.suspend_and_copy(dm, &|| copy_moves(devnode, &moves, bd_mgr))
+                   .and_then(|_| self.set_data_segments(dm, &data_segs)) {
+                release_moves(&moves, bd_mgr);
+                return Err(err);
+            }
+            let old_segs = mem::replace(&mut self.data_segments, data_segs);
+            evacuation.data = Some(Relocation { old_segs, moves });
+        }
+
+        Ok(())
+    }
+
+    /// Undo the moves made by evacuate(), returning the pool's devices to
+    /// the blockdev whose device node is devnode, and releasing the space
+    /// to which they were moved. Devices which hold data are copied back.
+    /// A device which can not be moved back is left where it is, and the
+    /// failure is logged.
+    pub fn undo_evacuation(&mut self,
+                           dm: &DM,
+                           evacuation: Evacuation,
+                           devnode: &Path,
+                           bd_mgr: &mut BlockDevMgr) {
+        if let Some(relocation) = evacuation.data {
+            let copy_back = || copy_moves_back(devnode, &relocation.moves, bd_mgr);
+            match self.suspend_and_copy(dm, &copy_back)
+                      .and_then(|_| self.set_data_segments(dm, &relocation.old_segs)) {
+                Ok(_) => {
+                    self.data_segments = relocation.old_segs;
+                    release_moves(&relocation.moves, bd_mgr);
+                }
+                Err(err) => {
+                    warn!("Could not move the data device of pool {} back: {}",
+                          self.pool_uuid,
+                          err);
                 }
-                None => false,
             }
-        };
+        }
 
-        if coalesced_new_first {
-            segments.extend_from_slice(&new_segs[1..]);
-        } else {
-            segments.extend_from_slice(new_segs);
+        if let Some(relocation) = evacuation.meta {
+            let copy_back = || copy_moves_back(devnode, &relocation.moves, bd_mgr);
+            match self.suspend_and_copy(dm, &copy_back)
+                      .and_then(|_| {
+                                    self.thin_pool
+                                        .set_meta_segments(dm, &map_to_dm(&relocation.old_segs))
+                                        .map_err(From::from)
+                                }) {
+                Ok(_) => {
+                    self.meta_segments = relocation.old_segs;
+                    release_moves(&relocation.moves, bd_mgr);
+                }
+                Err(err) => {
+                    warn!("Could not move the metadata device of pool {} back: {}",
+                          self.pool_uuid,
+                          err);
+                }
+            }
         }
 
-        self.thin_pool
-            .set_data_segments(dm, &map_to_dm(&segments))?;
-        self.data_segments = segments;
+        // The MDV has not been written since it was copied.
+        if let Some(relocation) = evacuation.mdv {
+            match self.mdv.set_segments(dm, &map_to_dm(&relocation.old_segs)) {
+                Ok(_) => {
+                    self.mdv_segments = relocation.old_segs;
+                    release_moves(&relocation.moves, bd_mgr);
+                }
+                Err(err) => {
+                    warn!("Could not move the MDV of pool {} back: {}",
+                          self.pool_uuid,
+                          err);
+                }
+            }
+        }
 
+        if let Some(relocation) = evacuation.spare {
+            self.meta_spare_segments = relocation.old_segs;
+            release_moves(&relocation.moves, bd_mgr);
+        }
+    }
+
+    /// Suspend the thinpool and run copy. If the copy fails, resume the
+    /// thinpool before returning the error.
+    fn suspend_and_copy(&self, dm: &DM, copy: &Fn() -> EngineResult<()>) -> EngineResult<()> {
+        let id = DevId::Name(self.thin_pool.name());
+        dm.device_suspend(&id, DM_SUSPEND)?;
+        if let Err(err) = copy() {
+            if let Err(resume_err) = dm.device_suspend(&id, DmFlags::empty()) {
+                warn!("Failed to resume thinpool {} after failed copy: {}",
+                      self.thin_pool.name(),
+                      resume_err);
+            }
+            return Err(err);
+        }
         Ok(())
     }
 
@@ -398,10 +983,10 @@ impl ThinPool {
     // This includes all the sectors being held as spares for the meta device,
     // all the sectors allocated to the meta data device, and all the sectors
     // in use on the data device.
-    pub fn total_physical_used(&self) -> EngineResult<Sectors> {
-        let data_dev_used = match self.thin_pool.status(&DM::new()?)? {
-            dm::ThinPoolStatus::Good(_, usage) => *usage.used_data * DATA_BLOCK_SIZE,
-            _ => {
+    pub fn total_physical_used(&self, dm: &DM) -> EngineResult<Sectors> {
+        let data_dev_used = match self.status(dm)? {
+            Some(usage) => *usage.used_data * DATA_BLOCK_SIZE,
+            None => {
                 let err_msg = "thin pool failed, could not obtain usage";
                 return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg.into()));
             }
@@ -418,12 +1003,15 @@ impl ThinPool {
             .map(|s| s.length)
             .sum();
 
-        let mdv_total = self.mdv_segments
+        Ok(data_dev_used + spare_total + meta_dev_total + self.mdv_size())
+    }
+
+    /// The size of the filesystem metadata volume.
+    pub fn mdv_size(&self) -> Sectors {
+        self.mdv_segments
             .iter()
             .map(|s| s.segment.length)
-            .sum();
-
-        Ok(data_dev_used + spare_total + meta_dev_total + mdv_total)
+            .sum()
     }
 
     pub fn get_filesystem_by_uuid(&self, uuid: FilesystemUuid) -> Option<&StratFilesystem> {
@@ -449,6 +1037,15 @@ impl ThinPool {
         !self.filesystems.is_empty()
     }
 
+    /// Write the metadata of all filesystems to the MDV again, in the
+    /// current format.
+    pub fn save_filesystems(&self) -> EngineResult<()> {
+        for fs in &self.filesystems {
+            self.mdv.save_fs(fs)?;
+        }
+        Ok(())
+    }
+
     pub fn filesystems(&self) -> Vec<&Filesystem> {
         self.filesystems
             .into_iter()
@@ -456,13 +1053,71 @@ impl ThinPool {
             .collect()
     }
 
+    /// The names and device nodes of the filesystems which are active.
+    pub fn filesystem_devnodes(&self) -> Vec<(String, PathBuf)> {
+        self.filesystems
+            .into_iter()
+            .filter(|fs| fs.is_active())
+            .map(|fs| (fs.name().to_owned(), fs.devnode()))
+            .collect()
+    }
+
     /// Create a filesystem within the thin pool. Given name must not
     /// already be in use.
+    #[cfg(test)]
     pub fn create_filesystem(&mut self,
                              name: &str,
                              dm: &DM,
                              size: Option<Sectors>)
                              -> EngineResult<FilesystemUuid> {
+        Ok(self.create_filesystems(dm, &[(name, size, FsOptions::default())])?[0])
+    }
+
+    /// Create all the filesystems specified by specs, or none of them.
+    /// The metadata of the filesystems is saved only once all have been
+    /// created. If any filesystem can not be created, or any metadata can
+    /// not be saved, all the filesystems already created are destroyed.
+    /// Given names must not already be in use.
+    pub fn create_filesystems(&mut self,
+                              dm: &DM,
+                              specs: &[(&str, Option<Sectors>, FsOptions)])
+                              -> EngineResult<Vec<FilesystemUuid>> {
+        let mut new_filesystems = Vec::new();
+        for &(name, size, options) in specs {
+            match self.new_filesystem(name, dm, size, options) {
+                Ok(new_filesystem) => new_filesystems.push(new_filesystem),
+                Err(err) => {
+                    self.discard_filesystems(dm, new_filesystems);
+                    return Err(err);
+                }
+            }
+        }
+
+        for new_filesystem in &new_filesystems {
+            let result = fail_point("ThinPool::create_filesystems")
+                .and_then(|_| self.mdv.save_fs(new_filesystem));
+            if let Err(err) = result {
+                self.discard_filesystems(dm, new_filesystems);
+                return Err(err);
+            }
+        }
+
+        let fs_uuids = new_filesystems.iter().map(|fs| fs.uuid()).collect();
+        for new_filesystem in new_filesystems {
+            self.filesystems.insert(new_filesystem);
+        }
+
+        Ok(fs_uuids)
+    }
+
+    /// Set up a new thin device and make a filesystem on it with options.
+    /// The filesystem is neither saved nor added to the pool's filesystems.
+    fn new_filesystem(&mut self,
+                      name: &str,
+                      dm: &DM,
+                      size: Option<Sectors>,
+                      options: FsOptions)
+                      -> EngineResult<StratFilesystem> {
         let fs_uuid = Uuid::new_v4();
         let device_name = format_thin_name(self.pool_uuid, ThinRole::Filesystem(fs_uuid));
         let thin_dev = ThinDev::new(dm,
@@ -470,13 +1125,23 @@ impl ThinPool {
                                     None,
                                     &self.thin_pool,
                                     self.id_gen.new_id()?,
-                                    size.unwrap_or(DEFAULT_THIN_DEV_SIZE))?;
+                                    size.unwrap_or(DEFAULT_FS_SIZE))?;
 
-        let new_filesystem = StratFilesystem::initialize(fs_uuid, name, thin_dev)?;
-        self.mdv.save_fs(&new_filesystem)?;
-        self.filesystems.insert(new_filesystem);
+        StratFilesystem::initialize(fs_uuid, name, dm, &self.thin_pool, thin_dev, options)
+    }
 
-        Ok(fs_uuid)
+    /// Destroy filesystems which were never added to the pool's
+    /// filesystems, and remove any of their metadata which was saved.
+    fn discard_filesystems(&self, dm: &DM, filesystems: Vec<StratFilesystem>) {
+        for fs in filesystems {
+            let fs_uuid = fs.uuid();
+            if let Err(err) = self.mdv.rm_fs(fs_uuid) {
+                warn!("Could not remove metadata of filesystem {}: {}", fs_uuid, err);
+            }
+            if let Err(err) = fs.destroy(dm, &self.thin_pool) {
+                warn!("Could not destroy filesystem {}: {}", fs_uuid, err);
+            }
+        }
     }
 
     /// Create a filesystem snapshot of the origin.  Given origin_uuid
@@ -511,56 +1176,554 @@ impl ThinPool {
         Ok(snapshot_fs_uuid)
     }
 
-    /// Destroy a filesystem within the thin pool.
-    pub fn destroy_filesystem(&mut self, dm: &DM, uuid: FilesystemUuid) -> EngineResult<()> {
-        if let Some(fs) = self.filesystems.remove_by_uuid(uuid) {
-            fs.destroy(dm, &self.thin_pool)?;
-            self.mdv.rm_fs(uuid)?;
+    /// Create a copy of the filesystem origin_uuid, named copy_name, which
+    /// shares no blocks with it. The copy is read from a temporary snapshot
+    /// of the filesystem, which is destroyed once the copy has been made.
+    pub fn clone_filesystem(&mut self,
+                            dm: &DM,
+                            origin_uuid: FilesystemUuid,
+                            copy_name: &str)
+                            -> EngineResult<FilesystemUuid> {
+        let copy_fs_uuid = Uuid::new_v4();
+        let copy_dmname = format_thin_name(self.pool_uuid, ThinRole::Filesystem(copy_fs_uuid));
+        let copy_id = self.id_gen.new_id()?;
+        let source_dmname = format_thin_name(self.pool_uuid, ThinRole::Filesystem(Uuid::new_v4()));
+        let source_id = self.id_gen.new_id()?;
+        let new_filesystem = match self.filesystems.get_by_uuid(origin_uuid) {
+            Some(filesystem) => {
+                filesystem
+                    .copy(dm,
+                          &self.thin_pool,
+                          copy_name,
+                          copy_dmname.as_ref(),
+                          copy_fs_uuid,
+                          copy_id,
+                          source_dmname.as_ref(),
+                          source_id)?
+            }
+            None => return Err(EngineError::Engine(ErrorEnum::NotFound, origin_uuid.to_string())),
+        };
+        if let Err(err) = self.mdv.save_fs(&new_filesystem) {
+            self.discard_filesystems(dm, vec![new_filesystem]);
+            return Err(err);
+        }
+        self.filesystems.insert(new_filesystem);
+        Ok(copy_fs_uuid)
+    }
+
+    /// Revert the filesystem fs_uuid to the state of snapshot_uuid, which
+    /// must be a snapshot of it. The filesystem's thin device is replaced
+    /// by a new snapshot of the snapshot, so the snapshot is preserved.
+    /// Returns an error if the filesystem is busy and can not be unmounted.
+    pub fn revert_to_snapshot(&mut self,
+                              dm: &DM,
+                              fs_uuid: FilesystemUuid,
+                              snapshot_uuid: FilesystemUuid)
+                              -> EngineResult<()> {
+        let (name, origin, created, options) = match self.filesystems.get_by_uuid(fs_uuid) {
+            Some(fs) if fs.read_only() => {
+                let err_msg = format!("filesystem {} is read-only", fs_uuid);
+                return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+            }
+            Some(fs) => {
+                (fs.name().to_owned(),
+                 fs.snapshot_of(),
+                 fs.created().timestamp() as u64,
+                 fs.options())
+            }
+            None => return Err(EngineError::Engine(ErrorEnum::NotFound, fs_uuid.to_string())),
+        };
+
+        // Copy the snapshot to a new thin device, under a temporary name,
+        // so that nothing is lost if the copy can not be made.
+        let copy = match self.filesystems.get_by_uuid(snapshot_uuid) {
+            Some(snapshot) => {
+                if snapshot.snapshot_of() != Some(fs_uuid) {
+                    let err_msg = format!("filesystem {} is not a snapshot of filesystem {}",
+                                          snapshot_uuid,
+                                          fs_uuid);
+                    return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+                }
+                let copy_dmname = format_thin_name(self.pool_uuid,
+                                                   ThinRole::Filesystem(Uuid::new_v4()));
+                self.filesystems
+                    .get_by_uuid(fs_uuid)
+                    .expect("found above")
+                    .unmount()?;
+                let copy_thin_id = self.id_gen.new_id()?;
+                snapshot
+                    .snapshot(dm,
+                              &self.thin_pool,
+                              &name,
+                              copy_dmname.as_ref(),
+                              fs_uuid,
+                              copy_thin_id)?
+            }
+            None => {
+                return Err(EngineError::Engine(ErrorEnum::NotFound, snapshot_uuid.to_string()))
+            }
+        };
+        let copy_save = copy.record();
+        let copy_thin_id = copy_save.thin_id;
+
+        // Record the filesystem as the copy before anything of the
+        // filesystem is destroyed, so that an interruption leaves its record
+        // naming either its own thin device or the copy, both of which
+        // exist.
+        let device_name = format_thin_name(self.pool_uuid, ThinRole::Filesystem(fs_uuid));
+        let mut reverted = StratFilesystem::setup_inactive(fs_uuid,
+                                                           &name,
+                                                           device_name.as_ref(),
+                                                           copy_thin_id,
+                                                           copy_save.size,
+                                                           created,
+                                                           origin,
+                                                           options);
+        if let Err(err) = copy.teardown(dm).and_then(|_| self.mdv.save_fs(&reverted)) {
+            self.delete_thin_dev(dm, copy_thin_id);
+            return Err(err);
+        }
+
+        let fs = self.filesystems
+            .remove_by_uuid(fs_uuid)
+            .expect("Must succeed since self.filesystems.get_by_uuid() returned a value");
+        let (fs_thin_id, fs_size) = (fs.thin_id(), fs.size());
+        let result = fs.teardown(dm)
+            .and_then(|_| fail_point("ThinPool::revert_to_snapshot"))
+            .and_then(|_| reverted.activate(dm, &self.thin_pool));
+        if let Err(err) = result {
+            // Put the filesystem back as it was, and its record.
+            let mut fs = StratFilesystem::setup_inactive(fs_uuid,
+                                                         &name,
+                                                         device_name.as_ref(),
+                                                         fs_thin_id,
+                                                         fs_size,
+                                                         created,
+                                                         origin,
+                                                         options);
+            if let Err(err) = fs.activate(dm, &self.thin_pool) {
+                warn!("Could not set up filesystem {} again: {}", fs_uuid, err);
+            }
+            if let Err(err) = self.mdv.save_fs(&fs) {
+                warn!("Could not restore metadata of filesystem {}: {}", fs_uuid, err);
+            }
+            self.filesystems.insert(fs);
+            self.delete_thin_dev(dm, copy_thin_id);
+            return Err(err);
         }
+        self.filesystems.insert(reverted);
+
+        // The filesystem's own thin device is destroyed last, once nothing
+        // refers to it.
+        self.delete_thin_dev(dm, fs_thin_id);
+
         Ok(())
     }
 
-    /// Rename a filesystem within the thin pool.
-    pub fn rename_filesystem(&mut self,
-                             uuid: FilesystemUuid,
-                             new_name: &str)
-                             -> EngineResult<RenameAction> {
+    /// Delete the thin device thin_id, which no filesystem refers to.
+    /// A failure is logged rather than returned.
+    fn delete_thin_dev(&self, dm: &DM, thin_id: ThinDevId) {
+        if let Err(err) = self.thin_pool.message(dm, &format!("delete {}", thin_id)) {
+            warn!("Could not delete thin device {} of pool {}: {}",
+                  thin_id,
+                  self.pool_uuid,
+                  err);
+        }
+    }
 
-        let old_name = rename_filesystem_pre!(self; uuid; new_name);
+    /// Activate the thin device of the filesystem uuid, if it was not
+    /// activated when the pool was set up.
+    pub fn activate_filesystem(&mut self, dm: &DM, uuid: FilesystemUuid) -> EngineResult<()> {
+        match self.filesystems.get_mut_by_uuid(uuid) {
+            Some(fs) => fs.activate(dm, &self.thin_pool),
+            None => Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
 
-        let mut filesystem =
-            self.filesystems
-                .remove_by_uuid(uuid)
-                .expect("Must succeed since self.filesystems.get_by_uuid() returned a value");
-
-        filesystem.rename(new_name);
-        if let Err(err) = self.mdv.save_fs(&filesystem) {
-            filesystem.rename(&old_name);
-            self.filesystems.insert(filesystem);
-            Err(err)
+    /// Destroy a filesystem within the thin pool.
+    /// A mounted filesystem is unmounted first. If it is in use, a Busy
+    /// error is returned, unless force is true, in which case it is
+    /// detached from its mount points and its thin device is removed once
+    /// it is no longer in use. The thin device can not be deleted from the
+    /// thin pool until then, so it is kept as an orphaned thin device; see
+    /// delete_orphaned_thin_devs().
+    pub fn destroy_filesystem(&mut self,
+                              dm: &DM,
+                              uuid: FilesystemUuid,
+                              force: bool)
+                              -> EngineResult<()> {
+        let in_use = match self.filesystems.get_by_uuid(uuid) {
+            Some(fs) => {
+                match fs.unmount() {
+                    Ok(_) => false,
+                    Err(EngineError::Engine(ErrorEnum::Busy, _)) if force => {
+                        fs.unmount_lazy()?;
+                        true
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+            None => return Ok(()),
+        };
+
+        let fs = self.filesystems
+            .remove_by_uuid(uuid)
+            .expect("Must succeed since self.filesystems.get_by_uuid() returned a value");
+        if in_use {
+            let thin_id = fs.thin_id();
+            fs.remove_deferred(dm)?;
+            self.orphaned_thin_ids.push(thin_id);
         } else {
-            self.filesystems.insert(filesystem);
-            Ok(RenameAction::Renamed)
+            fs.destroy(dm, &self.thin_pool)?;
         }
+        self.mdv.rm_fs(uuid)?;
+        Ok(())
     }
-}
 
-impl Recordable<FlexDevsSave> for ThinPool {
-    fn record(&self) -> FlexDevsSave {
-        FlexDevsSave {
-            meta_dev: self.mdv_segments.record(),
-            thin_meta_dev: self.meta_segments.record(),
-            thin_data_dev: self.data_segments.record(),
-            thin_meta_dev_spare: self.meta_spare_segments.record(),
+    /// Grow the filesystem uuid to size, and record its new size.
+    /// If the filesystem can not be grown, its former size is kept.
+    /// Returns the size of the filesystem afterwards.
+    /// Returns a NoSpace error if the thin pool is out of data space, since
+    /// XFS must write to the filesystem to grow it.
+    pub fn set_filesystem_size(&mut self,
+                               dm: &DM,
+                               uuid: FilesystemUuid,
+                               size: Sectors)
+                               -> EngineResult<Sectors> {
+        if let Some(ThinPoolStatus { health: ThinPoolHealth::OutOfDataSpace, .. }) =
+            self.status(dm)? {
+            let err_msg = format!("pool {} is out of data space", self.pool_uuid);
+            return Err(EngineError::Engine(ErrorEnum::NoSpace, err_msg));
         }
-    }
-}
 
-impl Recordable<ThinPoolDevSave> for ThinPool {
-    fn record(&self) -> ThinPoolDevSave {
-        ThinPoolDevSave { data_block_size: self.thin_pool.data_block_size() }
+        let filesystem = match self.filesystems.get_mut_by_uuid(uuid) {
+            Some(filesystem) => filesystem,
+            None => return Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        };
+        let mdv = &self.mdv;
+        filesystem.grow(dm, &self.thin_pool, size, &|fs| mdv.save_fs(fs))?;
+        Ok(filesystem.size())
     }
-}
+
+    /// Make the filesystem uuid read-only, or writable again.
+    pub fn set_filesystem_read_only(&mut self,
+                                    uuid: FilesystemUuid,
+                                    read_only: bool)
+                                    -> EngineResult<()> {
+        let filesystem = match self.filesystems.get_mut_by_uuid(uuid) {
+            Some(filesystem) => filesystem,
+            None => return Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        };
+        if filesystem.set_read_only(read_only)? {
+            self.mdv.save_fs(filesystem)?;
+        }
+        Ok(())
+    }
+
+    /// Let the filesystem uuid pass discards to the thin pool, or refuse them.
+    pub fn set_filesystem_discards(&mut self,
+                                   uuid: FilesystemUuid,
+                                   enable: bool)
+                                   -> EngineResult<()> {
+        let filesystem = match self.filesystems.get_mut_by_uuid(uuid) {
+            Some(filesystem) => filesystem,
+            None => return Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        };
+        if filesystem.set_discards(enable)? {
+            self.mdv.save_fs(filesystem)?;
+        }
+        Ok(())
+    }
+
+    /// Cap the size of the filesystem uuid at limit, or lift its cap.
+    pub fn set_filesystem_quota(&mut self,
+                                uuid: FilesystemUuid,
+                                limit: Option<Sectors>)
+                                -> EngineResult<()> {
+        let filesystem = match self.filesystems.get_mut_by_uuid(uuid) {
+            Some(filesystem) => filesystem,
+            None => return Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        };
+        if filesystem.set_quota(limit)? {
+            self.mdv.save_fs(filesystem)?;
+        }
+        Ok(())
+    }
+
+    /// How much of the filesystem uuid is backed by the thin pool's data
+    /// blocks; None if the filesystem has not been activated.
+    pub fn filesystem_allocation_info(&self,
+                                      dm: &DM,
+                                      uuid: FilesystemUuid)
+                                      -> EngineResult<Option<AllocationInfo>> {
+        match self.filesystems.get_by_uuid(uuid) {
+            Some(filesystem) => {
+                filesystem.allocation_info(dm, self.thin_pool.data_block_size())
+            }
+            None => Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
+
+    /// Discard the unused blocks of the mounted filesystem uuid.
+    /// Mount the filesystem uuid at mount_point with the mount options data.
+    pub fn mount_filesystem(&self,
+                            uuid: FilesystemUuid,
+                            mount_point: &Path,
+                            data: &str)
+                            -> EngineResult<()> {
+        match self.filesystems.get_by_uuid(uuid) {
+            Some(filesystem) => filesystem.mount(mount_point, data),
+            None => Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
+
+    pub fn trim_filesystem(&self, uuid: FilesystemUuid) -> EngineResult<()> {
+        match self.filesystems.get_by_uuid(uuid) {
+            Some(filesystem) => filesystem.trim(),
+            None => Err(EngineError::Engine(ErrorEnum::NotFound, uuid.to_string())),
+        }
+    }
+
+    /// Rename a filesystem within the thin pool.
+    pub fn rename_filesystem(&mut self,
+                             uuid: FilesystemUuid,
+                             new_name: &str)
+                             -> EngineResult<RenameAction> {
+
+        let old_name = rename_filesystem_pre!(self; uuid; new_name);
+
+        self.filesystems.rename(uuid, new_name)?;
+        let result = self.mdv.save_fs(self.filesystems
+                                          .get_by_uuid(uuid)
+                                          .expect("the filesystem was just renamed"));
+        if let Err(err) = result {
+            self.filesystems
+                .rename(uuid, &old_name)
+                .expect("the old name was freed by the rename");
+            return Err(err);
+        }
+        Ok(RenameAction::Renamed)
+    }
+}
+
+impl Recordable<FlexDevsSave> for ThinPool {
+    fn record(&self) -> FlexDevsSave {
+        FlexDevsSave {
+            meta_dev: self.mdv_segments.record(),
+            thin_meta_dev: self.meta_segments.record(),
+            thin_data_dev: self.data_segments.record(),
+            thin_meta_dev_spare: self.meta_spare_segments.record(),
+        }
+    }
+}
+
+impl Recordable<ThinPoolDevSave> for ThinPool {
+    fn record(&self) -> ThinPoolDevSave {
+        ThinPoolDevSave { data_block_size: self.thin_pool.data_block_size() }
+    }
+}
+
+/// Returns true if the thinpool's metadata device is so nearly full that it
+/// should be extended.
+fn metadata_low(usage: &ThinPoolStatus) -> bool {
+    usage.total_meta - usage.used_meta < META_LOWATER
+}
+
+/// Returns true if less of the thinpool's data device is free than
+/// low_water, so that it should be extended. A low water mark in sectors is
+/// rounded up to whole data blocks of data_block_size.
+fn data_low(usage: &ThinPoolStatus, low_water: DataLowWater, data_block_size: Sectors) -> bool {
+    let low_water_blocks = match low_water {
+        DataLowWater::Percent(percent) => *usage.total_data * u64::from(percent) / 100,
+        DataLowWater::Sectors(sectors) => {
+            let blocks = *sectors / *data_block_size;
+            if *sectors % *data_block_size == 0 {
+                blocks
+            } else {
+                blocks + 1
+            }
+        }
+    };
+    *(usage.total_data - usage.used_data) < low_water_blocks
+}
+
+/// A segment to be moved, paired with the segments it is moved to.
+type SegmentMove = (BlkDevSegment, Vec<BlkDevSegment>);
+
+/// Replace each of segments which is on the blockdev dev_uuid with space
+/// newly allocated from tier of bd_mgr. Return the resulting segments, and a
+/// list of each replaced segment paired with its replacements.
+fn relocate_segs(segments: &[BlkDevSegment],
+                 dev_uuid: DevUuid,
+                 tier: BlockDevTier,
+                 bd_mgr: &mut BlockDevMgr)
+                 -> EngineResult<(Vec<BlkDevSegment>, Vec<SegmentMove>)> {
+    let mut relocated = Vec::new();
+    let mut moves = Vec::new();
+    for seg in segments {
+        if seg.uuid != dev_uuid {
+            relocated.push(seg.clone());
+            continue;
+        }
+        match bd_mgr.alloc_space(tier, &[seg.segment.length]) {
+            Some(mut lists) => {
+                let new_segs = lists.pop().expect("len(lists) == 1");
+                relocated.extend(new_segs.iter().cloned());
+                moves.push((seg.clone(), new_segs));
+            }
+            None => {
+                release_moves(&moves, bd_mgr);
+                let err_msg = format!("Insufficient space to relocate {} from device {}",
+                                      seg.segment.length,
+                                      dev_uuid);
+                return Err(EngineError::Engine(ErrorEnum::NoSpace, err_msg));
+            }
+        }
+    }
+    Ok((relocated, moves))
+}
+
+/// Copy the data for each of moves from its segment on devnode to its
+/// replacement segments.
+fn copy_moves(devnode: &Path,
+              moves: &[SegmentMove],
+              bd_mgr: &BlockDevMgr)
+              -> EngineResult<()> {
+    for &(ref old, ref new_segs) in moves.iter() {
+        let mut offset = old.segment.start;
+        for new in new_segs {
+            let dst = bd_mgr
+                .get_blockdev_by_uuid(new.uuid)
+                .expect("segments were just allocated from bd_mgr")
+                .devnode();
+            copy_sectors(devnode,
+                         offset,
+                         &dst,
+                         new.segment.start,
+                         new.segment.length)?;
+            offset += new.segment.length;
+        }
+    }
+    Ok(())
+}
+
+/// Copy the data for each of moves back from its replacement segments to
+/// its segment on devnode.
+fn copy_moves_back(devnode: &Path,
+                   moves: &[SegmentMove],
+                   bd_mgr: &BlockDevMgr)
+                   -> EngineResult<()> {
+    for mv in moves {
+        let mut offset = mv.0.segment.start;
+        for new in &mv.1 {
+            let src = bd_mgr
+                .get_blockdev_by_uuid(new.uuid)
+                .expect("segments were allocated from bd_mgr")
+                .devnode();
+            copy_sectors(&src,
+                         new.segment.start,
+                         devnode,
+                         offset,
+                         new.segment.length)?;
+            offset += new.segment.length;
+        }
+    }
+    Ok(())
+}
+
+/// Release the space allocated from bd_mgr for moves.
+fn release_moves(moves: &[SegmentMove], bd_mgr: &mut BlockDevMgr) {
+    for mv in moves {
+        bd_mgr.release_space(&mv.1);
+    }
+}
+
+/// Append new_segs to segments. The last existing and first new segment are
+/// coalesced into a single BlkDevSegment if they are contiguous.
+fn coalesce_segs(segments: &[BlkDevSegment], new_segs: &[BlkDevSegment]) -> Vec<BlkDevSegment> {
+    let mut segments = segments.to_vec();
+    segments.reserve(new_segs.len());
+
+    let coalesced_new_first = {
+        match new_segs.first() {
+            Some(new_first) => {
+                let old_last = segments
+                    .last_mut()
+                    .expect("thin pool devices must always have some segments");
+                if old_last.uuid == new_first.uuid &&
+                   (old_last.segment.start + old_last.segment.length ==
+                    new_first.segment.start) {
+                    old_last.segment.length += new_first.segment.length;
+                    true
+                } else {
+                    false
+                }
+            }
+            None => false,
+        }
+    };
+
+    if coalesced_new_first {
+        segments.extend_from_slice(&new_segs[1..]);
+    } else {
+        segments.extend_from_slice(new_segs);
+    }
+    segments
+}
+
+/// Find the thin devices of thin_pool, the thinpool of pool_uuid, which no
+/// filesystem is recorded for, e.g., because a filesystem creation was
+/// interrupted before the filesystem's metadata was saved.
+///
+/// Nothing is deleted: a device may lack a record because the record is
+/// missing or damaged rather than because its creation was interrupted.
+/// Thin ids are allocated in increasing order, so such devices have the
+/// ids following the highest id recorded, recorded_ids; they are looked for
+/// there, by setting up a device for each id, until an id is found which
+/// has no device.
+fn find_orphaned_thin_devs(dm: &DM,
+                           pool_uuid: PoolUuid,
+                           thin_pool: &ThinPoolDev,
+                           recorded_ids: &[ThinDevId])
+                           -> EngineResult<Vec<ThinDevId>> {
+    let mut probe_ids = ThinDevIdPool::new_from_ids(recorded_ids);
+    let mut found = Vec::new();
+    loop {
+        let thin_id = probe_ids.new_id()?;
+        let probe_name = format_thin_name(pool_uuid, ThinRole::Filesystem(Uuid::new_v4()));
+        match ThinDev::setup(dm, probe_name.as_ref(), None, thin_pool, thin_id, Sectors(1)) {
+            Ok(thin_dev) => {
+                thin_dev.teardown(dm)?;
+                found.push(thin_id);
+            }
+            Err(_) => return Ok(found),
+        }
+    }
+}
+
+/// Set up the data device for the thinpool of pool_uuid on data_segments.
+/// If key is given, the data device maps a dm-crypt device on the
+/// segments, which encrypts with the key key describes; return the dm-crypt
+/// device as well.
+fn setup_datadev(dm: &DM,
+                 pool_uuid: PoolUuid,
+                 data_segments: &[BlkDevSegment],
+                 key: Option<&KeyDescription>)
+                 -> EngineResult<(LinearDev, Option<CryptDev>)> {
+    let name = format_flex_name(pool_uuid, FlexRole::ThinData);
+    match key {
+        Some(key) => {
+            let crypt_dev = CryptDev::setup(dm, pool_uuid, &map_to_dm(data_segments), key)?;
+            let data_dev = retry_dm(|| LinearDev::setup(dm, &name, None, &[crypt_dev.segment()]))?;
+            Ok((data_dev, Some(crypt_dev)))
+        }
+        None => {
+            let data_dev =
+                retry_dm(|| LinearDev::setup(dm, &name, None, &map_to_dm(data_segments)))?;
+            Ok((data_dev, None))
+        }
+    }
+}
 
 /// Setup metadata dev for thinpool.
 /// Attempt to verify that the metadata dev is valid for the given thinpool
@@ -631,15 +1794,21 @@ fn attempt_thin_repair(pool_uuid: PoolUuid,
 
 #[cfg(test)]
 mod tests {
+    use std::fs;
     use std::fs::OpenOptions;
     use std::io::{Read, Write};
     use std::path::Path;
+    use std::process;
 
     use nix::mount::{MsFlags, mount, umount};
     use uuid::Uuid;
 
-    use devicemapper::{Bytes, SECTOR_SIZE};
+    use devicemapper::{Bytes, IEC, SECTOR_SIZE};
 
+    use super::super::super::types::{CreateForce, DEFAULT_MDV_SIZE};
+
+    use super::super::device::wipe_sectors;
+    use super::super::failpoint::arm;
     use super::super::filesystem::{FILESYSTEM_LOWATER, fs_usage};
     use super::super::metadata::MIN_MDA_SECTORS;
     use super::super::tests::{loopbacked, real};
@@ -647,12 +1816,73 @@ mod tests {
 
     use super::*;
 
+    /// Get the status of a working thin pool from a status line, as
+    /// reported by the kernel, e.g., "0 10/4096 20/32768 - rw
+    /// discard_passdown queue_if_no_space - ".
+    fn usage_from_status(status_line: &str) -> ThinPoolStatus {
+        parse_thinpool_status(status_line).unwrap().unwrap()
+    }
+
+    #[test]
+    /// Verify that metadata is considered low only if less than
+    /// META_LOWATER blocks remain, regardless of data usage.
+    fn metadata_low_status() {
+        let not_low = ["0 10/4096 20/32768 - rw discard_passdown queue_if_no_space - ",
+                       "5 3584/4096 32700/32768 - rw discard_passdown queue_if_no_space - ",
+                       "9 100/8192 32768/32768 - out_of_data_space discard_passdown \
+                        queue_if_no_space - "];
+        let low = ["5 3585/4096 20/32768 - rw discard_passdown queue_if_no_space - ",
+                   "7 4096/4096 20/32768 - ro discard_passdown queue_if_no_space - ",
+                   "0 399/400 20/32768 - rw discard_passdown queue_if_no_space - "];
+
+        assert!(not_low
+                    .iter()
+                    .all(|s| !metadata_low(&usage_from_status(s))));
+        assert!(low.iter().all(|s| metadata_low(&usage_from_status(s))));
+    }
+
+    #[test]
+    /// Verify that data is considered low only if fewer free blocks remain
+    /// than the low water mark, whether it is a percentage or a size.
+    fn data_low_status() {
+        let usage = |used| {
+            usage_from_status(&format!("0 10/4096 {}/32768 - rw discard_passdown \
+                                        queue_if_no_space - ",
+                                       used))
+        };
+        let percent = DataLowWater::Percent(10);
+        assert!(!data_low(&usage(32768 - 3276), percent, DATA_BLOCK_SIZE));
+        assert!(data_low(&usage(32768 - 3275), percent, DATA_BLOCK_SIZE));
+
+        let sectors = DataLowWater::Sectors(DATA_BLOCK_SIZE * 512u64);
+        assert!(!data_low(&usage(32768 - 512), sectors, DATA_BLOCK_SIZE));
+        assert!(data_low(&usage(32768 - 511), sectors, DATA_BLOCK_SIZE));
+
+        // A size that is not a whole number of blocks is rounded up.
+        let partial = DataLowWater::Sectors(DATA_BLOCK_SIZE * 511u64 + Sectors(1));
+        assert!(!data_low(&usage(32768 - 512), partial, DATA_BLOCK_SIZE));
+        assert!(data_low(&usage(32768 - 511), partial, DATA_BLOCK_SIZE));
+    }
+
     /// Verify a snapshot has the same files and same contents as the origin.
     fn test_filesystem_snapshot(paths: &[&Path]) {
         let pool_uuid = Uuid::new_v4();
         let dm = DM::new().unwrap();
-        let mut mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
-        let mut pool = ThinPool::new(pool_uuid, &dm, DATA_BLOCK_SIZE, DATA_LOWATER, &mut mgr)
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
             .unwrap();
 
         let fs_uuid = pool.create_filesystem("stratis_test_filesystem", &dm, None)
@@ -727,6 +1957,129 @@ mod tests {
         real::test_with_spec(real::DeviceLimits::AtLeast(2), test_filesystem_snapshot);
     }
 
+    /// Verify that reverting a filesystem to a snapshot restores the
+    /// contents the filesystem had when the snapshot was taken.
+    /// 1. Write to a filesystem, snapshot it, and overwrite the data.
+    /// 2. Verify that the filesystem can not be reverted to a filesystem
+    ///    which is not its snapshot.
+    /// 3. Verify that a revert which fails part-way leaves the filesystem,
+    ///    and its record, as they were.
+    /// 4. Revert the filesystem to the snapshot and verify the original
+    ///    data is back.
+    fn test_revert_to_snapshot(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
+            .unwrap();
+        pool.extend_thinpool(&dm, INITIAL_DATA_SIZE, &mut mgr)
+            .unwrap();
+
+        let fs_uuid = pool.create_filesystem("stratis_test_filesystem", &dm, None)
+            .unwrap();
+        let other_uuid = pool.create_filesystem("stratis_test_other", &dm, None)
+            .unwrap();
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        let file_path = tmp_dir.path().join("stratis_test.txt");
+        let write_file = |pool: &ThinPool, contents: &[u8]| {
+            mount(Some(&pool.get_filesystem_by_uuid(fs_uuid).unwrap().devnode()),
+                  tmp_dir.path(),
+                  Some("xfs"),
+                  MsFlags::empty(),
+                  None as Option<&str>)
+                    .unwrap();
+            {
+                let mut f = OpenOptions::new()
+                    .create(true)
+                    .truncate(true)
+                    .write(true)
+                    .open(&file_path)
+                    .unwrap();
+                f.write_all(contents).unwrap();
+                f.sync_all().unwrap();
+            }
+            umount(tmp_dir.path()).unwrap();
+        };
+
+        write_file(&pool, b"original");
+        let snapshot_uuid = pool.snapshot_filesystem(&dm, fs_uuid, "test_snapshot")
+            .unwrap();
+        write_file(&pool, b"overwritten");
+
+        assert!(match pool.revert_to_snapshot(&dm, other_uuid, snapshot_uuid) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        // A revert which fails part-way leaves the filesystem as it was.
+        arm("ThinPool::revert_to_snapshot", 0);
+        assert!(pool.revert_to_snapshot(&dm, fs_uuid, snapshot_uuid).is_err());
+        let read_file = |pool: &ThinPool| {
+            mount(Some(&pool.get_filesystem_by_uuid(fs_uuid).unwrap().devnode()),
+                  tmp_dir.path(),
+                  Some("xfs"),
+                  MsFlags::empty(),
+                  None as Option<&str>)
+                    .unwrap();
+            let mut contents = Vec::new();
+            OpenOptions::new()
+                .read(true)
+                .open(&file_path)
+                .unwrap()
+                .read_to_end(&mut contents)
+                .unwrap();
+            umount(tmp_dir.path()).unwrap();
+            contents
+        };
+        assert_eq!(read_file(&pool), b"overwritten".to_vec());
+        let flexdevs: FlexDevsSave = pool.record();
+        pool.teardown(&dm).unwrap();
+        let mut pool = ThinPool::setup(pool_uuid,
+                                       &dm,
+                                       &stratis_paths,
+                                       DATA_BLOCK_SIZE,
+                                       DATA_LOWATER,
+                                       &flexdevs,
+                                       None,
+                                       &mgr,
+                                       false,
+                                       true)
+                .unwrap();
+        assert_eq!(read_file(&pool), b"overwritten".to_vec());
+
+        pool.revert_to_snapshot(&dm, fs_uuid, snapshot_uuid)
+            .unwrap();
+        assert!(pool.get_filesystem_by_uuid(snapshot_uuid).is_some());
+        assert_eq!(read_file(&pool), b"original".to_vec());
+
+        pool.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_revert_to_snapshot() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3),
+                                   test_revert_to_snapshot);
+    }
+
+    #[test]
+    pub fn real_test_revert_to_snapshot() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(2), test_revert_to_snapshot);
+    }
+
     /// Verify that a filesystem rename causes the filesystem metadata to be
     /// updated.
     fn test_filesystem_rename(paths: &[&Path]) {
@@ -735,8 +2088,21 @@ mod tests {
 
         let pool_uuid = Uuid::new_v4();
         let dm = DM::new().unwrap();
-        let mut mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
-        let mut pool = ThinPool::new(pool_uuid, &dm, DATA_BLOCK_SIZE, DATA_LOWATER, &mut mgr)
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
             .unwrap();
 
         let fs_uuid = pool.create_filesystem(&name1, &dm, None).unwrap();
@@ -748,10 +2114,14 @@ mod tests {
 
         let pool = ThinPool::setup(pool_uuid,
                                    &dm,
+                                   &stratis_paths,
                                    DATA_BLOCK_SIZE,
                                    DATA_LOWATER,
                                    &flexdevs,
-                                   &mgr)
+                                   None,
+                                   &mgr,
+                                   false,
+                                   true)
                 .unwrap();
 
         assert_eq!(pool.get_filesystem_by_uuid(fs_uuid).unwrap().name(), name2);
@@ -774,8 +2144,21 @@ mod tests {
     fn test_pool_setup(paths: &[&Path]) {
         let pool_uuid = Uuid::new_v4();
         let dm = DM::new().unwrap();
-        let mut mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
-        let mut pool = ThinPool::new(pool_uuid, &dm, DATA_BLOCK_SIZE, DATA_LOWATER, &mut mgr)
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
             .unwrap();
 
         let fs_uuid = pool.create_filesystem("fsname", &dm, None).unwrap();
@@ -801,10 +2184,14 @@ mod tests {
 
         let new_pool = ThinPool::setup(pool_uuid,
                                        &dm,
+                                       &stratis_paths,
                                        DATA_BLOCK_SIZE,
                                        DATA_LOWATER,
                                        &pool.record(),
-                                       &mgr)
+                                       None,
+                                       &mgr,
+                                       false,
+                                       true)
                 .unwrap();
 
         assert!(new_pool.get_filesystem_by_uuid(fs_uuid).is_some());
@@ -819,14 +2206,644 @@ mod tests {
     pub fn real_test_pool_setup() {
         real::test_with_spec(real::DeviceLimits::AtLeast(1), test_pool_setup);
     }
+
+    /// Verify that the creation time of a filesystem is unchanged when its
+    /// pool is torn down and set up again.
+    fn test_filesystem_created(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
+            .unwrap();
+
+        let fs_uuid = pool.create_filesystem("fsname", &dm, None).unwrap();
+        let created = pool.get_filesystem_by_uuid(fs_uuid).unwrap().created();
+        assert!(created.timestamp() > 0);
+
+        let flexdevs: FlexDevsSave = pool.record();
+        pool.teardown(&dm).unwrap();
+
+        let pool = ThinPool::setup(pool_uuid,
+                                   &dm,
+                                   &stratis_paths,
+                                   DATA_BLOCK_SIZE,
+                                   DATA_LOWATER,
+                                   &flexdevs,
+                                   None,
+                                   &mgr,
+                                   false,
+                                   true)
+                .unwrap();
+        assert_eq!(pool.get_filesystem_by_uuid(fs_uuid).unwrap().created(),
+                   created);
+        pool.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_filesystem_created() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_filesystem_created);
+    }
+
+    #[test]
+    pub fn real_test_filesystem_created() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_filesystem_created);
+    }
+
+    /// Verify that if the MDV's filesystem is damaged, setting up the pool
+    /// without repairing the MDV fails with a Corrupt error.
+    fn test_mdv_corrupt(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let pool = ThinPool::new(pool_uuid,
+                                 &dm,
+                                 &stratis_paths,
+                                 DATA_BLOCK_SIZE,
+                                 DATA_LOWATER,
+                                 DEFAULT_MDV_SIZE,
+                                 None,
+                                 &mut mgr)
+            .unwrap();
+
+        wipe_sectors(pool.mdv.devnode(), Sectors(0), Sectors(16)).unwrap();
+
+        let flexdevs: FlexDevsSave = pool.record();
+        pool.teardown(&dm).unwrap();
+
+        assert!(match ThinPool::setup(pool_uuid,
+                                      &dm,
+                                      &stratis_paths,
+                                      DATA_BLOCK_SIZE,
+                                      DATA_LOWATER,
+                                      &flexdevs,
+                                      None,
+                                      &mgr,
+                                      false,
+                                      true) {
+                    Err(EngineError::Engine(ErrorEnum::Corrupt, _)) => true,
+                    _ => false,
+                });
+    }
+
+    #[test]
+    pub fn loop_test_mdv_corrupt() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_mdv_corrupt);
+    }
+
+    #[test]
+    pub fn real_test_mdv_corrupt() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_mdv_corrupt);
+    }
+
+    /// Verify that an audit finds no drift in a new pool, and that once a
+    /// filesystem's record is removed from the MDV behind the engine's
+    /// back, the audit finds the filesystem to be unrecorded, and repairs
+    /// nothing.
+    fn test_audit(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
+            .unwrap();
+
+        let fs_uuid = pool.create_filesystem("fsname", &dm, None).unwrap();
+        pool.create_filesystem("fsname2", &dm, None).unwrap();
+        assert!(pool.audit(&dm).unwrap().is_clean());
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&pool.mdv.devnode()),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        fs::remove_file(tmp_dir
+                            .path()
+                            .join("filesystems")
+                            .join(fs_uuid.simple().to_string())
+                            .with_extension("json"))
+                .unwrap();
+        umount(tmp_dir.path()).unwrap();
+
+        for _ in 0..2 {
+            assert_eq!(pool.audit(&dm).unwrap(),
+                       AuditReport {
+                           unknown_records: vec![],
+                           unrecorded_filesystems: vec![fs_uuid],
+                           size_mismatches: vec![],
+                           orphaned_thin_devs: vec![],
+                           unreadable_records: vec![],
+                       });
+        }
+
+        pool.teardown(&dm).unwrap();
+    }
+
+    /// Verify that a filesystem record which can not be read does not
+    /// prevent the pool from being set up with its other filesystems, that
+    /// it is reported, and that the thin device of its filesystem, which
+    /// appears orphaned, can not be deleted.
+    fn test_unreadable_record(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
+            .unwrap();
+
+        let fs_uuid = pool.create_filesystem("fsname", &dm, None).unwrap();
+        let lost_uuid = pool.create_filesystem("fsname2", &dm, None).unwrap();
+        let lost_id = pool.get_filesystem_by_uuid(lost_uuid).unwrap().thin_id();
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&pool.mdv.devnode()),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        fs::write(tmp_dir
+                      .path()
+                      .join("filesystems")
+                      .join(lost_uuid.simple().to_string())
+                      .with_extension("json"),
+                  b"{\"name\": ")
+                .unwrap();
+        umount(tmp_dir.path()).unwrap();
+
+        let flexdevs: FlexDevsSave = pool.record();
+        pool.teardown(&dm).unwrap();
+
+        let mut pool = ThinPool::setup(pool_uuid,
+                                       &dm,
+                                       &stratis_paths,
+                                       DATA_BLOCK_SIZE,
+                                       DATA_LOWATER,
+                                       &flexdevs,
+                                       None,
+                                       &mgr,
+                                       false,
+                                       true)
+                .unwrap();
+        assert!(pool.get_filesystem_by_uuid(fs_uuid).is_some());
+        assert!(pool.get_filesystem_by_uuid(lost_uuid).is_none());
+        assert_eq!(pool.unreadable_records().len(), 1);
+
+        let report = pool.audit(&dm).unwrap();
+        assert_eq!(report.unreadable_records.len(), 1);
+        assert_eq!(report.orphaned_thin_devs, vec![lost_id]);
+
+        assert!(match pool.delete_orphaned_thin_devs(&dm) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+        assert_eq!(pool.audit(&dm).unwrap().orphaned_thin_devs, vec![lost_id]);
+
+        pool.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_unreadable_record() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_unreadable_record);
+    }
+
+    #[test]
+    pub fn real_test_unreadable_record() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_unreadable_record);
+    }
+
+    #[test]
+    pub fn loop_test_audit() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3), test_audit);
+    }
+
+    #[test]
+    pub fn real_test_audit() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_audit);
+    }
+
+    /// Verify that a filesystem which is mounted with an open file can not be
+    /// destroyed, and that the process holding the file is named, but that
+    /// once the file is closed the filesystem is unmounted and destroyed.
+    fn test_filesystem_destroy_busy(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
+            .unwrap();
+
+        let fs_uuid = pool.create_filesystem("fsname", &dm, None).unwrap();
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&pool.get_filesystem_by_uuid(fs_uuid).unwrap().devnode()),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+
+        {
+            let _file = OpenOptions::new()
+                .create(true)
+                .write(true)
+                .open(tmp_dir.path().join("stratis_test.txt"))
+                .unwrap();
+
+            let openers = pool.get_filesystem_by_uuid(fs_uuid)
+                .unwrap()
+                .list_openers()
+                .unwrap();
+            assert!(openers.iter().any(|info| info.pid == process::id()));
+
+            let holder = format!("({})", process::id());
+            assert!(match pool.destroy_filesystem(&dm, fs_uuid, false) {
+                        Err(EngineError::Engine(ErrorEnum::Busy, msg)) => msg.contains(&holder),
+                        _ => false,
+                    });
+            assert!(pool.get_filesystem_by_uuid(fs_uuid).is_some());
+        }
+
+        pool.destroy_filesystem(&dm, fs_uuid, false).unwrap();
+        assert!(pool.get_filesystem_by_uuid(fs_uuid).is_none());
+
+        pool.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_filesystem_destroy_busy() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_filesystem_destroy_busy);
+    }
+
+    #[test]
+    pub fn real_test_filesystem_destroy_busy() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_filesystem_destroy_busy);
+    }
+
+    /// Verify that a filesystem with a file open is destroyed if force is
+    /// given, and that its thin device is kept as an orphaned device, which
+    /// can be deleted once the file is closed.
+    fn test_filesystem_destroy_force(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
+            .unwrap();
+
+        let fs_uuid = pool.create_filesystem("fsname", &dm, None).unwrap();
+        let thin_id = pool.get_filesystem_by_uuid(fs_uuid).unwrap().thin_id();
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&pool.get_filesystem_by_uuid(fs_uuid).unwrap().devnode()),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+
+        {
+            let _file = fs::File::create(tmp_dir.path().join("stratis_test.txt")).unwrap();
+
+            pool.destroy_filesystem(&dm, fs_uuid, true).unwrap();
+            assert!(pool.get_filesystem_by_uuid(fs_uuid).is_none());
+            assert_eq!(pool.orphaned_thin_ids, vec![thin_id]);
+        }
+
+        assert_eq!(pool.delete_orphaned_thin_devs(&dm).unwrap(), vec![thin_id]);
+        assert!(pool.mdv.filesystems().unwrap().0.is_empty());
+
+        pool.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_filesystem_destroy_force() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_filesystem_destroy_force);
+    }
+
+    #[test]
+    pub fn real_test_filesystem_destroy_force() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_filesystem_destroy_force);
+    }
+
+    /// Verify that a filesystem is grown whether or not it is mounted, that
+    /// its new size is recorded, and that it can not be shrunk.
+    fn test_set_filesystem_size(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
+            .unwrap();
+
+        let fs_uuid = pool.create_filesystem("fsname", &dm, None).unwrap();
+        let size = pool.get_filesystem_by_uuid(fs_uuid).unwrap().size();
+
+        assert_eq!(pool.set_filesystem_size(&dm, fs_uuid, size * 2u64).unwrap(),
+                   size * 2u64);
+        assert!(match pool.set_filesystem_size(&dm, fs_uuid, size) {
+                    Err(EngineError::Engine(ErrorEnum::Invalid, _)) => true,
+                    _ => false,
+                });
+
+        let tmp_dir = TempDir::new("stratis_testing").unwrap();
+        mount(Some(&pool.get_filesystem_by_uuid(fs_uuid).unwrap().devnode()),
+              tmp_dir.path(),
+              Some("xfs"),
+              MsFlags::empty(),
+              None as Option<&str>)
+                .unwrap();
+        let (total_before, _) = fs_usage(tmp_dir.path()).unwrap();
+        assert_eq!(pool.set_filesystem_size(&dm, fs_uuid, size * 3u64).unwrap(),
+                   size * 3u64);
+        let (total_after, _) = fs_usage(tmp_dir.path()).unwrap();
+        assert!(total_after > total_before);
+        umount(tmp_dir.path()).unwrap();
+
+        assert!(pool.audit(&dm).unwrap().is_clean());
+        pool.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_set_filesystem_size() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_set_filesystem_size);
+    }
+
+    #[test]
+    pub fn real_test_set_filesystem_size() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_set_filesystem_size);
+    }
+
+    /// Verify that a filesystem is found to be mounted while it is mounted
+    /// anywhere, even once it has been unmounted from some of its mount
+    /// points by someone else, and that it is not found to be mounted once
+    /// it has been unmounted from all of them.
+    fn test_filesystem_is_mounted(paths: &[&Path]) {
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
+            .unwrap();
+
+        let fs_uuid = pool.create_filesystem("fsname", &dm, None).unwrap();
+        let devnode = pool.get_filesystem_by_uuid(fs_uuid).unwrap().devnode();
+        assert!(!pool.get_filesystem_by_uuid(fs_uuid)
+                     .unwrap()
+                     .is_mounted()
+                     .unwrap());
+
+        let tmp_dirs = [TempDir::new("stratis_testing").unwrap(),
+                        TempDir::new("stratis_testing").unwrap()];
+        for tmp_dir in &tmp_dirs {
+            mount(Some(&devnode),
+                  tmp_dir.path(),
+                  Some("xfs"),
+                  MsFlags::empty(),
+                  None as Option<&str>)
+                    .unwrap();
+        }
+        {
+            let fs = pool.get_filesystem_by_uuid(fs_uuid).unwrap();
+            assert!(fs.is_mounted().unwrap());
+            assert_eq!(fs.mount_points().unwrap(),
+                       tmp_dirs
+                           .iter()
+                           .map(|d| d.path().to_owned())
+                           .collect::<Vec<_>>());
+        }
+
+        umount(tmp_dirs[0].path()).unwrap();
+        assert!(pool.get_filesystem_by_uuid(fs_uuid)
+                    .unwrap()
+                    .is_mounted()
+                    .unwrap());
+
+        umount(tmp_dirs[1].path()).unwrap();
+        assert!(!pool.get_filesystem_by_uuid(fs_uuid)
+                     .unwrap()
+                     .is_mounted()
+                     .unwrap());
+
+        pool.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_filesystem_is_mounted() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(1, 3),
+                                   test_filesystem_is_mounted);
+    }
+
+    #[test]
+    pub fn real_test_filesystem_is_mounted() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_filesystem_is_mounted);
+    }
+    /// Verify that the thin device left by a filesystem creation which was
+    /// interrupted before its metadata was saved is reported, but kept, when
+    /// the pool is set up, that its thin id is not allocated again, that the
+    /// filesystems whose metadata was saved are set up, and that the device
+    /// is deleted only when the pool is told to delete it.
+    fn test_orphaned_thin_dev(paths: &[&Path]) -> () {
+        let pool_uuid = Uuid::new_v4();
+        let dm = DM::new().unwrap();
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
+            .unwrap();
+        let fs_uuid = pool.create_filesystem("fsname", &dm, None).unwrap();
+
+        // Create a thin device, as create_filesystems() does, but save no
+        // metadata for it, as if the creation had been interrupted.
+        let orphan_id = pool.id_gen.new_id().unwrap();
+        let orphan_name = format_thin_name(pool_uuid, ThinRole::Filesystem(Uuid::new_v4()));
+        ThinDev::new(&dm,
+                     orphan_name.as_ref(),
+                     None,
+                     &pool.thin_pool,
+                     orphan_id,
+                     DEFAULT_FS_SIZE)
+                .unwrap()
+                .teardown(&dm)
+                .unwrap();
+        let flexdevs: FlexDevsSave = pool.record();
+        pool.teardown(&dm).unwrap();
+
+        let mut pool = ThinPool::setup(pool_uuid,
+                                       &dm,
+                                       &stratis_paths,
+                                       DATA_BLOCK_SIZE,
+                                       DATA_LOWATER,
+                                       &flexdevs,
+                                       None,
+                                       &mgr,
+                                       false,
+                                       true)
+                .unwrap();
+        assert!(pool.get_filesystem_by_uuid(fs_uuid).is_some());
+        assert_eq!(pool.audit(&dm).unwrap().orphaned_thin_devs, vec![orphan_id]);
+        ThinDev::setup(&dm,
+                       orphan_name.as_ref(),
+                       None,
+                       &pool.thin_pool,
+                       orphan_id,
+                       DEFAULT_FS_SIZE)
+                .unwrap()
+                .teardown(&dm)
+                .unwrap();
+
+        let new_uuid = pool.create_filesystem("new_fsname", &dm, None).unwrap();
+        assert!(pool.get_filesystem_by_uuid(new_uuid).unwrap().thin_id() != orphan_id);
+
+        assert_eq!(pool.delete_orphaned_thin_devs(&dm).unwrap(), vec![orphan_id]);
+        assert!(pool.audit(&dm).unwrap().orphaned_thin_devs.is_empty());
+        assert!(ThinDev::setup(&dm,
+                               orphan_name.as_ref(),
+                               None,
+                               &pool.thin_pool,
+                               orphan_id,
+                               DEFAULT_FS_SIZE)
+                        .is_err());
+        pool.teardown(&dm).unwrap();
+    }
+
+    #[test]
+    pub fn loop_test_orphaned_thin_dev() {
+        loopbacked::test_with_spec(loopbacked::DeviceLimits::Range(2, 3), test_orphaned_thin_dev);
+    }
+
+    #[test]
+    pub fn real_test_orphaned_thin_dev() {
+        real::test_with_spec(real::DeviceLimits::AtLeast(1), test_orphaned_thin_dev);
+    }
+
     /// Verify that destroy_filesystems actually deallocates the space
     /// from the thinpool, by attempting to reinstantiate it using the
     /// same thin id and verifying that it fails.
     fn test_thindev_destroy(paths: &[&Path]) -> () {
         let pool_uuid = Uuid::new_v4();
         let dm = DM::new().unwrap();
-        let mut mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
-        let mut pool = ThinPool::new(pool_uuid, &dm, DATA_BLOCK_SIZE, DATA_LOWATER, &mut mgr)
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
             .unwrap();
         let fs_name = "stratis_test_filesystem";
         let fs_uuid = pool.create_filesystem(&fs_name, &dm, None).unwrap();
@@ -837,16 +2854,16 @@ mod tests {
                                      None,
                                      &pool.thin_pool,
                                      thin_id,
-                                     DEFAULT_THIN_DEV_SIZE);
+                                     DEFAULT_FS_SIZE);
         assert!(thindev.is_ok());
-        pool.destroy_filesystem(&dm, fs_uuid).unwrap();
+        pool.destroy_filesystem(&dm, fs_uuid, false).unwrap();
 
         let thindev = ThinDev::setup(&dm,
                                      device_name.as_ref(),
                                      None,
                                      &pool.thin_pool,
                                      thin_id,
-                                     DEFAULT_THIN_DEV_SIZE);
+                                     DEFAULT_FS_SIZE);
         assert!(thindev.is_err());
         let flexdevs: FlexDevsSave = pool.record();
         pool.teardown(&dm).unwrap();
@@ -856,10 +2873,14 @@ mod tests {
         // thinpool, ::setup() will fail.
         let pool = ThinPool::setup(pool_uuid,
                                    &dm,
+                                   &stratis_paths,
                                    DATA_BLOCK_SIZE,
                                    DATA_LOWATER,
                                    &flexdevs,
-                                   &mgr)
+                                   None,
+                                   &mgr,
+                                   false,
+                                   true)
                 .unwrap();
 
         assert!(pool.get_filesystem_by_uuid(fs_uuid).is_none());
@@ -884,8 +2905,21 @@ mod tests {
     fn test_thinpool_expand(paths: &[&Path]) -> () {
         let pool_uuid = Uuid::new_v4();
         let dm = DM::new().unwrap();
-        let mut mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
-        let mut pool = ThinPool::new(pool_uuid, &dm, DATA_BLOCK_SIZE, DATA_LOWATER, &mut mgr)
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
             .unwrap();
         let fs_name = "stratis_test_filesystem";
         let fs_uuid = pool.create_filesystem(&fs_name, &dm, None).unwrap();
@@ -929,8 +2963,21 @@ mod tests {
     fn test_xfs_expand(paths: &[&Path]) -> () {
         let pool_uuid = Uuid::new_v4();
         let dm = DM::new().unwrap();
-        let mut mgr = BlockDevMgr::initialize(pool_uuid, paths, MIN_MDA_SECTORS, false).unwrap();
-        let mut pool = ThinPool::new(pool_uuid, &dm, DATA_BLOCK_SIZE, DATA_LOWATER, &mut mgr)
+        let stratis_paths = StratisPaths::from_env();
+        let mut mgr = BlockDevMgr::initialize(pool_uuid,
+                                              paths,
+                                              &[],
+                                              MIN_MDA_SECTORS,
+                                              CreateForce::default())
+            .unwrap();
+        let mut pool = ThinPool::new(pool_uuid,
+                                     &dm,
+                                     &stratis_paths,
+                                     DATA_BLOCK_SIZE,
+                                     DATA_LOWATER,
+                                     DEFAULT_MDV_SIZE,
+                                     None,
+                                     &mut mgr)
             .unwrap();
 
         // Create a filesytem as small as possible.  Allocate 1 MiB bigger than
@@ -9,19 +9,75 @@ use std::process::Command;
 
 use uuid::Uuid;
 
+use devicemapper::Bytes;
+
 use super::super::errors::{EngineError, EngineResult, ErrorEnum};
+use super::super::types::{FilesystemType, FsOptions};
+
 
+/// The SMART overall health assessment of the device at devnode, e.g.,
+/// "PASSED", according to smartctl. None if smartctl is not installed, or
+/// if the device does not support SMART.
+pub fn smart_health(devnode: &Path) -> Option<String> {
+    let result = match Command::new("smartctl").arg("-H").arg(devnode).output() {
+        Ok(result) => result,
+        Err(_) => return None,
+    };
+    // smartctl's exit status is a bit mask which is nonzero even for a
+    // device it could query, if the device reports problems, so only its
+    // output is considered.
+    let prefixes = ["SMART overall-health self-assessment test result:", "SMART Health Status:"];
+    String::from_utf8_lossy(&result.stdout)
+        .lines()
+        .filter_map(|line| {
+                        prefixes
+                            .iter()
+                            .find(|prefix| line.starts_with(*prefix))
+                            .map(|prefix| line[prefix.len()..].trim().to_owned())
+                    })
+        .next()
+}
 
-/// Create a filesystem on devnode.
-pub fn create_fs(devnode: &Path, uuid: Uuid) -> EngineResult<()> {
-    if Command::new("mkfs.xfs")
-           .arg("-f")
-           .arg("-q")
-           .arg(&devnode)
-           .arg("-m")
-           .arg(format!("uuid={}", uuid))
-           .status()?
-           .success() {
+/// Create a filesystem of the type options.fs_type on devnode with options.
+/// Options which are not specified are left to the type's mkfs command.
+pub fn create_fs(devnode: &Path, uuid: Uuid, options: &FsOptions) -> EngineResult<()> {
+    let mut command;
+    match options.fs_type {
+        FilesystemType::Xfs => {
+            command = Command::new("mkfs.xfs");
+            command
+                .arg("-f")
+                .arg("-q")
+                .arg(&devnode)
+                .arg("-m")
+                .arg(format!("uuid={}", uuid));
+            if let Some(block_size) = options.block_size {
+                command.arg("-b").arg(format!("size={}", *block_size));
+            }
+            if let Some(inode_size) = options.inode_size {
+                command.arg("-i").arg(format!("size={}", *inode_size));
+            }
+            if let Some(reflink) = options.reflink {
+                command.arg("-m").arg(format!("reflink={}", if reflink { 1 } else { 0 }));
+            }
+        }
+        FilesystemType::Ext4 => {
+            command = Command::new("mkfs.ext4");
+            command
+                .arg("-F")
+                .arg("-q")
+                .arg("-U")
+                .arg(format!("{}", uuid));
+            if let Some(block_size) = options.block_size {
+                command.arg("-b").arg(format!("{}", *block_size));
+            }
+            if let Some(inode_size) = options.inode_size {
+                command.arg("-I").arg(format!("{}", *inode_size));
+            }
+            command.arg(devnode);
+        }
+    }
+    if command.status()?.success() {
         Ok(())
     } else {
         let err_msg = format!("Failed to create new filesystem at {:?}", devnode);
@@ -29,9 +85,18 @@ pub fn create_fs(devnode: &Path, uuid: Uuid) -> EngineResult<()> {
     }
 }
 
+/// Expand the filesystem of type fs_type on devnode, which is mounted at
+/// mount_point, to fill its device.
+pub fn grow_fs(fs_type: FilesystemType, devnode: &Path, mount_point: &Path) -> EngineResult<()> {
+    match fs_type {
+        FilesystemType::Xfs => xfs_growfs(mount_point),
+        FilesystemType::Ext4 => resize2fs(devnode),
+    }
+}
+
 /// Use the xfs_growfs command to expand a filesystem mounted at the given
 /// mount point.
-pub fn xfs_growfs(mount_point: &Path) -> EngineResult<()> {
+fn xfs_growfs(mount_point: &Path) -> EngineResult<()> {
 
     let result = Command::new("xfs_growfs")
         .arg(mount_point)
@@ -51,10 +116,91 @@ pub fn xfs_growfs(mount_point: &Path) -> EngineResult<()> {
     }
 }
 
-/// Set a new UUID for filesystem on the devnode.
-pub fn set_uuid(devnode: &Path, uuid: Uuid) -> EngineResult<()> {
+/// Use the resize2fs command to expand the ext4 filesystem on devnode, which
+/// may be mounted, to fill its device.
+fn resize2fs(devnode: &Path) -> EngineResult<()> {
+
+    let result = Command::new("resize2fs").arg(devnode).output()?;
+
+    if result.status.success() {
+        Ok(())
+    } else {
+        let std_out_txt = String::from_utf8_lossy(&result.stdout);
+        let std_err_txt = String::from_utf8_lossy(&result.stderr);
+        let err_msg = format!("Failed to expand filesystem {:?} stdout: {} stderr: {}",
+                              devnode,
+                              std_out_txt,
+                              std_err_txt);
+        Err(EngineError::Engine(ErrorEnum::Error, err_msg))
+    }
+}
+
+/// Use the fstrim command to discard the unused blocks of a filesystem
+/// mounted at the given mount point.
+pub fn fstrim(mount_point: &Path) -> EngineResult<()> {
 
-    let result = Command::new("xfs_admin")
+    let result = Command::new("fstrim").arg(mount_point).output()?;
+
+    if result.status.success() {
+        Ok(())
+    } else {
+        let std_out_txt = String::from_utf8_lossy(&result.stdout);
+        let std_err_txt = String::from_utf8_lossy(&result.stderr);
+        let err_msg = format!("Failed to trim filesystem {:?} stdout: {} stderr: {}",
+                              mount_point,
+                              std_out_txt,
+                              std_err_txt);
+        Err(EngineError::Engine(ErrorEnum::Error, err_msg))
+    }
+}
+
+/// Use the xfs_repair command to repair an unmounted filesystem on devnode.
+pub fn xfs_repair(devnode: &Path) -> EngineResult<()> {
+
+    let result = Command::new("xfs_repair").arg(&devnode).output()?;
+
+    if result.status.success() {
+        Ok(())
+    } else {
+        let std_out_txt = String::from_utf8_lossy(&result.stdout);
+        let std_err_txt = String::from_utf8_lossy(&result.stderr);
+        let err_msg = format!("Failed to repair filesystem {:?} stdout: {} stderr: {}",
+                              devnode,
+                              std_out_txt,
+                              std_err_txt);
+        Err(EngineError::Engine(ErrorEnum::Error, err_msg))
+    }
+}
+
+/// Use xfs_repair in no modify mode to check the unmounted filesystem on
+/// devnode. Returns true if the filesystem was found to be damaged.
+pub fn xfs_is_damaged(devnode: &Path) -> EngineResult<bool> {
+
+    let result = Command::new("xfs_repair").arg("-n").arg(devnode).output()?;
+
+    match result.status.code() {
+        Some(0) => Ok(false),
+        Some(1) => Ok(true),
+        _ => {
+            let std_out_txt = String::from_utf8_lossy(&result.stdout);
+            let std_err_txt = String::from_utf8_lossy(&result.stderr);
+            let err_msg = format!("Failed to check filesystem {:?} stdout: {} stderr: {}",
+                                  devnode,
+                                  std_out_txt,
+                                  std_err_txt);
+            Err(EngineError::Engine(ErrorEnum::Error, err_msg))
+        }
+    }
+}
+
+/// Set a new UUID for the filesystem of type fs_type on the devnode.
+pub fn set_uuid(devnode: &Path, fs_type: FilesystemType, uuid: Uuid) -> EngineResult<()> {
+
+    let command = match fs_type {
+        FilesystemType::Xfs => "xfs_admin",
+        FilesystemType::Ext4 => "tune2fs",
+    };
+    let result = Command::new(command)
         .arg("-U")
         .arg(format!("{}", uuid))
         .arg(&devnode)
@@ -72,3 +218,43 @@ pub fn set_uuid(devnode: &Path, uuid: Uuid) -> EngineResult<()> {
         Err(EngineError::Engine(ErrorEnum::Error, err_msg))
     }
 }
+
+/// Use the wipefs command to find the signatures of filesystems, partition
+/// tables, and the like on devnode, without removing them.
+/// Returns the offset of each signature found.
+pub fn signature_offsets(devnode: &Path) -> EngineResult<Vec<Bytes>> {
+
+    let result = Command::new("wipefs")
+        .arg("--no-act")
+        .arg("--parsable")
+        .arg(devnode)
+        .output()?;
+
+    let std_out_txt = String::from_utf8_lossy(&result.stdout);
+    if !result.status.success() {
+        let std_err_txt = String::from_utf8_lossy(&result.stderr);
+        let err_msg = format!("Failed to find signatures on {:?} stdout: {} stderr: {}",
+                              devnode,
+                              std_out_txt,
+                              std_err_txt);
+        return Err(EngineError::Engine(ErrorEnum::Error, err_msg));
+    }
+
+    // Each line is "<offset>,<uuid>,<label>,<type>", with the offset in
+    // hexadecimal. Older versions of wipefs begin with a commented header.
+    let parse_offset = |line: &str| -> EngineResult<Bytes> {
+        let offset = line.split(',').next().unwrap_or("");
+        if offset.starts_with("0x") {
+            if let Ok(offset) = u64::from_str_radix(offset.trim_start_matches("0x"), 16) {
+                return Ok(Bytes(offset));
+            }
+        }
+        let err_msg = format!("Failed to parse wipefs output {:?} for {:?}", line, devnode);
+        Err(EngineError::Engine(ErrorEnum::Error, err_msg))
+    };
+    std_out_txt
+        .lines()
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(parse_offset)
+        .collect()
+}
@@ -9,8 +9,16 @@ use std::slice::{Iter, IterMut};
 use uuid::Uuid;
 
 use super::engine::{HasName, HasUuid};
+use super::errors::{EngineError, EngineResult, ErrorEnum};
+use super::types::RenameAction;
 
 
+/// An item whose name can be changed. Where the item is held in a Table,
+/// use Table::rename() instead, so that the table's name index follows.
+pub trait SetName: HasName {
+    fn set_name(&mut self, name: &str) -> ();
+}
+
 /// Map UUID and name to T items.
 #[derive(Debug)]
 pub struct Table<T: HasName + HasUuid> {
@@ -49,9 +57,9 @@ impl<'a, T: HasName + HasUuid> IntoIterator for &'a Table<T> {
 
 /// All operations are O(1).
 /// The implementation does not priviledge the name key over the UUID key
-/// in any way. They are both treated as constants once the item has been
-/// inserted. In order to rename a T item, it must be removed, renamed, and
-/// reinserted under the new name.
+/// in any way. The UUID is treated as a constant once the item has been
+/// inserted; the name may be changed only by means of rename(), which
+/// keeps the name index consistent with the item.
 impl<T: HasName + HasUuid> Table<T> {
     /// Empty this table of all its items, returning them in a vector.
     pub fn empty(self) -> Vec<T> {
@@ -181,6 +189,32 @@ impl<T: HasName + HasUuid> Table<T> {
     }
 }
 
+impl<T: HasName + HasUuid + SetName> Table<T> {
+    /// Rename the item corresponding to uuid to new_name, in place.
+    /// Returns NoSource if there is no such item, Identity if it is already
+    /// named new_name, and an AlreadyExists error if another item is named
+    /// new_name.
+    pub fn rename(&mut self, uuid: Uuid, new_name: &str) -> EngineResult<RenameAction> {
+        let index = match self.uuid_map.get(&uuid) {
+            Some(index) => *index,
+            None => return Ok(RenameAction::NoSource),
+        };
+
+        if self.items[index].name() == new_name {
+            return Ok(RenameAction::Identity);
+        }
+
+        if self.name_map.contains_key(new_name) {
+            return Err(EngineError::Engine(ErrorEnum::AlreadyExists, new_name.into()));
+        }
+
+        self.name_map.remove(self.items[index].name());
+        self.items[index].set_name(new_name);
+        self.name_map.insert(new_name.into(), index);
+        Ok(RenameAction::Renamed)
+    }
+}
+
 #[cfg(test)]
 mod tests {
 
@@ -188,8 +222,10 @@ mod tests {
     use uuid::Uuid;
 
     use super::super::engine::{HasName, HasUuid};
+    use super::super::errors::{EngineError, ErrorEnum};
+    use super::super::types::RenameAction;
 
-    use super::Table;
+    use super::{SetName, Table};
 
     #[derive(Debug)]
     struct TestThing {
@@ -247,6 +283,12 @@ mod tests {
         }
     }
 
+    impl SetName for TestThing {
+        fn set_name(&mut self, name: &str) {
+            self.name = name.to_owned();
+        }
+    }
+
     #[test]
     /// Remove a test object by its uuid.
     /// Mutate the removed test object.
@@ -363,4 +405,57 @@ mod tests {
         assert_eq!(t.get_by_name(&name).unwrap().stuff, thing_key2);
         assert_eq!(t.len(), 1);
     }
+
+    #[test]
+    /// Renaming an item to its own name does nothing.
+    fn rename_same_name() {
+        let mut t: Table<TestThing> = Table::default();
+        let uuid = Uuid::new_v4();
+        t.insert(TestThing::new("name", uuid));
+
+        assert_eq!(t.rename(uuid, "name").unwrap(), RenameAction::Identity);
+        table_invariant(&t);
+        assert_eq!(t.get_by_uuid(uuid).unwrap().name(), "name");
+        assert!(t.contains_name("name"));
+        assert_eq!(t.rename(Uuid::new_v4(), "name").unwrap(), RenameAction::NoSource);
+    }
+
+    #[test]
+    /// Renaming an item to an unused name changes its name, and it is found
+    /// by the new name and not by the old; the other items are untouched.
+    fn rename_new_name() {
+        let mut t: Table<TestThing> = Table::default();
+        let uuid = Uuid::new_v4();
+        let other_uuid = Uuid::new_v4();
+        t.insert(TestThing::new("name", uuid));
+        t.insert(TestThing::new("other", other_uuid));
+        let thing_key = t.get_by_uuid(uuid).unwrap().stuff;
+
+        assert_eq!(t.rename(uuid, "new_name").unwrap(), RenameAction::Renamed);
+        table_invariant(&t);
+        assert!(!t.contains_name("name"));
+        assert_eq!(t.get_by_name("new_name").unwrap().stuff, thing_key);
+        assert_eq!(t.get_by_uuid(uuid).unwrap().name(), "new_name");
+        assert_eq!(t.get_by_uuid(other_uuid).unwrap().name(), "other");
+        assert_eq!(t.len(), 2);
+    }
+
+    #[test]
+    /// Renaming an item to the name of another item is refused, and both
+    /// items keep their names.
+    fn rename_colliding_name() {
+        let mut t: Table<TestThing> = Table::default();
+        let uuid = Uuid::new_v4();
+        let other_uuid = Uuid::new_v4();
+        t.insert(TestThing::new("name", uuid));
+        t.insert(TestThing::new("other", other_uuid));
+
+        assert!(match t.rename(uuid, "other") {
+                    Err(EngineError::Engine(ErrorEnum::AlreadyExists, _)) => true,
+                    _ => false,
+                });
+        table_invariant(&t);
+        assert_eq!(t.get_by_name("name").unwrap().uuid(), uuid);
+        assert_eq!(t.get_by_name("other").unwrap().uuid(), other_uuid);
+    }
 }
@@ -2,12 +2,72 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
 
+use std::fmt;
+use std::path::PathBuf;
+
+use chrono::{DateTime, Utc};
 use uuid::Uuid;
 
+use devicemapper::{Bytes, DataBlocks, IEC, Sectors, ThinDevId};
+
+use super::errors::{EngineError, EngineResult, ErrorEnum};
+
 pub type DevUuid = Uuid;
 pub type FilesystemUuid = Uuid;
 pub type PoolUuid = Uuid;
 
+/// The size of a new filesystem, unless its pool or the request for it
+/// specifies a different size.
+pub const DEFAULT_FS_SIZE: Sectors = Sectors(2 * IEC::Gi); // 1 TiB
+
+/// The percentage of a filesystem's space which may be used before clients
+/// are warned that it is low on space, unless its pool specifies a
+/// different percentage.
+pub const DEFAULT_FS_SPACE_WATERMARK: u8 = 85;
+
+/// How little of the data device of a pool's thinpool may be free before the
+/// data device is extended, unless the pool specifies otherwise; 512 MiB.
+pub const DEFAULT_DATA_LOW_WATER: DataLowWater = DataLowWater::Sectors(Sectors(IEC::Mi));
+
+/// The size of a new pool's metadata volume, unless the request for the
+/// pool specifies a different size.
+pub const DEFAULT_MDV_SIZE: Sectors = Sectors(32 * IEC::Ki); // 16 MiB
+
+/// The smallest metadata volume a pool may be created with.
+pub const MIN_MDV_SIZE: Sectors = DEFAULT_MDV_SIZE;
+
+/// The minimum size of a device that can be added to a pool. Besides the
+/// BDA, which includes the MDA regions, a device must be able to hold its
+/// share of the pool's metadata devices and leave room for a usable
+/// thin pool.
+pub const MIN_DEV_SIZE: Bytes = Bytes(IEC::Gi);
+
+/// The number of pools an engine may have, unless configured otherwise.
+pub const DEFAULT_MAX_POOLS: usize = 1024;
+
+/// The number of filesystems, including snapshots, a pool may have, unless
+/// configured otherwise.
+pub const DEFAULT_MAX_FILESYSTEMS_PER_POOL: usize = 8192;
+
+/// Limits on the resources an engine may create.
+/// Pools and filesystems which already exist when an engine is set up are
+/// not subject to the limits, but no more can be created while a limit is
+/// exceeded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EngineLimits {
+    pub max_pools: usize,
+    pub max_filesystems_per_pool: usize,
+}
+
+impl Default for EngineLimits {
+    fn default() -> EngineLimits {
+        EngineLimits {
+            max_pools: DEFAULT_MAX_POOLS,
+            max_filesystems_per_pool: DEFAULT_MAX_FILESYSTEMS_PER_POOL,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub enum RenameAction {
     Identity,
@@ -15,6 +75,304 @@ pub enum RenameAction {
     Renamed,
 }
 
+/// The result of a request to create something, which is returned whether
+/// or not it had to be created.
+#[derive(Debug, PartialEq, Eq)]
+pub enum CreateAction<T> {
+    /// What was requested already existed.
+    Identity(T),
+    Created(T),
+}
+
+impl<T> CreateAction<T> {
+    /// Whether anything was created.
+    pub fn is_changed(&self) -> bool {
+        match *self {
+            CreateAction::Identity(_) => false,
+            CreateAction::Created(_) => true,
+        }
+    }
+
+    /// What was requested, whether or not it was created.
+    pub fn into_inner(self) -> T {
+        match self {
+            CreateAction::Identity(t) |
+            CreateAction::Created(t) => t,
+        }
+    }
+}
+
+/// The result of a request to destroy something.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DestroyAction<T> {
+    /// What was destroyed.
+    Destroyed(T),
+    /// There was nothing to destroy.
+    NotFound,
+}
+
+impl<T> DestroyAction<T> {
+    /// Whether anything was destroyed.
+    pub fn is_changed(&self) -> bool {
+        match *self {
+            DestroyAction::Destroyed(_) => true,
+            DestroyAction::NotFound => false,
+        }
+    }
+}
+
+/// The failure of a request to destroy a number of things, some of which
+/// may have been destroyed before it failed.
+#[derive(Debug)]
+pub struct DestroyError<T> {
+    /// What was destroyed before the failure.
+    pub destroyed: T,
+    pub error: EngineError,
+}
+
+impl<T> fmt::Display for DestroyError<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.error)
+    }
+}
+
+impl<T> From<DestroyError<T>> for EngineError {
+    fn from(err: DestroyError<T>) -> EngineError {
+        err.error
+    }
+}
+
+/// The type of the filesystem made on a Stratis filesystem's thin device.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FilesystemType {
+    Xfs,
+    Ext4,
+}
+
+impl FilesystemType {
+    /// The filesystem type named name, as the kernel names it.
+    /// Returns an Invalid error if there is no such type.
+    pub fn new(name: &str) -> EngineResult<FilesystemType> {
+        match name {
+            "xfs" => Ok(FilesystemType::Xfs),
+            "ext4" => Ok(FilesystemType::Ext4),
+            _ => {
+                let err_msg = format!("filesystem type {:?} is not supported", name);
+                Err(EngineError::Engine(ErrorEnum::Invalid, err_msg))
+            }
+        }
+    }
+
+    /// The name of the filesystem type, as the kernel names it.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            FilesystemType::Xfs => "xfs",
+            FilesystemType::Ext4 => "ext4",
+        }
+    }
+}
+
+/// Filesystems are XFS unless another type is requested.
+impl Default for FilesystemType {
+    fn default() -> FilesystemType {
+        FilesystemType::Xfs
+    }
+}
+
+/// Options for making the filesystem of a new Stratis filesystem.
+/// An option which is not given takes the default of the filesystem type's
+/// mkfs command.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FsOptions {
+    /// The type of the filesystem.
+    pub fs_type: FilesystemType,
+    /// The size of a filesystem block.
+    pub block_size: Option<Bytes>,
+    /// The size of an inode.
+    pub inode_size: Option<Bytes>,
+    /// Whether data blocks may be shared between files.
+    pub reflink: Option<bool>,
+}
+
+/// The checks on the devices of a new pool which are overridden. Each must
+/// be asked for separately, so that a client overrides no more than it
+/// intends to.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CreateForce {
+    /// Overwrite devices which carry the signature of another application,
+    /// e.g., a filesystem or a partition table.
+    pub overwrite_signatures: bool,
+    /// Accept devices smaller than MIN_DEV_SIZE, so long as they can hold
+    /// their Stratis metadata.
+    pub ignore_size: bool,
+    /// Reuse devices which appear to be Stratis devices, but which have no
+    /// valid static header, e.g., because their initialization was
+    /// interrupted.
+    pub reuse_partial: bool,
+}
+
+/// The description by which a key is found in the kernel keyring.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct KeyDescription(String);
+
+impl KeyDescription {
+    /// Make a key description.
+    /// Returns an Invalid error if description is empty or contains
+    /// whitespace or control characters, since it must be a single word of
+    /// a devicemapper table.
+    pub fn new(description: &str) -> EngineResult<KeyDescription> {
+        if description.is_empty() ||
+           description
+               .chars()
+               .any(|c| c.is_whitespace() || c.is_control()) {
+            let err_msg = format!("key description {:?} is invalid", description);
+            return Err(EngineError::Engine(ErrorEnum::Invalid, err_msg));
+        }
+        Ok(KeyDescription(description.to_owned()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+/// How little of the data device of a pool's thinpool may be free before
+/// the data device is extended: once less than this is free, it is.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DataLowWater {
+    /// A percentage of the size of the data device.
+    Percent(u8),
+    /// A number of sectors.
+    Sectors(Sectors),
+}
+
+/// Who, according to its contents, a block device belongs to.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DevOwnership {
+    /// The device belongs to a Stratis pool, as the device with this UUID.
+    Ours(PoolUuid, DevUuid),
+    /// The device is empty.
+    Unowned,
+    /// The device holds data which Stratis does not recognize.
+    Theirs,
+}
+
+/// What is known of a block device which is a candidate for a pool.
+#[derive(Debug, PartialEq, Eq)]
+pub struct AvailableBlockDev {
+    /// Who the device belongs to.
+    pub ownership: DevOwnership,
+    /// The size of the device.
+    pub size: Bytes,
+    /// Whether the device is at least MIN_DEV_SIZE, so that it may be
+    /// added to a pool.
+    pub meets_min_size: bool,
+}
+
+/// The identity of a block device, as reported by its hardware.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HardwareInfo {
+    /// The model of the device.
+    pub model: Option<String>,
+    /// The serial number of the device, which identifies it across
+    /// reboots, whatever its device node.
+    pub serial: Option<String>,
+}
+
+/// The Stratis metadata found on a device.
+#[derive(Debug, PartialEq, Eq)]
+pub struct DeviceMetadata {
+    /// The UUID of the pool to which the device belongs.
+    pub pool_uuid: PoolUuid,
+    /// The UUID of the device.
+    pub dev_uuid: DevUuid,
+    /// The size of the device when it was added to its pool.
+    pub dev_size: Sectors,
+    /// The offset of the metadata area, i.e., the size of the static
+    /// header.
+    pub mda_offset: Sectors,
+    /// The offset of the first sector following the metadata, i.e., the
+    /// number of sectors reserved for the metadata at the start of the
+    /// device.
+    pub data_offset: Sectors,
+    /// The time the device was added to its pool.
+    pub initialization_time: DateTime<Utc>,
+    /// The time the pool's metadata was most recently written to the
+    /// device, if ever.
+    pub last_update_time: Option<DateTime<Utc>>,
+}
+
+/// Drift between a pool's filesystems as the engine knows them, as they are
+/// recorded in the pool's metadata, and as their devices exist.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AuditReport {
+    /// Filesystems which are recorded but which the pool does not have.
+    pub unknown_records: Vec<FilesystemUuid>,
+    /// Filesystems which the pool has but which are not recorded.
+    pub unrecorded_filesystems: Vec<FilesystemUuid>,
+    /// Filesystems whose device is not the recorded size, with the
+    /// recorded size and the size of the device.
+    pub size_mismatches: Vec<(FilesystemUuid, Sectors, Sectors)>,
+    /// Thin devices which the pool has but which no filesystem is recorded
+    /// for, e.g., because a filesystem creation was interrupted. They are
+    /// kept until the pool is explicitly told to delete them.
+    pub orphaned_thin_devs: Vec<ThinDevId>,
+    /// Filesystem records in the pool's metadata which can not be read.
+    pub unreadable_records: Vec<PathBuf>,
+}
+
+impl AuditReport {
+    /// Whether no drift was found.
+    pub fn is_clean(&self) -> bool {
+        *self == AuditReport::default()
+    }
+}
+
+/// How much of a filesystem's thin device is backed by data blocks of its
+/// pool.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AllocationInfo {
+    /// The logical size of the filesystem's thin device.
+    pub size: Sectors,
+    /// The number of the pool's data blocks allocated to the filesystem.
+    pub allocated_blocks: DataBlocks,
+    /// The number of sectors in the data blocks allocated to the filesystem.
+    pub allocated: Sectors,
+    /// An estimate of the fragmentation of the filesystem's allocation: the
+    /// percentage of the blocks up to its highest allocated block which are
+    /// not allocated.
+    pub fragmentation: u8,
+}
+
+/// The outcome of a check of a pool's state.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolCheckReport {
+    /// The outcome of any extension of the pool's data device.
+    pub extend_state: PoolExtendState,
+    /// Whether the pool's thinpool metadata is low on space, even after any
+    /// attempt to extend it.
+    pub metadata_low: bool,
+    /// Filesystems found to be inconsistent with their devices.
+    pub inconsistent_filesystems: Vec<FilesystemUuid>,
+    /// Filesystems which use more of their space than the pool's watermark.
+    pub space_low_filesystems: Vec<FilesystemUuid>,
+    /// The number of temp files, left in the pool's MDV by saves which were
+    /// interrupted, which were removed.
+    pub temp_files_removed: u64,
+    /// Filesystem records which could not be read when the pool was set up,
+    /// so that their filesystems were not set up.
+    pub unreadable_records: Vec<PathBuf>,
+}
+
+/// The outcome of a check of all of an engine's pools.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CheckReport {
+    /// The report of each pool which was checked.
+    pub pools: Vec<(PoolUuid, PoolCheckReport)>,
+    /// The pools which could not be checked, and why.
+    pub failed: Vec<(PoolUuid, String)>,
+}
+
 /// See Design Doc section 10.2.1 for more details.
 #[derive(Debug, PartialEq, Eq)]
 pub enum BlockDevState {
@@ -25,9 +383,41 @@ pub enum BlockDevState {
     InUse,
 }
 
+/// The tier to which a pool's blockdev belongs, which determines what is
+/// placed on it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BlockDevTier {
+    /// The blockdev holds the pool's data, and everything else which is not
+    /// placed in the metadata tier.
+    Data,
+    /// The blockdev holds the thinpool's metadata device and its spare.
+    /// If a pool has no blockdevs in this tier, these are placed in the
+    /// data tier.
+    Metadata,
+}
+
+/// The outcome of the most recent check of whether a pool's data device
+/// needed to be extended.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PoolExtendState {
+    /// No extension was necessary.
+    Good,
+    /// An extension was necessary and succeeded.
+    Extended,
+    /// An extension was necessary but failed, e.g., the pool is out of room.
+    Failed,
+}
+
+/// Get the u16 value of this PoolExtendState constructor.
+impl From<PoolExtendState> for u16 {
+    fn from(s: PoolExtendState) -> u16 {
+        s as u16
+    }
+}
+
 /// Redundancy classifications which the engine allows for pools.
 custom_derive! {
-    #[derive(Debug, Eq, PartialEq, EnumDisplay,
+    #[derive(Clone, Copy, Debug, Eq, PartialEq, EnumDisplay,
              IterVariants(RedundancyVariants))]
     #[allow(non_camel_case_types)]
     /// Redundancy specification for a pool.
@@ -42,3 +432,13 @@ impl From<Redundancy> for u16 {
         r as u16
     }
 }
+
+impl Redundancy {
+    /// The number of its blockdevs which a pool of this redundancy can be
+    /// set up without.
+    pub fn max_missing_devices(&self) -> usize {
+        match *self {
+            Redundancy::NONE => 0,
+        }
+    }
+}
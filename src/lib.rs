@@ -17,6 +17,9 @@ extern crate term;
 extern crate rand;
 extern crate serde;
 extern crate mnt;
+extern crate libc;
+extern crate sha2;
+extern crate hmac;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde_json;